@@ -0,0 +1,127 @@
+//! Correlation of the bytes changing in a capture with the times actions were taken on the devices
+
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use rebus_core::config::ebusd::type_name;
+use rebus_core::layer2::reader::BusReader;
+use rebus_core::layer2::BusEvent;
+use rebus_core::layer7::analysis::{Analyser, Correlation};
+use rebus_core::layer7::Part;
+use rebus_core::transport::capture::Chunk;
+
+use crate::read_chunks;
+
+/// A time of the capture at which something was done, e.g. a setting changed on a panel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub at: Duration,
+    pub label: String,
+}
+
+/// `SECONDS[:LABEL]`, the seconds being counted from the start of the capture
+pub fn marker(s: &str) -> Result<Marker, String> {
+    let (seconds, label) = s.split_once(':').unwrap_or((s, ""));
+    let at = seconds.parse().ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("`{}` is not a number of seconds", seconds))?;
+    Ok(Marker { at, label: label.to_string() })
+}
+
+/// Options of `analyse`
+pub struct AnalyseArgs<'a> {
+    pub capture: &'a Path,
+    pub markers: &'a [Marker],
+    pub window: Duration,
+    pub id_length: usize,
+    /// Print the bytes following no marker too
+    pub all: bool,
+}
+
+/// The telegrams of the chunks, each one timed by the chunk completing it
+fn analyse_chunks(chunks: &[Chunk], id_length: usize) -> Analyser {
+    let mut analyser = Analyser::new(id_length);
+    let mut reader = BusReader::new();
+    for chunk in chunks {
+        for c in &chunk.bytes {
+            if let Some(BusEvent::Telegram(packet)) = reader.read_byte(*c) {
+                analyser.push(chunk.at, &packet);
+            }
+        }
+    }
+    analyser
+}
+
+/// `b510 0000 m2`, then the time of each change with the marker it follows
+fn format_correlation(correlation: &Correlation, markers: &[Marker], window: Duration) -> String {
+    let prefix = if correlation.part == Part::Master { "m" } else { "s" };
+    let mut line = format!("{} {}{}:", correlation.key, prefix, correlation.offset);
+    if !markers.is_empty() {
+        line += &format!(" {}/{} marker(s),", correlation.matched, markers.len());
+    }
+    line += &format!(" {} change(s)", correlation.changes.len());
+    for at in &correlation.changes {
+        line += &format!(" {:.1}s", at.as_secs_f64());
+        if let Some(marker) = markers.iter().find(|m| *at >= m.at && *at <= m.at + window) {
+            line += &format!(" ({})", if marker.label.is_empty() { format!("{:.1}s", marker.at.as_secs_f64()) } else { marker.label.clone() });
+        }
+    }
+    if !correlation.suggestions.is_empty() {
+        let types: Vec<String> = correlation.suggestions.iter().map(|t| type_name(*t)).collect();
+        line += &format!(", maybe {}", types.join(" "));
+    }
+    line
+}
+
+/// Print the bytes of a capture which changed, those following the markers first
+pub fn analyse(args: AnalyseArgs) -> ExitCode {
+    let chunks = match read_chunks(args.capture) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            eprintln!("{}: {}", args.capture.display(), e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let analyser = analyse_chunks(&chunks, args.id_length);
+    let times: Vec<Duration> = args.markers.iter().map(|m| m.at).collect();
+    for correlation in analyser.correlate(&times, args.window) {
+        if args.markers.is_empty() || args.all || correlation.matched > 0 {
+            println!("{}", format_correlation(&correlation, args.markers, args.window));
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rebus_core::layer2::encoder::{encode_master, encode_slave};
+    use rebus_core::layer2::{Packet, EBUS_ACKOK, EBUS_SYN};
+
+    fn chunk(at: u64, packet: &Packet) -> Chunk {
+        let mut bytes = encode_master(packet).to_vec();
+        bytes.push(EBUS_ACKOK);
+        bytes.extend(encode_slave(packet));
+        bytes.extend([EBUS_ACKOK, EBUS_SYN]);
+        Chunk { at: Duration::from_secs(at), bytes }
+    }
+
+    #[test]
+    fn changes_are_timed_by_chunk() {
+        let request = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d, 0x2d, 0x00]).unwrap();
+        let chunks: Vec<Chunk> = [(0, 0x00), (30, 0x00), (61, 0x01), (90, 0x01), (121, 0x00)].iter()
+            .map(|(at, value)| chunk(*at, &request.clone().with_response(&[*value]).unwrap()))
+            .collect();
+        let markers = [marker("60:eco on").unwrap(), marker("120").unwrap()];
+        assert_eq!(markers[1], Marker { at: Duration::from_secs(120), label: String::new() });
+        assert!(marker("1m").is_err());
+
+        let analyser = analyse_chunks(&chunks, 2);
+        let window = Duration::from_secs(5);
+        let correlations = analyser.correlate(&[markers[0].at, markers[1].at], window);
+        assert_eq!(correlations.len(), 1);
+        assert_eq!(format_correlation(&correlations[0], &markers, window),
+            "b509 0d2d s0: 2/2 marker(s), 2 change(s) 61.0s (eco on) 121.0s (120.0s), maybe BI0 BCD D1C UCH");
+    }
+}
@@ -0,0 +1,442 @@
+//! `rebus serve`, the daemon: the servers share the bus handle, a task running on the tokio runtime
+//! of the main thread keeping what is known of the bus up to date with its events
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::net::TcpListener;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::SystemTime;
+
+use clap::Args;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, watch};
+
+use rebus_core::layer2::{BusEvent, Packet};
+use rebus_core::layer7::identification::Inventory;
+use rebus_core::layer7::paged::PagedRecords;
+use rebus_core::layer7::types::DecodeError;
+use rebus_core::layer7::{DecodedMessage, MessageDefinition};
+use rebus_core::transport::asynchronous::{BusHandle, Published};
+use rebus_core::transport::{self, Transport};
+
+use crate::ebusd_server;
+#[cfg(feature = "http")]
+use crate::http;
+#[cfg(feature = "mqtt")]
+use crate::mqtt;
+use crate::{decode_responses, no_request, Context, Stats};
+
+/// Options of `serve`
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Port of the server speaking the ebusd protocol
+    #[arg(long, default_value_t = ebusd_server::DEFAULT_PORT)]
+    pub port: u16,
+    /// Accept connections from this host only
+    #[arg(long)]
+    pub localhost: bool,
+    /// Port of the HTTP API
+    #[cfg(feature = "http")]
+    #[arg(long, value_name = "PORT")]
+    pub http: Option<u16>,
+    /// Address of the HTTP API, the loopback by default since the API writes to the bus without
+    /// authentication
+    #[cfg(feature = "http")]
+    #[arg(long, value_name = "HOST", default_value = "127.0.0.1")]
+    pub http_host: String,
+    #[cfg(feature = "mqtt")]
+    #[command(flatten)]
+    pub mqtt: mqtt::MqttArgs,
+}
+
+/// What is known of the bus
+#[derive(Default)]
+pub struct State {
+    pub inventory: Inventory,
+    pub stats: Stats,
+    /// Last message decoded by circuit and name, with the time it was received
+    pub values: BTreeMap<(String, String), (SystemTime, DecodedMessage)>,
+    /// Addresses seen sending telegrams
+    pub masters: BTreeSet<u8>,
+    pub connected: bool,
+}
+
+/// An event of the bus, with the decoding of its telegram
+pub struct Observed {
+    /// Streamed by the HTTP API only
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub event: BusEvent,
+    pub decoded: Option<Result<DecodedMessage, DecodeError>>,
+}
+
+impl Observed {
+    /// The message decoded from the telegram, if any
+    pub fn message(&self) -> Option<&DecodedMessage> {
+        self.decoded.as_ref().and_then(|decoded| decoded.as_ref().ok())
+    }
+}
+
+/// The bus shared by the servers
+pub struct Daemon {
+    pub context: Context,
+    pub bus: BusHandle,
+    /// The runtime of the bus task, for the servers running in their own threads
+    runtime: Handle,
+    state: Mutex<State>,
+    stopped: watch::Sender<bool>,
+}
+
+impl Daemon {
+    /// Start the bus task on the current runtime, returning the daemon with the events to `follow`
+    pub fn start<T: Transport + Send + 'static>(context: Context, transport: T) -> (Daemon, broadcast::Receiver<Published>) {
+        let connected = transport.is_connected();
+        let (bus, _) = BusHandle::spawn(transport);
+        let events = bus.subscribe();
+        let daemon = Daemon {
+            context,
+            bus,
+            runtime: Handle::current(),
+            state: Mutex::new(State { connected, ..State::default() }),
+            stopped: watch::Sender::new(false),
+        };
+        (daemon, events)
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait for a future from a thread outside the runtime, such as a sending on the bus
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// Run a future on the runtime without waiting for it
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        self.runtime.spawn(future);
+    }
+
+    /// Send telegrams one after the other without waiting for them, their responses being
+    /// published as any telegram
+    pub fn queue(&self, requests: Vec<Packet>) {
+        let bus = self.bus.clone();
+        self.spawn(async move {
+            for request in requests {
+                let _ = bus.send(request).await;
+            }
+        });
+    }
+
+    /// The events of the bus from now on, decoded
+    pub fn subscribe(&self) -> Events<'_> {
+        Events::new(self, self.bus.subscribe())
+    }
+
+    /// Stop serving the bus
+    pub fn stop(&self) {
+        self.stopped.send_replace(true);
+    }
+
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.borrow()
+    }
+
+    /// Wait for the daemon to be stopped
+    pub async fn stopped(&self) {
+        // the sender lives as long as the daemon
+        let _ = self.stopped.subscribe().wait_for(|stopped| *stopped).await;
+    }
+
+    /// Keep what is known of the bus up to date with its events until the daemon is stopped, the
+    /// errors of the reads being printed
+    pub async fn follow(&self, mut events: broadcast::Receiver<Published>, name: &str) {
+        let mut pages = PagedRecords::new();
+        loop {
+            tokio::select! {
+                published = events.recv() => match published {
+                    Ok(Ok(event)) => self.handle(&event, &mut pages),
+                    Ok(Err(e)) => eprintln!("{}: {}", name, e),
+                    Err(RecvError::Lagged(missed)) => eprintln!("{}: {} events missed", name, missed),
+                    // the end of a replayed capture, still served until the daemon is stopped
+                    Err(RecvError::Closed) => break,
+                },
+                () = self.stopped() => return,
+            }
+        }
+        self.stopped().await
+    }
+
+    fn handle<'a>(&'a self, event: &BusEvent, pages: &mut PagedRecords<'a>) {
+        let catalogue = &self.context.settings.catalogue;
+        let mut state = self.state();
+        let State { inventory, stats, .. } = &mut *state;
+        stats.count(event, catalogue, inventory);
+        match event {
+            BusEvent::Telegram(packet) => {
+                state.masters.insert(packet.source());
+                let decoded = catalogue.find(packet, &state.inventory).and_then(|d| pages.decode(d, packet));
+                if let Some(Ok(message)) = decoded {
+                    state.values.insert((message.circuit.clone(), message.name.clone()), (SystemTime::now(), message));
+                }
+            },
+            BusEvent::ConnectionLost => state.connected = false,
+            BusEvent::ConnectionRestored => state.connected = true,
+            _ => (),
+        }
+    }
+}
+
+/// The events of the bus received by a server, their telegrams decoded
+pub struct Events<'a> {
+    daemon: &'a Daemon,
+    events: broadcast::Receiver<Published>,
+    pages: PagedRecords<'a>,
+}
+
+impl<'a> Events<'a> {
+    /// Decode the events of a subscription to the bus of the daemon
+    pub fn new(daemon: &'a Daemon, events: broadcast::Receiver<Published>) -> Events<'a> {
+        Events { daemon, events, pages: PagedRecords::new() }
+    }
+
+    fn observe(&mut self, event: BusEvent) -> Observed {
+        let decoded = match &event {
+            BusEvent::Telegram(packet) => {
+                let catalogue = &self.daemon.context.settings.catalogue;
+                let definition = catalogue.find(packet, &self.daemon.state().inventory);
+                definition.and_then(|d| self.pages.decode(d, packet))
+            },
+            _ => None,
+        };
+        Observed { event, decoded }
+    }
+
+    /// The next event, `None` once the bus task has stopped. The errors of the reads are left to
+    /// the daemon, and the events missed by a late receiver are lost.
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub async fn recv(&mut self) -> Option<Observed> {
+        loop {
+            match self.events.recv().await {
+                Ok(Ok(event)) => return Some(self.observe(event)),
+                Ok(Err(_)) | Err(RecvError::Lagged(_)) => (),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The next event received so far, without waiting
+    pub fn try_recv(&mut self) -> Option<Observed> {
+        loop {
+            match self.events.try_recv() {
+                Ok(Ok(event)) => return Some(self.observe(event)),
+                Ok(Err(_)) | Err(TryRecvError::Lagged(_)) => (),
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Send the requests of a message on the bus and decode the responses
+pub async fn query(bus: &BusHandle, definition: &MessageDefinition, requests: Vec<Packet>) -> Result<Result<DecodedMessage, DecodeError>, transport::Error> {
+    if requests.is_empty() {
+        return Err(no_request());
+    }
+    let mut responses = Vec::new();
+    for request in requests {
+        responses.push(bus.send(request).await?);
+    }
+    Ok(decode_responses(definition, &responses))
+}
+
+/// Serve the bus until the process is killed
+pub fn serve(context: Context, args: &ServeArgs) -> ExitCode {
+    let (transport, name) = match context.open() {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let host = if args.localhost { "127.0.0.1" } else { "0.0.0.0" };
+    let listener = match TcpListener::bind((host, args.port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{}:{}: {}", host, args.port, e);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    #[cfg(feature = "http")]
+    let http = match args.http.map(|port| (port, TcpListener::bind((args.http_host.as_str(), port)))) {
+        Some((port, Err(e))) => {
+            eprintln!("{}:{}: {}", args.http_host, port, e);
+            return ExitCode::FAILURE;
+        },
+        Some((_, Ok(listener))) => Some(listener),
+        None => None,
+    };
+
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let (daemon, events) = {
+        let _runtime = runtime.enter();
+        Daemon::start(context, transport)
+    };
+    // shared with the tasks of the HTTP API
+    let daemon = Arc::new(daemon);
+    let status = thread::scope(|scope| {
+        let server = scope.spawn(|| {
+            let result = ebusd_server::serve(&daemon, listener);
+            daemon.stop();
+            result
+        });
+        #[cfg(feature = "mqtt")]
+        if let Some(broker) = &args.mqtt.mqtt {
+            scope.spawn(|| mqtt::run(&daemon, &args.mqtt, broker));
+        }
+        runtime.block_on(async {
+            #[cfg(feature = "http")]
+            if let Some(listener) = http {
+                let (daemon, host) = (Arc::clone(&daemon), args.http_host.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = http::serve(Arc::clone(&daemon), listener).await {
+                        eprintln!("{}: {}", host, e);
+                    }
+                    daemon.stop();
+                });
+            }
+            daemon.follow(events, &name).await
+        });
+        server.join()
+    });
+    match status {
+        Ok(Err(e)) => {
+            eprintln!("{}:{}: {}", host, args.port, e);
+            ExitCode::FAILURE
+        },
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+/// What the tests of the servers share: a catalogue, a capture to replay and a guard stopping the daemon
+#[cfg(test)]
+pub mod fixture {
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use clap::Parser;
+    use rebus_core::layer2::encoder::{encode_master, encode_slave};
+    use rebus_core::layer2::{Packet, EBUS_ACKOK, EBUS_SYN};
+    use rebus_core::layer7::types::DataType;
+    use rebus_core::layer7::{FieldDefinition, MessageDefinition, MessageKind, Part};
+    use rebus_core::transport::capture::{Chunk, Pacing, Replay};
+    use rebus_core::transport::Transport;
+    use tokio::runtime::Runtime;
+
+    use super::Daemon;
+    use crate::{Cli, Command, Context};
+
+    /// The context and the command of `rebus serve` run with `args`, the catalogue holding
+    /// `bai.FlowTemp`, read from 08, and `hc1.DesiredTemp`, written without destination
+    pub fn context(args: &[&str]) -> (Context, Command) {
+        let cli = Cli::try_parse_from(["rebus", "serve"].iter().chain(args)).unwrap();
+        let Ok(mut context) = Context::new(cli.options) else {
+            unreachable!()
+        };
+        let mut flow_temp = MessageDefinition::new("bai", "FlowTemp", MessageKind::Read, 0xb5, 0x09);
+        flow_temp.destination = Some(0x08);
+        flow_temp.id = vec![0x0d, 0x18, 0x00];
+        let mut temp = FieldDefinition::new("temp", Part::Slave, 0, DataType::Data2c);
+        temp.unit = Some("°C".to_string());
+        flow_temp.fields.push(temp);
+        let mut desired_temp = MessageDefinition::new("hc1", "DesiredTemp", MessageKind::Write, 0xb5, 0x10);
+        desired_temp.fields.push(FieldDefinition::new("temp", Part::Master, 0, DataType::Data1c));
+        context.settings.catalogue = [flow_temp, desired_temp].into_iter().collect();
+        (context, cli.command)
+    }
+
+    /// A runtime like the one of `serve`
+    pub fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    /// A capture of 10 reading `bai.FlowTemp` at 36 °C, silent until started
+    pub struct Capture {
+        replay: Replay,
+        started: Arc<AtomicBool>,
+    }
+
+    impl Transport for Capture {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.started.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+                return Ok(0);
+            }
+            self.replay.read(buf)
+        }
+
+        fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.replay.write(bytes)
+        }
+
+        fn is_finished(&self) -> bool {
+            self.started.load(Ordering::Relaxed) && self.replay.is_finished()
+        }
+    }
+
+    /// Starts the replay of the capture
+    pub struct Start(Arc<AtomicBool>);
+
+    impl Start {
+        pub fn start(&self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The daemon of `context` on the capture, followed by a thread of `scope` until it is stopped
+    pub fn daemon<'s>(scope: &'s thread::Scope<'s, '_>, runtime: &'s Runtime, context: Context) -> (Arc<Daemon>, Start) {
+        let telegram = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap().with_response(&[0x40, 0x02]).unwrap();
+        let bytes: Vec<u8> = [EBUS_SYN].into_iter()
+            .chain(encode_master(&telegram))
+            .chain([EBUS_ACKOK])
+            .chain(encode_slave(&telegram))
+            .chain([EBUS_ACKOK, EBUS_SYN])
+            .collect();
+        let started = Arc::new(AtomicBool::new(false));
+        let capture = Capture { replay: Replay::new(vec![Chunk { at: Duration::ZERO, bytes }], Pacing::Fast), started: Arc::clone(&started) };
+        let (daemon, events) = {
+            let _runtime = runtime.enter();
+            Daemon::start(context, capture)
+        };
+        let daemon = Arc::new(daemon);
+        let follower = Arc::clone(&daemon);
+        scope.spawn(move || runtime.block_on(follower.follow(events, "test")));
+        (daemon, Start(started))
+    }
+
+    /// Wait up to 5 s for the daemon to have read the telegram of the capture
+    pub fn wait_for_telegram(daemon: &Daemon) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while daemon.state().stats.telegrams == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Stops the daemon when the test ends, even by a panic
+    pub struct Stop<'a>(pub &'a Daemon);
+
+    impl Drop for Stop<'_> {
+        fn drop(&mut self) {
+            self.0.stop();
+        }
+    }
+}
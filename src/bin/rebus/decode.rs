@@ -0,0 +1,116 @@
+//! Offline decoding of telegrams written in hex and of capture files, without any bus
+
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use rebus_core::config::parse_hex_bytes;
+use rebus_core::layer2::reader::BusReader;
+use rebus_core::layer2::{TelegramComponent, EBUS_SYN};
+
+use crate::monitor::{Format, Monitor, OutputArgs};
+use crate::{read_chunks, Context};
+
+/// Frame the bytes and print the events read, each one after its bytes when `verbose`.
+/// Idle SYNs are not reported.
+fn decode_bytes<W: Write>(monitor: &mut Monitor<W>, bytes: &[u8], verbose: bool) -> io::Result<()> {
+    let mut reader = BusReader::new();
+    let mut read = Vec::new();
+
+    for c in bytes {
+        let component = if *c == EBUS_SYN { TelegramComponent::SYN } else { reader.waiting_for() };
+        let event = reader.read_byte(*c);
+        if *c == EBUS_SYN && event.is_none() {
+            read.clear();
+        }
+        read.push((*c, component));
+
+        if let Some(event) = event {
+            monitor.handle_bytes(&event, if verbose { &read } else { &[] })?;
+            read.clear();
+        }
+    }
+    Ok(())
+}
+
+/// Decode each input: a telegram in hex from QQ to the last ACK, with or without its SYN, or a capture file
+pub fn decode(context: &Context, inputs: &[String], output: &OutputArgs, verbose: bool) -> ExitCode {
+    if verbose && output.format() == Format::Csv {
+        eprintln!("--verbose annotates the text and JSON outputs only");
+        return ExitCode::FAILURE;
+    }
+    let mut monitor = match Monitor::new(&context.settings.catalogue, output, io::stdout(), io::stdout().is_terminal()) {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let mut status = ExitCode::SUCCESS;
+
+    for input in inputs {
+        let path = Path::new(input);
+        let bytes: Vec<u8> = if path.is_file() {
+            match read_chunks(path) {
+                Ok(chunks) => chunks.into_iter().flat_map(|chunk| chunk.bytes).collect(),
+                Err(e) => {
+                    eprintln!("{}: {}", input, e);
+                    status = ExitCode::FAILURE;
+                    continue;
+                },
+            }
+        } else {
+            match parse_hex_bytes(input) {
+                // the SYN ending the telegram reports it when it is incomplete
+                Some(bytes) => [EBUS_SYN].into_iter().chain(bytes).chain([EBUS_SYN]).collect(),
+                None => {
+                    eprintln!("`{}` is neither hex nor a capture file", input);
+                    status = ExitCode::FAILURE;
+                    continue;
+                },
+            }
+        };
+        if let Err(e) = decode_bytes(&mut monitor, &bytes, verbose) {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use rebus_core::layer2::encoder::encode_master;
+    use rebus_core::layer2::{BusEvent, Packet};
+    use rebus_core::layer7::Catalogue;
+
+    fn decode_verbose(bytes: &[u8]) -> String {
+        let cli = crate::Cli::try_parse_from(["rebus", "decode", "--color", "never", "-"]).unwrap();
+        let crate::Command::Decode { output: args, .. } = cli.command else {
+            unreachable!()
+        };
+        let catalogue = Catalogue::new();
+        let mut output = Vec::new();
+        let mut monitor = Monitor::new(&catalogue, &args, &mut output, false).unwrap();
+        decode_bytes(&mut monitor, bytes, true).unwrap();
+        drop(monitor);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn bytes_are_annotated() {
+        // idle SYNs, then a broadcast with an escaped byte
+        let broadcast = Packet::request(0x10, 0xfe, 0x07, 0x00, &[EBUS_SYN]).unwrap();
+        let bytes: Vec<u8> = [EBUS_SYN, EBUS_SYN].into_iter().chain(encode_master(&broadcast)).collect();
+        let text = decode_verbose(&bytes);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "  aa SYN");
+        assert_eq!(lines[1], "  10 Source");
+        assert_eq!(&lines[6..8], ["  a9 MasterPayload", "  01 MasterEscapedPayload"]);
+        assert!(lines[8].ends_with(" MasterCRC"));
+        assert_eq!(lines[9], BusEvent::Telegram(broadcast).to_string());
+        assert_eq!(lines.len(), 10);
+    }
+}
@@ -0,0 +1,300 @@
+//! Home Assistant MQTT discovery: an entity for each field of the messages read or observed, in a
+//! device by eBUS address described by its identification (07 04)
+
+use std::collections::BTreeSet;
+
+use serde_json::json;
+
+use rebus_core::layer2::AddressClass;
+use rebus_core::layer7::identification::{Identification, Inventory};
+use rebus_core::layer7::types::DataType;
+use rebus_core::layer7::{Catalogue, FieldDefinition, MessageDefinition, MessageKind};
+
+use crate::mqtt::{level, status, topic, MqttArgs, Payload};
+
+/// The address of the device a message is about: its destination, else the master sending it
+fn address(definition: &MessageDefinition) -> Option<u8> {
+    definition.destination
+        .filter(|zz| !matches!(AddressClass::of(*zz), AddressClass::Broadcast))
+        .or(definition.source)
+}
+
+/// The addresses of the devices having entities
+pub fn addresses(catalogue: &Catalogue) -> BTreeSet<u8> {
+    catalogue.definitions().iter()
+        .filter(|d| d.kind != MessageKind::Write)
+        .filter_map(address)
+        .collect()
+}
+
+/// An identifier made of lowercase letters, digits and underscores, as Home Assistant expects
+fn identifier(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+fn device(address: u8, identification: Option<&Identification>) -> serde_json::Value {
+    let mut device = json!({
+        "identifiers": [format!("rebus_{:02x}", address)],
+        "name": format!("eBUS {:02x}", address),
+    });
+    if let Some(identification) = identification {
+        let version = |v: u16| format!("{}.{:02}", v / 100, v % 100);
+        device["manufacturer"] = identification.manufacturer_name()
+            .map_or_else(|| format!("MF={:02x}", identification.manufacturer), str::to_string)
+            .into();
+        device["model"] = identification.device_id.clone().into();
+        device["sw_version"] = version(identification.software).into();
+        device["hw_version"] = version(identification.hardware).into();
+    }
+    device
+}
+
+/// The device class of Home Assistant measured in a unit
+fn device_class(unit: &str) -> Option<&'static str> {
+    Some(match unit {
+        "°C" | "°F" | "K" => "temperature",
+        "bar" | "mbar" | "Pa" | "hPa" | "kPa" => "pressure",
+        "W" | "kW" => "power",
+        "Wh" | "kWh" | "MWh" => "energy",
+        "V" | "mV" => "voltage",
+        "A" | "mA" => "current",
+        "Hz" => "frequency",
+        "s" | "min" | "h" | "d" => "duration",
+        "L/min" | "L/h" | "m³/h" => "volume_flow_rate",
+        _ => return None,
+    })
+}
+
+/// The lowest and the highest value of a numeric field, those of its type unless the definition
+/// sets them, and the step between two values
+fn range(field: &FieldDefinition) -> (f64, f64, f64) {
+    let (low, high, divider) = match field.data_type {
+        DataType::Bcd => (0, 99, 1.0),
+        DataType::Data1b | DataType::Sch => (-127, 127, 1.0),
+        DataType::Data1c => (0, 254, 2.0),
+        DataType::Uch => (0, 254, 1.0),
+        DataType::Data2b => (-32767, 32767, 256.0),
+        DataType::Data2c => (-32767, 32767, 16.0),
+        DataType::Sin => (-32767, 32767, 1.0),
+        DataType::Uin => (0, 65534, 1.0),
+        DataType::Ulg => (0, u32::MAX as i64 - 1, 1.0),
+        DataType::Slg => (i32::MIN as i64 + 1, i32::MAX as i64, 1.0),
+        _ => (0, 1, 1.0),
+    };
+    let scale = |raw: i64| match field.divider {
+        Some(d) if d < 0.0 => raw as f64 / divider * -d,
+        Some(d) => raw as f64 / divider / d,
+        None => raw as f64 / divider,
+    };
+    (field.min.unwrap_or(scale(low)), field.max.unwrap_or(scale(high)), scale(1))
+}
+
+/// The payloads turning a switch off and on, which are also its states
+fn switch(config: &mut serde_json::Value, off: &str, on: &str) {
+    config["payload_off"] = off.into();
+    config["payload_on"] = on.into();
+    config["state_off"] = off.into();
+    config["state_on"] = on.into();
+}
+
+/// The discovery topic and the configuration of the entity of a field, `input` being the field
+/// written by a message of the same name when there is one
+fn entity(args: &MqttArgs, definition: &MessageDefinition, index: usize, field: &FieldDefinition, input: Option<&FieldDefinition>, address: u8, identification: Option<&Identification>) -> (String, String) {
+    let described = input.unwrap_or(field);
+    let values = described.values.as_deref().filter(|_| described.data_type.is_numeric());
+    let bit = matches!(described.data_type, DataType::Bit(_));
+    let component = match (input, values) {
+        (None, _) => "sensor",
+        (Some(_), Some([_, _])) => "switch",
+        (Some(_), None) if bit => "switch",
+        (Some(_), Some(_)) => "select",
+        (Some(_), None) if described.data_type.is_numeric() => "number",
+        (Some(_), None) => "text",
+    };
+
+    let field_name = if field.name.is_empty() { index.to_string() } else { field.name.clone() };
+    let id = identifier(&format!("rebus_{:02x}_{}_{}_{}", address, definition.circuit, definition.name, field_name));
+    let single = definition.fields.iter().filter(|f| !matches!(f.data_type, DataType::Ignore(_))).count() == 1;
+    let mut config = json!({
+        "name": if single { definition.name.clone() } else { format!("{} {}", definition.name, field_name) },
+        "unique_id": id,
+        "object_id": id,
+        "state_topic": topic(&args.mqtt_prefix, &definition.circuit, &definition.name, index, &field.name),
+        "availability_topic": status(&args.mqtt_prefix),
+        "device": device(address, identification),
+    });
+    if args.mqtt_payload == Payload::Json {
+        // the switches of a bit compare the text of the value with `1` and `0`
+        config["value_template"] = if bit { "{{ 1 if value_json.value else 0 }}" } else { "{{ value_json.value }}" }.into();
+    }
+    if input.is_some() {
+        config["command_topic"] = format!("{}/{}/{}/set", args.mqtt_prefix, level(&definition.circuit), level(&definition.name)).into();
+    }
+
+    match (component, values) {
+        ("switch", Some([first, second])) => {
+            let (off, on) = if first.0 < second.0 { (first, second) } else { (second, first) };
+            switch(&mut config, &off.1, &on.1);
+        },
+        ("switch", _) => switch(&mut config, "0", "1"),
+        (_, Some(values)) => {
+            config["options"] = values.iter().map(|(_, name)| name.as_str()).collect::<Vec<_>>().into();
+            if component == "sensor" {
+                config["device_class"] = "enum".into();
+            }
+        },
+        ("sensor", None) | ("number", None) if described.data_type.is_numeric() && !bit => {
+            if let Some(unit) = field.unit.as_deref().or(input.and_then(|f| f.unit.as_deref())) {
+                config["unit_of_measurement"] = unit.into();
+                if let Some(class) = device_class(unit) {
+                    config["device_class"] = class.into();
+                }
+            }
+            if component == "number" {
+                let (min, max, step) = range(described);
+                config["min"] = min.into();
+                config["max"] = max.into();
+                config["step"] = step.into();
+                config["mode"] = "box".into();
+            } else {
+                config["state_class"] = if config["device_class"] == "energy" { "total_increasing" } else { "measurement" }.into();
+            }
+        },
+        _ => (),
+    }
+    (format!("{}/{}/{}/config", args.mqtt_discovery_prefix, component, id), config.to_string())
+}
+
+/// The discovery topic and the configuration of the entities of the devices at the addresses
+/// accepted, an entity by field of the messages read or observed. A field is writable when it is
+/// the only one of its message and a message of the same name writes a single value.
+pub fn configs(args: &MqttArgs, catalogue: &Catalogue, inventory: &Inventory, accept: impl Fn(u8) -> bool) -> Vec<(String, String)> {
+    let definitions = catalogue.definitions();
+    definitions.iter()
+        .filter(|d| d.kind != MessageKind::Write)
+        .filter_map(|d| address(d).filter(|a| accept(*a)).map(|a| (d, a)))
+        .flat_map(|(definition, address)| {
+            let fields: Vec<&FieldDefinition> = definition.fields.iter()
+                .filter(|f| !matches!(f.data_type, DataType::Ignore(_)))
+                .collect();
+            let input = definitions.iter()
+                .find(|w| w.kind == MessageKind::Write && w.circuit == definition.circuit && w.name == definition.name)
+                .and_then(|w| match w.inputs().collect::<Vec<_>>()[..] {
+                    [input] => Some(input),
+                    _ => None,
+                })
+                .filter(|_| fields.len() == 1);
+            fields.into_iter()
+                .enumerate()
+                .map(|(i, field)| entity(args, definition, i, field, input, address, inventory.get(address)))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use rebus_core::layer7::Part;
+
+    fn configs_of(definitions: Vec<MessageDefinition>, inventory: &Inventory) -> Vec<(String, serde_json::Value)> {
+        let cli = crate::Cli::try_parse_from(["rebus", "serve", "--mqtt", "localhost", "--mqtt-discovery"]).unwrap();
+        let crate::Command::Serve(serve) = &cli.command else {
+            unreachable!()
+        };
+        let catalogue = definitions.into_iter().collect();
+        configs(&serve.mqtt, &catalogue, inventory, |_| true).into_iter()
+            .map(|(topic, config)| (topic, serde_json::from_str(&config).unwrap()))
+            .collect()
+    }
+
+    fn message(circuit: &str, name: &str, kind: MessageKind, part: Part, field: FieldDefinition) -> MessageDefinition {
+        let mut message = MessageDefinition::new(circuit, name, kind, 0xb5, 0x09);
+        message.destination = Some(0x08);
+        message.fields.push(FieldDefinition { part, ..field });
+        message
+    }
+
+    #[test]
+    fn entities_follow_the_fields() {
+        let mut flow_temp = FieldDefinition::new("temp", Part::Slave, 0, DataType::Data2c);
+        flow_temp.unit = Some("°C".to_string());
+        let mut desired_temp = FieldDefinition::new("", Part::Slave, 0, DataType::Data1c);
+        desired_temp.unit = Some("°C".to_string());
+        let mut written_temp = desired_temp.clone();
+        written_temp.min = Some(15.0);
+        written_temp.max = Some(30.0);
+        let mut mode = FieldDefinition::new("mode", Part::Slave, 0, DataType::Uch);
+        mode.values = Some(vec![(0, "off".to_string()), (1, "auto".to_string()), (2, "day".to_string())]);
+        let pump = FieldDefinition::new("pump", Part::Slave, 0, DataType::Bit(0));
+        let mut inventory = Inventory::new();
+        inventory.insert(0x08, Identification { manufacturer: 0xb5, device_id: "BAI00".to_string(), software: 703, hardware: 7603 });
+
+        let configs = configs_of(vec![
+            message("bai", "FlowTemp", MessageKind::Read, Part::Slave, flow_temp),
+            message("hc1", "DesiredTemp", MessageKind::Read, Part::Slave, desired_temp),
+            message("hc1", "DesiredTemp", MessageKind::Write, Part::Master, written_temp),
+            message("hc1", "Mode", MessageKind::Read, Part::Slave, mode.clone()),
+            message("hc1", "Mode", MessageKind::Write, Part::Master, mode),
+            message("bai", "Pump", MessageKind::Passive, Part::Slave, pump),
+        ], &inventory);
+        let topics: Vec<&str> = configs.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, [
+            "homeassistant/sensor/rebus_08_bai_flowtemp_temp/config",
+            "homeassistant/number/rebus_08_hc1_desiredtemp_0/config",
+            "homeassistant/select/rebus_08_hc1_mode_mode/config",
+            "homeassistant/sensor/rebus_08_bai_pump_pump/config",
+        ]);
+
+        let sensor = &configs[0].1;
+        assert_eq!(sensor["state_topic"], "rebus/bai/FlowTemp/temp");
+        assert_eq!(sensor["availability_topic"], "rebus/status");
+        assert_eq!(sensor["device_class"], "temperature");
+        assert_eq!(sensor["state_class"], "measurement");
+        assert_eq!(sensor["device"], json!({
+            "identifiers": ["rebus_08"],
+            "name": "eBUS 08",
+            "manufacturer": "Vaillant",
+            "model": "BAI00",
+            "sw_version": "7.03",
+            "hw_version": "76.03",
+        }));
+
+        let number = &configs[1].1;
+        assert_eq!(number["name"], "DesiredTemp");
+        assert_eq!(number["state_topic"], "rebus/hc1/DesiredTemp/0");
+        assert_eq!(number["command_topic"], "rebus/hc1/DesiredTemp/set");
+        assert_eq!((&number["min"], &number["max"], &number["step"]), (&json!(15.0), &json!(30.0), &json!(0.5)));
+        assert_eq!(number["unit_of_measurement"], "°C");
+
+        assert_eq!(configs[2].1["options"], json!(["off", "auto", "day"]));
+        assert!(configs[3].1.get("command_topic").is_none());
+    }
+
+    #[test]
+    fn switches_and_devices_without_identification() {
+        let mut heating = FieldDefinition::new("on", Part::Slave, 0, DataType::Uch);
+        heating.values = Some(vec![(0, "off".to_string()), (1, "on".to_string())]);
+        let mut written = heating.clone();
+        written.values = Some(vec![(1, "on".to_string()), (0, "off".to_string())]);
+        let mut power = FieldDefinition::new("power", Part::Slave, 0, DataType::Uin);
+        power.divider = Some(-100.0);
+        power.unit = Some("W".to_string());
+
+        let configs = configs_of(vec![
+            message("hc1", "Heating", MessageKind::Read, Part::Slave, heating),
+            message("hc1", "Heating", MessageKind::Write, Part::Master, written),
+            message("bai", "Power", MessageKind::Read, Part::Slave, power.clone()),
+            message("bai", "Power", MessageKind::Write, Part::Master, power),
+        ], &Inventory::new());
+        let switch = &configs[0].1;
+        assert_eq!(configs[0].0, "homeassistant/switch/rebus_08_hc1_heating_on/config");
+        assert_eq!((&switch["payload_on"], &switch["payload_off"]), (&json!("on"), &json!("off")));
+        assert_eq!(switch["device"], json!({ "identifiers": ["rebus_08"], "name": "eBUS 08" }));
+
+        let number = &configs[1].1;
+        assert_eq!((&number["min"], &number["max"], &number["step"]), (&json!(0.0), &json!(6553400.0), &json!(100.0)));
+        assert_eq!(number["device_class"], "power");
+    }
+}
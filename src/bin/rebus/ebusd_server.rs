@@ -0,0 +1,399 @@
+//! A server speaking the line protocol of ebusd, for the integrations written for it (Home Assistant,
+//! FHEM, openHAB): `read`, `write`, `find`, `hex`, `scan`, `info`, `listen` and `quit`. The answer
+//! to a command ends with an empty line, errors starting with `ERR: `.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use rebus_core::config::parse_hex_bytes;
+use rebus_core::layer2::Packet;
+use rebus_core::layer7::identification::{Identification, IDENTIFICATION_COMMAND};
+use rebus_core::layer7::types::Value;
+use rebus_core::layer7::{DecodedMessage, MessageKind};
+
+use crate::daemon::{query, Daemon, Events};
+use crate::{format_hex, is_slave_address, MessageArgs};
+
+pub const DEFAULT_PORT: u16 = 8888;
+/// Age of a value above which `read` asks the bus again, unless `-m` says otherwise
+const MAX_AGE: Duration = Duration::from_secs(300);
+/// How often the updates are written to a listening client
+const LISTEN_PERIOD: Duration = Duration::from_millis(100);
+
+const HELP: &str = "read [-f] [-m SECONDS] [-c CIRCUIT] [-d ZZ] [-v] NAME [FIELD]
+write -c CIRCUIT [-d ZZ] NAME VALUE[;VALUE]*
+find [-r] [-w] [-p] [-v] [-c CIRCUIT] [NAME]
+hex [-s QQ] ZZPBSBNN[DD]*
+scan [full|result|ZZ]
+info
+listen [stop]
+quit";
+
+/// The options of a command, `-x` or `-x VALUE`, and its other arguments
+#[derive(Default)]
+struct Arguments<'w> {
+    flags: Vec<char>,
+    values: BTreeMap<char, &'w str>,
+    others: Vec<&'w str>,
+}
+
+impl<'w> Arguments<'w> {
+    /// `valued` lists the options followed by a value
+    fn parse(words: &[&'w str], valued: &str) -> Result<Arguments<'w>, String> {
+        let mut arguments = Arguments::default();
+        let mut words = words.iter();
+        while let Some(word) = words.next() {
+            let mut option = word.strip_prefix('-').map(str::chars).into_iter().flatten();
+            match (option.next(), option.next()) {
+                (Some(c), None) if valued.contains(c) => {
+                    let value = words.next().ok_or_else(|| format!("missing value of -{}", c))?;
+                    arguments.values.insert(c, value);
+                },
+                (Some(c), None) => arguments.flags.push(c),
+                _ => arguments.others.push(word),
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn flag(&self, c: char) -> bool {
+        self.flags.contains(&c)
+    }
+}
+
+/// The values of the fields separated by `;`, `-` standing for the replacement value, with their
+/// names and units when `verbose`
+fn format_values(message: &DecodedMessage, field: Option<&str>, verbose: bool) -> Result<String, String> {
+    let fields: Vec<String> = message.fields.iter()
+        .filter(|f| field.is_none_or(|name| f.name == name))
+        .map(|f| {
+            let value = f.value.as_ref().map_or_else(|| "-".to_string(), Value::to_string);
+            match (verbose, &f.unit, &f.value) {
+                (false, _, _) => value,
+                (true, Some(unit), Some(_)) => format!("{}={} {}", f.name, value, unit),
+                (true, _, _) => format!("{}={}", f.name, value),
+            }
+        })
+        .collect();
+    if fields.is_empty() && field.is_some() {
+        return Err("element not found".to_string());
+    }
+    Ok(fields.join(";"))
+}
+
+/// `circuit name = values`, as `find` and `listen` write them
+fn format_update(message: &DecodedMessage, verbose: bool) -> String {
+    let values = format_values(message, None, verbose).unwrap_or_default();
+    format!("{} {} = {}", message.circuit, message.name, values)
+}
+
+fn read(daemon: &Daemon, words: &[&str]) -> Result<String, String> {
+    let arguments = Arguments::parse(words, "cmdsp")?;
+    let (name, field) = match arguments.others.as_slice() {
+        [name] => (*name, None),
+        [name, field] => (*name, Some(*field)),
+        _ => return Err("invalid argument".to_string()),
+    };
+    let circuit = arguments.values.get(&'c').copied();
+    let max_age = match arguments.values.get(&'m') {
+        Some(seconds) => Duration::from_secs(seconds.parse().map_err(|_| "invalid argument")?),
+        None => MAX_AGE,
+    };
+    let destination = match arguments.values.get(&'d') {
+        Some(zz) => Some(crate::hex_byte(zz)?),
+        None => None,
+    };
+    let verbose = arguments.flag('v') || arguments.flag('V');
+
+    let catalogue = &daemon.context.settings.catalogue;
+    let definition = catalogue.definitions().iter()
+        .filter(|d| d.name.eq_ignore_ascii_case(name) && circuit.is_none_or(|c| d.circuit.eq_ignore_ascii_case(c)))
+        .filter(|d| d.kind != MessageKind::Write)
+        .min_by_key(|d| d.kind != MessageKind::Read)
+        .ok_or("element not found")?;
+
+    let cached = daemon.state().values.get(&(definition.circuit.clone(), definition.name.clone()))
+        .filter(|(at, _)| !arguments.flag('f') && at.elapsed().unwrap_or_default() <= max_age)
+        .map(|(_, message)| message.clone());
+    let message = match cached {
+        Some(message) => message,
+        None if definition.kind != MessageKind::Read => return Err("no data stored".to_string()),
+        None => {
+            let args = MessageArgs { circuit: definition.circuit.clone(), name: definition.name.clone(), values: Vec::new(), destination };
+            let (definition, requests) = daemon.context.requests(&args, MessageKind::Read)?;
+            daemon.block_on(query(&daemon.bus, definition, requests))
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?
+        },
+    };
+    format_values(&message, field, verbose)
+}
+
+fn write(daemon: &Daemon, words: &[&str]) -> Result<String, String> {
+    let arguments = Arguments::parse(words, "cds")?;
+    let [name, values @ ..] = arguments.others.as_slice() else {
+        return Err("invalid argument".to_string());
+    };
+    let circuit = arguments.values.get(&'c').copied();
+    let definition = daemon.context.settings.catalogue.definitions().iter()
+        .find(|d| d.kind == MessageKind::Write && d.name.eq_ignore_ascii_case(name) && circuit.is_none_or(|c| d.circuit.eq_ignore_ascii_case(c)))
+        .ok_or("element not found")?;
+    let destination = match arguments.values.get(&'d') {
+        Some(zz) => Some(crate::hex_byte(zz)?),
+        None => None,
+    };
+    let values = values.join(" ");
+    let args = MessageArgs {
+        circuit: definition.circuit.clone(),
+        name: definition.name.clone(),
+        values: if values.is_empty() { Vec::new() } else { values.split(';').map(str::to_string).collect() },
+        destination,
+    };
+    let (definition, requests) = daemon.context.requests(&args, MessageKind::Write)?;
+    // the response of a write needs no decoding
+    daemon.block_on(query(&daemon.bus, definition, requests))
+        .map(|_| "done".to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn find(daemon: &Daemon, words: &[&str]) -> Result<String, String> {
+    let arguments = Arguments::parse(words, "cdilF")?;
+    let name = match arguments.others.as_slice() {
+        [] => None,
+        [name] => Some(name.to_lowercase()),
+        _ => return Err("invalid argument".to_string()),
+    };
+    let circuit = arguments.values.get(&'c').copied();
+    let kinds = match (arguments.flag('r'), arguments.flag('w'), arguments.flag('p')) {
+        (false, false, false) => vec![MessageKind::Read, MessageKind::Passive],
+        (r, w, p) => [(r, MessageKind::Read), (w, MessageKind::Write), (p, MessageKind::Passive)].into_iter()
+            .filter_map(|(selected, kind)| selected.then_some(kind))
+            .collect(),
+    };
+    let verbose = arguments.flag('v') || arguments.flag('V');
+
+    let state = daemon.state();
+    let lines: Vec<String> = daemon.context.settings.catalogue.definitions().iter()
+        .filter(|d| kinds.contains(&d.kind))
+        .filter(|d| circuit.is_none_or(|c| d.circuit.eq_ignore_ascii_case(c)))
+        .filter(|d| name.as_ref().is_none_or(|name| d.name.to_lowercase().contains(name)))
+        .map(|d| match state.values.get(&(d.circuit.clone(), d.name.clone())) {
+            Some((_, message)) if d.kind != MessageKind::Write => format_update(message, verbose),
+            _ => format!("{} {} = no data stored", d.circuit, d.name),
+        })
+        .collect();
+    if lines.is_empty() {
+        return Err("element not found".to_string());
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Send a telegram and answer the NN and data bytes of the response, `done` without response
+fn hex(daemon: &Daemon, words: &[&str]) -> Result<String, String> {
+    let arguments = Arguments::parse(words, "s")?;
+    let source = match arguments.values.get(&'s') {
+        Some(qq) => crate::hex_byte(qq)?,
+        None => daemon.context.settings.address,
+    };
+    let request = match parse_hex_bytes(&arguments.others.concat()).as_deref() {
+        Some([zz, pb, sb, nn, data @ ..]) if *nn as usize == data.len() => Packet::request(source, *zz, *pb, *sb, data),
+        _ => None,
+    };
+    let request = request.ok_or("invalid argument")?;
+    let response = daemon.block_on(daemon.bus.send(request)).map_err(|e| e.to_string())?;
+    match response.slave_payload() {
+        [] => Ok("done".to_string()),
+        payload => Ok(format!("{:02x}{}", payload.len(), format_hex(payload))),
+    }
+}
+
+/// The 07 04 request identifying a device
+pub fn identification_request(daemon: &Daemon, address: u8) -> Option<Packet> {
+    let (primary, secondary) = IDENTIFICATION_COMMAND;
+    Packet::request(daemon.context.settings.address, address, primary, secondary, &[])
+}
+
+/// Identify a device, every slave in the background, or list the devices identified
+fn scan(daemon: &Daemon, words: &[&str]) -> Result<String, String> {
+    match words {
+        [] | ["full"] => {
+            let own = daemon.context.settings.slave_address();
+            let requests = (0..=0xff)
+                .filter(|a| is_slave_address(*a) && *a != own)
+                .filter_map(|address| identification_request(daemon, address))
+                .collect();
+            daemon.queue(requests);
+            Ok("done".to_string())
+        },
+        ["result"] => {
+            let state = daemon.state();
+            let lines: Vec<String> = state.inventory.iter()
+                .map(|(address, identification)| format!("{:02x};{}", address, identification))
+                .collect();
+            Ok(if lines.is_empty() { "no devices found".to_string() } else { lines.join("\n") })
+        },
+        [zz] => {
+            let address = crate::hex_byte(zz)?;
+            let request = identification_request(daemon, address).ok_or("invalid argument")?;
+            let response = daemon.block_on(daemon.bus.send(request)).map_err(|e| e.to_string())?;
+            let identification = Identification::parse(response.slave_payload()).ok_or("unexpected answer")?;
+            Ok(format!("{:02x};{}", address, identification))
+        },
+        _ => Err("invalid argument".to_string()),
+    }
+}
+
+fn info(daemon: &Daemon) -> String {
+    let state = daemon.state();
+    let mut lines = vec![
+        format!("version: rebus {}", env!("CARGO_PKG_VERSION")),
+        format!("signal: {}", if state.connected { "acquired" } else { "no signal" }),
+        format!("masters: {}", state.masters.len()),
+        format!("messages: {}", daemon.context.settings.catalogue.definitions().len()),
+        format!("telegrams: {}", state.stats.telegrams),
+    ];
+    let addresses = state.masters.iter().copied().chain(state.inventory.iter().map(|(address, _)| address));
+    for address in addresses.collect::<BTreeSet<u8>>() {
+        let mut line = format!("address {:02x}: {}", address, if state.masters.contains(&address) { "master" } else { "slave" });
+        if let Some(identification) = state.inventory.get(address) {
+            line += &format!(", scanned \"{}\"", identification);
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// The answer to a command other than `listen` and `quit`
+fn execute(daemon: &Daemon, words: &[&str]) -> String {
+    let result = match words {
+        ["read", words @ ..] => read(daemon, words),
+        ["write", words @ ..] => write(daemon, words),
+        ["find", words @ ..] => find(daemon, words),
+        ["hex", words @ ..] => hex(daemon, words),
+        ["scan", words @ ..] => scan(daemon, words),
+        ["info", ..] => Ok(info(daemon)),
+        ["help", ..] => Ok(HELP.to_string()),
+        [command, ..] => Err(format!("command not found: {}", command)),
+        [] => Ok(String::new()),
+    };
+    result.unwrap_or_else(|e| format!("ERR: {}", e))
+}
+
+/// Answer the commands of a client until it quits. A listening client is also written the
+/// messages decoded meanwhile, one line each.
+fn session(daemon: &Daemon, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut updates: Option<Events> = None;
+    let mut line = String::new();
+    loop {
+        while let Some(observed) = updates.as_mut().and_then(Events::try_recv) {
+            if let Some(message) = observed.message() {
+                writeln!(writer, "{}", format_update(message, false))?;
+            }
+        }
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => (),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        }
+        let command = std::mem::take(&mut line);
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let answer = match words.as_slice() {
+            [] => continue,
+            ["quit", ..] => return Ok(()),
+            ["listen", "stop"] => {
+                updates = None;
+                "listen stopped".to_string()
+            },
+            ["listen", ..] => {
+                updates = Some(daemon.subscribe());
+                "listen started".to_string()
+            },
+            words => execute(daemon, words),
+        };
+        writer.set_read_timeout(updates.as_ref().map(|_| LISTEN_PERIOD))?;
+        write!(writer, "{}\n\n", answer)?;
+    }
+}
+
+/// Serve each client in its own thread, until accepting fails
+pub fn serve(daemon: &Daemon, listener: TcpListener) -> io::Result<()> {
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            scope.spawn(move || {
+                let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                if let Err(e) = session(daemon, stream) {
+                    eprintln!("{}: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::daemon::fixture::{context, daemon, runtime, wait_for_telegram, Stop};
+
+    /// Send a command and read its answer, without the empty line ending it
+    fn ask(client: &mut BufReader<TcpStream>, command: &str) -> String {
+        writeln!(client.get_mut(), "{}", command).unwrap();
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            match line.trim_end() {
+                "" => return lines.join("\n"),
+                line => lines.push(line.to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn commands_are_answered() {
+        let (context, _) = context(&[]);
+        let runtime = runtime();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::scope(|scope| {
+            let (daemon, capture) = daemon(scope, &runtime, context);
+            let _stop = Stop(&daemon);
+            capture.start();
+            let server = Arc::clone(&daemon);
+            scope.spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                session(&server, stream).unwrap();
+            });
+            wait_for_telegram(&daemon);
+
+            let mut client = BufReader::new(TcpStream::connect(address).unwrap());
+            assert_eq!(ask(&mut client, "read -c bai FlowTemp"), "36");
+            assert_eq!(ask(&mut client, "read -v flowtemp temp"), "temp=36 °C");
+            assert_eq!(ask(&mut client, "read FlowTemp status"), "ERR: element not found");
+            assert_eq!(ask(&mut client, "find"), "bai FlowTemp = 36");
+            assert_eq!(ask(&mut client, "find -w"), "hc1 DesiredTemp = no data stored");
+            // a replayed capture cannot be written to
+            assert!(ask(&mut client, "read -f FlowTemp").starts_with("ERR: "));
+            assert!(ask(&mut client, "hex 08b50903").starts_with("ERR: invalid argument"));
+            assert!(ask(&mut client, "info").ends_with("\naddress 10: master"));
+            assert_eq!(ask(&mut client, "scan result"), "no devices found");
+            assert_eq!(ask(&mut client, "listen"), "listen started");
+            assert_eq!(ask(&mut client, "listen stop"), "listen stopped");
+            assert_eq!(ask(&mut client, "frobnicate"), "ERR: command not found: frobnicate");
+            writeln!(client.get_mut(), "quit").unwrap();
+            let mut rest = String::new();
+            assert_eq!(client.read_line(&mut rest).unwrap(), 0);
+        });
+    }
+}
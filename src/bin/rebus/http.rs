@@ -0,0 +1,328 @@
+//! The HTTP API of `serve`: what the daemon knows of the bus as JSON, the reads and the writes of the
+//! messages, and a WebSocket streaming the events of the bus as `monitor --format json` prints them
+
+use std::collections::BTreeSet;
+use std::future::IntoFuture;
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::json;
+
+use rebus_core::config::ebusd::type_name;
+use rebus_core::layer7::types::{DataType, DecodeError};
+use rebus_core::layer7::{DecodedMessage, MessageDefinition, MessageKind, Part};
+
+use crate::daemon::{query, Daemon, Events};
+use crate::monitor::{json_value, Format, Printer};
+use crate::{format_hex, hex_byte, MessageArgs};
+
+/// An error answered as `{"error": ..}`
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+fn seconds(time: SystemTime) -> f64 {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    (time * 1000.0).round() / 1000.0
+}
+
+fn version(v: u16) -> String {
+    format!("{}.{:02}", v / 100, v % 100)
+}
+
+fn message_json(message: &DecodedMessage, time: SystemTime) -> serde_json::Value {
+    json!({
+        "circuit": message.circuit,
+        "name": message.name,
+        "time": seconds(time),
+        "fields": message.fields.iter()
+            .map(|f| json!({ "name": f.name, "value": json_value(f.value.as_ref()), "unit": f.unit }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn definition_json(definition: &MessageDefinition) -> serde_json::Value {
+    let fields: Vec<_> = definition.fields.iter()
+        .filter(|f| !matches!(f.data_type, DataType::Ignore(_)))
+        .map(|f| json!({
+            "name": f.name,
+            "part": if f.part == Part::Master { "master" } else { "slave" },
+            "type": type_name(f.data_type),
+            "unit": f.unit,
+            "min": f.min,
+            "max": f.max,
+            "values": f.values.as_ref().map(|values| values.iter().map(|(raw, name)| json!({ "raw": raw, "name": name })).collect::<Vec<_>>()),
+            "comment": f.comment,
+        }))
+        .collect();
+    json!({
+        "circuit": definition.circuit,
+        "name": definition.name,
+        "kind": match definition.kind {
+            MessageKind::Read => "read",
+            MessageKind::Write => "write",
+            MessageKind::Passive => "passive",
+        },
+        "destination": definition.destination.map(|zz| format!("{:02x}", zz)),
+        "command": format!("{:02x}{:02x}", definition.primary, definition.secondary),
+        "id": format_hex(&definition.id),
+        "fields": fields,
+        "comment": definition.comment,
+    })
+}
+
+/// The masters seen on the bus and the devices identified
+async fn devices(State(daemon): State<Arc<Daemon>>) -> Json<serde_json::Value> {
+    let state = daemon.state();
+    let addresses: BTreeSet<u8> = state.masters.iter().copied().chain(state.inventory.iter().map(|(a, _)| a)).collect();
+    Json(addresses.into_iter()
+        .map(|address| {
+            let mut device = json!({ "address": format!("{:02x}", address), "master": state.masters.contains(&address) });
+            if let Some(identification) = state.inventory.get(address) {
+                device["manufacturer"] = identification.manufacturer_name()
+                    .map_or_else(|| format!("{:02x}", identification.manufacturer), str::to_string)
+                    .into();
+                device["device_id"] = identification.device_id.clone().into();
+                device["software"] = version(identification.software).into();
+                device["hardware"] = version(identification.hardware).into();
+            }
+            device
+        })
+        .collect())
+}
+
+async fn catalogue(State(daemon): State<Arc<Daemon>>) -> Json<serde_json::Value> {
+    Json(daemon.context.settings.catalogue.definitions().iter().map(definition_json).collect())
+}
+
+/// The last message decoded of each name
+async fn values(State(daemon): State<Arc<Daemon>>) -> Json<serde_json::Value> {
+    Json(daemon.state().values.values().map(|(time, message)| message_json(message, *time)).collect())
+}
+
+async fn value(State(daemon): State<Arc<Daemon>>, Path((circuit, name)): Path<(String, String)>) -> Result<Json<serde_json::Value>, ApiError> {
+    daemon.state().values.get(&(circuit.clone(), name.clone()))
+        .map(|(time, message)| Json(message_json(message, *time)))
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("{}.{}: no value received", circuit, name)))
+}
+
+/// The values of the master fields and the destination of a read or a write
+#[derive(Deserialize, Default)]
+struct Exchange {
+    #[serde(default)]
+    values: Vec<serde_json::Value>,
+    destination: Option<String>,
+}
+
+/// Send a read or a write on the bus, returning the decoding of the response
+async fn exchange(daemon: &Daemon, circuit: String, name: String, exchange: Exchange, kind: MessageKind) -> Result<Result<DecodedMessage, DecodeError>, ApiError> {
+    let bad_request = |e| ApiError(StatusCode::BAD_REQUEST, e);
+    daemon.context.definition(&circuit, &name, kind).map_err(|e| ApiError(StatusCode::NOT_FOUND, e))?;
+    let values = exchange.values.into_iter()
+        .map(|value| match value {
+            serde_json::Value::String(s) => s,
+            value => value.to_string(),
+        })
+        .collect();
+    let destination = exchange.destination.as_deref().map(hex_byte).transpose().map_err(bad_request)?;
+    let args = MessageArgs { circuit, name, values, destination };
+    let (definition, requests) = daemon.context.requests(&args, kind).map_err(bad_request)?;
+    query(&daemon.bus, definition, requests).await.map_err(|e| ApiError(StatusCode::BAD_GATEWAY, e.to_string()))
+}
+
+/// Read a message from the bus
+async fn read(State(daemon): State<Arc<Daemon>>, Path((circuit, name)): Path<(String, String)>, body: Option<Json<Exchange>>) -> Result<Json<serde_json::Value>, ApiError> {
+    let Json(args) = body.unwrap_or_default();
+    exchange(&daemon, circuit, name, args, MessageKind::Read).await?
+        .map(|message| Json(message_json(&message, SystemTime::now())))
+        .map_err(|e| ApiError(StatusCode::BAD_GATEWAY, format!("cannot decode: {}", e)))
+}
+
+/// Write a message to the bus, its response needing no decoding
+async fn write(State(daemon): State<Arc<Daemon>>, Path((circuit, name)): Path<(String, String)>, Json(args): Json<Exchange>) -> Result<StatusCode, ApiError> {
+    exchange(&daemon, circuit, name, args, MessageKind::Write).await.map(|_| StatusCode::NO_CONTENT)
+}
+
+/// The counters of `rebus stats` since the daemon started
+async fn stats(State(daemon): State<Arc<Daemon>>) -> Json<serde_json::Value> {
+    let state = daemon.state();
+    let stats = &state.stats;
+    Json(json!({
+        "connected": state.connected,
+        "telegrams": stats.telegrams,
+        "crc_errors": stats.crc_errors,
+        "nacks": stats.nacks,
+        "timeouts": stats.timeouts,
+        "unexpected": stats.unexpected,
+        "connection_losses": stats.connection_losses,
+        "sources": stats.sources.iter().map(|(source, count)| (format!("{:02x}", source), json!(count))).collect::<serde_json::Map<_, _>>(),
+        "commands": stats.commands.iter()
+            .map(|((primary, secondary), (count, message))| json!({ "command": format!("{:02x}{:02x}", primary, secondary), "count": count, "message": message }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+async fn events(State(daemon): State<Arc<Daemon>>, upgrade: WebSocketUpgrade) -> Response {
+    // from the request on, for no event to be missed while upgrading
+    let events = daemon.bus.subscribe();
+    upgrade.on_upgrade(move |socket| async move { stream(socket, Events::new(&daemon, events)).await })
+}
+
+/// Send the events to a socket until it is closed, a JSON object by message
+async fn stream(mut socket: WebSocket, mut events: Events<'_>) {
+    loop {
+        tokio::select! {
+            observed = events.recv() => {
+                let Some(observed) = observed else {
+                    break;
+                };
+                let mut line = Vec::new();
+                if Printer::new(Format::Json, false, &mut line).and_then(|mut p| p.print(&observed.event, observed.decoded.as_ref(), &[])).is_err() {
+                    continue;
+                }
+                if socket.send(Message::Text(String::from_utf8_lossy(line.trim_ascii_end()).into_owned().into())).await.is_err() {
+                    break;
+                }
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+}
+
+fn router(daemon: Arc<Daemon>) -> Router {
+    Router::new()
+        .route("/api/devices", get(devices))
+        .route("/api/catalogue", get(catalogue))
+        .route("/api/values", get(values))
+        .route("/api/values/{circuit}/{name}", get(value))
+        .route("/api/read/{circuit}/{name}", post(read))
+        .route("/api/write/{circuit}/{name}", post(write))
+        .route("/api/stats", get(stats))
+        .route("/api/events", get(events))
+        .with_state(daemon)
+}
+
+/// Serve the API on the runtime of the daemon until it is stopped
+pub async fn serve(daemon: Arc<Daemon>, listener: TcpListener) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let app = router(Arc::clone(&daemon));
+    tokio::select! {
+        served = axum::serve(listener, app).into_future() => served,
+        () = daemon.stopped() => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::daemon::fixture::{context, daemon, runtime, wait_for_telegram, Stop};
+
+    /// Send a request, returning the status and the body of the response
+    fn request(address: std::net::SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let content = if body.is_empty() { String::new() } else { format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()) };
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n{}", method, path, content, body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), body.to_string())
+    }
+
+    fn json(body: &str) -> serde_json::Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn api_and_events() {
+        let (context, _) = context(&[]);
+        let runtime = runtime();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::scope(|scope| {
+            let (daemon, capture) = daemon(scope, &runtime, context);
+            let _stop = Stop(&daemon);
+            let server = runtime.spawn(serve(Arc::clone(&daemon), listener));
+
+            // the events are sent from the upgrade on
+            let mut socket = TcpStream::connect(address).unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            write!(socket, "GET /api/events HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                socket.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            assert!(head.starts_with(b"HTTP/1.1 101"));
+
+            capture.start();
+            let mut frame = [0; 2];
+            socket.read_exact(&mut frame).unwrap();
+            assert_eq!(frame[0], 0x81);
+            let length = match frame[1] {
+                126 => {
+                    let mut length = [0; 2];
+                    socket.read_exact(&mut length).unwrap();
+                    u16::from_be_bytes(length) as usize
+                },
+                length => length as usize,
+            };
+            let mut text = vec![0; length];
+            socket.read_exact(&mut text).unwrap();
+            let event = json(std::str::from_utf8(&text).unwrap());
+            assert_eq!(event["event"], "telegram");
+            assert_eq!(event["message"], "bai.FlowTemp");
+            assert_eq!(event["fields"][0]["value"], 36.0);
+
+            wait_for_telegram(&daemon);
+            let (status, body) = request(address, "GET", "/api/values", "");
+            assert_eq!(status, 200);
+            let values = json(&body);
+            assert_eq!(values[0]["fields"][0], json!({ "name": "temp", "value": 36.0, "unit": "°C" }));
+            assert_eq!(request(address, "GET", "/api/values/bai/FlowTemp", "").0, 200);
+            assert_eq!(request(address, "GET", "/api/values/bai/ReturnTemp", "").0, 404);
+            assert_eq!(json(&request(address, "GET", "/api/devices", "").1), json!([{ "address": "10", "master": true }]));
+            assert_eq!(json(&request(address, "GET", "/api/stats", "").1)["commands"][0]["message"], "bai.FlowTemp");
+            let catalogue = json(&request(address, "GET", "/api/catalogue", "").1);
+            assert_eq!(catalogue[1]["fields"][0]["type"], "D1C");
+
+            // a replayed capture cannot be written to
+            assert_eq!(request(address, "POST", "/api/read/bai/FlowTemp", "").0, 502);
+            assert_eq!(request(address, "POST", "/api/read/bai/ReturnTemp", "").0, 404);
+            let (status, body) = request(address, "POST", "/api/write/hc1/DesiredTemp", r#"{"values": [21, 22], "destination": "15"}"#);
+            assert_eq!(status, 400);
+            assert!(json(&body)["error"].as_str().unwrap().contains("expecting 1 value(s)"));
+            let (status, body) = request(address, "POST", "/api/write/hc1/DesiredTemp", r#"{"values": [21.5]}"#);
+            assert_eq!(status, 400);
+            assert!(json(&body)["error"].as_str().unwrap().contains("no destination"));
+            assert_eq!(request(address, "POST", "/api/write/hc1/DesiredTemp", r#"{"values": ["21.5"], "destination": "15"}"#).0, 502);
+
+            daemon.stop();
+            assert!(runtime.block_on(server).unwrap().is_ok());
+        });
+    }
+}
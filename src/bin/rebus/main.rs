@@ -0,0 +1,744 @@
+//! `rebus`, the command-line tool to monitor, query and decode an eBUS
+
+mod analyse;
+mod daemon;
+mod decode;
+#[cfg(feature = "mqtt")]
+mod discovery;
+mod ebusd_server;
+#[cfg(feature = "http")]
+mod http;
+mod monitor;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "tui")]
+mod tui;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use clap::{Args, Parser, Subcommand};
+
+use rebus_core::config::native::{Settings, TransportConfig};
+use rebus_core::config::validate::{lint_dir, validate, Severity};
+use rebus_core::config::{csowada, ebusd, load_dir, native, parse_hex_byte, parse_hex_bytes};
+use rebus_core::layer2::sender::SendError;
+use rebus_core::layer2::*;
+use rebus_core::layer7::grab::{Grabber, DEFAULT_ID_LENGTH};
+use rebus_core::layer7::identification::{Identification, Inventory, IDENTIFICATION_COMMAND};
+use rebus_core::layer7::paged::PagedRecord;
+use rebus_core::layer7::types::DecodeError;
+use rebus_core::layer7::{Catalogue, DecodedMessage, MessageDefinition, MessageKind};
+use rebus_core::transport::capture::{self, Chunk, Pacing, Recorder, Replay};
+use rebus_core::transport::ebusd::{parse_dump, parse_log};
+use rebus_core::transport::pcap::{self, PcapRecorder};
+use rebus_core::transport::{self, Bus, Transport};
+
+use monitor::{format_message, Monitor, OutputArgs};
+
+#[derive(Parser)]
+#[command(name = "rebus", version, about = "Monitor, query and decode an eBUS")]
+struct Cli {
+    #[command(flatten)]
+    options: Options,
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The bus and its configuration. Without transport option, the `[bus]` section of a `rebus.toml` is used.
+#[derive(Args)]
+struct Options {
+    /// Serial device of an adapter, e.g. /dev/ttyUSB0
+    #[arg(long, global = true, value_name = "PATH", conflicts_with_all = ["tcp", "capture"])]
+    serial: Option<String>,
+    /// Network adapter, e.g. 192.168.1.10:9999
+    #[arg(long, global = true, value_name = "HOST:PORT", conflicts_with = "capture")]
+    tcp: Option<String>,
+    /// Read the bus from a capture: recorded by `--record`, `.pcapng`, ebusd `.log` or `.dump`
+    #[arg(long, global = true, value_name = "FILE")]
+    capture: Option<PathBuf>,
+    /// Record the byte stream to a capture, or the telegrams to a `.pcapng` file, those of the
+    /// own address as sent
+    #[arg(long, global = true, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// The adapter speaks the ebusd enhanced protocol
+    #[arg(long, global = true)]
+    enhanced: bool,
+    /// Replay captures at their original speed instead of as fast as possible
+    #[arg(long, global = true)]
+    original_speed: bool,
+    /// Directory of ebusd CSV or csowada JSON files, or a rebus.toml
+    #[arg(short, long, global = true, value_name = "DIR|FILE")]
+    config: Option<PathBuf>,
+    /// Own master address, in hex
+    #[arg(long, global = true, value_name = "QQ", value_parser = hex_byte)]
+    address: Option<u8>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the events of the bus and the decoded messages
+    Monitor {
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Send a telegram and print the response
+    Send {
+        /// ZZ PB SB NN DD.. in hex, spaces allowed
+        #[arg(required = true)]
+        telegram: Vec<String>,
+    },
+    /// Read a message of the configuration
+    Read(MessageArgs),
+    /// Write the values of a message of the configuration
+    Write(MessageArgs),
+    /// Identify the devices answering 07 04
+    Scan {
+        /// Addresses to query in hex, every slave address by default
+        #[arg(value_parser = hex_byte)]
+        addresses: Vec<u8>,
+    },
+    /// Print the events of a capture, as `monitor --capture FILE`
+    Replay {
+        file: PathBuf,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Decode telegrams given in hex, from QQ to the last ACK, or capture files
+    Decode {
+        #[arg(required = true, value_name = "HEX|FILE")]
+        inputs: Vec<String>,
+        /// Print every byte with the component it has been read as
+        #[arg(short, long)]
+        verbose: bool,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Collect the telegrams the configuration does not describe, printing each new one
+    Grab {
+        /// Leading bytes of the master payload telling messages apart
+        #[arg(long, default_value_t = DEFAULT_ID_LENGTH)]
+        id_length: usize,
+        /// Seconds to observe a live bus, a capture being read up to its end
+        #[arg(long, default_value_t = 600)]
+        duration: u64,
+        /// Write the report to a file instead of the standard output
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+        /// Write skeleton definitions of the messages, one file per destination
+        #[arg(long, value_name = "DIR")]
+        export: Option<PathBuf>,
+        #[arg(long, value_parser = ["csv", "json"], default_value = "csv")]
+        export_format: String,
+    },
+    /// Find the bytes of a capture which changed after actions taken at given times
+    Analyse {
+        capture: PathBuf,
+        /// Time of an action from the start of the capture, e.g. `95.5:comfort +1`
+        #[arg(short, long = "marker", value_name = "SECONDS[:LABEL]", value_parser = analyse::marker)]
+        markers: Vec<analyse::Marker>,
+        /// Seconds after a marker within which a change follows it
+        #[arg(long, default_value_t = 10.0)]
+        window: f64,
+        /// Leading bytes of the master payload telling messages apart
+        #[arg(long, default_value_t = DEFAULT_ID_LENGTH)]
+        id_length: usize,
+        /// Print the bytes following no marker too
+        #[arg(long)]
+        all: bool,
+    },
+    /// Full-screen view of the bus: the telegrams, the devices, the last values and the counters
+    #[cfg(feature = "tui")]
+    Tui {
+        #[command(flatten)]
+        filter: monitor::Filter,
+    },
+    /// Serve the bus to other programs, through the protocol of ebusd
+    Serve(daemon::ServeArgs),
+    /// Count the telegrams and the errors of the bus
+    Stats {
+        /// Seconds to observe a live bus, a capture being read up to its end
+        #[arg(long, default_value_t = 60)]
+        duration: u64,
+    },
+    /// Report the problems of a configuration
+    Lint {
+        #[arg(value_name = "DIR|FILE")]
+        config: PathBuf,
+    },
+    /// Export a configuration, one file per circuit
+    Convert {
+        #[arg(value_name = "DIR|FILE")]
+        config: PathBuf,
+        #[arg(value_parser = ["csv", "json"])]
+        format: String,
+        output: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct MessageArgs {
+    circuit: String,
+    name: String,
+    /// Values of the master fields, `-` for the replacement value
+    values: Vec<String>,
+    /// Destination in hex, when the definition does not set one
+    #[arg(long, value_name = "ZZ", value_parser = hex_byte)]
+    destination: Option<u8>,
+}
+
+fn hex_byte(s: &str) -> Result<u8, String> {
+    parse_hex_byte(s).ok_or_else(|| format!("`{}` is not a byte in hex", s))
+}
+
+/// Report the problems of a configuration directory, failing when any of them is an error
+fn lint(dir: &Path) -> ExitCode {
+    let problems = lint_dir(dir);
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+
+    let errors = problems.iter().filter(|p| p.severity == Severity::Error).count();
+    eprintln!("{} error(s), {} warning(s)", errors, problems.len() - errors);
+    if errors > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Load a `rebus.toml`, or the catalogue of a configuration directory with the default settings
+fn load_settings(path: &Path) -> Result<Settings, ExitCode> {
+    let loaded = if path.extension().is_some_and(|e| e == "toml") {
+        native::load(path)
+    } else {
+        load_dir(path).map(|catalogue| Settings { catalogue, ..Settings::default() })
+    };
+    let settings = loaded.map_err(|errors| {
+        errors.iter().for_each(|e| eprintln!("{}", e));
+        ExitCode::FAILURE
+    })?;
+
+    // a definition which cannot decode correctly could fail the exchanges, see `lint` for the warnings
+    let errors: Vec<_> = validate(&settings.catalogue).into_iter().filter(|p| p.severity == Severity::Error).collect();
+    if !errors.is_empty() {
+        errors.iter().for_each(|e| eprintln!("{}", e));
+        return Err(ExitCode::FAILURE);
+    }
+    Ok(settings)
+}
+
+/// Export a configuration directory into the `csv` (ebusd) or `json` (csowada) format, one file per circuit
+fn convert(dir: &Path, format: &str, output: &Path) -> ExitCode {
+    match load_settings(dir) {
+        Ok(settings) => export(settings.catalogue.definitions(), format, output),
+        Err(code) => code,
+    }
+}
+
+/// Write definitions into the `csv` (ebusd) or `json` (csowada) format, one file per circuit
+fn export(definitions: &[MessageDefinition], format: &str, output: &Path) -> ExitCode {
+    let mut errors = Vec::new();
+    let files = match format {
+        "json" => csowada::export(definitions, &mut errors),
+        _ => {
+            let circuits: BTreeSet<&str> = definitions.iter().map(|d| d.circuit.as_str()).collect();
+            circuits.iter()
+                .map(|circuit| {
+                    let definitions: Vec<_> = definitions.iter().filter(|d| d.circuit == *circuit).cloned().collect();
+                    (format!("{}.csv", circuit), ebusd::export(&definitions, &mut errors))
+                })
+                .collect()
+        },
+    };
+    errors.iter().for_each(|e| eprintln!("{}", e));
+
+    for (name, content) in files {
+        if let Err(e) = fs::create_dir_all(output).and_then(|_| fs::write(output.join(&name), content)) {
+            eprintln!("{}: {}", name, e);
+            return ExitCode::FAILURE;
+        }
+    }
+    if errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn is_pcapng(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "pcapng")
+}
+
+/// The chunks of a capture: recorded by us, telegrams of a `.pcapng` file, raw data of an ebusd
+/// `.log` or an ebusd `.dump`
+fn read_chunks(path: &Path) -> io::Result<Vec<Chunk>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("pcapng") => pcap::import(fs::File::open(path)?),
+        Some("log") => parse_log(&fs::read_to_string(path)?),
+        Some("dump") => Ok(parse_dump(&fs::read(path)?)),
+        _ => capture::parse(&fs::read_to_string(path)?),
+    }
+}
+
+fn open_capture(path: &Path, pacing: Pacing) -> io::Result<Replay> {
+    Ok(Replay::new(read_chunks(path)?, pacing))
+}
+
+/// The settings with the global options applied
+struct Context {
+    options: Options,
+    settings: Settings,
+}
+
+impl Context {
+    fn new(options: Options) -> Result<Context, ExitCode> {
+        let mut settings = match &options.config {
+            Some(path) => load_settings(path)?,
+            None => Settings::default(),
+        };
+        if let Some(address) = options.address {
+            settings.address = address;
+        }
+        Ok(Context { options, settings })
+    }
+
+    /// Open the transport given by the options, else by the settings, with a name for the messages,
+    /// its bytes being recorded when asked
+    fn open(&self) -> Result<(Box<dyn Transport + Send>, String), ExitCode> {
+        let (transport, name) = self.open_transport()?;
+        let Some(record) = &self.options.record else {
+            return Ok((transport, name));
+        };
+        let created = fs::File::create(record);
+        let recorder: io::Result<Box<dyn Transport + Send>> = if is_pcapng(record) {
+            created.and_then(|file| PcapRecorder::new(transport, file, self.settings.address)).map(|r| Box::new(r) as _)
+        } else {
+            created.and_then(|file| Recorder::new(transport, file)).map(|r| Box::new(r) as _)
+        };
+        match recorder {
+            Ok(recorder) => Ok((recorder, name)),
+            Err(e) => {
+                eprintln!("{}: {}", record.display(), e);
+                Err(ExitCode::FAILURE)
+            },
+        }
+    }
+
+    fn open_transport(&self) -> Result<(Box<dyn Transport + Send>, String), ExitCode> {
+        let enhanced = self.options.enhanced;
+        let config = match (&self.options.serial, &self.options.tcp, &self.options.capture) {
+            (_, _, Some(path)) => {
+                let pacing = if self.options.original_speed { Pacing::Original } else { Pacing::Fast };
+                return match open_capture(path, pacing) {
+                    Ok(replay) => Ok((Box::new(replay), path.display().to_string())),
+                    Err(e) => {
+                        eprintln!("{}: {}", path.display(), e);
+                        Err(ExitCode::FAILURE)
+                    },
+                };
+            },
+            (Some(device), _, _) => TransportConfig::Serial { device: device.clone(), enhanced },
+            (_, Some(target), _) => match target.rsplit_once(':').map(|(host, port)| (host, port.parse())) {
+                Some((host, Ok(port))) => TransportConfig::Tcp { host: host.to_string(), port, enhanced },
+                _ => {
+                    eprintln!("{}: expecting host:port", target);
+                    return Err(ExitCode::FAILURE);
+                },
+            },
+            (None, None, None) => match &self.settings.transport {
+                Some(config) => config.clone(),
+                None => {
+                    eprintln!("no bus: set --serial, --tcp or --capture, or the transport of rebus.toml");
+                    return Err(ExitCode::FAILURE);
+                },
+            },
+        };
+
+        let name = match &config {
+            TransportConfig::Serial { device, .. } => device.clone(),
+            TransportConfig::Tcp { host, port, .. } => format!("{}:{}", host, port),
+        };
+        match transport::open(&config) {
+            Ok(transport) => Ok((transport, name)),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                Err(ExitCode::FAILURE)
+            },
+        }
+    }
+
+    fn open_bus(&self) -> Result<(Bus<Box<dyn Transport + Send>>, String), ExitCode> {
+        self.open().map(|(transport, name)| (Bus::new(transport), name))
+    }
+
+    /// The definition of a message which can be read or written
+    fn definition(&self, circuit: &str, name: &str, kind: MessageKind) -> Result<&MessageDefinition, String> {
+        self.settings.catalogue.definitions().iter()
+            .find(|d| d.circuit == circuit && d.name == name && d.kind == kind)
+            .ok_or_else(|| {
+                let kind = if kind == MessageKind::Read { "read" } else { "written" };
+                format!("{}.{}: no such message to be {} in the configuration", circuit, name, kind)
+            })
+    }
+
+    /// The definition of a message to read or write and the requests doing it: the pages of a
+    /// paged message read without values, else a request with the values of the master fields
+    fn requests(&self, args: &MessageArgs, kind: MessageKind) -> Result<(&MessageDefinition, Vec<Packet>), String> {
+        let definition = self.definition(&args.circuit, &args.name, kind)?;
+        let Some(destination) = args.destination.or(definition.destination) else {
+            return Err(format!("{}.{}: the definition has no destination, set --destination", args.circuit, args.name));
+        };
+
+        let requests = if definition.paging.is_some() && args.values.is_empty() {
+            definition.page_requests(self.settings.address, destination).ok_or_else(|| "the paging has no page or its requests are too long".to_string())
+        } else {
+            let inputs: Vec<_> = definition.inputs().collect();
+            if inputs.len() != args.values.len() {
+                let names: Vec<&str> = inputs.iter().map(|f| f.name.as_str()).collect();
+                return Err(format!("{}.{}: expecting {} value(s): {}", args.circuit, args.name, inputs.len(), names.join(" ")));
+            }
+            inputs.iter()
+                .zip(&args.values)
+                .map(|(field, text)| field.parse(text).map_err(|e| format!("{}: {}", field.name, e)))
+                .collect::<Result<Vec<_>, String>>()
+                .and_then(|values| definition.request(self.settings.address, destination, &values).map_err(|e| e.to_string()))
+                .map(|request| vec![request])
+        };
+        requests.map(|requests| (definition, requests)).map_err(|e| format!("{}.{}: {}", args.circuit, args.name, e))
+    }
+}
+
+/// Send the requests of a message and decode the responses
+fn query<T: Transport>(bus: &mut Bus<T>, definition: &MessageDefinition, requests: &[Packet]) -> Result<Result<DecodedMessage, DecodeError>, transport::Error> {
+    if requests.is_empty() {
+        return Err(no_request());
+    }
+    let responses = requests.iter().map(|request| bus.send(request)).collect::<Result<Vec<_>, _>>()?;
+    Ok(decode_responses(definition, &responses))
+}
+
+fn no_request() -> transport::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "no request to send").into()
+}
+
+/// Decode the responses to the requests of a message, those of the pages of a paged message together
+fn decode_responses(definition: &MessageDefinition, responses: &[Packet]) -> Result<DecodedMessage, DecodeError> {
+    let pages = definition.paging.as_ref().map_or(0, |paging| paging.count as usize);
+    match PagedRecord::new(definition).filter(|_| responses.len() == pages) {
+        Some(mut record) => {
+            responses.iter().for_each(|response| { record.push(response); });
+            record.decode()
+        },
+        None => definition.decode(&responses[0]),
+    }
+}
+
+fn format_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|c| format!("{:02x}", c)).collect()
+}
+
+/// Hand the events of a bus to `handle`, until the process is killed, the deadline or the end
+/// of a replayed capture
+fn for_each_event<T: Transport>(bus: &mut Bus<T>, name: &str, deadline: Option<Instant>, mut handle: impl FnMut(&BusEvent) -> io::Result<()>) -> ExitCode {
+    while deadline.is_none_or(|d| Instant::now() < d) {
+        match bus.next_event() {
+            Ok(Some(event)) => {
+                if let Err(e) = handle(&event) {
+                    eprintln!("{}: {}", name, e);
+                    return ExitCode::FAILURE;
+                }
+            },
+            Ok(None) if bus.is_finished() => break,
+            Ok(None) => (),
+            Err(e) => eprintln!("{}: {}", name, e),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Print the events of the bus
+fn monitor(context: &Context, output: &OutputArgs) -> ExitCode {
+    let (mut bus, name) = match context.open_bus() {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let mut monitor = match Monitor::new(&context.settings.catalogue, output, io::stdout(), io::stdout().is_terminal()) {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        },
+    };
+    for_each_event(&mut bus, &name, None, |event| monitor.handle(event))
+}
+
+/// Send `ZZ PB SB NN DD..` and print the slave payload, `done` when there is no response
+fn send(context: &Context, telegram: &[String]) -> ExitCode {
+    let bytes = parse_hex_bytes(&telegram.join(""));
+    let request = match bytes.as_deref() {
+        Some([zz, pb, sb, nn, data @ ..]) if *nn as usize == data.len() => Packet::request(context.settings.address, *zz, *pb, *sb, data),
+        _ => None,
+    };
+    let Some(request) = request else {
+        eprintln!("expecting ZZ PB SB NN and NN data bytes, in hex");
+        return ExitCode::FAILURE;
+    };
+
+    let (mut bus, name) = match context.open_bus() {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    match bus.send(&request) {
+        Ok(response) if response.slave_payload().is_empty() => println!("done"),
+        Ok(response) => println!("{}", format_hex(response.slave_payload())),
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            return ExitCode::FAILURE;
+        },
+    }
+    ExitCode::SUCCESS
+}
+
+/// Read or write a message of the configuration and print what is decoded from the exchange
+fn exchange(context: &Context, args: &MessageArgs, kind: MessageKind) -> ExitCode {
+    let (definition, requests) = match context.requests(args, kind) {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        },
+    };
+    let (mut bus, name) = match context.open_bus() {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    match query(&mut bus, definition, &requests) {
+        Ok(Ok(message)) => {
+            println!("{}", format_message(&message));
+            ExitCode::SUCCESS
+        },
+        Ok(Err(e)) => {
+            eprintln!("{}.{}: {}", args.circuit, args.name, e);
+            ExitCode::FAILURE
+        },
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+/// Whether a device may answer at an address
+fn is_slave_address(address: u8) -> bool {
+    matches!(AddressClass::of(address), AddressClass::Slave | AddressClass::MasterSlave(_))
+}
+
+/// Ask each address for its identification, printing the devices which answer
+fn scan(context: &Context, addresses: &[u8]) -> ExitCode {
+    let addresses: Vec<u8> = match addresses {
+        [] => (0..=0xff).filter(|a| is_slave_address(*a) && *a != context.settings.slave_address()).collect(),
+        addresses => addresses.to_vec(),
+    };
+    let (mut bus, name) = match context.open_bus() {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+
+    let (primary, secondary) = IDENTIFICATION_COMMAND;
+    let mut found = 0;
+    for address in addresses {
+        let Some(request) = Packet::request(context.settings.address, address, primary, secondary, &[]) else {
+            continue;
+        };
+        match bus.send(&request) {
+            Ok(response) => match Identification::parse(response.slave_payload()) {
+                Some(identification) => {
+                    println!("{:02x} {}", address, identification);
+                    found += 1;
+                },
+                None => println!("{:02x} unexpected answer {}", address, format_hex(response.slave_payload())),
+            },
+            Err(transport::Error::Send(SendError::Timeout | SendError::Nack)) => (),
+            Err(e @ transport::Error::Send(_)) => eprintln!("{:02x}: {}", address, e),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                return ExitCode::FAILURE;
+            },
+        }
+    }
+    eprintln!("{} device(s) found", found);
+    ExitCode::SUCCESS
+}
+
+#[derive(Default)]
+struct Stats {
+    telegrams: usize,
+    crc_errors: usize,
+    nacks: usize,
+    timeouts: usize,
+    unexpected: usize,
+    connection_losses: usize,
+    /// Telegrams by source
+    sources: BTreeMap<u8, usize>,
+    /// Telegrams by PB SB, with the name of the last message decoded
+    commands: BTreeMap<(u8, u8), (usize, Option<String>)>,
+}
+
+impl Stats {
+    fn count(&mut self, event: &BusEvent, catalogue: &Catalogue, inventory: &mut Inventory) {
+        match event {
+            BusEvent::Telegram(packet) => {
+                self.telegrams += 1;
+                if !packet.is_master_crc_valid() || !packet.is_slave_crc_valid() {
+                    self.crc_errors += 1;
+                }
+                *self.sources.entry(packet.source()).or_default() += 1;
+                inventory.observe(packet);
+                let command = self.commands.entry((packet.primary(), packet.secondary())).or_default();
+                command.0 += 1;
+                if let Some(Ok(message)) = catalogue.decode(packet, inventory) {
+                    command.1 = Some(format!("{}.{}", message.circuit, message.name));
+                }
+            },
+            BusEvent::Nack(..) => self.nacks += 1,
+            BusEvent::Timeout(_) => self.timeouts += 1,
+            BusEvent::Unexpected(..) => self.unexpected += 1,
+            BusEvent::ConnectionLost => self.connection_losses += 1,
+            BusEvent::ConnectionRestored => (),
+        }
+    }
+
+    fn print(&self, elapsed: Duration) {
+        println!("{} telegram(s) in {:.1} s", self.telegrams, elapsed.as_secs_f64());
+        println!("  {} CRC error(s), {} NACK(s), {} timeout(s), {} unexpected byte(s), {} connection loss(es)",
+            self.crc_errors, self.nacks, self.timeouts, self.unexpected, self.connection_losses);
+        println!("by source:");
+        for (source, count) in &self.sources {
+            println!("  {:02x} {}", source, count);
+        }
+        println!("by command:");
+        for ((primary, secondary), (count, name)) in &self.commands {
+            println!("  {:02x}{:02x} {} {}", primary, secondary, count, name.as_deref().unwrap_or(""));
+        }
+    }
+}
+
+/// Count the events of the bus for a while, or of a whole capture
+fn stats(context: &Context, duration: Duration) -> ExitCode {
+    let (mut bus, name) = match context.open_bus() {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let start = Instant::now();
+    let deadline = context.options.capture.is_none().then_some(start + duration);
+    let mut stats = Stats::default();
+    let mut inventory = Inventory::new();
+
+    let status = for_each_event(&mut bus, &name, deadline, |event| {
+        stats.count(event, &context.settings.catalogue, &mut inventory);
+        Ok(())
+    });
+    stats.print(start.elapsed());
+    status
+}
+
+/// Options of `grab`
+struct GrabArgs<'a> {
+    id_length: usize,
+    duration: Duration,
+    report: Option<&'a Path>,
+    export: Option<&'a Path>,
+    export_format: &'a str,
+}
+
+/// Collect the unknown telegrams for a while, or of a whole capture, then report them
+fn grab(context: &Context, args: GrabArgs) -> ExitCode {
+    let (mut bus, name) = match context.open_bus() {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let deadline = context.options.capture.is_none().then(|| Instant::now() + args.duration);
+    let mut grabber = Grabber::new(args.id_length);
+    let mut inventory = Inventory::new();
+
+    let status = for_each_event(&mut bus, &name, deadline, |event| {
+        if let BusEvent::Telegram(packet) = event {
+            inventory.observe(packet);
+            if grabber.grab(packet, &context.settings.catalogue, &inventory) {
+                eprintln!("new: {}  {}", grabber.key(packet), event);
+            }
+        }
+        Ok(())
+    });
+
+    let written = match args.report {
+        Some(path) => fs::write(path, grabber.to_string()).map_err(|e| format!("{}: {}", path.display(), e)),
+        None => {
+            print!("{}", grabber);
+            Ok(())
+        },
+    };
+    if let Err(e) = written {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    match args.export {
+        Some(dir) if !grabber.is_empty() => export(&grabber.skeletons(), args.export_format, dir),
+        _ => status,
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut options = cli.options;
+
+    // commands reading their configuration on their own
+    match &cli.command {
+        Command::Lint { config } => return lint(config),
+        Command::Analyse { capture, markers, window, id_length, all } => return analyse::analyse(analyse::AnalyseArgs {
+            capture,
+            markers,
+            window: Duration::from_secs_f64(*window),
+            id_length: *id_length,
+            all: *all,
+        }),
+        Command::Convert { config, format, output } => return convert(config, format, output),
+        Command::Replay { file, .. } => {
+            options.serial = None;
+            options.tcp = None;
+            options.capture = Some(file.clone());
+        },
+        _ => (),
+    }
+    let context = match Context::new(options) {
+        Ok(context) => context,
+        Err(code) => return code,
+    };
+
+    match &cli.command {
+        Command::Monitor { output } => monitor(&context, output),
+        Command::Replay { output, .. } => monitor(&context, output),
+        Command::Send { telegram } => send(&context, telegram),
+        Command::Read(args) => exchange(&context, args, MessageKind::Read),
+        Command::Write(args) => exchange(&context, args, MessageKind::Write),
+        Command::Scan { addresses } => scan(&context, addresses),
+        Command::Decode { inputs, output, verbose } => decode::decode(&context, inputs, output, *verbose),
+        Command::Grab { id_length, duration, report, export, export_format } => grab(&context, GrabArgs {
+            id_length: *id_length,
+            duration: Duration::from_secs(*duration),
+            report: report.as_deref(),
+            export: export.as_deref(),
+            export_format,
+        }),
+        Command::Stats { duration } => stats(&context, Duration::from_secs(*duration)),
+        Command::Serve(args) => daemon::serve(context, args),
+        #[cfg(feature = "tui")]
+        Command::Tui { filter } => tui::run(&context, filter),
+        Command::Lint { .. } | Command::Convert { .. } | Command::Analyse { .. } => unreachable!(),
+    }
+}
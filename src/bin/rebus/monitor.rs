@@ -0,0 +1,429 @@
+//! How the events of the bus are printed: filtered, then written as text, JSON lines or CSV
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+
+use rebus_core::layer2::{BusEvent, Packet, TelegramComponent};
+use rebus_core::layer7::identification::Inventory;
+use rebus_core::layer7::paged::PagedRecords;
+use rebus_core::layer7::types::{DecodeError, Value};
+use rebus_core::layer7::{Catalogue, DecodedMessage, MessageDefinition};
+
+use crate::hex_byte;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// The telegrams as exchanged, followed by their decoded fields
+    Text,
+    /// A JSON object per line
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Color {
+    /// When printing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// Events to print. Given any filter, only the telegrams satisfying all of them are printed.
+#[derive(Args)]
+pub struct Filter {
+    /// Only the telegrams sent by these masters, in hex
+    #[arg(long, value_name = "QQ", value_parser = hex_byte)]
+    source: Vec<u8>,
+    /// Only the telegrams sent to these addresses, in hex
+    #[arg(long, value_name = "ZZ", value_parser = hex_byte)]
+    destination: Vec<u8>,
+    /// Only the telegrams of these commands, PB or PBSB in hex
+    #[arg(long, value_name = "PB[SB]", value_parser = command)]
+    command: Vec<(u8, Option<u8>)>,
+    /// Only these messages of the configuration, `circuit` or `circuit.name`
+    #[arg(long, value_name = "CIRCUIT[.NAME]")]
+    message: Vec<String>,
+}
+
+fn command(s: &str) -> Result<(u8, Option<u8>), String> {
+    match (s.get(..2).and_then(|pb| hex_byte(pb).ok()), s.get(2..)) {
+        (Some(pb), Some("")) => Ok((pb, None)),
+        (Some(pb), Some(sb)) => hex_byte(sb).map(|sb| (pb, Some(sb))),
+        _ => Err(format!("`{}` is not PB or PBSB in hex", s)),
+    }
+}
+
+impl Filter {
+    fn is_empty(&self) -> bool {
+        self.source.is_empty() && self.destination.is_empty() && self.command.is_empty() && self.message.is_empty()
+    }
+
+    pub fn accepts(&self, event: &BusEvent, definition: Option<&MessageDefinition>) -> bool {
+        let Some(packet) = packet(event) else {
+            return self.is_empty();
+        };
+        let message = |filter: &String| definition.is_some_and(|d| match filter.split_once('.') {
+            Some((circuit, name)) => d.circuit == circuit && d.name == name,
+            None => d.circuit == *filter,
+        });
+
+        (self.source.is_empty() || self.source.contains(&packet.source()))
+            && (self.destination.is_empty() || self.destination.contains(&packet.destination()))
+            && (self.command.is_empty() || self.command.iter().any(|(pb, sb)| *pb == packet.primary() && sb.is_none_or(|sb| sb == packet.secondary())))
+            && (self.message.is_empty() || self.message.iter().any(message))
+    }
+}
+
+/// Output options of the commands printing events
+#[derive(Args)]
+pub struct OutputArgs {
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Highlight the errors in the text output
+    #[arg(long, value_enum, default_value_t = Color::Auto)]
+    color: Color,
+    #[command(flatten)]
+    filter: Filter,
+}
+
+impl OutputArgs {
+    pub fn format(&self) -> Format {
+        self.format
+    }
+}
+
+pub fn packet(event: &BusEvent) -> Option<&Packet> {
+    match event {
+        BusEvent::Telegram(packet) | BusEvent::Nack(packet, _) => Some(packet),
+        _ => None,
+    }
+}
+
+fn kind(event: &BusEvent) -> &'static str {
+    match event {
+        BusEvent::Telegram(_) => "telegram",
+        BusEvent::Nack(..) => "nack",
+        BusEvent::Timeout(_) => "timeout",
+        BusEvent::Unexpected(..) => "unexpected",
+        BusEvent::ConnectionLost => "connection_lost",
+        BusEvent::ConnectionRestored => "connection_restored",
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// What went wrong with an event
+pub fn problem(event: &BusEvent) -> Option<(Severity, String)> {
+    match event {
+        BusEvent::Telegram(packet) if !packet.is_master_crc_valid() => Some((Severity::Error, "master CRC error".to_string())),
+        BusEvent::Telegram(packet) if !packet.is_slave_crc_valid() => Some((Severity::Error, "slave CRC error".to_string())),
+        BusEvent::Telegram(_) | BusEvent::ConnectionRestored => None,
+        BusEvent::Nack(_, TelegramComponent::SlaveACK) => Some((Severity::Error, "NACK from the addressee".to_string())),
+        BusEvent::Nack(..) => Some((Severity::Error, "NACK from the master".to_string())),
+        BusEvent::Timeout(_) => Some((Severity::Warning, event.to_string())),
+        BusEvent::Unexpected(..) | BusEvent::ConnectionLost => Some((Severity::Error, event.to_string())),
+    }
+}
+
+/// `circuit.name field=value unit ...`, `-` standing for the replacement value
+pub fn format_message(message: &DecodedMessage) -> String {
+    let mut line = format!("{}.{}", message.circuit, message.name);
+    for field in &message.fields {
+        let value = field.value.as_ref().map_or_else(|| "-".to_string(), Value::to_string);
+        line += &format!(" {}={}", field.name, value);
+        if let (Some(unit), Some(_)) = (&field.unit, &field.value) {
+            line += &format!(" {}", unit);
+        }
+    }
+    line
+}
+
+#[derive(Serialize)]
+struct JsonField<'a> {
+    name: &'a str,
+    value: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonByte {
+    byte: String,
+    component: String,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    /// Seconds since the UNIX epoch
+    time: f64,
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    telegram: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<JsonField<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    bytes: Vec<JsonByte>,
+}
+
+pub fn json_value(value: Option<&Value>) -> serde_json::Value {
+    match value {
+        None => serde_json::Value::Null,
+        Some(Value::Integer(i)) => (*i).into(),
+        Some(Value::Float(v)) => (*v).into(),
+        Some(Value::Boolean(b)) => (*b).into(),
+        Some(value) => value.to_string().into(),
+    }
+}
+
+/// Quote a CSV field when needed
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub struct Printer<W> {
+    format: Format,
+    color: bool,
+    output: W,
+}
+
+impl<W: Write> Printer<W> {
+    /// `color` enables the highlighting of the text output, the CSV header is written right away
+    pub fn new(format: Format, color: bool, mut output: W) -> io::Result<Printer<W>> {
+        if format == Format::Csv {
+            writeln!(output, "time,event,telegram,message,fields,error")?;
+        }
+        Ok(Printer { format, color, output })
+    }
+
+    fn highlight(&self, severity: Severity, text: &str) -> String {
+        match (self.color, severity) {
+            (false, _) => text.to_string(),
+            (true, Severity::Warning) => format!("\x1b[33m{}\x1b[0m", text),
+            (true, Severity::Error) => format!("\x1b[31m{}\x1b[0m", text),
+        }
+    }
+
+    /// Print an event, after the bytes it has been read from with the component each one was read as.
+    /// The bytes are left out of the CSV output.
+    pub fn print(&mut self, event: &BusEvent, decoded: Option<&Result<DecodedMessage, DecodeError>>, bytes: &[(u8, TelegramComponent)]) -> io::Result<()> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let telegram = packet(event).map(|_| event.to_string());
+        let message = match decoded {
+            Some(Ok(message)) => Some(message),
+            _ => None,
+        };
+        let mut problem = problem(event);
+        if let (None, Some(Err(e))) = (&problem, decoded) {
+            problem = Some((Severity::Warning, format!("cannot decode: {}", e)));
+        }
+
+        match self.format {
+            Format::Text => {
+                for (c, component) in bytes {
+                    writeln!(self.output, "  {:02x} {:?}", c, component)?;
+                }
+                let mut line = match (&telegram, &problem) {
+                    (Some(telegram), _) => telegram.clone(),
+                    (None, Some((severity, text))) => self.highlight(*severity, text),
+                    (None, None) => event.to_string(),
+                };
+                if let Some(message) = message {
+                    line += &format!("  {}", format_message(message));
+                }
+                if let (Some(_), Some((severity, text))) = (&telegram, &problem) {
+                    line += &format!("  {}", self.highlight(*severity, text));
+                }
+                writeln!(self.output, "{}", line)
+            },
+            Format::Json => {
+                let line = JsonLine {
+                    time: (time * 1000.0).round() / 1000.0,
+                    event: kind(event),
+                    telegram,
+                    message: message.map(|m| format!("{}.{}", m.circuit, m.name)),
+                    fields: message.iter()
+                        .flat_map(|m| &m.fields)
+                        .map(|f| JsonField { name: &f.name, value: json_value(f.value.as_ref()), unit: f.unit.as_deref() })
+                        .collect(),
+                    error: problem.map(|(_, text)| text),
+                    bytes: bytes.iter()
+                        .map(|(c, component)| JsonByte { byte: format!("{:02x}", c), component: format!("{:?}", component) })
+                        .collect(),
+                };
+                serde_json::to_writer(&mut self.output, &line)?;
+                writeln!(self.output)
+            },
+            Format::Csv => {
+                let (name, fields) = match message {
+                    Some(message) => {
+                        let line = format_message(message);
+                        let fields = line.split_once(' ').map_or("", |(_, fields)| fields).to_string();
+                        (format!("{}.{}", message.circuit, message.name), fields)
+                    },
+                    None => (String::new(), String::new()),
+                };
+                writeln!(self.output, "{:.3},{},{},{},{},{}", time, kind(event), telegram.unwrap_or_default(),
+                    csv_field(&name), csv_field(&fields), csv_field(&problem.map(|(_, text)| text).unwrap_or_default()))
+            },
+        }
+    }
+}
+
+/// Decode, filter and print the events of a bus, learning the identifications of its devices on the way
+pub struct Monitor<'a, W> {
+    catalogue: &'a Catalogue,
+    inventory: Inventory,
+    pages: PagedRecords<'a>,
+    filter: &'a Filter,
+    printer: Printer<W>,
+}
+
+impl<'a, W: Write> Monitor<'a, W> {
+    /// `terminal` tells whether the output is a terminal, for `Color::Auto`
+    pub fn new(catalogue: &'a Catalogue, args: &'a OutputArgs, output: W, terminal: bool) -> io::Result<Monitor<'a, W>> {
+        let color = match args.color {
+            Color::Auto => terminal,
+            Color::Always => true,
+            Color::Never => false,
+        };
+        Ok(Monitor {
+            catalogue,
+            inventory: Inventory::new(),
+            pages: PagedRecords::new(),
+            filter: &args.filter,
+            printer: Printer::new(args.format, color, output)?,
+        })
+    }
+
+    pub fn handle(&mut self, event: &BusEvent) -> io::Result<()> {
+        self.handle_bytes(event, &[])
+    }
+
+    /// Handle an event with the bytes it has been read from, see `Printer::print`
+    pub fn handle_bytes(&mut self, event: &BusEvent, bytes: &[(u8, TelegramComponent)]) -> io::Result<()> {
+        if let BusEvent::Telegram(packet) = event {
+            self.inventory.observe(packet);
+        }
+        let definition = packet(event).and_then(|p| self.catalogue.find(p, &self.inventory));
+        if !self.filter.accepts(event, definition) {
+            return Ok(());
+        }
+        let decoded = match (event, definition) {
+            (BusEvent::Telegram(packet), Some(definition)) => self.pages.decode(definition, packet),
+            _ => None,
+        };
+        self.printer.print(event, decoded.as_ref(), bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rebus_core::layer7::paged::Paging;
+    use rebus_core::layer7::types::DataType;
+    use rebus_core::layer7::{FieldDefinition, MessageKind, Part};
+
+    fn flow_temp() -> Catalogue {
+        let mut definition = MessageDefinition::new("bai", "FlowTemp", MessageKind::Read, 0xb5, 0x09);
+        definition.id = vec![0x0d, 0x18, 0x00];
+        let mut temp = FieldDefinition::new("temp", Part::Slave, 0, DataType::Data2c);
+        temp.unit = Some("°C".to_string());
+        definition.fields.push(temp);
+        [definition].into_iter().collect()
+    }
+
+    fn events() -> Vec<BusEvent> {
+        let packet = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap()
+            .with_response(&[0x40, 0x02]).unwrap();
+        let other = Packet::request(0x31, 0x15, 0x07, 0x04, &[]).unwrap();
+        vec![
+            BusEvent::Telegram(packet),
+            BusEvent::Nack(other, TelegramComponent::SlaveACK),
+            BusEvent::Timeout(TelegramComponent::SlaveACK),
+        ]
+    }
+
+    fn print(format: Format, filter: &[&str]) -> String {
+        let catalogue = flow_temp();
+        let args = <crate::Cli as clap::Parser>::try_parse_from(
+            ["rebus", "monitor", "--color", "never", "--format", format.to_possible_value().unwrap().get_name()].iter().chain(filter)
+        ).unwrap();
+        let crate::Command::Monitor { output: args, .. } = args.command else {
+            unreachable!()
+        };
+        let mut output = Vec::new();
+        let mut monitor = Monitor::new(&catalogue, &args, &mut output, false).unwrap();
+        events().iter().for_each(|event| monitor.handle(event).unwrap());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn text_lines() {
+        assert_eq!(print(Format::Text, &[]), "\
+>1008b509030d1800a6<00024002e6>00  bai.FlowTemp temp=36 °C
+>31150704008b<ff  NACK from the addressee
+timeout waiting for SlaveACK
+");
+        assert_eq!(print(Format::Text, &["--message", "bai"]).lines().count(), 1);
+        assert_eq!(print(Format::Text, &["--source", "31"]).lines().count(), 1);
+        assert_eq!(print(Format::Text, &["--command", "b5", "--destination", "08"]).lines().count(), 1);
+        assert_eq!(print(Format::Text, &["--command", "b510"]), "");
+    }
+
+    #[test]
+    fn paged_messages_are_decoded_with_their_last_page() {
+        let mut definition = MessageDefinition::new("hc1", "Timer", MessageKind::Read, 0xb5, 0x55);
+        definition.id = vec![0xa3];
+        definition.paging = Some(Paging::new(1, 2));
+        definition.fields.push(FieldDefinition::new("from", Part::Slave, 0, DataType::Uin));
+        definition.fields.push(FieldDefinition::new("to", Part::Slave, 2, DataType::Uin));
+        let requests = definition.page_requests(0x10, 0x15).unwrap();
+        let catalogue: Catalogue = [definition].into_iter().collect();
+        let args = <crate::Cli as clap::Parser>::try_parse_from(["rebus", "monitor", "--color", "never"]).unwrap();
+        let crate::Command::Monitor { output: args, .. } = args.command else {
+            unreachable!()
+        };
+
+        let mut output = Vec::new();
+        let mut monitor = Monitor::new(&catalogue, &args, &mut output, false).unwrap();
+        for (request, response) in requests.into_iter().zip([[0x68, 0x01], [0x84, 0x03]]) {
+            monitor.handle(&BusEvent::Telegram(request.with_response(&response).unwrap())).unwrap();
+        }
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(!lines[0].contains("hc1.Timer") && !lines[0].contains("cannot decode"), "{}", lines[0]);
+        assert!(lines[1].ends_with("  hc1.Timer from=360 to=900"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn json_and_csv_lines() {
+        let json = print(Format::Json, &[]);
+        let first: serde_json::Value = serde_json::from_str(json.lines().next().unwrap()).unwrap();
+        assert_eq!(first["telegram"], ">1008b509030d1800a6<00024002e6>00");
+        assert_eq!(first["message"], "bai.FlowTemp");
+        assert_eq!(first["fields"][0]["value"], 36.0);
+        let last: serde_json::Value = serde_json::from_str(json.lines().last().unwrap()).unwrap();
+        assert_eq!(last["event"], "timeout");
+
+        let csv = print(Format::Csv, &[]);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "time,event,telegram,message,fields,error");
+        assert!(lines[1].ends_with(",telegram,>1008b509030d1800a6<00024002e6>00,bai.FlowTemp,temp=36 °C,"));
+        assert!(lines[2].ends_with(",NACK from the addressee"));
+    }
+}
@@ -0,0 +1,395 @@
+//! Publishing of the decoded values to an MQTT broker, each field to a retained topic, and the reads
+//! and writes asked by publishing to the `get` and `set` topics of a message
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use clap::{Args, ValueEnum};
+use rumqttc::{Client, Connection, Event, Incoming, LastWill, MqttOptions, Outgoing, QoS, SubscribeFilter};
+
+use rebus_core::layer7::identification::Identification;
+use rebus_core::layer7::{Catalogue, DecodedField, DecodedMessage, MessageDefinition, MessageKind};
+
+use crate::daemon::{query, Daemon, Events};
+use crate::discovery;
+use crate::ebusd_server::identification_request;
+use crate::monitor::json_value;
+use crate::{is_slave_address, MessageArgs};
+
+pub const DEFAULT_PORT: u16 = 1883;
+/// Publications waiting for the connection, those beyond being dropped
+const CAPACITY: usize = 100;
+/// Wait when no message was decoded, before looking again and whether the daemon was stopped
+const IDLE: Duration = Duration::from_millis(100);
+/// Wait before connecting again to the broker
+const RETRY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Payload {
+    /// The value as text, `-` for the replacement value
+    Plain,
+    /// `{"value": .., "unit": ..}`
+    Json,
+}
+
+#[derive(Clone)]
+pub struct Broker {
+    host: String,
+    port: u16,
+}
+
+impl fmt::Display for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+fn broker(s: &str) -> Result<Broker, String> {
+    match s.rsplit_once(':') {
+        Some((host, port)) => port.parse()
+            .map(|port| Broker { host: host.to_string(), port })
+            .map_err(|_| format!("{}: expecting host[:port]", s)),
+        None => Ok(Broker { host: s.to_string(), port: DEFAULT_PORT }),
+    }
+}
+
+/// Options of the MQTT publishing of `serve`
+#[derive(Args)]
+pub struct MqttArgs {
+    /// Broker to publish the decoded values to
+    #[arg(long, value_name = "HOST[:PORT]", value_parser = broker)]
+    pub mqtt: Option<Broker>,
+    /// First level of the topics, followed by the circuit, the message and the field
+    #[arg(long, value_name = "PREFIX", default_value = "rebus")]
+    pub mqtt_prefix: String,
+    #[arg(long, value_enum, default_value_t = Payload::Plain)]
+    pub mqtt_payload: Payload,
+    #[arg(long, value_name = "USER")]
+    pub mqtt_username: Option<String>,
+    #[arg(long, value_name = "PASSWORD", requires = "mqtt_username")]
+    pub mqtt_password: Option<String>,
+    /// Announce an entity for each field of the catalogue to Home Assistant
+    #[arg(long)]
+    pub mqtt_discovery: bool,
+    /// First level of the discovery topics of Home Assistant
+    #[arg(long, value_name = "PREFIX", default_value = "homeassistant")]
+    pub mqtt_discovery_prefix: String,
+}
+
+/// A topic level naming a circuit, a message or a field, without the characters MQTT reserves
+pub fn level(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+/// Topic of a field, named by its index among the decoded fields when unnamed
+pub fn topic(prefix: &str, circuit: &str, message: &str, index: usize, field: &str) -> String {
+    let field = match field {
+        "" => index.to_string(),
+        // the topics of the commands on the message
+        "get" | "set" => format!("{}_", field),
+        name => level(name),
+    };
+    format!("{}/{}/{}/{}", prefix, level(circuit), level(message), field)
+}
+
+fn payload(field: &DecodedField, format: Payload) -> String {
+    match format {
+        Payload::Plain => field.value.as_ref().map_or_else(|| "-".to_string(), ToString::to_string),
+        Payload::Json => serde_json::json!({ "value": json_value(field.value.as_ref()), "unit": field.unit }).to_string(),
+    }
+}
+
+/// The topic and the payload of each field of a message
+pub fn publications(prefix: &str, message: &DecodedMessage, format: Payload) -> Vec<(String, String)> {
+    message.fields.iter()
+        .enumerate()
+        .map(|(i, field)| (topic(prefix, &message.circuit, &message.name, i, &field.name), payload(field, format)))
+        .collect()
+}
+
+/// The circuit, the message and the kind of exchange asked by a publication on a command topic
+fn command<'t>(prefix: &str, topic: &'t str) -> Option<(&'t str, &'t str, MessageKind)> {
+    let levels: Vec<&str> = topic.strip_prefix(prefix)?.strip_prefix('/')?.split('/').collect();
+    match levels.as_slice() {
+        [circuit, name, "get"] => Some((circuit, name, MessageKind::Read)),
+        [circuit, name, "set"] => Some((circuit, name, MessageKind::Write)),
+        _ => None,
+    }
+}
+
+/// The definition of a message named by the levels of a command topic
+fn named<'c>(catalogue: &'c Catalogue, circuit: &str, name: &str, kind: MessageKind) -> Option<&'c MessageDefinition> {
+    catalogue.definitions().iter().find(|d| d.kind == kind && level(&d.circuit) == circuit && level(&d.name) == name)
+}
+
+/// The values of the master fields of a command: a JSON array, else separated by `;` as ebusd does
+fn values(payload: &str) -> Vec<String> {
+    let payload = payload.trim();
+    if payload.is_empty() {
+        return Vec::new();
+    }
+    if let Ok(values) = serde_json::from_str::<Vec<serde_json::Value>>(payload) {
+        return values.into_iter()
+            .map(|value| match value {
+                serde_json::Value::String(s) => s,
+                value => value.to_string(),
+            })
+            .collect();
+    }
+    payload.split(';').map(|value| value.trim().to_string()).collect()
+}
+
+/// Queue the read or the write of a message, the response of a read being published as any telegram
+fn order(daemon: &Daemon, circuit: &str, name: &str, kind: MessageKind, payload: &str) -> Result<(), String> {
+    let (circuit, name) = named(&daemon.context.settings.catalogue, circuit, name, kind)
+        .map_or((circuit, name), |d| (d.circuit.as_str(), d.name.as_str()));
+    let args = MessageArgs { circuit: circuit.to_string(), name: name.to_string(), values: values(payload), destination: None };
+    let (definition, requests) = daemon.context.requests(&args, kind)?;
+    let (bus, definition) = (daemon.bus.clone(), definition.clone());
+    daemon.spawn(async move {
+        match query(&bus, &definition, requests).await {
+            Err(e) => eprintln!("{}.{}: {}", definition.circuit, definition.name, e),
+            Ok(Err(e)) if kind == MessageKind::Read => eprintln!("{}.{}: {}", definition.circuit, definition.name, e),
+            Ok(_) => (),
+        }
+    });
+    Ok(())
+}
+
+pub fn status(prefix: &str) -> String {
+    format!("{}/status", prefix)
+}
+
+/// Announce to Home Assistant the entities of the devices whose identification changed, those of
+/// every device when `all`
+fn announce(daemon: &Daemon, client: &Client, args: &MqttArgs, identified: &mut BTreeMap<u8, Identification>, all: bool) {
+    let inventory = daemon.state().inventory.clone();
+    let changed: BTreeSet<u8> = inventory.iter()
+        .filter(|(address, identification)| identified.get(address) != Some(identification))
+        .map(|(address, _)| address)
+        .collect();
+    if !all && changed.is_empty() {
+        return;
+    }
+    *identified = inventory.iter().map(|(address, identification)| (address, identification.clone())).collect();
+    for (topic, config) in discovery::configs(args, &daemon.context.settings.catalogue, &inventory, |a| all || changed.contains(&a)) {
+        let _ = client.publish(topic, QoS::AtLeastOnce, true, config);
+    }
+}
+
+/// Publish the decoded messages until the daemon is stopped, then disconnect
+fn publish(daemon: &Daemon, client: Client, mut events: Events, args: &MqttArgs, connected: &AtomicBool) {
+    let mut identified = BTreeMap::new();
+    while !daemon.is_stopped() {
+        if args.mqtt_discovery {
+            announce(daemon, &client, args, &mut identified, connected.swap(false, Ordering::Relaxed));
+        }
+        let Some(observed) = events.try_recv() else {
+            thread::sleep(IDLE);
+            continue;
+        };
+        for (topic, payload) in observed.message().iter().flat_map(|message| publications(&args.mqtt_prefix, message, args.mqtt_payload)) {
+            // dropped while the broker cannot be reached, the retained values being replaced anyway
+            let _ = client.try_publish(topic, QoS::AtLeastOnce, true, payload);
+        }
+    }
+    let _ = client.try_publish(status(&args.mqtt_prefix), QoS::AtLeastOnce, true, "offline");
+    let _ = client.try_disconnect();
+}
+
+/// Keep the connection to the broker, subscribing to the command topics on each connection, until
+/// the disconnection once the daemon is stopped
+fn connect(daemon: &Daemon, client: Client, mut connection: Connection, args: &MqttArgs, broker: &Broker, connected: &AtomicBool) {
+    let prefix = &args.mqtt_prefix;
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                let filters = ["get", "set"].map(|verb| SubscribeFilter::new(format!("{}/+/+/{}", prefix, verb), QoS::AtLeastOnce));
+                let _ = client.try_subscribe_many(filters);
+                let _ = client.try_publish(status(prefix), QoS::AtLeastOnce, true, "online");
+                connected.store(true, Ordering::Relaxed);
+            },
+            // the commands left retained on the broker are not run again
+            Ok(Event::Incoming(Incoming::Publish(publish))) if !publish.retain => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                if let Some((circuit, name, kind)) = command(prefix, &publish.topic) {
+                    if let Err(e) = order(daemon, circuit, name, kind, &payload) {
+                        eprintln!("{}: {}", publish.topic, e);
+                    }
+                }
+            },
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => (),
+            Err(_) if daemon.is_stopped() => break,
+            Err(e) => {
+                eprintln!("{}: {}", broker, e);
+                thread::sleep(RETRY);
+            },
+        }
+    }
+}
+
+/// Publish the decoded messages to the broker and run the commands published to it, until the
+/// daemon is stopped
+pub fn run(daemon: &Daemon, args: &MqttArgs, broker: &Broker) {
+    let mut options = MqttOptions::new(format!("rebus-{}", std::process::id()), &broker.host, broker.port);
+    options.set_last_will(LastWill::new(status(&args.mqtt_prefix), "offline", QoS::AtLeastOnce, true));
+    if let Some(username) = &args.mqtt_username {
+        options.set_credentials(username, args.mqtt_password.as_deref().unwrap_or_default());
+    }
+    if args.mqtt_discovery {
+        let inventory = daemon.state().inventory.clone();
+        let requests = discovery::addresses(&daemon.context.settings.catalogue).into_iter()
+            .filter(|address| is_slave_address(*address) && inventory.get(*address).is_none())
+            .filter_map(|address| identification_request(daemon, address))
+            .collect();
+        daemon.queue(requests);
+    }
+    // before connecting, for no message decoded once connected to be missed
+    let events = daemon.subscribe();
+    let (client, connection) = Client::new(options, CAPACITY);
+    let connected = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let subscriber = client.clone();
+        scope.spawn(|| connect(daemon, subscriber, connection, args, broker, &connected));
+        publish(daemon, client, events, args, &connected);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use rebus_core::layer7::types::Value;
+
+    use crate::daemon::fixture::{context, daemon, runtime, Stop};
+
+    #[test]
+    fn topics_and_payloads() {
+        let field = |name: &str, value, unit: Option<&str>| DecodedField { name: name.to_string(), value, unit: unit.map(str::to_string) };
+        let message = DecodedMessage {
+            circuit: "bai".to_string(),
+            name: "Status/01".to_string(),
+            fields: vec![
+                field("temp", Some(Value::Float(36.5)), Some("°C")),
+                field("", None, None),
+                field("set", Some(Value::Named(1, "on".to_string())), None),
+            ],
+        };
+        assert_eq!(publications("rebus", &message, Payload::Plain), [
+            ("rebus/bai/Status_01/temp".to_string(), "36.5".to_string()),
+            ("rebus/bai/Status_01/1".to_string(), "-".to_string()),
+            ("rebus/bai/Status_01/set_".to_string(), "on".to_string()),
+        ]);
+        let json: Vec<_> = publications("home/ebus", &message, Payload::Json).into_iter().map(|(_, payload)| payload).collect();
+        assert_eq!(json, [r#"{"unit":"°C","value":36.5}"#, r#"{"unit":null,"value":null}"#, r#"{"unit":null,"value":"on"}"#]);
+
+        assert_eq!(command("rebus", "rebus/hc1/DesiredTemp/set"), Some(("hc1", "DesiredTemp", MessageKind::Write)));
+        assert_eq!(command("home/ebus", "home/ebus/bai/FlowTemp/get"), Some(("bai", "FlowTemp", MessageKind::Read)));
+        assert_eq!(command("rebus", "rebus/bai/FlowTemp/temp"), None);
+        assert_eq!(command("rebus", "rebusx/bai/FlowTemp/get"), None);
+        let catalogue: Catalogue = [MessageDefinition::new("hc1", "Timer/Mo", MessageKind::Read, 0xb5, 0x55)].into_iter().collect();
+        assert_eq!(named(&catalogue, "hc1", "Timer_Mo", MessageKind::Read).map(|d| d.name.as_str()), Some("Timer/Mo"));
+        assert!(named(&catalogue, "hc1", "Timer/Mo", MessageKind::Read).is_none());
+        assert!(named(&catalogue, "hc1", "Timer_Mo", MessageKind::Write).is_none());
+        assert_eq!(values(" 21.5; on "), ["21.5", "on"]);
+        assert_eq!(values(r#"[21.5, "on"]"#), ["21.5", "on"]);
+        assert!(values("").is_empty());
+        assert_eq!(broker("localhost").unwrap().to_string(), "localhost:1883");
+        assert_eq!(broker("10.0.0.2:1884").unwrap().to_string(), "10.0.0.2:1884");
+        assert!(broker("host:port").is_err());
+    }
+
+    /// Read an MQTT packet: its first byte and what follows its length
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        let kind = byte[0];
+        let mut length = 0;
+        for shift in (0..28).step_by(7) {
+            stream.read_exact(&mut byte).unwrap();
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        (kind, body)
+    }
+
+    /// Answer the packets of the client as a broker would, until a publication satisfying `until`,
+    /// returning the topic, the payload and the retain flag of the publications
+    fn broker_until(stream: &mut TcpStream, until: impl Fn(&str) -> bool) -> Vec<(String, String, bool)> {
+        let mut publications = Vec::new();
+        loop {
+            let (kind, body) = read_packet(stream);
+            match kind >> 4 {
+                1 => stream.write_all(&[0x20, 2, 0, 0]).unwrap(),
+                3 => {
+                    let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let topic = String::from_utf8(body[2..2 + length].to_vec()).unwrap();
+                    let mut payload = &body[2 + length..];
+                    if kind & 0x06 != 0 {
+                        stream.write_all(&[0x40, 2, payload[0], payload[1]]).unwrap();
+                        payload = &payload[2..];
+                    }
+                    publications.push((topic.clone(), String::from_utf8(payload.to_vec()).unwrap(), kind & 1 == 1));
+                    if until(&topic) {
+                        return publications;
+                    }
+                },
+                8 => {
+                    let mut filters = 0;
+                    let mut rest = &body[2..];
+                    while let [high, low, ..] = *rest {
+                        rest = &rest[2 + u16::from_be_bytes([high, low]) as usize + 1..];
+                        filters += 1;
+                    }
+                    stream.write_all(&[0x90, 2 + filters, body[0], body[1]]).unwrap();
+                    stream.write_all(&vec![1; filters as usize]).unwrap();
+                },
+                12 => stream.write_all(&[0xd0, 0]).unwrap(),
+                14 => return publications,
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn values_are_published() {
+        let (context, command) = context(&["--mqtt", "127.0.0.1:0"]);
+        let crate::Command::Serve(serve) = &command else {
+            unreachable!()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = Broker { host: "127.0.0.1".to_string(), port: listener.local_addr().unwrap().port() };
+        let runtime = runtime();
+
+        thread::scope(|scope| {
+            let (daemon, capture) = daemon(scope, &runtime, context);
+            let _stop = Stop(&daemon);
+            assert!(order(&daemon, "hc1", "DesiredTemp", MessageKind::Write, "21").unwrap_err().contains("no destination"));
+            assert!(order(&daemon, "bai", "Missing", MessageKind::Read, "").is_err());
+            let client = Arc::clone(&daemon);
+            scope.spawn(move || run(&client, &serve.mqtt, &broker));
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let connected = broker_until(&mut stream, |topic| topic == "rebus/status");
+            assert_eq!(connected, [("rebus/status".to_string(), "online".to_string(), true)]);
+
+            capture.start();
+            let published = broker_until(&mut stream, |topic| topic == "rebus/bai/FlowTemp/temp");
+            assert_eq!(published, [("rebus/bai/FlowTemp/temp".to_string(), "36".to_string(), true)]);
+
+            daemon.stop();
+            let stopped = broker_until(&mut stream, |_| false);
+            assert_eq!(stopped, [("rebus/status".to_string(), "offline".to_string(), true)]);
+        });
+    }
+}
@@ -0,0 +1,511 @@
+//! Full-screen terminal interface: the telegrams as they come, the devices seen, the last value of
+//! each message and the counters of the bus, messages being read and written on demand
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::iter;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use rebus_core::layer2::{AddressClass, BusEvent, Packet};
+use rebus_core::layer7::identification::Inventory;
+use rebus_core::layer7::paged::PagedRecords;
+use rebus_core::layer7::{Catalogue, MessageKind};
+use rebus_core::transport::{Bus, Transport};
+
+use crate::monitor::{format_message, packet, problem, Filter, Severity};
+use crate::{query, Cli, Command, Context, MessageArgs, Stats};
+
+/// Events kept for scrolling back
+const HISTORY: usize = 1000;
+/// Bytes per second of the bus, at 2400 bauds
+const BUS_SPEED: f64 = 240.0;
+/// Period over which the load of the bus is measured
+const LOAD_PERIOD: Duration = Duration::from_secs(10);
+/// Longest time spent reading the bus between two redraws
+const FRAME: Duration = Duration::from_millis(50);
+
+const HELP: &str = "q quit  / search  : read or write  tab pane  ↑↓ select or scroll  r read the selected message";
+
+/// Bytes a telegram takes on the bus, SYN excluded and escapes ignored
+fn length(packet: &Packet) -> usize {
+    let master = 6 + packet.master_payload().len();
+    match AddressClass::of(packet.destination()) {
+        AddressClass::Broadcast => master,
+        AddressClass::Master(_) => master + 1,
+        _ => master + 4 + packet.slave_payload().len(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Telegrams,
+    Devices,
+    Values,
+}
+
+/// What the keys are typed into
+enum Input {
+    Keys,
+    Search,
+    Command(String),
+}
+
+enum Action {
+    Quit,
+    Exchange(MessageArgs, MessageKind),
+}
+
+/// The fields of a message last decoded
+struct LastValue {
+    circuit: String,
+    name: String,
+    /// Time since the start
+    at: Duration,
+    line: String,
+}
+
+struct Entry {
+    /// Time since the start
+    at: Duration,
+    text: String,
+    severity: Option<Severity>,
+}
+
+/// The state of the interface, fed with the events of the bus and the keys
+struct App<'a> {
+    catalogue: &'a Catalogue,
+    filter: &'a Filter,
+    start: Instant,
+    stats: Stats,
+    inventory: Inventory,
+    pages: PagedRecords<'a>,
+    entries: VecDeque<Entry>,
+    /// Telegrams by address, as source or destination
+    devices: BTreeMap<u8, usize>,
+    /// By circuit and name
+    values: BTreeMap<(String, String), LastValue>,
+    /// Bytes of the telegrams of the last `LOAD_PERIOD`
+    load: VecDeque<(Instant, usize)>,
+    /// Only the telegrams and values containing it are shown
+    search: String,
+    input: Input,
+    focus: Pane,
+    devices_state: ListState,
+    values_state: ListState,
+    /// Entries scrolled back from the last one
+    scroll: usize,
+    status: String,
+}
+
+impl<'a> App<'a> {
+    fn new(catalogue: &'a Catalogue, filter: &'a Filter, start: Instant) -> App<'a> {
+        App {
+            catalogue,
+            filter,
+            start,
+            stats: Stats::default(),
+            inventory: Inventory::new(),
+            pages: PagedRecords::new(),
+            entries: VecDeque::new(),
+            devices: BTreeMap::new(),
+            values: BTreeMap::new(),
+            load: VecDeque::new(),
+            search: String::new(),
+            input: Input::Keys,
+            focus: Pane::Telegrams,
+            devices_state: ListState::default(),
+            values_state: ListState::default(),
+            scroll: 0,
+            status: String::new(),
+        }
+    }
+
+    fn handle(&mut self, event: &BusEvent, now: Instant) {
+        self.stats.count(event, self.catalogue, &mut self.inventory);
+        if let Some(packet) = packet(event) {
+            self.load.push_back((now, length(packet)));
+        }
+        while self.load.front().is_some_and(|(at, _)| now.duration_since(*at) > LOAD_PERIOD) {
+            self.load.pop_front();
+        }
+        if let BusEvent::Telegram(packet) = event {
+            *self.devices.entry(packet.source()).or_default() += 1;
+            if AddressClass::of(packet.destination()) != AddressClass::Broadcast {
+                *self.devices.entry(packet.destination()).or_default() += 1;
+            }
+        }
+
+        let definition = packet(event).and_then(|p| self.catalogue.find(p, &self.inventory));
+        if !self.filter.accepts(event, definition) {
+            return;
+        }
+        let at = now.duration_since(self.start);
+        let mut problem = problem(event);
+        let mut text = match (packet(event), &problem) {
+            (None, Some((_, problem))) => problem.clone(),
+            _ => event.to_string(),
+        };
+        if let (BusEvent::Telegram(packet), Some(definition)) = (event, definition) {
+            match self.pages.decode(definition, packet) {
+                Some(Ok(message)) => {
+                    let line = format_message(&message);
+                    text += &format!("  {}", line);
+                    let key = (message.circuit.clone(), message.name.clone());
+                    self.values.insert(key, LastValue { circuit: message.circuit, name: message.name, at, line });
+                },
+                Some(Err(e)) if problem.is_none() => problem = Some((Severity::Warning, format!("cannot decode: {}", e))),
+                _ => (),
+            }
+        }
+        if let (Some(_), Some((_, problem))) = (packet(event), &problem) {
+            text += &format!("  {}", problem);
+        }
+
+        self.entries.push_back(Entry { at, text, severity: problem.map(|(severity, _)| severity) });
+        if self.entries.len() > HISTORY {
+            self.entries.pop_front();
+        }
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    fn shows(&self, text: &str) -> bool {
+        text.to_lowercase().contains(&self.search.to_lowercase())
+    }
+
+    fn shown_values(&self) -> Vec<&LastValue> {
+        self.values.values().filter(|value| self.shows(&value.line)).collect()
+    }
+
+    /// Percentage of the time the bus carried telegrams
+    fn load(&self) -> f64 {
+        let bytes: usize = self.load.iter().map(|(_, bytes)| bytes).sum();
+        100.0 * bytes as f64 / BUS_SPEED / LOAD_PERIOD.as_secs_f64()
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Option<Action> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Some(Action::Quit);
+        }
+        match &mut self.input {
+            Input::Search => match key.code {
+                KeyCode::Char(c) => self.search.push(c),
+                KeyCode::Backspace => {
+                    self.search.pop();
+                },
+                KeyCode::Enter => self.input = Input::Keys,
+                KeyCode::Esc => {
+                    self.search.clear();
+                    self.input = Input::Keys;
+                },
+                _ => (),
+            },
+            Input::Command(line) => match key.code {
+                KeyCode::Char(c) => line.push(c),
+                KeyCode::Backspace => {
+                    line.pop();
+                },
+                KeyCode::Enter => {
+                    let line = std::mem::take(line);
+                    self.input = Input::Keys;
+                    return self.command(&line);
+                },
+                KeyCode::Esc => self.input = Input::Keys,
+                _ => (),
+            },
+            Input::Keys => match key.code {
+                KeyCode::Char('q') => return Some(Action::Quit),
+                KeyCode::Char('/') => self.input = Input::Search,
+                KeyCode::Char(':') => self.input = Input::Command(String::new()),
+                KeyCode::Tab => {
+                    self.focus = match self.focus {
+                        Pane::Telegrams => Pane::Devices,
+                        Pane::Devices => Pane::Values,
+                        Pane::Values => Pane::Telegrams,
+                    };
+                },
+                KeyCode::Up => match self.focus {
+                    Pane::Telegrams => self.scroll = (self.scroll + 1).min(self.entries.len().saturating_sub(1)),
+                    Pane::Devices => self.devices_state.select_previous(),
+                    Pane::Values => self.values_state.select_previous(),
+                },
+                KeyCode::Down => match self.focus {
+                    Pane::Telegrams => self.scroll = self.scroll.saturating_sub(1),
+                    Pane::Devices => self.devices_state.select_next(),
+                    Pane::Values => self.values_state.select_next(),
+                },
+                KeyCode::End => self.scroll = 0,
+                KeyCode::Char('r') if self.focus == Pane::Values => {
+                    let values = self.shown_values();
+                    let value = values.get(self.values_state.selected()?)?;
+                    let args = MessageArgs { circuit: value.circuit.clone(), name: value.name.clone(), values: Vec::new(), destination: None };
+                    return Some(Action::Exchange(args, MessageKind::Read));
+                },
+                _ => (),
+            },
+        }
+        None
+    }
+
+    /// `read` or `write` with the arguments of the commands of the same name
+    fn command(&mut self, line: &str) -> Option<Action> {
+        match Cli::try_parse_from(iter::once("rebus").chain(line.split_whitespace())) {
+            Ok(Cli { command: Command::Read(args), .. }) => Some(Action::Exchange(args, MessageKind::Read)),
+            Ok(Cli { command: Command::Write(args), .. }) => Some(Action::Exchange(args, MessageKind::Write)),
+            Ok(_) => {
+                self.status = "only read and write can be run here".to_string();
+                None
+            },
+            Err(e) => {
+                self.status = e.to_string().lines().next().unwrap_or_default().to_string();
+                None
+            },
+        }
+    }
+
+    fn block(&self, title: String, pane: Pane) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        if self.focus == pane {
+            block.border_style(Style::new().cyan())
+        } else {
+            block
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, name: &str) {
+        let [header, top, bottom, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Percentage(40),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+        let [devices_area, values_area] = Layout::horizontal([Constraint::Percentage(35), Constraint::Min(0)]).areas(top);
+
+        let stats = &self.stats;
+        frame.render_widget(Paragraph::new(Line::from(format!(
+            "rebus {}  load {:.0}%  {} telegram(s)  {} CRC error(s)  {} NACK(s)  {} timeout(s)  {} unexpected  {} connection loss(es)",
+            name, self.load(), stats.telegrams, stats.crc_errors, stats.nacks, stats.timeouts, stats.unexpected, stats.connection_losses,
+        )).bold()), header);
+
+        let devices: Vec<ListItem> = self.devices.iter()
+            .map(|(address, count)| {
+                let identification = self.inventory.get(*address).map(|i| i.to_string()).unwrap_or_default();
+                ListItem::new(format!("{:02x} {:>5} {}", address, count, identification))
+            })
+            .collect();
+        let list = List::new(devices).block(self.block("Devices".to_string(), Pane::Devices)).highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, devices_area, &mut self.devices_state);
+
+        let now = self.start.elapsed();
+        let values: Vec<ListItem> = self.shown_values().into_iter()
+            .map(|value| ListItem::new(format!("{}  ({:.0} s ago)", value.line, now.saturating_sub(value.at).as_secs_f64())))
+            .collect();
+        let list = List::new(values).block(self.block("Values".to_string(), Pane::Values)).highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, values_area, &mut self.values_state);
+
+        let mut title = "Telegrams".to_string();
+        if !self.search.is_empty() {
+            title += &format!(" /{}", self.search);
+        }
+        if self.scroll > 0 {
+            title += &format!(" ({} back)", self.scroll);
+        }
+        let height = bottom.height.saturating_sub(2) as usize;
+        let shown: Vec<&Entry> = self.entries.iter().filter(|e| self.shows(&e.text)).collect();
+        let end = shown.len().saturating_sub(self.scroll);
+        let lines: Vec<Line> = shown[end.saturating_sub(height)..end].iter()
+            .map(|entry| {
+                let line = Line::from(format!("{:>9.3} {}", entry.at.as_secs_f64(), entry.text));
+                match entry.severity {
+                    Some(Severity::Error) => line.red(),
+                    Some(Severity::Warning) => line.yellow(),
+                    None => line,
+                }
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(self.block(title, Pane::Telegrams)), bottom);
+
+        let footer_line = match &self.input {
+            Input::Search => format!("/{}", self.search),
+            Input::Command(line) => format!(":{}", line),
+            Input::Keys if !self.status.is_empty() => self.status.clone(),
+            Input::Keys => HELP.to_string(),
+        };
+        frame.render_widget(Paragraph::new(footer_line), footer);
+    }
+}
+
+/// Read or write a message, the result being reported in the status line
+fn exchange<T: Transport>(context: &Context, bus: &mut Bus<T>, args: &MessageArgs, kind: MessageKind) -> String {
+    let (definition, requests) = match context.requests(args, kind) {
+        Ok(prepared) => prepared,
+        Err(e) => return e,
+    };
+    match query(bus, definition, &requests) {
+        Ok(Ok(message)) => format_message(&message),
+        Ok(Err(e)) => format!("{}.{}: {}", args.circuit, args.name, e),
+        Err(e) => format!("{}.{}: {}", args.circuit, args.name, e),
+    }
+}
+
+fn event_loop<T: Transport>(terminal: &mut DefaultTerminal, context: &Context, bus: &mut Bus<T>, app: &mut App, name: &str) -> io::Result<()> {
+    let mut ended = false;
+    loop {
+        let mut idle = ended;
+        let frame_end = Instant::now() + FRAME;
+        while !idle && Instant::now() < frame_end {
+            match bus.next_event() {
+                Ok(Some(event)) => app.handle(&event, Instant::now()),
+                Ok(None) if bus.is_finished() => {
+                    app.status = "end of the capture".to_string();
+                    ended = true;
+                    idle = true;
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    app.status = e.to_string();
+                    idle = true;
+                },
+            }
+        }
+
+        terminal.draw(|frame| app.draw(frame, name))?;
+        if event::poll(if idle { FRAME } else { Duration::ZERO })? {
+            if let Event::Key(key) = event::read()? {
+                match app.on_key(key) {
+                    Some(Action::Quit) => return Ok(()),
+                    Some(Action::Exchange(args, kind)) => app.status = exchange(context, bus, &args, kind),
+                    None => (),
+                }
+            }
+        }
+    }
+}
+
+/// Show the bus until `q` is pressed
+pub fn run(context: &Context, filter: &Filter) -> ExitCode {
+    let (mut bus, name) = match context.open_bus() {
+        Ok(opened) => opened,
+        Err(code) => return code,
+    };
+    let mut app = App::new(&context.settings.catalogue, filter, Instant::now());
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, context, &mut bus, &mut app, &name);
+    ratatui::restore();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use rebus_core::layer2::TelegramComponent;
+    use rebus_core::layer7::types::DataType;
+    use rebus_core::layer7::{FieldDefinition, MessageDefinition, Part};
+
+    fn filter() -> Filter {
+        let cli = Cli::try_parse_from(["rebus", "tui"]).unwrap();
+        let Command::Tui { filter } = cli.command else {
+            unreachable!()
+        };
+        filter
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn screen(app: &mut App) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(120, 20)).unwrap();
+        terminal.draw(|frame| app.draw(frame, "test")).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer.content().chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn events_fill_the_panes() {
+        let mut definition = MessageDefinition::new("bai", "FlowTemp", MessageKind::Read, 0xb5, 0x09);
+        definition.id = vec![0x0d, 0x18, 0x00];
+        definition.fields.push(FieldDefinition::new("temp", Part::Slave, 0, DataType::Data2c));
+        let catalogue: Catalogue = [definition].into_iter().collect();
+        let filter = filter();
+        let start = Instant::now();
+        let mut app = App::new(&catalogue, &filter, start);
+
+        let telegram = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap().with_response(&[0x40, 0x02]).unwrap();
+        app.handle(&BusEvent::Telegram(telegram.clone()), start);
+        app.handle(&BusEvent::Timeout(TelegramComponent::SlaveACK), start);
+        assert_eq!(length(&telegram), 15);
+        assert_eq!(app.load(), 100.0 * 15.0 / 2400.0);
+
+        let text = screen(&mut app);
+        assert!(text.contains("1 telegram(s)"));
+        assert!(text.contains("bai.FlowTemp temp=36 "));
+        assert!(text.contains(&format!("{}  bai.FlowTemp", BusEvent::Telegram(telegram))));
+        assert!(text.contains("timeout waiting for SlaveACK"));
+        assert!(text.contains("10     1"));
+
+        // a search hides what does not contain it
+        for c in ['/', 'x', 'y'] {
+            app.on_key(key(KeyCode::Char(c)));
+        }
+        app.on_key(key(KeyCode::Enter));
+        assert!(!screen(&mut app).contains("timeout waiting"));
+
+        app.on_key(key(KeyCode::Char('/')));
+        app.on_key(key(KeyCode::Esc));
+        assert!(app.search.is_empty());
+        app.on_key(key(KeyCode::Tab));
+        app.on_key(key(KeyCode::Tab));
+        app.on_key(key(KeyCode::Down));
+        let Some(Action::Exchange(args, MessageKind::Read)) = app.on_key(key(KeyCode::Char('r'))) else {
+            panic!("expecting a read");
+        };
+        assert_eq!((args.circuit.as_str(), args.name.as_str()), ("bai", "FlowTemp"));
+    }
+
+    #[test]
+    fn read_and_write_are_typed() {
+        let catalogue = Catalogue::new();
+        let filter = filter();
+        let mut app = App::new(&catalogue, &filter, Instant::now());
+
+        app.on_key(key(KeyCode::Char(':')));
+        for c in "write hc1 DesiredTemp 21.5".chars() {
+            app.on_key(key(KeyCode::Char(c)));
+        }
+        let Some(Action::Exchange(args, MessageKind::Write)) = app.on_key(key(KeyCode::Enter)) else {
+            panic!("expecting a write");
+        };
+        assert_eq!((args.circuit.as_str(), args.name.as_str(), args.values), ("hc1", "DesiredTemp", vec!["21.5".to_string()]));
+
+        app.on_key(key(KeyCode::Char(':')));
+        app.on_key(key(KeyCode::Char('s')));
+        app.on_key(key(KeyCode::Char('c')));
+        assert!(app.on_key(key(KeyCode::Enter)).is_none());
+        assert!(!app.status.is_empty());
+        assert!(matches!(app.on_key(key(KeyCode::Char('q'))), Some(Action::Quit)));
+    }
+}
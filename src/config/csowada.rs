@@ -0,0 +1,537 @@
+//! Definition files of [csowada/ebus](https://github.com/csowada/ebus-configuration) in JSON format.
+//!
+//! A file describes one device (`id`) and its `commands`, each of them having a `get`, `set`
+//! and/or `broadcast` method made of `master` and `slave` fields. The leading `static` master
+//! fields are the ID of the message. The `identification` list restricts the file to the
+//! devices announcing (07 04) one of these identifiers.
+//!
+//! The export writes one file per circuit. Conditions on versions or on the manufacturer are not
+//! part of the format, neither are the paged messages.
+
+use std::fmt;
+use std::path::Path;
+
+use serde::de::{MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{format_hex_bytes, parse_hex_byte, parse_hex_bytes, sequential_layout, ConfigError, ExportError};
+use crate::layer7::condition::Condition;
+use crate::layer7::types::DataType;
+use crate::layer7::{FieldDefinition, Location, MessageDefinition, MessageKind, Part};
+
+#[derive(Deserialize, Serialize)]
+struct File {
+    id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    identification: Vec<String>,
+    #[serde(default)]
+    commands: Vec<Command>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Command {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    src: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    get: Option<Method>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    set: Option<Method>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broadcast: Option<Method>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Method {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    master: Vec<Field>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    slave: Vec<Field>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct Field {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(rename = "type")]
+    data_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    factor: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<f64>,
+    #[serde(default, deserialize_with = "ordered_mapping", serialize_with = "write_mapping", skip_serializing_if = "Option::is_none")]
+    mapping: Option<Vec<(String, String)>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bit: Option<u8>,
+}
+
+/// Keep the mapping entries in their written order, duplicates included
+fn ordered_mapping<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<(String, String)>>, D::Error> {
+    struct MappingVisitor;
+
+    impl<'de> Visitor<'de> for MappingVisitor {
+        type Value = Option<Vec<(String, String)>>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an object of value names")
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut entries = Vec::new();
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            Ok(Some(entries))
+        }
+    }
+
+    deserializer.deserialize_any(MappingVisitor)
+}
+
+fn write_mapping<S: Serializer>(mapping: &Option<Vec<(String, String)>>, serializer: S) -> Result<S::Ok, S::Error> {
+    let entries = mapping.as_deref().unwrap_or_default();
+    let mut map = serializer.serialize_map(Some(entries.len()))?;
+    for (k, v) in entries {
+        map.serialize_entry(k, v)?;
+    }
+    map.end()
+}
+
+/// Type of a field from its csowada name (`data2c`, `uchar`, `string`...)
+pub fn parse_type(name: &str, length: Option<u8>, bit: Option<u8>) -> Option<DataType> {
+    let data_type = match (name.to_ascii_lowercase().as_str(), length) {
+        ("bcd", _) => DataType::Bcd,
+        ("data1b", _) => DataType::Data1b,
+        ("data1c", _) => DataType::Data1c,
+        ("data2b", _) => DataType::Data2b,
+        ("data2c", _) => DataType::Data2c,
+        ("uchar", _) | ("byte", _) => DataType::Uch,
+        ("char", _) => DataType::Sch,
+        ("uint", _) | ("word", _) => DataType::Uin,
+        ("int", _) => DataType::Sin,
+        ("ulong", _) => DataType::Ulg,
+        ("long", _) => DataType::Slg,
+        ("date", _) => DataType::Date,
+        ("time", _) => DataType::Time,
+        ("bit", _) => DataType::Bit(bit.filter(|b| *b < 8)?),
+        ("string", Some(n)) => DataType::Str(n),
+        ("bytes", Some(n)) => DataType::Hex(n),
+        ("bytes", None) => DataType::Hex(1),
+        ("ignore", Some(n)) => DataType::Ignore(n),
+        ("ignore", None) => DataType::Ignore(1),
+        _ => return None,
+    };
+    Some(data_type)
+}
+
+/// csowada name of a type, with its length and bit number
+pub fn type_name(data_type: DataType) -> (&'static str, Option<u8>, Option<u8>) {
+    match data_type {
+        DataType::Bcd => ("bcd", None, None),
+        DataType::Data1b => ("data1b", None, None),
+        DataType::Data1c => ("data1c", None, None),
+        DataType::Data2b => ("data2b", None, None),
+        DataType::Data2c => ("data2c", None, None),
+        DataType::Uch => ("uchar", None, None),
+        DataType::Sch => ("char", None, None),
+        DataType::Uin => ("uint", None, None),
+        DataType::Sin => ("int", None, None),
+        DataType::Ulg => ("ulong", None, None),
+        DataType::Slg => ("long", None, None),
+        DataType::Date => ("date", None, None),
+        DataType::Time => ("time", None, None),
+        DataType::Bit(n) => ("bit", None, Some(n)),
+        DataType::Str(n) => ("string", Some(n), None),
+        DataType::Hex(n) => ("bytes", Some(n), None),
+        DataType::Ignore(n) => ("ignore", Some(n), None),
+    }
+}
+
+/// csowada factor equivalent to a divider
+fn factor_of(divider: f64) -> f64 {
+    if divider < 0.0 {
+        -divider
+    } else {
+        1.0 / divider
+    }
+}
+
+/// Divider equivalent to a csowada factor, which must be positive
+fn divider_of(factor: f64) -> Result<Option<f64>, String> {
+    match factor {
+        1.0 => Ok(None),
+        f if f > 1.0 => Ok(Some(-f)),
+        f if f > 0.0 => Ok(Some((1.0 / f * 1e6).round() / 1e6)),
+        f => Err(format!("invalid factor `{}`", f)),
+    }
+}
+
+/// 1-based line of the first occurrence of `needle` at or after `from`, which is moved past it
+fn line_of(source: &str, needle: &str, from: &mut usize) -> usize {
+    match source[*from..].find(needle) {
+        Some(i) => {
+            *from += i + needle.len();
+            source[..*from].lines().count()
+        },
+        None => 0,
+    }
+}
+
+fn parse_address(value: Option<&str>, name: &str) -> Result<Option<u8>, String> {
+    value.map(|a| parse_hex_byte(a).ok_or_else(|| format!("invalid {} `{}`", name, a))).transpose()
+}
+
+fn parse_method(
+    file: &File,
+    command: &Command,
+    method: &Method,
+    kind: MessageKind,
+    location: Location,
+) -> Result<MessageDefinition, String> {
+    let (primary, secondary) = match parse_hex_bytes(&command.command).as_deref() {
+        Some([pb, sb]) => (*pb, *sb),
+        _ => return Err(format!("invalid command `{}`", command.command)),
+    };
+
+    let mut definition = MessageDefinition::new(&file.id, &command.id, kind, primary, secondary);
+    definition.source = parse_address(command.src.as_deref(), "source")?;
+    definition.destination = parse_address(command.dst.as_deref(), "destination")?;
+    definition.comment = command.label.clone();
+    definition.location = Some(location);
+    if !file.identification.is_empty() {
+        definition.condition = Some(Condition { device_ids: file.identification.clone(), ..Default::default() });
+    }
+
+    for (part, fields) in [(Part::Master, &method.master), (Part::Slave, &method.slave)] {
+        let mut offset = 0;
+        let mut previous: Option<DataType> = None;
+        for field in fields {
+            if field.data_type.eq_ignore_ascii_case("static") {
+                let bytes = field.default.as_deref()
+                    .and_then(parse_hex_bytes)
+                    .ok_or_else(|| format!("invalid static bytes in `{}`", command.id))?;
+                if part != Part::Master || offset != definition.id.len() {
+                    return Err(format!("static bytes must lead the master fields in `{}`", command.id));
+                }
+                definition.id.extend(bytes);
+                offset = definition.id.len();
+                continue;
+            }
+
+            let data_type = parse_type(&field.data_type, field.length, field.bit)
+                .ok_or_else(|| format!("unknown type `{}` in `{}`", field.data_type, command.id))?;
+            if let (Some(DataType::Bit(a)), DataType::Bit(b)) = (previous, data_type) {
+                if b > a {
+                    offset -= 1;
+                }
+            }
+
+            let name = field.name.as_deref().unwrap_or("");
+            let mut definition_field = FieldDefinition::new(name, part, offset, data_type);
+            definition_field.divider = field.factor.map(divider_of).transpose()?.flatten();
+            definition_field.unit = field.unit.clone();
+            definition_field.min = field.min;
+            definition_field.max = field.max;
+            definition_field.comment = field.label.clone();
+            definition_field.values = field.mapping.as_ref()
+                .map(|mapping| mapping.iter()
+                    .map(|(k, v)| k.trim().parse::<i64>().map(|k| (k, v.clone())).map_err(|_| format!("invalid mapping key `{}`", k)))
+                    .collect::<Result<Vec<_>, String>>())
+                .transpose()?;
+
+            offset = definition_field.end();
+            previous = Some(data_type);
+            definition.fields.push(definition_field);
+        }
+    }
+    Ok(definition)
+}
+
+/// Parse the commands of a definition file
+pub fn parse(source: &str, file: &Path, errors: &mut Vec<ConfigError>) -> Vec<MessageDefinition> {
+    let parsed: File = match serde_json::from_str(source) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            errors.push(ConfigError::new(file, e.line(), e.to_string()));
+            return Vec::new();
+        },
+    };
+
+    let mut definitions = Vec::new();
+    let mut cursor = 0;
+    for command in &parsed.commands {
+        let line = line_of(source, &format!("\"{}\"", command.id), &mut cursor);
+        let methods = [
+            (&command.get, MessageKind::Read),
+            (&command.set, MessageKind::Write),
+            (&command.broadcast, MessageKind::Passive),
+        ];
+        for (method, kind) in methods {
+            let Some(method) = method else { continue };
+            let location = Location { file: file.display().to_string(), line };
+            match parse_method(&parsed, command, method, kind, location) {
+                Ok(definition) => definitions.push(definition),
+                Err(e) => errors.push(ConfigError::new(file, line, e)),
+            }
+        }
+    }
+    definitions
+}
+
+fn export_method(definition: &MessageDefinition) -> Result<Method, String> {
+    let mut method = Method { master: Vec::new(), slave: Vec::new() };
+    if !definition.id.is_empty() {
+        method.master.push(Field {
+            data_type: "static".to_string(),
+            default: Some(format_hex_bytes(&definition.id, " ").to_uppercase()),
+            ..Default::default()
+        });
+    }
+
+    for (part, fields) in [(Part::Master, &mut method.master), (Part::Slave, &mut method.slave)] {
+        for (gap, field) in sequential_layout(definition, part)? {
+            if gap > 0 {
+                fields.push(Field { data_type: "ignore".to_string(), length: Some(gap as u8), ..Default::default() });
+            }
+
+            let (data_type, length, bit) = type_name(field.data_type);
+            fields.push(Field {
+                name: Some(field.name.clone()).filter(|n| !n.is_empty()),
+                data_type: data_type.to_string(),
+                label: field.comment.clone(),
+                factor: field.divider.map(factor_of),
+                unit: field.unit.clone(),
+                min: field.min,
+                max: field.max,
+                mapping: field.values.as_ref().map(|values| values.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()),
+                length,
+                bit,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(method)
+}
+
+fn export_command(file: &mut File, definition: &MessageDefinition) -> Result<(), String> {
+    if definition.paging.is_some() {
+        return Err("paged messages are not supported".to_string());
+    }
+    let device_ids = match &definition.condition {
+        None => Vec::new(),
+        Some(c) if c.manufacturer.is_none() && c.software.is_none() && c.hardware.is_none() && c.address.is_none() => c.device_ids.clone(),
+        Some(_) => return Err("only conditions on the device identifier are supported".to_string()),
+    };
+    if file.commands.is_empty() {
+        file.identification = device_ids;
+    } else if file.identification != device_ids {
+        return Err(format!("the condition differs from the other messages of `{}`", file.id));
+    }
+
+    let address = |a: Option<u8>| a.map(|a| format!("{:02X}", a));
+    let command_bytes = format!("{:02X} {:02X}", definition.primary, definition.secondary);
+    let method = export_method(definition)?;
+
+    let command = match file.commands.iter_mut().find(|c| c.id == definition.name) {
+        Some(c) if c.command != command_bytes || c.src != address(definition.source) || c.dst != address(definition.destination) => {
+            return Err(format!("`{}` is already defined with another command or address", definition.name));
+        },
+        Some(c) => c,
+        None => {
+            file.commands.push(Command {
+                id: definition.name.clone(),
+                label: definition.comment.clone(),
+                command: command_bytes,
+                src: address(definition.source),
+                dst: address(definition.destination),
+                get: None,
+                set: None,
+                broadcast: None,
+            });
+            file.commands.last_mut().unwrap()
+        },
+    };
+
+    let slot = match definition.kind {
+        MessageKind::Read => &mut command.get,
+        MessageKind::Write => &mut command.set,
+        MessageKind::Passive => &mut command.broadcast,
+    };
+    if slot.is_some() {
+        return Err(format!("`{}` is defined twice", definition.name));
+    }
+    *slot = Some(method);
+    Ok(())
+}
+
+/// Write definitions as JSON files, one per circuit, returned as `(file name, content)`.
+/// The definitions which cannot be represented are skipped and reported into `errors`.
+pub fn export(definitions: &[MessageDefinition], errors: &mut Vec<ExportError>) -> Vec<(String, String)> {
+    let mut files: Vec<File> = Vec::new();
+    for definition in definitions {
+        let file = match files.iter().position(|f| f.id == definition.circuit) {
+            Some(i) => &mut files[i],
+            None => {
+                files.push(File { id: definition.circuit.clone(), identification: Vec::new(), commands: Vec::new() });
+                files.last_mut().unwrap()
+            },
+        };
+        if let Err(e) = export_command(file, definition) {
+            errors.push(ExportError::new(definition, e));
+        }
+    }
+
+    files.into_iter()
+        .filter(|f| !f.commands.is_empty())
+        .map(|f| (format!("{}.json", f.id), serde_json::to_string_pretty(&f).expect("definitions are always serializable")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ebusd;
+    use crate::layer7::types::Value;
+
+    const BAI: &str = r#"{
+  "id": "bai",
+  "identification": ["BAI00"],
+  "commands": [
+    {
+      "id": "flow_temp",
+      "label": "Flow temperature",
+      "command": "B5 09",
+      "dst": "08",
+      "get": {
+        "master": [{"type": "static", "default": "0D 18 00"}],
+        "slave": [{"name": "temp", "type": "data2c", "unit": "°C"}]
+      }
+    },
+    {
+      "id": "heating_mode",
+      "command": "B5 09",
+      "dst": "08",
+      "get": {
+        "master": [{"type": "static", "default": "0D 2A 00"}],
+        "slave": [{"name": "mode", "type": "uchar", "mapping": {"0": "off", "1": "auto"}}]
+      },
+      "set": {
+        "master": [{"type": "static", "default": "0E 2A 00"}, {"name": "mode", "type": "uchar", "mapping": {"0": "off", "1": "auto"}}]
+      }
+    },
+    {
+      "id": "pressure",
+      "command": "B5 11",
+      "dst": "08",
+      "get": {
+        "master": [{"type": "static", "default": "01"}],
+        "slave": [{"name": "pressure", "type": "uint", "factor": 0.001, "unit": "bar"}]
+      }
+    }
+  ]
+}"#;
+
+    #[test]
+    fn commands_are_loaded() {
+        let mut errors = Vec::new();
+        let definitions = parse(BAI, Path::new("bai.json"), &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(definitions.len(), 4);
+
+        let flow = &definitions[0];
+        assert_eq!((flow.circuit.as_str(), flow.name.as_str()), ("bai", "flow_temp"));
+        assert_eq!(flow.id, vec![0x0d, 0x18, 0x00]);
+        assert_eq!(flow.destination, Some(0x08));
+        assert_eq!(flow.condition.as_ref().unwrap().device_ids, vec!["BAI00".to_string()]);
+        assert_eq!(flow.location.as_ref().unwrap().line, 6);
+
+        let set_mode = &definitions[2];
+        assert_eq!(set_mode.kind, MessageKind::Write);
+        assert_eq!(set_mode.fields[0].offset, 3);
+        assert_eq!(set_mode.location.as_ref().unwrap().line, 16);
+
+        let pressure = &definitions[3].fields[0];
+        assert_eq!(pressure.divider, Some(1000.0));
+        assert_eq!(pressure.decode(&[0xdc, 0x05]), Ok(Some(Value::Float(1.5))));
+    }
+
+    fn without_locations(mut definitions: Vec<MessageDefinition>) -> Vec<MessageDefinition> {
+        definitions.iter_mut().for_each(|d| d.location = None);
+        definitions
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut errors = Vec::new();
+        let definitions = parse(BAI, Path::new("bai.json"), &mut errors);
+
+        let mut export_errors = Vec::new();
+        let files = export(&definitions, &mut export_errors);
+        assert!(export_errors.is_empty());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, "bai.json");
+
+        let reloaded = parse(&files[0].1, Path::new("bai.json"), &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(without_locations(reloaded), without_locations(definitions));
+    }
+
+    #[test]
+    fn conversion_through_ebusd_keeps_the_fields() {
+        let mut errors = Vec::new();
+        let definitions = parse(BAI, Path::new("bai.json"), &mut errors);
+
+        let mut export_errors = Vec::new();
+        let csv = ebusd::export(&definitions, &mut export_errors);
+        let from_csv = ebusd::parse(&csv, Path::new("bai.csv"), &ebusd::Templates::default(), &mut errors);
+        let files = export(&from_csv, &mut export_errors);
+        let reloaded = parse(&files[0].1, Path::new("bai.json"), &mut errors);
+        assert!(errors.is_empty() && export_errors.is_empty());
+
+        let fields = |definitions: &[MessageDefinition]| -> Vec<_> {
+            definitions.iter()
+                .flat_map(|d| d.fields.iter().map(|f| (f.name.clone(), f.data_type, f.divider, f.values.clone())))
+                .collect()
+        };
+        assert_eq!(fields(&reloaded), fields(&definitions));
+        assert_eq!(without_locations(reloaded).len(), definitions.len());
+    }
+
+    #[test]
+    fn factors_must_be_positive() {
+        assert_eq!(divider_of(0.1), Ok(Some(10.0)));
+        assert_eq!(divider_of(10.0), Ok(Some(-10.0)));
+        assert_eq!(divider_of(1.0), Ok(None));
+        for factor in [-0.5, 0.0, f64::NAN] {
+            assert!(divider_of(factor).is_err());
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_located() {
+        let mut errors = Vec::new();
+        parse("{\n  \"id\": \"bai\",\n  \"commands\": [\n", Path::new("bai.json"), &mut errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location.line, 4);
+    }
+}
@@ -0,0 +1,651 @@
+//! Definition files of [ebusd](https://github.com/john30/ebusd-configuration) in CSV format.
+//!
+//! A message row is `type,circuit,name,comment,QQ,ZZ,PBSB,ID` followed by groups of
+//! `field,part,type,divider/values,unit,comment`. Rows whose type starts with `*` hold the
+//! defaults of the following rows of the same type. A file named `ZZ.ID.SWxxxx.HWxxxx.csv`
+//! only applies to the device at `ZZ` which identifies itself (07 04) with `ID`.
+//!
+//! The export writes the conditions inline and the addresses on every row. Field minimums and
+//! maximums are not part of the format and are dropped.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::{format_hex_bytes, parse_hex_byte, parse_hex_bytes, sequential_layout, ConfigError, ExportError};
+use crate::layer7::condition::{Condition, VersionRange};
+use crate::layer7::types::DataType;
+use crate::layer7::{FieldDefinition, Location, MessageDefinition, MessageKind, Part};
+
+/// Name of the file holding the field templates of a directory
+pub const TEMPLATES_FILE: &str = "_templates.csv";
+
+const MESSAGE_COLUMNS: usize = 8;
+const FIELD_COLUMNS: usize = 6;
+
+/// A reusable field type, referenced by its name in the type column of a field
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pub data_type: DataType,
+    pub divider: Option<f64>,
+    pub values: Option<Vec<(i64, String)>>,
+    pub unit: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    templates: HashMap<String, Template>,
+}
+
+impl Templates {
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.get(name)
+    }
+
+    pub fn insert(&mut self, name: &str, template: Template) {
+        self.templates.insert(name.to_string(), template);
+    }
+
+    pub fn extend(&mut self, other: Templates) {
+        self.templates.extend(other.templates);
+    }
+}
+
+/// Split a CSV line, honouring double quotes (a doubled quote being a literal one)
+pub(crate) fn split_row(line: &str) -> Vec<String> {
+    let mut columns = Vec::new();
+    let mut column = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                column.push('"');
+                chars.next();
+            },
+            ('"', _) => quoted = !quoted,
+            (',', false) => columns.push(std::mem::take(&mut column)),
+            (c, _) => column.push(c),
+        }
+    }
+    columns.push(column);
+    columns
+}
+
+/// Type of a field from its ebusd name (`D2C`, `UCH`, `STR:5`, `BI3`...)
+pub fn parse_type(name: &str) -> Option<DataType> {
+    let name = name.trim().to_ascii_uppercase();
+    let (base, length) = match name.split_once(':') {
+        Some((base, length)) => (base, Some(length.parse::<u8>().ok()?)),
+        None => (name.as_str(), None),
+    };
+
+    let data_type = match (base, length) {
+        ("BCD", None) => DataType::Bcd,
+        ("D1B", None) => DataType::Data1b,
+        ("D1C", None) => DataType::Data1c,
+        ("D2B", None) => DataType::Data2b,
+        ("D2C", None) => DataType::Data2c,
+        ("UCH", None) => DataType::Uch,
+        ("SCH", None) => DataType::Sch,
+        ("UIN", None) => DataType::Uin,
+        ("SIN", None) => DataType::Sin,
+        ("ULG", None) => DataType::Ulg,
+        ("SLG", None) => DataType::Slg,
+        ("BDA", None) => DataType::Date,
+        ("BTI", None) => DataType::Time,
+        ("STR", Some(n)) => DataType::Str(n),
+        ("HEX", Some(n)) => DataType::Hex(n),
+        ("IGN", Some(n)) => DataType::Ignore(n),
+        ("IGN", None) => DataType::Ignore(1),
+        (bit, None) if bit.len() == 3 && bit.starts_with("BI") => {
+            match bit[2..].parse::<u8>().ok()? {
+                n if n < 8 => DataType::Bit(n),
+                _ => return None,
+            }
+        },
+        _ => return None,
+    };
+    Some(data_type)
+}
+
+type DividerValues = (Option<f64>, Option<Vec<(i64, String)>>);
+
+/// Parse the `divider/values` column: either a number or a list like `0=off;1=on`
+fn parse_divider_values(s: &str) -> Result<DividerValues, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok((None, None));
+    }
+    if !s.contains('=') {
+        return s.parse::<f64>()
+            .map(|d| (Some(d), None))
+            .map_err(|_| format!("invalid divider `{}`", s));
+    }
+
+    let values = s.split(';')
+        .map(|pair| {
+            let (key, name) = pair.split_once('=').ok_or_else(|| format!("invalid value `{}`", pair))?;
+            let key = key.trim();
+            let raw = match key.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => key.parse::<i64>(),
+            };
+            raw.map(|raw| (raw, name.trim().to_string()))
+                .map_err(|_| format!("invalid value key `{}`", key))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok((None, Some(values)))
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    match s.trim() {
+        "" => None,
+        s => Some(s.to_string()),
+    }
+}
+
+/// Parse a condition such as `SW>=0409`, `HW<7000`, `ID=bai` or `MF=b5`
+fn parse_condition_term(term: &str, condition: &mut Condition) -> Result<(), String> {
+    let term = term.trim();
+    let split = term.find(['=', '<', '>']).ok_or_else(|| format!("invalid condition `{}`", term))?;
+    let (key, rest) = term.split_at(split);
+    let operator_len = rest.chars().take_while(|c| matches!(c, '=' | '<' | '>')).count();
+    let (operator, value) = rest.split_at(operator_len);
+
+    match key.trim().to_ascii_uppercase().as_str() {
+        "ID" if operator == "=" => condition.device_ids.extend(value.split(';').map(|v| v.trim().to_string())),
+        "MF" if operator == "=" => condition.manufacturer = Some(parse_hex_byte(value).ok_or_else(|| format!("invalid manufacturer `{}`", value))?),
+        k @ ("SW" | "HW") => {
+            let version = value.trim().parse::<u16>().map_err(|_| format!("invalid version `{}`", value))?;
+            let range = VersionRange::from_comparison(operator, version).ok_or_else(|| format!("invalid operator `{}`", operator))?;
+            let current = if k == "SW" { &mut condition.software } else { &mut condition.hardware };
+            *current = Some(current.map_or(range, |c| c.intersect(range)));
+        },
+        _ => return Err(format!("unsupported condition `{}`", term)),
+    }
+    Ok(())
+}
+
+/// Split the leading `[...]` conditions of a type column
+fn parse_type_column(column: &str) -> Result<(Option<Condition>, String), String> {
+    let mut rest = column.trim();
+    let mut condition: Option<Condition> = None;
+
+    while let Some(inner) = rest.strip_prefix('[') {
+        let end = inner.find(']').ok_or("unterminated condition")?;
+        for term in inner[..end].split(',') {
+            parse_condition_term(term, condition.get_or_insert_with(Condition::default))?;
+        }
+        rest = &inner[end + 1..];
+    }
+    Ok((condition, rest.to_string()))
+}
+
+/// Address and condition implied by a file name `ZZ.ID.SWxxxx.HWxxxx.csv`
+fn file_defaults(file: &Path) -> (Option<u8>, Option<String>, Option<Condition>) {
+    let Some(stem) = file.file_stem().and_then(|s| s.to_str()) else {
+        return (None, None, None);
+    };
+    let mut parts = stem.split('.');
+    let Some(address) = parts.next().and_then(parse_hex_byte) else {
+        return (None, None, None);
+    };
+
+    let mut circuit = None;
+    let mut condition = Condition::default();
+    for part in parts {
+        let upper = part.to_ascii_uppercase();
+        let version = |prefix: &str| upper.strip_prefix(prefix).and_then(|v| v.parse::<u16>().ok());
+        if let Some(sw) = version("SW") {
+            condition.software = VersionRange::from_comparison("=", sw);
+        } else if let Some(hw) = version("HW") {
+            condition.hardware = VersionRange::from_comparison("=", hw);
+        } else if circuit.is_none() {
+            circuit = Some(part.to_string());
+            condition.device_ids.push(part.to_string());
+        }
+    }
+
+    let condition = if condition.is_empty() { None } else { Some(condition) };
+    (Some(address), circuit, condition)
+}
+
+fn kind_of(type_column: &str) -> Option<MessageKind> {
+    match type_column.chars().next()?.to_ascii_lowercase() {
+        'r' => Some(MessageKind::Read),
+        'w' => Some(MessageKind::Write),
+        'u' => Some(MessageKind::Passive),
+        _ => None,
+    }
+}
+
+/// Default columns of the messages following a `*` row
+#[derive(Default, Clone)]
+struct Defaults {
+    circuit: String,
+    source: String,
+    destination: String,
+    pbsb: String,
+    id: String,
+}
+
+struct Row<'a> {
+    file: &'a Path,
+    line: usize,
+    columns: Vec<String>,
+}
+
+impl Row<'_> {
+    fn column(&self, i: usize) -> &str {
+        self.columns.get(i).map(|c| c.trim()).unwrap_or("")
+    }
+
+    fn error(&self, message: impl Into<String>) -> ConfigError {
+        ConfigError::new(self.file, self.line, message)
+    }
+}
+
+fn parse_fields(row: &Row, kind: MessageKind, id_len: usize, templates: &Templates) -> Result<Vec<FieldDefinition>, ConfigError> {
+    let mut fields: Vec<FieldDefinition> = Vec::new();
+    let mut offsets = [id_len, 0];
+
+    for start in (MESSAGE_COLUMNS..row.columns.len()).step_by(FIELD_COLUMNS) {
+        let group: Vec<&str> = (start..start + FIELD_COLUMNS).map(|i| row.column(i)).collect();
+        if group.iter().all(|c| c.is_empty()) {
+            continue;
+        }
+        let [name, part, type_name, divider_values, unit, comment] = group[..] else {
+            unreachable!()
+        };
+
+        let part = match (part.to_ascii_lowercase().as_str(), kind) {
+            ("m", _) | ("", MessageKind::Write) | ("", MessageKind::Passive) => Part::Master,
+            ("s", _) | ("", MessageKind::Read) => Part::Slave,
+            (other, _) => return Err(row.error(format!("invalid part `{}`", other))),
+        };
+
+        let template = templates.get(type_name);
+        let data_type = match (parse_type(type_name), template) {
+            (Some(t), _) => t,
+            (None, Some(template)) => template.data_type,
+            (None, None) => return Err(row.error(format!("unknown type `{}`", type_name))),
+        };
+        let (divider, values) = parse_divider_values(divider_values).map_err(|e| row.error(e))?;
+
+        let slot = if part == Part::Master { 0 } else { 1 };
+        let previous = fields.iter().rev().find(|f| f.part == part);
+        let offset = match (previous.map(|f| f.data_type), data_type) {
+            // consecutive bits share their byte
+            (Some(DataType::Bit(a)), DataType::Bit(b)) if b > a => offsets[slot] - 1,
+            _ => offsets[slot],
+        };
+
+        let mut field = FieldDefinition::new(name, part, offset, data_type);
+        field.divider = divider.or(template.and_then(|t| t.divider));
+        field.values = values.or(template.and_then(|t| t.values.clone()));
+        field.unit = non_empty(unit).or(template.and_then(|t| t.unit.clone()));
+        field.comment = non_empty(comment).or(template.and_then(|t| t.comment.clone()));
+        offsets[slot] = field.end();
+        fields.push(field);
+    }
+    Ok(fields)
+}
+
+/// Parse the rows of a template file: `name,type,divider/values,unit,comment`
+pub fn parse_templates(source: &str, file: &Path, errors: &mut Vec<ConfigError>) -> Templates {
+    let mut templates = Templates::default();
+
+    for (i, line) in source.lines().enumerate() {
+        let row = Row { file, line: i + 1, columns: split_row(line) };
+        let name = row.column(0);
+        if name.is_empty() || name.starts_with('#') || name.eq_ignore_ascii_case("name") {
+            continue;
+        }
+
+        let data_type = match parse_type(row.column(1)).or_else(|| templates.get(row.column(1)).map(|t| t.data_type)) {
+            Some(t) => t,
+            None => {
+                errors.push(row.error(format!("unknown type `{}`", row.column(1))));
+                continue;
+            },
+        };
+        match parse_divider_values(row.column(2)) {
+            Ok((divider, values)) => templates.insert(name, Template {
+                data_type,
+                divider,
+                values,
+                unit: non_empty(row.column(3)),
+                comment: non_empty(row.column(4)),
+            }),
+            Err(e) => errors.push(row.error(e)),
+        }
+    }
+    templates
+}
+
+/// Parse the message rows of a definition file
+pub fn parse(source: &str, file: &Path, templates: &Templates, errors: &mut Vec<ConfigError>) -> Vec<MessageDefinition> {
+    let (file_address, file_circuit, file_condition) = file_defaults(file);
+    let mut defaults: HashMap<char, Defaults> = HashMap::new();
+    let mut definitions = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let row = Row { file, line: i + 1, columns: split_row(line) };
+        let type_column = row.column(0);
+        if type_column.is_empty() || type_column.starts_with('#') || type_column.eq_ignore_ascii_case("type") {
+            continue;
+        }
+
+        if let Some(default_type) = type_column.strip_prefix('*') {
+            match default_type.chars().next() {
+                Some(k) if kind_of(default_type).is_some() => {
+                    defaults.insert(k.to_ascii_lowercase(), Defaults {
+                        circuit: row.column(1).to_string(),
+                        source: row.column(4).to_string(),
+                        destination: row.column(5).to_string(),
+                        pbsb: row.column(6).to_string(),
+                        id: row.column(7).to_string(),
+                    });
+                },
+                _ => errors.push(row.error(format!("unsupported default row `{}`", type_column))),
+            }
+            continue;
+        }
+
+        match parse_message(&row, file_address, file_circuit.as_deref(), file_condition.as_ref(), &defaults, templates) {
+            Ok(definition) => definitions.push(definition),
+            Err(e) => errors.push(e),
+        }
+    }
+    definitions
+}
+
+fn parse_message(
+    row: &Row,
+    file_address: Option<u8>,
+    file_circuit: Option<&str>,
+    file_condition: Option<&Condition>,
+    defaults: &HashMap<char, Defaults>,
+    templates: &Templates,
+) -> Result<MessageDefinition, ConfigError> {
+    let (row_condition, type_column) = parse_type_column(row.column(0)).map_err(|e| row.error(e))?;
+    let kind = kind_of(&type_column).ok_or_else(|| row.error(format!("unknown message type `{}`", type_column)))?;
+    let default = type_column.chars().next()
+        .and_then(|k| defaults.get(&k.to_ascii_lowercase()))
+        .cloned()
+        .unwrap_or_default();
+    let or_default = |column: usize, default: &str| match row.column(column) {
+        "" => default.to_string(),
+        c => c.to_string(),
+    };
+
+    let circuit = or_default(1, &default.circuit);
+    let circuit = match (circuit.as_str(), file_circuit) {
+        ("", Some(c)) => c.to_string(),
+        _ => circuit,
+    };
+
+    let address = |column: usize, default: &str| -> Result<Option<u8>, ConfigError> {
+        match or_default(column, default).as_str() {
+            "" => Ok(None),
+            a => parse_hex_byte(a).map(Some).ok_or_else(|| row.error(format!("invalid address `{}`", a))),
+        }
+    };
+    let source = address(4, &default.source)?;
+    let destination = address(5, &default.destination)?.or(file_address);
+
+    let pbsb = or_default(6, &default.pbsb);
+    let (primary, secondary) = match parse_hex_bytes(&pbsb).as_deref() {
+        Some([pb, sb]) => (*pb, *sb),
+        _ => return Err(row.error(format!("invalid PBSB `{}`", pbsb))),
+    };
+    let id = format!("{}{}", default.id, row.column(7));
+    let id = parse_hex_bytes(&id).ok_or_else(|| row.error(format!("invalid ID `{}`", id)))?;
+
+    let condition = match (file_condition.cloned(), row_condition) {
+        (None, None) => None,
+        (Some(c), None) | (None, Some(c)) => Some(c),
+        (Some(file), Some(row_condition)) => Some(file.intersect(row_condition)
+            .ok_or_else(|| row.error("the condition excludes every device the file applies to"))?),
+    };
+
+    let mut definition = MessageDefinition::new(&circuit, row.column(2), kind, primary, secondary);
+    definition.source = source;
+    definition.destination = destination;
+    definition.fields = parse_fields(row, kind, id.len(), templates)?;
+    definition.id = id;
+    definition.condition = condition;
+    definition.comment = non_empty(row.column(3));
+    definition.location = Some(Location { file: row.file.display().to_string(), line: row.line });
+    Ok(definition)
+}
+
+/// ebusd name of a type
+pub fn type_name(data_type: DataType) -> String {
+    match data_type {
+        DataType::Bcd => "BCD".to_string(),
+        DataType::Data1b => "D1B".to_string(),
+        DataType::Data1c => "D1C".to_string(),
+        DataType::Data2b => "D2B".to_string(),
+        DataType::Data2c => "D2C".to_string(),
+        DataType::Uch => "UCH".to_string(),
+        DataType::Sch => "SCH".to_string(),
+        DataType::Uin => "UIN".to_string(),
+        DataType::Sin => "SIN".to_string(),
+        DataType::Ulg => "ULG".to_string(),
+        DataType::Slg => "SLG".to_string(),
+        DataType::Date => "BDA".to_string(),
+        DataType::Time => "BTI".to_string(),
+        DataType::Bit(n) => format!("BI{}", n),
+        DataType::Str(n) => format!("STR:{}", n),
+        DataType::Hex(n) => format!("HEX:{}", n),
+        DataType::Ignore(n) => format!("IGN:{}", n),
+    }
+}
+
+/// Quote a column when needed
+fn quote(column: &str) -> String {
+    if column.contains([',', '"']) {
+        format!("\"{}\"", column.replace('"', "\"\""))
+    } else {
+        column.to_string()
+    }
+}
+
+fn format_condition(condition: &Condition) -> String {
+    let mut terms = Vec::new();
+    if !condition.device_ids.is_empty() {
+        terms.push(format!("ID={}", condition.device_ids.join(";")));
+    }
+    if let Some(manufacturer) = condition.manufacturer {
+        terms.push(format!("MF={:02x}", manufacturer));
+    }
+    for (key, range) in [("SW", condition.software), ("HW", condition.hardware)] {
+        match range {
+            Some(VersionRange { min: Some(min), max: Some(max) }) if min == max => terms.push(format!("{}={:04}", key, min)),
+            Some(VersionRange { min, max }) => {
+                terms.extend(min.map(|v| format!("{}>={:04}", key, v)));
+                terms.extend(max.map(|v| format!("{}<={:04}", key, v)));
+            },
+            None => (),
+        }
+    }
+    format!("[{}]", terms.join(","))
+}
+
+/// Field columns of a part, padding the gaps with ignored bytes
+fn format_fields(definition: &MessageDefinition, part: Part, columns: &mut Vec<String>) -> Result<(), String> {
+    for (gap, field) in sequential_layout(definition, part)? {
+        if gap > 0 {
+            columns.extend([String::new(), part_name(part).to_string(), type_name(DataType::Ignore(gap as u8)), String::new(), String::new(), String::new()]);
+        }
+
+        let divider_values = match (&field.values, field.divider) {
+            (Some(values), _) => values.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(";"),
+            (None, Some(divider)) => divider.to_string(),
+            (None, None) => String::new(),
+        };
+        columns.extend([
+            field.name.clone(),
+            part_name(part).to_string(),
+            type_name(field.data_type),
+            divider_values,
+            field.unit.clone().unwrap_or_default(),
+            field.comment.clone().unwrap_or_default(),
+        ]);
+    }
+    Ok(())
+}
+
+fn part_name(part: Part) -> &'static str {
+    match part {
+        Part::Master => "m",
+        Part::Slave => "s",
+    }
+}
+
+fn format_message(definition: &MessageDefinition) -> Result<String, String> {
+    if definition.paging.is_some() {
+        return Err("paged messages are not supported".to_string());
+    }
+    if definition.condition.as_ref().is_some_and(|c| c.address.is_some()) {
+        return Err("conditions on another address are not supported".to_string());
+    }
+
+    let kind = match definition.kind {
+        MessageKind::Read => "r",
+        MessageKind::Write => "w",
+        MessageKind::Passive => "u",
+    };
+    let address = |a: Option<u8>| a.map(|a| format!("{:02x}", a)).unwrap_or_default();
+    let mut columns = vec![
+        format!("{}{}", definition.condition.as_ref().map(format_condition).unwrap_or_default(), kind),
+        definition.circuit.clone(),
+        definition.name.clone(),
+        definition.comment.clone().unwrap_or_default(),
+        address(definition.source),
+        address(definition.destination),
+        format!("{:02x}{:02x}", definition.primary, definition.secondary),
+        format_hex_bytes(&definition.id, ""),
+    ];
+    format_fields(definition, Part::Master, &mut columns)?;
+    format_fields(definition, Part::Slave, &mut columns)?;
+
+    Ok(columns.iter().map(|c| quote(c)).collect::<Vec<_>>().join(","))
+}
+
+/// Write definitions as a single CSV file.
+/// The definitions which cannot be represented are skipped and reported into `errors`.
+pub fn export(definitions: &[MessageDefinition], errors: &mut Vec<ExportError>) -> String {
+    let mut csv = String::from("type,circuit,name,comment,qq,zz,pbsb,id,field,part,type,divider/values,unit,comment\n");
+    for definition in definitions {
+        match format_message(definition) {
+            Ok(row) => {
+                csv.push_str(&row);
+                csv.push('\n');
+            },
+            Err(e) => errors.push(ExportError::new(definition, e)),
+        }
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer7::types::Value;
+
+    const BAI: &str = "\
+type,circuit,name,comment,QQ,ZZ,PBSB,ID,field1,part,type,divider/values,unit,comment
+*r,,,,,,B509,0D
+r,,FlowTemp,Flow temperature,,,,1800,temp,,D2C,,°C,
+[SW>=0409]r,,Pressure,,,,,0200,,,pressure,,,
+w,,HeatingMode,,,,B509,0E2A00,mode,,UCH,0=off;1=auto;2=day,,
+";
+
+    #[test]
+    fn messages_and_defaults() {
+        let mut errors = Vec::new();
+        let definitions = parse(BAI, Path::new("08.bai.csv"), &Templates::default(), &mut errors);
+        assert_eq!(errors, vec![ConfigError::new(Path::new("08.bai.csv"), 4, "unknown type `pressure`")]);
+        assert_eq!(definitions.len(), 2);
+
+        let flow = &definitions[0];
+        assert_eq!(flow.circuit, "bai");
+        assert_eq!(flow.destination, Some(0x08));
+        assert_eq!((flow.primary, flow.secondary), (0xb5, 0x09));
+        assert_eq!(flow.id, vec![0x0d, 0x18, 0x00]);
+        assert_eq!(flow.fields[0].part, Part::Slave);
+        assert_eq!(flow.fields[0].unit.as_deref(), Some("°C"));
+        assert_eq!(flow.condition.as_ref().unwrap().device_ids, vec!["bai".to_string()]);
+        assert_eq!(flow.location.as_ref().unwrap().line, 3);
+
+        let mode = &definitions[1];
+        assert_eq!(mode.kind, MessageKind::Write);
+        assert_eq!(mode.fields[0].part, Part::Master);
+        assert_eq!(mode.fields[0].offset, 3);
+        assert_eq!(mode.fields[0].decode(&[0x0e, 0x2a, 0x00, 0x01]), Ok(Some(Value::Named(1, "auto".to_string()))));
+    }
+
+    #[test]
+    fn inline_condition_and_templates() {
+        let mut errors = Vec::new();
+        let templates = parse_templates("temp,D2C,,°C,Temperature\npressure,UCH,10,bar,", Path::new(TEMPLATES_FILE), &mut errors);
+        let definitions = parse(BAI, Path::new("08.bai.csv"), &templates, &mut errors);
+        assert!(errors.is_empty());
+
+        let pressure = &definitions[1];
+        assert_eq!(pressure.fields[0].divider, Some(10.0));
+        let condition = pressure.condition.as_ref().unwrap();
+        assert_eq!(condition.software, VersionRange::from_comparison(">=", 409));
+        assert_eq!(condition.device_ids, vec!["bai".to_string()]);
+    }
+
+    #[test]
+    fn row_conditions_narrow_the_file_condition() {
+        let mut errors = Vec::new();
+        let source = "[ID=bai00;vrc]r,,Flow,,,,B509,0D1800,temp,,D2C,,,\n[ID=xyz]r,,Other,,,,B509,0D1900,temp,,D2C,,,\n";
+        let definitions = parse(source, Path::new("08.bai.SW0409.csv"), &Templates::default(), &mut errors);
+        assert_eq!(errors, vec![ConfigError::new(Path::new("08.bai.SW0409.csv"), 2, "the condition excludes every device the file applies to")]);
+        assert_eq!(definitions.len(), 1);
+
+        let condition = definitions[0].condition.as_ref().unwrap();
+        assert_eq!(condition.device_ids, vec!["bai00".to_string()]);
+        assert_eq!(condition.software, VersionRange::from_comparison("=", 409));
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut errors = Vec::new();
+        let templates = parse_templates("pressure,UCH,10,bar,", Path::new(TEMPLATES_FILE), &mut errors);
+        let mut definitions = parse(BAI, Path::new("08.bai.csv"), &templates, &mut errors);
+        definitions.extend(parse("u,,Status,,10,fe,B510,,,,IGN:2,,,,pump,,BI0,,,,valve,,BI2,,,,name,,\"STR:4\",,,\"a \"\"quoted\"\", comment\"", Path::new("broadcast.csv"), &templates, &mut errors));
+        assert!(errors.is_empty());
+
+        let mut export_errors = Vec::new();
+        let csv = export(&definitions, &mut export_errors);
+        assert!(export_errors.is_empty());
+
+        let mut reloaded = parse(&csv, Path::new("export.csv"), &Templates::default(), &mut errors);
+        assert!(errors.is_empty());
+        for definition in definitions.iter_mut().chain(reloaded.iter_mut()) {
+            definition.location = None;
+        }
+        assert_eq!(reloaded, definitions);
+    }
+
+    #[test]
+    fn quoted_columns() {
+        assert_eq!(split_row(r#"r,"a,b","say ""hi""",x"#), vec!["r", "a,b", r#"say "hi""#, "x"]);
+    }
+
+    #[test]
+    fn consecutive_bits_share_their_byte() {
+        let mut errors = Vec::new();
+        let definitions = parse("r,hc,Status,,,15,B504,0d,pump,,BI0,,,,valve,,BI1,,,,level,,UCH,,,", Path::new("hc.csv"), &Templates::default(), &mut errors);
+        let offsets: Vec<usize> = definitions[0].fields.iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, vec![0, 0, 1]);
+    }
+}
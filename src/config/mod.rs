@@ -0,0 +1,222 @@
+pub mod csowada;
+pub mod ebusd;
+pub mod native;
+pub mod validate;
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::layer7::types::DataType;
+use crate::layer7::{Catalogue, FieldDefinition, Location, MessageDefinition, Part};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub location: Location,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(file: &Path, line: usize, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            location: Location { file: file.display().to_string(), line },
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A definition which cannot be exactly represented in the format it is exported to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportError {
+    pub circuit: String,
+    pub name: String,
+    pub message: String,
+}
+
+impl ExportError {
+    pub(crate) fn new(definition: &MessageDefinition, message: impl Into<String>) -> ExportError {
+        ExportError {
+            circuit: definition.circuit.clone(),
+            name: definition.name.clone(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}: {}", self.circuit, self.name, self.message)
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+/// Parse a byte written as 2 hex digits
+pub fn parse_hex_byte(s: &str) -> Option<u8> {
+    let s = s.trim();
+    if s.len() != 2 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u8::from_str_radix(s, 16).ok()
+}
+
+/// Write bytes as hex digits, separated by `separator`
+pub(crate) fn format_hex_bytes(bytes: &[u8], separator: &str) -> String {
+    bytes.iter().map(|c| format!("{:02x}", c)).collect::<Vec<_>>().join(separator)
+}
+
+/// Parse bytes written as hex digits, optionally separated by spaces (`0d2700` or `0D 27 00`)
+pub fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    let digits: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) || !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..digits.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The fields of a part with the number of bytes to skip before each of them, as both ebusd and
+/// csowada formats lay the fields out one after the other (consecutive bits sharing their byte)
+pub(crate) fn sequential_layout(definition: &MessageDefinition, part: Part) -> Result<Vec<(usize, &FieldDefinition)>, String> {
+    let mut cursor = if part == Part::Master { definition.id.len() } else { 0 };
+    let mut previous: Option<&FieldDefinition> = None;
+    let mut layout = Vec::new();
+
+    for field in definition.fields.iter().filter(|f| f.part == part) {
+        let follows_bit = matches!((previous.map(|p| p.data_type), field.data_type), (Some(DataType::Bit(a)), DataType::Bit(b)) if b > a);
+        let gap = match previous {
+            Some(p) if follows_bit && p.offset == field.offset => 0,
+            _ if follows_bit || field.offset < cursor => return Err(format!("field `{}` cannot be laid out sequentially", field.name)),
+            _ => field.offset - cursor,
+        };
+        layout.push((gap, field));
+        cursor = field.end();
+        previous = Some(field);
+    }
+    Ok(layout)
+}
+
+/// Load the definitions of a single file, the format being chosen from its extension.
+/// Erroneous definitions are skipped and reported into `errors`.
+pub fn load_file(path: &Path, errors: &mut Vec<ConfigError>) -> Vec<MessageDefinition> {
+    let templates = ebusd::Templates::default();
+    load_file_with_templates(path, &templates, errors)
+}
+
+fn load_file_with_templates(path: &Path, templates: &ebusd::Templates, errors: &mut Vec<ConfigError>) -> Vec<MessageDefinition> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            errors.push(ConfigError::new(path, 0, e.to_string()));
+            return Vec::new();
+        },
+    };
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => csowada::parse(&source, path, errors),
+        Some("csv") => ebusd::parse(&source, path, templates, errors),
+        _ => {
+            errors.push(ConfigError::new(path, 0, "unknown definition file format"));
+            Vec::new()
+        },
+    }
+}
+
+fn definition_files(dir: &Path, errors: &mut Vec<ConfigError>) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.push(ConfigError::new(dir, 0, e.to_string()));
+            return Vec::new();
+        },
+    };
+
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    paths.sort();
+    paths
+}
+
+fn load_dir_into(dir: &Path, parent_templates: &ebusd::Templates, catalogue: &mut Catalogue, errors: &mut Vec<ConfigError>) {
+    let paths = definition_files(dir, errors);
+
+    // ebusd templates apply to their directory and its sub-directories
+    let mut templates = parent_templates.clone();
+    if let Some(path) = paths.iter().find(|p| p.file_name().is_some_and(|n| n == ebusd::TEMPLATES_FILE)) {
+        match fs::read_to_string(path) {
+            Ok(source) => templates.extend(ebusd::parse_templates(&source, path, errors)),
+            Err(e) => errors.push(ConfigError::new(path, 0, e.to_string())),
+        }
+    }
+
+    for path in &paths {
+        let is_hidden = path.file_name().and_then(|n| n.to_str()).is_none_or(|n| n.starts_with('.') || n.starts_with('_'));
+        if path.is_dir() && !is_hidden {
+            load_dir_into(path, &templates, catalogue, errors);
+        } else if !is_hidden {
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("json") | Some("csv") => catalogue.extend(load_file_with_templates(path, &templates, errors)),
+                // a rebus configuration, or a file it includes, may lie next to the definitions it imports
+                Some("toml") => (),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Load every definition file of a directory and its sub-directories.
+/// Erroneous definitions are skipped and reported besides the catalogue.
+pub fn load_dir_with_errors(dir: &Path) -> (Catalogue, Vec<ConfigError>) {
+    let mut catalogue = Catalogue::new();
+    let mut errors = Vec::new();
+    load_dir_into(dir, &ebusd::Templates::default(), &mut catalogue, &mut errors);
+    (catalogue, errors)
+}
+
+/// Load every definition file of a directory and its sub-directories, failing on any error
+pub fn load_dir(dir: &Path) -> Result<Catalogue, Vec<ConfigError>> {
+    match load_dir_with_errors(dir) {
+        (catalogue, errors) if errors.is_empty() => Ok(catalogue),
+        (_, errors) => Err(errors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn rebus_configurations_are_skipped_in_directories() {
+        let dir = env::temp_dir().join(format!("rebus-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("rebus.toml"), "import = [\".\"]\n").unwrap();
+        fs::write(dir.join("08.bai.csv"), "r,,FlowTemp,,,,B509,0D1800,temp,,D2C,,°C,\n").unwrap();
+
+        let (catalogue, errors) = load_dir_with_errors(&dir);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(catalogue.definitions().len(), 1);
+
+        let settings = native::load(&dir.join("rebus.toml")).unwrap();
+        assert_eq!(settings.catalogue.definitions()[0].name, "FlowTemp");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hex_is_made_of_hex_digits_only() {
+        assert_eq!(parse_hex_byte(" 0F "), Some(0x0f));
+        assert_eq!(parse_hex_byte("+f"), None);
+        assert_eq!(parse_hex_bytes("0D 27 00"), Some(vec![0x0d, 0x27, 0x00]));
+        assert_eq!(parse_hex_bytes("0d+f"), None);
+        assert_eq!(parse_hex_bytes("-1"), None);
+    }
+}
@@ -0,0 +1,511 @@
+//! rebus own configuration format, in TOML.
+//!
+//! ```toml
+//! include = ["vaillant/bai.toml"]      # merged before this file, relative to it
+//! import = ["ebusd-configuration/en"]  # directories of ebusd CSV or csowada JSON files
+//!
+//! [bus]
+//! address = "31"
+//! transport = { type = "serial", device = "/dev/ttyUSB0", enhanced = true }
+//!
+//! [templates.temp]
+//! type = "D2C"
+//! unit = "°C"
+//!
+//! [[circuits]]
+//! name = "bai"
+//! destination = "08"              # default ZZ of the messages
+//! identification = ["BAI00"]      # only for the devices identifying themselves so
+//! software = ">=0409"
+//!
+//! [[circuits.messages]]
+//! name = "FlowTemp"
+//! command = "B509"
+//! id = "0d1800"
+//! fields = [{ name = "temp", template = "temp" }]
+//! ```
+//!
+//! The fields are laid out one after the other like in ebusd files, unless an `offset` is given.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use super::{ebusd, load_dir_with_errors, parse_hex_byte, parse_hex_bytes, ConfigError};
+use crate::layer7::condition::{Condition, VersionRange};
+use crate::layer7::paged::Paging;
+use crate::layer7::types::DataType;
+use crate::layer7::{Catalogue, FieldDefinition, Location, MessageDefinition, MessageKind, Part};
+
+/// Master address used when the configuration does not set one
+pub const DEFAULT_ADDRESS: u8 = 0x31;
+
+/// How to reach the bus
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum TransportConfig {
+    /// USB or TTL adapter
+    Serial {
+        device: String,
+        /// The adapter speaks the ebusd enhanced protocol
+        #[serde(default)]
+        enhanced: bool,
+    },
+    /// Network adapter exposing the bus
+    Tcp {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        enhanced: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Own master address
+    pub address: u8,
+    pub transport: Option<TransportConfig>,
+    pub catalogue: Catalogue,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            address: DEFAULT_ADDRESS,
+            transport: None,
+            catalogue: Catalogue::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Own slave address, bound to the master address
+    pub fn slave_address(&self) -> u8 {
+        self.address.wrapping_add(5)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    import: Vec<String>,
+    bus: Option<BusDocument>,
+    #[serde(default)]
+    templates: HashMap<String, FieldDocument>,
+    #[serde(default)]
+    circuits: Vec<CircuitDocument>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BusDocument {
+    address: Option<String>,
+    transport: Option<TransportConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CircuitDocument {
+    name: String,
+    source: Option<String>,
+    destination: Option<String>,
+    #[serde(default)]
+    identification: Vec<String>,
+    software: Option<String>,
+    hardware: Option<String>,
+    #[serde(default)]
+    messages: Vec<Spanned<MessageDocument>>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum KindDocument {
+    Read,
+    Write,
+    Passive,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MessageDocument {
+    name: String,
+    kind: Option<KindDocument>,
+    comment: Option<String>,
+    source: Option<String>,
+    destination: Option<String>,
+    command: String,
+    id: Option<String>,
+    #[serde(default)]
+    fields: Vec<FieldDocument>,
+    paging: Option<PagingDocument>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum PartDocument {
+    Master,
+    Slave,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+struct FieldDocument {
+    name: Option<String>,
+    template: Option<String>,
+    part: Option<PartDocument>,
+    #[serde(rename = "type")]
+    data_type: Option<String>,
+    offset: Option<usize>,
+    divider: Option<f64>,
+    values: Option<BTreeMap<String, String>>,
+    unit: Option<String>,
+    min: Option<f64>,
+    max: Option<f64>,
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PagingDocument {
+    index: usize,
+    count: u8,
+    first: Option<u8>,
+    step: Option<u8>,
+    skip: Option<usize>,
+}
+
+impl FieldDocument {
+    /// Fill the unset properties from a template
+    fn inherit(mut self, template: &FieldDocument) -> FieldDocument {
+        self.part = self.part.or(template.part);
+        self.data_type = self.data_type.or_else(|| template.data_type.clone());
+        self.divider = self.divider.or(template.divider);
+        self.values = self.values.or_else(|| template.values.clone());
+        self.unit = self.unit.or_else(|| template.unit.clone());
+        self.min = self.min.or(template.min);
+        self.max = self.max.or(template.max);
+        self.comment = self.comment.or_else(|| template.comment.clone());
+        self
+    }
+}
+
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+fn parse_address(value: Option<&str>) -> Result<Option<u8>, String> {
+    value.map(|a| parse_hex_byte(a).ok_or_else(|| format!("invalid address `{}`", a))).transpose()
+}
+
+/// Parse a version requirement such as `>=0409`
+fn parse_version(value: Option<&str>) -> Result<Option<VersionRange>, String> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    let split = value.find(|c: char| c.is_ascii_digit()).unwrap_or(value.len());
+    let (operator, version) = value.split_at(split);
+    let operator = if operator.is_empty() { "=" } else { operator.trim() };
+    version.parse::<u16>().ok()
+        .and_then(|v| VersionRange::from_comparison(operator, v))
+        .map(Some)
+        .ok_or_else(|| format!("invalid version requirement `{}`", value))
+}
+
+/// A parsed file with the ones it includes, in merge order
+struct Parsed {
+    path: PathBuf,
+    source: String,
+    document: Document,
+}
+
+fn parse_tree(path: &Path, parsed: &mut Vec<Parsed>, stack: &mut Vec<PathBuf>, errors: &mut Vec<ConfigError>) {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&canonical) {
+        errors.push(ConfigError::new(path, 0, "recursive include"));
+        return;
+    }
+    if parsed.iter().any(|p| p.path == canonical) {
+        return;
+    }
+
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            errors.push(ConfigError::new(path, 0, e.to_string()));
+            return;
+        },
+    };
+    let document: Document = match toml::from_str(&source) {
+        Ok(document) => document,
+        Err(e) => {
+            let line = e.span().map_or(0, |s| line_at(&source, s.start));
+            errors.push(ConfigError::new(path, line, e.message()));
+            return;
+        },
+    };
+
+    stack.push(canonical.clone());
+    let base = path.parent().unwrap_or(Path::new(""));
+    for include in &document.include {
+        parse_tree(&base.join(include), parsed, stack, errors);
+    }
+    stack.pop();
+
+    parsed.push(Parsed { path: canonical, source, document });
+}
+
+struct Builder<'a> {
+    templates: &'a HashMap<String, FieldDocument>,
+    file: &'a Path,
+    line: usize,
+}
+
+impl Builder<'_> {
+    fn fields(&self, message: &MessageDocument, kind: MessageKind, id_len: usize) -> Result<Vec<FieldDefinition>, String> {
+        let mut fields: Vec<FieldDefinition> = Vec::new();
+        let mut cursors = [id_len, 0];
+
+        for (i, document) in message.fields.iter().enumerate() {
+            let document = match &document.template {
+                Some(name) => {
+                    let template = self.templates.get(name).ok_or_else(|| format!("unknown template `{}`", name))?;
+                    document.clone().inherit(template)
+                },
+                None => document.clone(),
+            };
+
+            let part = match (document.part, kind) {
+                (Some(PartDocument::Master), _) | (None, MessageKind::Write) | (None, MessageKind::Passive) => Part::Master,
+                (Some(PartDocument::Slave), _) | (None, MessageKind::Read) => Part::Slave,
+            };
+            let type_name = document.data_type.as_deref().ok_or_else(|| format!("field #{} has no type", i + 1))?;
+            let data_type = ebusd::parse_type(type_name).ok_or_else(|| format!("unknown type `{}`", type_name))?;
+
+            let slot = if part == Part::Master { 0 } else { 1 };
+            let previous = fields.iter().rev().find(|f| f.part == part);
+            let offset = match (document.offset, previous.map(|f| f.data_type), data_type) {
+                (Some(offset), _, _) => offset,
+                (None, Some(DataType::Bit(a)), DataType::Bit(b)) if b > a => cursors[slot] - 1,
+                (None, _, _) => cursors[slot],
+            };
+
+            let mut field = FieldDefinition::new(document.name.as_deref().unwrap_or(""), part, offset, data_type);
+            field.divider = document.divider;
+            field.values = document.values
+                .map(|values| values.into_iter()
+                    .map(|(k, v)| k.trim().parse::<i64>().map(|k| (k, v)).map_err(|_| format!("invalid value key `{}`", k)))
+                    .collect::<Result<Vec<_>, String>>())
+                .transpose()?
+                .map(|mut values| {
+                    values.sort_by_key(|(k, _)| *k);
+                    values
+                });
+            field.unit = document.unit;
+            field.min = document.min;
+            field.max = document.max;
+            field.comment = document.comment;
+            cursors[slot] = field.end();
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    fn message(&self, circuit: &CircuitDocument, message: &MessageDocument) -> Result<MessageDefinition, String> {
+        let kind = match message.kind.unwrap_or(KindDocument::Read) {
+            KindDocument::Read => MessageKind::Read,
+            KindDocument::Write => MessageKind::Write,
+            KindDocument::Passive => MessageKind::Passive,
+        };
+        let (primary, secondary) = match parse_hex_bytes(&message.command).as_deref() {
+            Some([pb, sb]) => (*pb, *sb),
+            _ => return Err(format!("invalid command `{}`", message.command)),
+        };
+        let id = message.id.as_deref().map_or(Some(Vec::new()), parse_hex_bytes).ok_or("invalid ID")?;
+
+        let condition = Condition {
+            device_ids: circuit.identification.clone(),
+            software: parse_version(circuit.software.as_deref())?,
+            hardware: parse_version(circuit.hardware.as_deref())?,
+            ..Default::default()
+        };
+
+        let mut definition = MessageDefinition::new(&circuit.name, &message.name, kind, primary, secondary);
+        definition.source = parse_address(message.source.as_deref().or(circuit.source.as_deref()))?;
+        definition.destination = parse_address(message.destination.as_deref().or(circuit.destination.as_deref()))?;
+        definition.fields = self.fields(message, kind, id.len())?;
+        definition.id = id;
+        definition.paging = message.paging.as_ref().map(|p| Paging {
+            index_offset: p.index,
+            first: p.first.unwrap_or(0),
+            step: p.step.unwrap_or(1),
+            count: p.count,
+            skip: p.skip.unwrap_or(0),
+        });
+        definition.condition = if condition.is_empty() { None } else { Some(condition) };
+        definition.comment = message.comment.clone();
+        definition.location = Some(Location { file: self.file.display().to_string(), line: self.line });
+        Ok(definition)
+    }
+}
+
+/// Load a configuration file with the files it includes and the directories it imports.
+/// Erroneous definitions are skipped and reported besides the settings.
+pub fn load_with_errors(path: &Path) -> (Settings, Vec<ConfigError>) {
+    let mut errors = Vec::new();
+    let mut parsed = Vec::new();
+    parse_tree(path, &mut parsed, &mut Vec::new(), &mut errors);
+
+    let mut settings = Settings::default();
+    let mut templates = HashMap::new();
+    for p in &parsed {
+        templates.extend(p.document.templates.clone());
+    }
+
+    for p in &parsed {
+        if let Some(bus) = &p.document.bus {
+            match parse_address(bus.address.as_deref()) {
+                Ok(address) => settings.address = address.unwrap_or(settings.address),
+                Err(e) => errors.push(ConfigError::new(&p.path, 0, e)),
+            }
+            settings.transport = bus.transport.clone().or(settings.transport.take());
+        }
+
+        let base = p.path.parent().unwrap_or(Path::new(""));
+        for import in &p.document.import {
+            let (catalogue, import_errors) = load_dir_with_errors(&base.join(import));
+            settings.catalogue.extend(catalogue.definitions().iter().cloned());
+            errors.extend(import_errors);
+        }
+
+        for circuit in &p.document.circuits {
+            for message in &circuit.messages {
+                let builder = Builder { templates: &templates, file: &p.path, line: line_at(&p.source, message.span().start) };
+                match builder.message(circuit, message.get_ref()) {
+                    Ok(definition) => settings.catalogue.push(definition),
+                    Err(e) => errors.push(ConfigError::new(&p.path, builder.line, e)),
+                }
+            }
+        }
+    }
+    (settings, errors)
+}
+
+/// Load a configuration file with the files it includes and the directories it imports, failing on any error
+pub fn load(path: &Path) -> Result<Settings, Vec<ConfigError>> {
+    match load_with_errors(path) {
+        (settings, errors) if errors.is_empty() => Ok(settings),
+        (_, errors) => Err(errors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer7::types::Value;
+    use std::env;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rebus-native-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn includes_templates_and_defaults() {
+        let dir = temp_dir("includes");
+        write(&dir, "common/templates.toml", r#"
+[templates.temp]
+type = "D2C"
+unit = "°C"
+
+[templates.onoff]
+type = "UCH"
+values = { 0 = "off", 1 = "on" }
+"#);
+        write(&dir, "vaillant/bai.toml", r#"
+[[circuits]]
+name = "bai"
+destination = "08"
+identification = ["BAI"]
+software = ">=0409"
+
+[[circuits.messages]]
+name = "FlowTemp"
+command = "B509"
+id = "0d1800"
+fields = [{ name = "temp", template = "temp" }]
+
+[[circuits.messages]]
+name = "Pump"
+kind = "write"
+command = "B509"
+id = "0e4400"
+fields = [{ name = "pump", template = "onoff", comment = "circulation pump" }]
+"#);
+        let root = write(&dir, "rebus.toml", r#"
+include = ["common/templates.toml", "vaillant/bai.toml"]
+
+[bus]
+address = "ff"
+transport = { type = "tcp", host = "192.168.1.10", port = 9999, enhanced = true }
+"#);
+
+        let settings = load(&root).unwrap();
+        assert_eq!(settings.address, 0xff);
+        assert_eq!(settings.slave_address(), 0x04);
+        assert_eq!(settings.transport, Some(TransportConfig::Tcp { host: "192.168.1.10".to_string(), port: 9999, enhanced: true }));
+
+        let definitions = settings.catalogue.definitions();
+        assert_eq!(definitions.len(), 2);
+
+        let flow = &definitions[0];
+        assert_eq!(flow.destination, Some(0x08));
+        assert_eq!(flow.fields[0].data_type, DataType::Data2c);
+        assert_eq!(flow.fields[0].part, Part::Slave);
+        assert_eq!(flow.fields[0].unit.as_deref(), Some("°C"));
+        assert_eq!(flow.condition.as_ref().unwrap().software, VersionRange::from_comparison(">=", 409));
+        assert_eq!(flow.location.as_ref().unwrap().line, 8);
+
+        let pump = &definitions[1].fields[0];
+        assert_eq!((pump.part, pump.offset), (Part::Master, 3));
+        assert_eq!(pump.comment.as_deref(), Some("circulation pump"));
+        assert_eq!(pump.decode(&[0x0e, 0x44, 0x00, 0x01]), Ok(Some(Value::Named(1, "on".to_string()))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors_are_located() {
+        let dir = temp_dir("errors");
+        let root = write(&dir, "rebus.toml", r#"include = ["rebus.toml"]
+
+[[circuits]]
+name = "bai"
+
+[[circuits.messages]]
+name = "FlowTemp"
+command = "B509"
+fields = [{ name = "temp", template = "missing" }]
+"#);
+
+        let errors = load(&root).unwrap_err();
+        let messages: Vec<(usize, &str)> = errors.iter().map(|e| (e.location.line, e.message.as_str())).collect();
+        assert_eq!(messages, vec![(0, "recursive include"), (6, "unknown template `missing`")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
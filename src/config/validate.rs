@@ -0,0 +1,311 @@
+//! Detection of the definition mistakes which would otherwise only show up as wrong decodes
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use super::{load_dir_with_errors, native, ConfigError};
+use crate::layer2::{AddressClass, MAX_NN};
+use crate::layer7::types::DataType;
+use crate::layer7::{Catalogue, FieldDefinition, Location, MessageDefinition, MessageKind, Part};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Suspicious but decodable
+    Warning,
+    /// The definition cannot decode correctly
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub location: Option<Location>,
+    pub message: String,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}: {}", location, self.severity, self.message),
+            None => write!(f, "{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+impl From<ConfigError> for Problem {
+    fn from(e: ConfigError) -> Problem {
+        Problem {
+            severity: Severity::Error,
+            location: Some(e.location),
+            message: e.message,
+        }
+    }
+}
+
+struct Report<'a> {
+    problems: &'a mut Vec<Problem>,
+    definition: &'a MessageDefinition,
+}
+
+impl Report<'_> {
+    fn push(&mut self, severity: Severity, message: String) {
+        self.problems.push(Problem {
+            severity,
+            location: self.definition.location.clone(),
+            message: format!("{}.{}: {}", self.definition.circuit, self.definition.name, message),
+        });
+    }
+}
+
+/// Bytes of a field, the bits being counted apart so that different bits of a byte do not overlap
+fn occupied(field: &FieldDefinition) -> Vec<(usize, Option<u8>)> {
+    match field.data_type {
+        DataType::Bit(bit) => vec![(field.offset, Some(bit))],
+        _ => (field.offset..field.end()).map(|o| (o, None)).collect(),
+    }
+}
+
+fn overlaps(a: &FieldDefinition, b: &FieldDefinition) -> bool {
+    a.part == b.part && occupied(a).iter().any(|(oa, ba)| {
+        occupied(b).iter().any(|(ob, bb)| oa == ob && (ba.is_none() || bb.is_none() || ba == bb))
+    })
+}
+
+fn check_fields(report: &mut Report) {
+    let definition = report.definition;
+    let slave_capacity = match &definition.paging {
+        Some(p) => (MAX_NN.saturating_sub(p.skip)) * p.count as usize,
+        None => MAX_NN,
+    };
+
+    for (i, field) in definition.fields.iter().enumerate() {
+        let name = if field.name.is_empty() { format!("#{}", i + 1) } else { format!("`{}`", field.name) };
+        let capacity = if field.part == Part::Master { MAX_NN } else { slave_capacity };
+
+        if field.data_type.is_empty() {
+            report.push(Severity::Error, format!("field {} has a zero length", name));
+        }
+        if field.end() > capacity {
+            report.push(Severity::Error, format!("field {} ends at byte {} beyond the {} bytes of the payload", name, field.end(), capacity));
+        }
+        if field.part == Part::Master && field.offset < definition.id.len() {
+            report.push(Severity::Error, format!("field {} overlaps the message ID", name));
+        }
+        if let Some(other) = definition.fields[..i].iter().find(|other| overlaps(other, field)) {
+            report.push(Severity::Error, format!("field {} overlaps field `{}`", name, other.name));
+        }
+        if !field.name.is_empty() && definition.fields[..i].iter().any(|other| other.name == field.name) {
+            report.push(Severity::Warning, format!("field {} is defined twice", name));
+        }
+        if field.part == Part::Slave && definition.destination.is_some_and(|zz| matches!(AddressClass::of(zz), AddressClass::Master(_) | AddressClass::Broadcast)) {
+            report.push(Severity::Error, format!("field {} is in the slave part but the destination never answers", name));
+        }
+
+        if field.divider == Some(0.0) {
+            report.push(Severity::Error, format!("field {} has a divider of zero", name));
+        }
+        if field.divider.is_some() && field.values.is_some() {
+            report.push(Severity::Warning, format!("field {} has both a divider and a value list, the divider is ignored", name));
+        }
+        if (field.divider.is_some() || field.values.is_some()) && !field.data_type.is_numeric() {
+            report.push(Severity::Warning, format!("field {} is not numeric, its divider or value list is ignored", name));
+        }
+        if let (Some(min), Some(max)) = (field.min, field.max) {
+            if min > max {
+                report.push(Severity::Error, format!("field {} has a minimum above its maximum", name));
+            }
+        }
+
+        for (j, (key, value)) in field.values.iter().flatten().enumerate() {
+            let previous = &field.values.as_ref().unwrap()[..j];
+            if previous.iter().any(|(k, _)| k == key) {
+                report.push(Severity::Error, format!("field {} has the value {} listed twice", name, key));
+            } else if previous.iter().any(|(_, v)| v == value) {
+                report.push(Severity::Warning, format!("field {} has the name `{}` listed twice", name, value));
+            }
+        }
+    }
+}
+
+fn check_message(report: &mut Report) {
+    let definition = report.definition;
+
+    if definition.name.is_empty() {
+        report.push(Severity::Error, "the message has no name".to_string());
+    }
+    for (role, address) in [("source", definition.source), ("destination", definition.destination)] {
+        match address.map(AddressClass::of) {
+            Some(AddressClass::Invalid) => report.push(Severity::Error, format!("invalid {} address", role)),
+            Some(AddressClass::Master(_)) | None => (),
+            Some(_) if role == "source" => report.push(Severity::Error, "the source address is not a master".to_string()),
+            Some(_) => (),
+        }
+    }
+    if definition.kind == MessageKind::Read && definition.destination == Some(0xfe) {
+        report.push(Severity::Error, "a broadcast cannot be read".to_string());
+    }
+    if definition.master_length() > MAX_NN {
+        report.push(Severity::Error, format!("the master payload of {} bytes exceeds {} bytes", definition.master_length(), MAX_NN));
+    }
+
+    if let Some(paging) = &definition.paging {
+        if paging.count == 0 || paging.step == 0 {
+            report.push(Severity::Error, "the paging has no page".to_string());
+        }
+        if paging.index_offset < definition.id.len() {
+            report.push(Severity::Error, "the page index overlaps the message ID".to_string());
+        }
+        if let Some(field) = definition.fields.iter().find(|f| f.part == Part::Master && f.offset <= paging.index_offset && paging.index_offset < f.end()) {
+            report.push(Severity::Error, format!("the page index overlaps field `{}`", field.name));
+        }
+    }
+
+    check_fields(report);
+}
+
+/// Whether two definitions can match the same telegram
+fn is_ambiguous(a: &MessageDefinition, b: &MessageDefinition) -> bool {
+    let compatible = |x: Option<u8>, y: Option<u8>| x.is_none() || y.is_none() || x == y;
+    a.kind == b.kind
+        && (a.primary, a.secondary) == (b.primary, b.secondary)
+        && a.id == b.id
+        && a.master_length() == b.master_length()
+        && compatible(a.source, b.source)
+        && compatible(a.destination, b.destination)
+        && a.condition == b.condition
+}
+
+fn kind_name(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::Read => "read",
+        MessageKind::Write => "write",
+        MessageKind::Passive => "passive",
+    }
+}
+
+fn check_collisions(catalogue: &Catalogue, problems: &mut Vec<Problem>) {
+    let definitions = catalogue.definitions();
+    let mut names: HashMap<(String, String, &str), usize> = HashMap::new();
+
+    for (i, definition) in definitions.iter().enumerate() {
+        let mut report = Report { problems, definition };
+        let key = (definition.circuit.to_lowercase(), definition.name.to_lowercase(), kind_name(definition.kind));
+        let same_condition = |j: &usize| definitions[*j].condition == definition.condition;
+
+        match names.get(&key).filter(|j| same_condition(j)) {
+            Some(j) => report.push(Severity::Error, format!("the {} message name is already used at {}", key.2, located(&definitions[*j]))),
+            None => {
+                names.insert(key, i);
+            },
+        }
+
+        if let Some(other) = definitions[..i].iter().find(|other| is_ambiguous(other, definition)) {
+            report.push(Severity::Error, format!("the ID {:02x}{:02x}{} is already used by {}.{} at {}",
+                definition.primary, definition.secondary,
+                definition.id.iter().map(|c| format!("{:02x}", c)).collect::<String>(),
+                other.circuit, other.name, located(other)));
+        }
+    }
+}
+
+fn located(definition: &MessageDefinition) -> String {
+    definition.location.as_ref().map_or("an unknown location".to_string(), Location::to_string)
+}
+
+/// Report every problem of a catalogue, in the order of its definitions
+pub fn validate(catalogue: &Catalogue) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for definition in catalogue.definitions() {
+        check_message(&mut Report { problems: &mut problems, definition });
+    }
+    check_collisions(catalogue, &mut problems);
+    sort_by_location(&mut problems);
+    problems
+}
+
+fn sort_by_location(problems: &mut [Problem]) {
+    problems.sort_by(|a, b| {
+        let key = |p: &Problem| p.location.as_ref().map(|l| (l.file.clone(), l.line));
+        key(a).cmp(&key(b))
+    });
+}
+
+/// Load a configuration directory (or a rebus `.toml` file) and report its loading errors and the problems of its definitions
+pub fn lint_dir(dir: &Path) -> Vec<Problem> {
+    let (catalogue, errors) = if dir.extension().is_some_and(|e| e == "toml") {
+        let (settings, errors) = native::load_with_errors(dir);
+        (settings.catalogue, errors)
+    } else {
+        load_dir_with_errors(dir)
+    };
+    let mut problems: Vec<Problem> = errors.into_iter().map(Problem::from).collect();
+    problems.extend(validate(&catalogue));
+    sort_by_location(&mut problems);
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(name: &str, line: usize) -> MessageDefinition {
+        let mut definition = MessageDefinition::new("bai", name, MessageKind::Read, 0xb5, 0x09);
+        definition.destination = Some(0x08);
+        definition.id = vec![0x0d, 0x18, 0x00];
+        definition.location = Some(Location { file: "08.bai.csv".to_string(), line });
+        definition
+    }
+
+    fn messages(problems: &[Problem]) -> Vec<String> {
+        problems.iter().map(Problem::to_string).collect()
+    }
+
+    #[test]
+    fn valid_catalogue() {
+        let mut flow = definition("FlowTemp", 2);
+        flow.fields.push(FieldDefinition::new("temp", Part::Slave, 0, DataType::Data2c));
+        let catalogue: Catalogue = [flow].into_iter().collect();
+        assert!(validate(&catalogue).is_empty());
+    }
+
+    #[test]
+    fn field_problems() {
+        let mut flow = definition("FlowTemp", 2);
+        let mut temp = FieldDefinition::new("temp", Part::Slave, 0, DataType::Data2c);
+        temp.divider = Some(0.0);
+        flow.fields.push(temp);
+        flow.fields.push(FieldDefinition::new("status", Part::Slave, 1, DataType::Uch));
+        flow.fields.push(FieldDefinition::new("name", Part::Slave, 2, DataType::Str(15)));
+        let mut mode = FieldDefinition::new("mode", Part::Master, 3, DataType::Uch);
+        mode.values = Some(vec![(0, "off".to_string()), (0, "on".to_string())]);
+        flow.fields.push(mode);
+
+        let catalogue: Catalogue = [flow].into_iter().collect();
+        assert_eq!(messages(&validate(&catalogue)), vec![
+            "08.bai.csv:2: error: bai.FlowTemp: field `temp` has a divider of zero",
+            "08.bai.csv:2: error: bai.FlowTemp: field `status` overlaps field `temp`",
+            "08.bai.csv:2: error: bai.FlowTemp: field `name` ends at byte 17 beyond the 16 bytes of the payload",
+            "08.bai.csv:2: error: bai.FlowTemp: field `mode` has the value 0 listed twice",
+        ]);
+    }
+
+    #[test]
+    fn collisions() {
+        let catalogue: Catalogue = [definition("FlowTemp", 2), definition("flowtemp", 3), definition("Other", 4)].into_iter().collect();
+        assert_eq!(messages(&validate(&catalogue)), vec![
+            "08.bai.csv:3: error: bai.flowtemp: the read message name is already used at 08.bai.csv:2",
+            "08.bai.csv:3: error: bai.flowtemp: the ID b5090d1800 is already used by bai.FlowTemp at 08.bai.csv:2",
+            "08.bai.csv:4: error: bai.Other: the ID b5090d1800 is already used by bai.FlowTemp at 08.bai.csv:2",
+        ]);
+    }
+}
@@ -0,0 +1,301 @@
+//! Buses over the `embedded-io` traits, to run the reader and the sender on a microcontroller
+//! UART, blocking or async.
+//!
+//! When the bytes are received by an interrupt handler, it pushes them into a lock-free queue
+//! read by `QueuedReader` in the main loop:
+//!
+//! ```ignore
+//! static mut RX: Queue<u8, 64> = Queue::new();
+//! let (producer, consumer) = unsafe { RX.split() };
+//! // in the interrupt handler
+//! let _ = producer.enqueue(uart.read_byte());
+//! // in the main loop
+//! let mut reader = QueuedReader::new(consumer);
+//! if let Some(event) = reader.poll() { /* ... */ }
+//! ```
+
+use heapless::spsc::Consumer;
+use heapless::Deque;
+
+use crate::layer2::reader::BusReader;
+use crate::layer2::sender::{Action, SendError, Sender};
+use crate::layer2::{BusEvent, Packet};
+
+/// Events read while sending, kept for `next_event`, the oldest being dropped beyond
+pub const EVENT_CAPACITY: usize = 4;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    /// The port has been closed
+    Eof,
+    Send(SendError),
+}
+
+impl<E> From<embedded_io::ReadExactError<E>> for Error<E> {
+    fn from(e: embedded_io::ReadExactError<E>) -> Error<E> {
+        match e {
+            embedded_io::ReadExactError::UnexpectedEof => Error::Eof,
+            embedded_io::ReadExactError::Other(e) => Error::Io(e),
+        }
+    }
+}
+
+/// The reader of the received bytes, with the events read while sending
+struct State {
+    reader: BusReader,
+    events: Deque<BusEvent, EVENT_CAPACITY>,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            reader: BusReader::new(),
+            events: Deque::new(),
+        }
+    }
+
+    fn keep(&mut self, c: u8) {
+        if let Some(event) = self.reader.read_byte(c) {
+            if self.events.is_full() {
+                self.events.pop_front();
+            }
+            let _ = self.events.push_back(event);
+        }
+    }
+}
+
+/// A bus behind a port implementing the blocking `embedded-io` traits
+pub struct EmbeddedBus<P> {
+    port: P,
+    state: State,
+}
+
+impl<P: embedded_io::Read + embedded_io::Write> EmbeddedBus<P> {
+    pub fn new(port: P) -> EmbeddedBus<P> {
+        EmbeddedBus { port, state: State::new() }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error<P::Error>> {
+        let mut byte = [0];
+        self.port.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Wait for the next event of the bus
+    pub fn next_event(&mut self) -> Result<BusEvent, Error<P::Error>> {
+        loop {
+            if let Some(event) = self.state.events.pop_front() {
+                return Ok(event);
+            }
+            let c = self.read_byte()?;
+            if let Some(event) = self.state.reader.read_byte(c) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Send a telegram built by `Packet::request` and return it completed by its response.
+    /// The events read meanwhile, the telegram itself included, are kept for `next_event`.
+    pub fn send(&mut self, request: &Packet) -> Result<Packet, Error<P::Error>> {
+        let mut sender = Sender::new(request);
+        let mut action = sender.start();
+        loop {
+            match action {
+                Action::Wait | Action::Arbitrate(_) => (),
+                Action::Write(c) => {
+                    self.port.write_all(&[c]).map_err(Error::Io)?;
+                    self.port.flush().map_err(Error::Io)?;
+                },
+                Action::Done(result) => return result.map_err(Error::Send),
+            }
+            let c = self.read_byte()?;
+            self.state.keep(c);
+            action = sender.on_byte(c);
+        }
+    }
+}
+
+/// A bus behind a port implementing the async `embedded-io` traits
+pub struct AsyncEmbeddedBus<P> {
+    port: P,
+    state: State,
+}
+
+impl<P: embedded_io_async::Read + embedded_io_async::Write> AsyncEmbeddedBus<P> {
+    pub fn new(port: P) -> AsyncEmbeddedBus<P> {
+        AsyncEmbeddedBus { port, state: State::new() }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    async fn read_byte(&mut self) -> Result<u8, Error<P::Error>> {
+        let mut byte = [0];
+        self.port.read_exact(&mut byte).await?;
+        Ok(byte[0])
+    }
+
+    /// Wait for the next event of the bus
+    pub async fn next_event(&mut self) -> Result<BusEvent, Error<P::Error>> {
+        loop {
+            if let Some(event) = self.state.events.pop_front() {
+                return Ok(event);
+            }
+            let c = self.read_byte().await?;
+            if let Some(event) = self.state.reader.read_byte(c) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Send a telegram built by `Packet::request` and return it completed by its response.
+    /// The events read meanwhile, the telegram itself included, are kept for `next_event`.
+    pub async fn send(&mut self, request: &Packet) -> Result<Packet, Error<P::Error>> {
+        let mut sender = Sender::new(request);
+        let mut action = sender.start();
+        loop {
+            match action {
+                Action::Wait | Action::Arbitrate(_) => (),
+                Action::Write(c) => {
+                    self.port.write_all(&[c]).await.map_err(Error::Io)?;
+                    self.port.flush().await.map_err(Error::Io)?;
+                },
+                Action::Done(result) => return result.map_err(Error::Send),
+            }
+            let c = self.read_byte().await?;
+            self.state.keep(c);
+            action = sender.on_byte(c);
+        }
+    }
+}
+
+/// Reads the bytes pushed by an interrupt handler into a `heapless::spsc::Queue`
+pub struct QueuedReader<'a, const N: usize> {
+    consumer: Consumer<'a, u8, N>,
+    reader: BusReader,
+}
+
+impl<'a, const N: usize> QueuedReader<'a, N> {
+    pub fn new(consumer: Consumer<'a, u8, N>) -> QueuedReader<'a, N> {
+        QueuedReader {
+            consumer,
+            reader: BusReader::new(),
+        }
+    }
+
+    /// Read the queued bytes up to the next event, `None` once the queue is empty
+    pub fn poll(&mut self) -> Option<BusEvent> {
+        while let Some(c) = self.consumer.dequeue() {
+            if let Some(event) = self.reader.read_byte(c) {
+                return Some(event);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer2::encoder::{encode_master, encode_slave};
+    use crate::layer2::{EBUS_ACKOK, EBUS_SYN};
+    #[cfg(feature = "std")]
+    use crate::transport::simulator::SimulatedBus;
+    use heapless::spsc::Queue;
+    use std::thread;
+
+    /// The simulated bus seen as a UART, available with `std` as the transports
+    #[cfg(feature = "std")]
+    mod uart {
+        use core::convert::Infallible;
+
+        use crate::transport::simulator::SimulatedBus;
+        use crate::transport::Transport;
+
+        impl embedded_io::ErrorType for SimulatedBus {
+            type Error = Infallible;
+        }
+
+        impl embedded_io::Read for SimulatedBus {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+                Ok(Transport::read(self, buf).unwrap())
+            }
+        }
+
+        impl embedded_io::Write for SimulatedBus {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+                Transport::write(self, buf).unwrap();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> Result<(), Infallible> {
+                Ok(())
+            }
+        }
+
+        impl embedded_io_async::Read for SimulatedBus {
+            async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+                embedded_io::Read::read(self, buf)
+            }
+        }
+
+        impl embedded_io_async::Write for SimulatedBus {
+            async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+                embedded_io::Write::write(self, buf)
+            }
+        }
+    }
+
+    fn request() -> Packet {
+        Packet::request(0x31, 0xf6, 0x50, 0x22, &[0xec, 0x11, 0x00]).unwrap()
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn blocking_bus_sends_and_reads() {
+        let mut bus = EmbeddedBus::new(SimulatedBus::answering(&request(), &[0xbd, 0x00]));
+        let response = bus.send(&request()).unwrap();
+        assert_eq!(response.slave_payload(), &[0xbd, 0x00]);
+        assert!(matches!(bus.next_event().unwrap(), BusEvent::Telegram(telegram) if telegram.source() == 0x31));
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn async_bus_sends_and_reads() {
+        let mut bus = AsyncEmbeddedBus::new(SimulatedBus::answering(&request(), &[0xbd, 0x00]));
+        let response = bus.send(&request()).await.unwrap();
+        assert!(response.is_slave_crc_valid());
+        assert!(matches!(bus.next_event().await.unwrap(), BusEvent::Telegram(_)));
+    }
+
+    #[test]
+    fn bytes_queued_by_an_interrupt_are_read() {
+        let request = request().with_response(&[0xbd, 0x00]).unwrap();
+        let bytes = [&[EBUS_SYN][..], &encode_master(&request), &[EBUS_ACKOK], &encode_slave(&request), &[EBUS_ACKOK, EBUS_SYN]].concat();
+        let mut queue: Queue<u8, 8> = Queue::new();
+        let (mut producer, consumer) = queue.split();
+        let mut reader = QueuedReader::new(consumer);
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for c in bytes {
+                    while producer.enqueue(c).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            });
+            let event = loop {
+                if let Some(event) = reader.poll() {
+                    break event;
+                }
+            };
+            assert!(matches!(event, BusEvent::Telegram(telegram) if telegram.slave_payload() == [0xbd, 0x00]));
+        });
+    }
+}
@@ -23,6 +23,22 @@ pub fn crc(data: &[u8]) -> u8 {
 }
 
 #[inline]
-pub fn stack_crc(acc: &mut u8, c: u8) -> () {
+pub fn stack_crc(acc: &mut u8, c: u8) {
     *acc = CRC[*acc as usize] ^ c
 }
+
+/// Stack an unescaped byte as it would be transmitted (`0xa9` and `0xaa` are sent as two bytes)
+#[inline]
+pub fn stack_escaped_crc(acc: &mut u8, c: u8) {
+    match c {
+        super::EBUS_ESCAPE => {
+            stack_crc(acc, super::EBUS_ESCAPE);
+            stack_crc(acc, 0x00);
+        },
+        super::EBUS_SYN => {
+            stack_crc(acc, super::EBUS_ESCAPE);
+            stack_crc(acc, 0x01);
+        },
+        c => stack_crc(acc, c),
+    }
+}
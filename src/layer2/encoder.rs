@@ -0,0 +1,73 @@
+use arrayvec::ArrayVec;
+
+use super::*;
+
+/// Maximum number of bytes sent by a master, from QQ to the CRC, when every byte is escaped
+pub const MAX_MASTER_LENGTH: usize = 2 * (6 + MAX_NN);
+/// Maximum number of bytes sent by a slave, from NN to the CRC, when every byte is escaped
+pub const MAX_SLAVE_LENGTH: usize = 2 * (2 + MAX_NN);
+
+/// Push a byte as it is transmitted (`0xa9` and `0xaa` are sent as two bytes)
+#[inline]
+fn push_escaped<const N: usize>(bytes: &mut ArrayVec<u8, N>, c: u8) {
+    match c {
+        EBUS_ESCAPE => bytes.extend([EBUS_ESCAPE, 0x00]),
+        EBUS_SYN => bytes.extend([EBUS_ESCAPE, 0x01]),
+        c => bytes.push(c),
+    }
+}
+
+/// The bytes a master transmits for a telegram, from QQ to the CRC
+pub fn encode_master(packet: &Packet) -> ArrayVec<u8, MAX_MASTER_LENGTH> {
+    let mut bytes = ArrayVec::new();
+    let header = [packet.source, packet.destination, packet.primary, packet.secondary, packet.master_payload_length];
+    for c in header.iter().chain(packet.master_payload.iter()) {
+        push_escaped(&mut bytes, *c);
+    }
+    push_escaped(&mut bytes, packet.master_crc);
+    bytes
+}
+
+/// The bytes a slave transmits in response to a telegram, from NN to the CRC
+pub fn encode_slave(packet: &Packet) -> ArrayVec<u8, MAX_SLAVE_LENGTH> {
+    let mut bytes = ArrayVec::new();
+    for c in [packet.slave_payload_length].iter().chain(packet.slave_payload.iter()) {
+        push_escaped(&mut bytes, *c);
+    }
+    push_escaped(&mut bytes, packet.slave_crc);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::reader::BusReader;
+
+    #[test]
+    fn encoded_telegram_is_read_back() {
+        // >31f6502203a9aaf3<02a900xx>00
+        let packet = Packet::request(0x31, 0xf6, 0x50, 0x22, &[EBUS_ESCAPE, EBUS_SYN, 0xf3]).unwrap()
+            .with_response(&[EBUS_ESCAPE, 0x00]).unwrap();
+
+        let master = encode_master(&packet);
+        assert_eq!(&master[..9], &[0x31, 0xf6, 0x50, 0x22, 0x03, 0xa9, 0x00, 0xa9, 0x01]);
+
+        let slave = encode_slave(&packet);
+        let mut reader = BusReader::new();
+        let bytes = [EBUS_SYN].iter()
+            .chain(master.iter())
+            .chain([EBUS_ACKOK].iter())
+            .chain(slave.iter())
+            .chain([EBUS_ACKOK].iter());
+        let events: Vec<BusEvent> = bytes.filter_map(|c| reader.read_byte(*c)).collect();
+
+        match events.as_slice() {
+            [BusEvent::Telegram(read)] => {
+                assert!(read.is_master_crc_valid() && read.is_slave_crc_valid());
+                assert_eq!(read.master_payload(), packet.master_payload());
+                assert_eq!(read.slave_payload(), packet.slave_payload());
+            },
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+}
@@ -1,9 +1,13 @@
 pub mod crc;
+pub mod encoder;
 pub mod reader;
+pub mod sender;
+#[cfg(feature = "serde")]
+mod serialization;
 
-#[cfg(any(test, not(no_std)))]
-use std::{fmt,format};
+use core::fmt;
 use arrayvec::ArrayVec;
+use crc::stack_escaped_crc;
 
 /// Maximum value for the NN part of a telegram
 pub const MAX_NN:usize = 16;
@@ -28,6 +32,12 @@ pub struct Packet {
     slave_crc: u8,
 }
 
+impl Default for Packet {
+    fn default() -> Self {
+        Packet::new()
+    }
+}
+
 impl Packet {
     pub fn new() -> Packet {
         Packet {
@@ -45,36 +55,109 @@ impl Packet {
             computed_slave_crc: 0,
         }
     }
+
+    /// Build a master telegram from its unescaped parts, the CRC is computed over the escaped form.
+    /// Return `None` when the payload exceeds `MAX_NN`.
+    pub fn request(source: u8, destination: u8, primary: u8, secondary: u8, payload: &[u8]) -> Option<Packet> {
+        let mut packet = Packet::new();
+        packet.source = source;
+        packet.destination = destination;
+        packet.primary = primary;
+        packet.secondary = secondary;
+        packet.master_payload.try_extend_from_slice(payload).ok()?;
+        packet.master_payload_length = payload.len() as u8;
+
+        let mut crc = 0;
+        for c in [source, destination, primary, secondary, packet.master_payload_length].iter().chain(payload) {
+            stack_escaped_crc(&mut crc, *c);
+        }
+        packet.computed_master_crc = crc;
+        packet.master_crc = crc;
+        Some(packet)
+    }
+
+    /// Attach a slave response to a master telegram.
+    /// Return `None` when the payload exceeds `MAX_NN`.
+    pub fn with_response(mut self, payload: &[u8]) -> Option<Packet> {
+        self.slave_payload.clear();
+        self.slave_payload.try_extend_from_slice(payload).ok()?;
+        self.slave_payload_length = payload.len() as u8;
+
+        let mut crc = 0;
+        for c in [self.slave_payload_length].iter().chain(payload) {
+            stack_escaped_crc(&mut crc, *c);
+        }
+        self.computed_slave_crc = crc;
+        self.slave_crc = crc;
+        Some(self)
+    }
+
+    /// QQ: the master which initiated the telegram
+    pub fn source(&self) -> u8 {
+        self.source
+    }
+
+    /// ZZ: the addressee of the telegram
+    pub fn destination(&self) -> u8 {
+        self.destination
+    }
+
+    /// PB: the primary command byte
+    pub fn primary(&self) -> u8 {
+        self.primary
+    }
+
+    /// SB: the secondary command byte
+    pub fn secondary(&self) -> u8 {
+        self.secondary
+    }
+
+    /// The unescaped data bytes sent by the master
+    pub fn master_payload(&self) -> &[u8] {
+        &self.master_payload
+    }
+
+    /// The unescaped data bytes answered by the slave, empty for master-master and broadcast telegrams
+    pub fn slave_payload(&self) -> &[u8] {
+        &self.slave_payload
+    }
+
+    pub fn is_master_crc_valid(&self) -> bool {
+        self.master_crc == self.computed_master_crc
+    }
+
+    pub fn is_slave_crc_valid(&self) -> bool {
+        self.slave_crc == self.computed_slave_crc
+    }
 }
 
-#[cfg(any(test, not(no_std)))]
 impl fmt::Debug for Packet {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.slave_payload_length > 0 {
             f.debug_struct("Packet")
-                .field("source", &format!("{:>#04x}", &self.source))
-                .field("destination", &format!("{:>#04x}", &self.destination))
-                .field("primary", &format!("{:>#04x}", &self.primary))
-                .field("secondary", &format!("{:>#04x}", &self.secondary))
+                .field("source", &format_args!("{:>#04x}", &self.source))
+                .field("destination", &format_args!("{:>#04x}", &self.destination))
+                .field("primary", &format_args!("{:>#04x}", &self.primary))
+                .field("secondary", &format_args!("{:>#04x}", &self.secondary))
                 .field("master_payload_length", &self.master_payload_length)
-                .field("master_payload", &format!("{:?}", &self.master_payload))
-                .field("computed_master_crc", &format!("{:>#04x}", &self.computed_master_crc))
-                .field("crc", &format!("{:>#04x}", &self.master_crc))
+                .field("master_payload", &format_args!("{:?}", &self.master_payload))
+                .field("computed_master_crc", &format_args!("{:>#04x}", &self.computed_master_crc))
+                .field("crc", &format_args!("{:>#04x}", &self.master_crc))
                 .field("slave_payload_length", &self.slave_payload_length)
-                .field("slave_payload", &format!("{:?}", &self.slave_payload))
-                .field("computed_slave_crc", &format!("{:>#04x}", &self.computed_slave_crc))
-                .field("crc_slave", &format!("{:>#04x}", &self.slave_crc))
+                .field("slave_payload", &format_args!("{:?}", &self.slave_payload))
+                .field("computed_slave_crc", &format_args!("{:>#04x}", &self.computed_slave_crc))
+                .field("crc_slave", &format_args!("{:>#04x}", &self.slave_crc))
                 .finish()
         } else {
             f.debug_struct("Packet")
-                .field("source", &format!("{:>#04x}", &self.source))
-                .field("destination", &format!("{:>#04x}", &self.destination))
-                .field("primary", &format!("{:>#04x}", &self.primary))
-                .field("secondary", &format!("{:>#04x}", &self.secondary))
+                .field("source", &format_args!("{:>#04x}", &self.source))
+                .field("destination", &format_args!("{:>#04x}", &self.destination))
+                .field("primary", &format_args!("{:>#04x}", &self.primary))
+                .field("secondary", &format_args!("{:>#04x}", &self.secondary))
                 .field("master_payload_length", &self.master_payload_length)
-                .field("master_payload", &format!("{:?}", &self.master_payload))
-                .field("computed_master_crc", &format!("{:>#04x}", &self.computed_master_crc))
-                .field("crc", &format!("{:>#04x}", &self.master_crc))
+                .field("master_payload", &format_args!("{:?}", &self.master_payload))
+                .field("computed_master_crc", &format_args!("{:>#04x}", &self.computed_master_crc))
+                .field("crc", &format_args!("{:>#04x}", &self.master_crc))
                 .finish()
         }
     }
@@ -87,9 +170,10 @@ const MASTER_NIBBLES: [Nibble; 5] = [0x00, 0x01, 0x03, 0x07, 0x0F];
 
 
 #[derive(Clone,Copy,PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AddressClass {
-    Master(Nibble),
-    MasterSlave(u8),
+    Master(#[cfg_attr(feature = "serde", serde(with = "serialization::priority"))] Nibble),
+    MasterSlave(#[cfg_attr(feature = "serde", serde(with = "serialization::hex_byte"))] u8),
     Slave,
     Broadcast,
     Invalid
@@ -106,30 +190,29 @@ impl AddressClass {
         }
 
         let addr: Nibble = c >> 4; // (c & 0xF0) >> 4;
-        if let None = MASTER_NIBBLES.iter().position(|&n| n == addr) {
-            return match AddressClass::of(c - 5) {
-                AddressClass::Master(_) => AddressClass::MasterSlave(c - 5),
+        if !MASTER_NIBBLES.contains(&addr) {
+            return match c.checked_sub(5).map(AddressClass::of) {
+                Some(AddressClass::Master(_)) => AddressClass::MasterSlave(c - 5),
                 _ => AddressClass::Slave
             };
         }
 
         let priority: Nibble = c & 0x0F;
-        return match MASTER_NIBBLES.iter().position(|&n| n == priority) {
+        match MASTER_NIBBLES.iter().position(|&n| n == priority) {
             Some(p) => AddressClass::Master(p.try_into().unwrap()),
-            None => match AddressClass::of(c - 5) {
-                AddressClass::Master(_) => AddressClass::MasterSlave(c - 5),
+            None => match c.checked_sub(5).map(AddressClass::of) {
+                Some(AddressClass::Master(_)) => AddressClass::MasterSlave(c - 5),
                 _ => AddressClass::Slave
             }
         }
     }
 }
 
-#[cfg(any(test, not(no_std)))]
 impl fmt::Debug for AddressClass {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            AddressClass::Master(priority) => f.debug_struct("Master").field("priority", &format!("{:#02x}", &priority)).finish(),
-            AddressClass::MasterSlave(master_addr) => f.debug_struct("MasterSlave").field("master", &format!("{:#02x}", &master_addr)).finish(),
+            AddressClass::Master(priority) => f.debug_struct("Master").field("priority", &format_args!("{:#02x}", &priority)).finish(),
+            AddressClass::MasterSlave(master_addr) => f.debug_struct("MasterSlave").field("master", &format_args!("{:#02x}", &master_addr)).finish(),
             AddressClass::Slave => f.debug_struct("Slave").finish(),
             AddressClass::Broadcast => f.debug_struct("Broadcast").finish(),
             AddressClass::Invalid => f.debug_struct("Invalid").finish(),
@@ -138,8 +221,8 @@ impl fmt::Debug for AddressClass {
 }
 
 
-#[cfg_attr(any(test, not(no_std)), derive(Debug))]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TelegramComponent {
     SYN,
     Source,
@@ -160,3 +243,83 @@ pub enum TelegramComponent {
     SlaveCRC,
     MasterACK,
 }
+
+/// What the reader reports at the end of a telegram
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BusEvent {
+    /// A telegram read up to its end, its CRCs may still be wrong
+    Telegram(Packet),
+    /// A telegram refused by its addressee (`SlaveACK`) or by its master (`MasterACK`)
+    Nack(Packet, TelegramComponent),
+    /// A telegram interrupted by a SYN, as nobody sent the awaited component in time
+    Timeout(TelegramComponent),
+    /// A telegram interrupted by a byte which cannot be the awaited component
+    Unexpected(TelegramComponent, #[cfg_attr(feature = "serde", serde(with = "serialization::hex_byte"))] u8),
+    /// The transport lost its connection to the bus, the telegram being read is dropped
+    ConnectionLost,
+    /// The transport is connected again
+    ConnectionRestored,
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    bytes.iter().try_for_each(|c| write!(f, "{:02x}", c))
+}
+
+/// A telegram as exchanged, unescaped: `>` before what the master sends, `<` before what the addressee sends.
+/// The exchange stops at the first NACK when `refused_at` is set.
+fn write_exchange(f: &mut fmt::Formatter<'_>, packet: &Packet, refused_at: Option<TelegramComponent>) -> fmt::Result {
+    write!(f, ">")?;
+    write_hex(f, &[packet.source, packet.destination, packet.primary, packet.secondary, packet.master_payload_length])?;
+    write_hex(f, &packet.master_payload)?;
+    write_hex(f, &[packet.master_crc])?;
+
+    match AddressClass::of(packet.destination) {
+        AddressClass::Broadcast | AddressClass::Invalid => Ok(()),
+        _ if refused_at == Some(TelegramComponent::SlaveACK) => write!(f, "<{:02x}", EBUS_ACKKO),
+        AddressClass::Master(_) => write!(f, "<{:02x}", EBUS_ACKOK),
+        _ => {
+            write!(f, "<{:02x}", EBUS_ACKOK)?;
+            write_hex(f, &[packet.slave_payload_length])?;
+            write_hex(f, &packet.slave_payload)?;
+            write_hex(f, &[packet.slave_crc])?;
+            write!(f, ">{:02x}", if refused_at.is_some() { EBUS_ACKKO } else { EBUS_ACKOK })
+        },
+    }
+}
+
+/// Telegrams in the `>31f6502203ec110087<0002bd0032>00` notation, the other events in words
+impl fmt::Display for BusEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusEvent::Telegram(packet) => write_exchange(f, packet, None),
+            BusEvent::Nack(packet, component) => write_exchange(f, packet, Some(*component)),
+            BusEvent::Timeout(component) => write!(f, "timeout waiting for {:?}", component),
+            BusEvent::Unexpected(component, c) => write!(f, "unexpected {:02x} instead of {:?}", c, component),
+            BusEvent::ConnectionLost => write!(f, "connection lost"),
+            BusEvent::ConnectionRestored => write!(f, "connection restored"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_displayed_as_exchanged() {
+        let packet = Packet::request(0x31, 0xf6, 0x50, 0x22, &[0xec, 0x11, 0x00]).unwrap()
+            .with_response(&[0xbd, 0x00]).unwrap();
+        assert_eq!(BusEvent::Telegram(packet.clone()).to_string(), ">31f6502203ec110087<0002bd0032>00");
+        assert_eq!(BusEvent::Nack(packet.clone(), TelegramComponent::MasterACK).to_string(), ">31f6502203ec110087<0002bd0032>ff");
+        assert_eq!(BusEvent::Nack(packet, TelegramComponent::SlaveACK).to_string(), ">31f6502203ec110087<ff");
+
+        let broadcast = Packet::request(0x10, 0xfe, 0x07, 0x00, &[]).unwrap();
+        assert_eq!(BusEvent::Telegram(broadcast).to_string(), ">10fe07000013");
+        let master = Packet::request(0x10, 0x31, 0x07, 0x00, &[]).unwrap();
+        assert!(BusEvent::Telegram(master).to_string().ends_with("<00"));
+
+        assert_eq!(BusEvent::Timeout(TelegramComponent::SlaveACK).to_string(), "timeout waiting for SlaveACK");
+        assert_eq!(BusEvent::Unexpected(TelegramComponent::Source, 0x02).to_string(), "unexpected 02 instead of Source");
+    }
+}
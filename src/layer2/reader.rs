@@ -10,92 +10,143 @@ fn escape(c: u8) -> Option<u8> {
     }
 }
 
-#[cfg_attr(any(test, not(no_std)), derive(Debug))]
+#[derive(Debug)]
 pub struct BusReader {
     waiting_for: TelegramComponent,
     packet_buffer: Packet,
+    /// The master part has been refused once, the master repeats it
+    master_repeated: bool,
+    /// The slave part has been refused once, the slave repeats it
+    slave_repeated: bool,
+}
+
+impl Default for BusReader {
+    fn default() -> Self {
+        BusReader::new()
+    }
 }
 
 impl BusReader {
     pub fn new() -> BusReader {
         BusReader {
             packet_buffer: Packet::new(),
-            waiting_for: TelegramComponent::SYN
+            waiting_for: TelegramComponent::SYN,
+            master_repeated: false,
+            slave_repeated: false,
         }
     }
 
     /// Drop the buffer and setup the reader for SYN-await
     fn reset(&mut self) {
         self.waiting_for = TelegramComponent::SYN;
+        self.master_repeated = false;
+        self.slave_repeated = false;
     }
 
     /// Similare to `BusReader.reset()` but setup the reader for the first byte of a telegram (the source address)
-    fn on_unexcepted_syn(&mut self) {
+    fn on_unexcepted_syn(&mut self) -> Option<BusEvent> {
+        let component = self.waiting_for;
         self.reset();
         self.waiting_for = TelegramComponent::Source;
+        Some(BusEvent::Timeout(component))
+    }
+
+    fn on_unexcepted_byte(&mut self, received: u8) -> Option<BusEvent> {
+        let component = self.waiting_for;
+        self.reset();
+        Some(BusEvent::Unexpected(component, received))
     }
 
-    fn on_unexcepted_byte(&mut self) {
+    /// The telegram has been read up to its end
+    fn on_complete(&mut self) -> Option<BusEvent> {
         self.reset();
+        Some(BusEvent::Telegram(self.packet_buffer.clone()))
     }
 
-    fn on_master_crc(&mut self, crc: u8) {
+    /// A part has been refused, it is repeated once before the telegram is abandoned
+    fn on_nack(&mut self) -> Option<BusEvent> {
+        let component = self.waiting_for;
+        let repeated = match component {
+            TelegramComponent::SlaveACK => &mut self.master_repeated,
+            _ => &mut self.slave_repeated,
+        };
+
+        if *repeated {
+            self.reset();
+        } else {
+            *repeated = true;
+            self.waiting_for = match component {
+                TelegramComponent::SlaveACK => TelegramComponent::Source,
+                _ => TelegramComponent::SlavePayloadLength,
+            };
+        }
+        Some(BusEvent::Nack(self.packet_buffer.clone(), component))
+    }
+
+    fn on_master_crc(&mut self, crc: u8) -> Option<BusEvent> {
         self.packet_buffer.master_crc = crc;
 
         match AddressClass::of(self.packet_buffer.destination) {
             AddressClass::Invalid => panic!("the reader state should never go futher on an protocole anomaly"),
-            AddressClass::Broadcast => {
-                self.waiting_for = TelegramComponent::SYN;
-                self.reset();
-            },
+            AddressClass::Broadcast => self.on_complete(),
             _ => {
                 self.waiting_for = TelegramComponent::SlaveACK;
+                None
             },
         }
     }
 
-    fn on_slave_crc(&mut self, crc: u8) {
+    fn on_slave_crc(&mut self, crc: u8) -> Option<BusEvent> {
         self.packet_buffer.slave_crc = crc;
         self.waiting_for = TelegramComponent::MasterACK;
+        None
     }
 
-    pub fn read_byte(&mut self, received: u8) {
-        if received == EBUS_SYN && match self.waiting_for {
-            TelegramComponent::SYN | TelegramComponent::Source => false,
-            _ => true
-        }{
-            self.on_unexcepted_syn();
-            return;
+    /// Component awaited by the reader
+    pub fn waiting_for(&self) -> TelegramComponent {
+        self.waiting_for
+    }
+
+    /// Feed the next byte of the bus, an event is returned when it ends a telegram
+    pub fn read_byte(&mut self, received: u8) -> Option<BusEvent> {
+        if received == EBUS_SYN && !matches!(self.waiting_for, TelegramComponent::SYN | TelegramComponent::Source) {
+            return self.on_unexcepted_syn();
         };
 
         match self.waiting_for {
             TelegramComponent::SYN => {
-                match received {
-                    EBUS_SYN => self.waiting_for = TelegramComponent::Source,
-                    _ => ()
+                if received == EBUS_SYN {
+                    self.waiting_for = TelegramComponent::Source;
                 }
+                None
             },
             TelegramComponent::Source => {
-                self.packet_buffer.source = received;
                 let addr = AddressClass::of(received);
                 match addr {
                     AddressClass::Master(_) => {
-                        self.packet_buffer.computed_master_crc = 0x00;
+                        // a broadcast or master-master telegram has no slave part, none is kept
+                        // from the previous telegram. `master_repeated` is left to `reset`, the
+                        // master repeating its part from the source right after a NACK.
+                        self.packet_buffer = Packet::new();
+                        self.packet_buffer.source = received;
+                        self.slave_repeated = false;
                         stack_crc(&mut self.packet_buffer.computed_master_crc, received);
                         self.waiting_for = TelegramComponent::Destination;
+                        None
                     },
-                    AddressClass::Invalid if received == EBUS_SYN => (),
-                    _ => self.on_unexcepted_byte()
+                    AddressClass::Invalid if received == EBUS_SYN => None,
+                    _ => self.on_unexcepted_byte(received)
                 }
             },
             TelegramComponent::Destination => {
                 self.packet_buffer.destination = received;
                 let addr = AddressClass::of(received);
                 match addr {
-                    AddressClass::Invalid => self.on_unexcepted_byte(),
+                    AddressClass::Invalid => self.on_unexcepted_byte(received),
                     _ => {
                         stack_crc(&mut self.packet_buffer.computed_master_crc, received);
                         self.waiting_for = TelegramComponent::Primary;
+                        None
                     }
                 }
             },
@@ -103,11 +154,13 @@ impl BusReader {
                 self.packet_buffer.primary = received;
                 stack_crc(&mut self.packet_buffer.computed_master_crc, received);
                 self.waiting_for = TelegramComponent::Secondary;
+                None
             },
             TelegramComponent::Secondary => {
                 self.packet_buffer.secondary = received;
                 stack_crc(&mut self.packet_buffer.computed_master_crc, received);
                 self.waiting_for = TelegramComponent::MasterPayloadLength;
+                None
             },
             TelegramComponent::MasterPayloadLength => {
                 self.packet_buffer.master_payload_length = received;
@@ -116,9 +169,10 @@ impl BusReader {
 
                 match received {
                     0 => self.waiting_for = TelegramComponent::MasterCRC,
-                    b if b as usize > MAX_NN => self.on_unexcepted_byte(),
+                    b if b as usize > MAX_NN => return self.on_unexcepted_byte(received),
                     _ => self.waiting_for = TelegramComponent::MasterPayload
                 }
+                None
             },
             TelegramComponent::MasterPayload => {
                 stack_crc(&mut self.packet_buffer.computed_master_crc, received);
@@ -138,13 +192,11 @@ impl BusReader {
                         };
                     }
                 }
+                None
             },
             TelegramComponent::MasterEscapedPayload => {
                 let escaped = match escape(received) {
-                    None => {
-                        self.on_unexcepted_byte();
-                        return;
-                    },
+                    None => return self.on_unexcepted_byte(received),
                     Some(p) => p
                 };
 
@@ -163,33 +215,33 @@ impl BusReader {
                         };
                     }
                 }
+                None
             },
             TelegramComponent::MasterCRC => {
                 match received {
-                    EBUS_ESCAPE => self.waiting_for = TelegramComponent::MasterEscapedCRC,
+                    EBUS_ESCAPE => {
+                        self.waiting_for = TelegramComponent::MasterEscapedCRC;
+                        None
+                    },
                     crc => self.on_master_crc(crc),
-                };
+                }
             },
             TelegramComponent::MasterEscapedCRC => {
                 match escape(received) {
-                    None => {
-                        self.on_unexcepted_byte();
-                        return;
-                    },
+                    None => self.on_unexcepted_byte(received),
                     Some(crc) => self.on_master_crc(crc),
-                };
+                }
             },
             TelegramComponent::SlaveACK => {
                 match (received, AddressClass::of(self.packet_buffer.destination)) {
                     (_, AddressClass::Invalid) | (_, AddressClass::Broadcast) => panic!("Illegal state"),
-                    (EBUS_ACKOK, AddressClass::Master(_)) => {
-                        self.reset();
-                    },
+                    (EBUS_ACKOK, AddressClass::Master(_)) => self.on_complete(),
                     (EBUS_ACKOK, AddressClass::Slave) | (EBUS_ACKOK, AddressClass::MasterSlave(_))=> {
-                        self.waiting_for = TelegramComponent::SlavePayloadLength
+                        self.waiting_for = TelegramComponent::SlavePayloadLength;
+                        None
                     },
-                    (EBUS_ACKKO, _) => self.reset(),
-                    (_, _) => self.on_unexcepted_byte(),
+                    (EBUS_ACKKO, _) => self.on_nack(),
+                    (_, _) => self.on_unexcepted_byte(received),
                 }
             },
             TelegramComponent::SlavePayloadLength => {
@@ -200,9 +252,10 @@ impl BusReader {
 
                 match received {
                     0 => self.waiting_for = TelegramComponent::SlaveCRC,
-                    b if b as usize > MAX_NN => self.on_unexcepted_byte(),
+                    b if b as usize > MAX_NN => return self.on_unexcepted_byte(received),
                     _ => self.waiting_for = TelegramComponent::SlavePayload,
                 }
+                None
             },
             TelegramComponent::SlavePayload => {
                 stack_crc(&mut self.packet_buffer.computed_slave_crc, received);
@@ -222,13 +275,11 @@ impl BusReader {
                         };
                     }
                 }
+                None
             },
             TelegramComponent::SlaveEscapedPayload => {
                 let escaped = match escape(received) {
-                    None => {
-                        self.on_unexcepted_byte();
-                        return;
-                    },
+                    None => return self.on_unexcepted_byte(received),
                     Some(p) => p
                 };
 
@@ -247,32 +298,31 @@ impl BusReader {
                         };
                     }
                 }
+                None
             },
             TelegramComponent::SlaveCRC => {
                 match received {
-                    EBUS_ESCAPE => self.waiting_for = TelegramComponent::SlaveEscapedCRC,
+                    EBUS_ESCAPE => {
+                        self.waiting_for = TelegramComponent::SlaveEscapedCRC;
+                        None
+                    },
                     crc => self.on_slave_crc(crc),
-                };
+                }
             },
             TelegramComponent::SlaveEscapedCRC => {
                 match escape(received) {
-                    None => {
-                        self.on_unexcepted_byte();
-                        return;
-                    },
+                    None => self.on_unexcepted_byte(received),
                     Some(crc) => self.on_slave_crc(crc),
-                };
+                }
             },
             TelegramComponent::MasterACK => {
                 match received {
-                    EBUS_ACKOK => {
-                        self.reset();
-                    },
-                    EBUS_ACKKO => self.reset(),
-                    _ => self.on_unexcepted_byte(),
+                    EBUS_ACKOK => self.on_complete(),
+                    EBUS_ACKKO => self.on_nack(),
+                    _ => self.on_unexcepted_byte(received),
                 }
             },
-        };
+        }
     }
 }
 
@@ -297,6 +347,7 @@ mod tests {
     fn slave_addr_is_recognized() {
         let c = AddressClass::of(0x20);
         assert_eq!(c, AddressClass::Slave);
+        assert_eq!(AddressClass::of(0x02), AddressClass::Slave);
     }
 
     #[test]
@@ -486,4 +537,53 @@ mod tests {
 
         assert_eq!(bus_reader.waiting_for, TelegramComponent::SYN);
     }
+
+    #[test]
+    fn busreader_forgets_the_slave_part_of_the_previous_telegram() {
+        let exchange = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d]).unwrap().with_response(&[0x01, 0x02]).unwrap();
+        let broadcast = Packet::request(0x10, 0xfe, 0x07, 0x00, &[]).unwrap();
+        let bytes: Vec<u8> = [EBUS_SYN].into_iter()
+            .chain(encoder::encode_master(&exchange))
+            .chain([EBUS_ACKOK])
+            .chain(encoder::encode_slave(&exchange))
+            .chain([EBUS_ACKOK, EBUS_SYN])
+            .chain(encoder::encode_master(&broadcast))
+            .chain([EBUS_SYN])
+            .collect();
+
+        let mut bus_reader = BusReader::new();
+        let telegrams: Vec<Packet> = bytes.iter()
+            .filter_map(|c| match bus_reader.read_byte(*c) {
+                Some(BusEvent::Telegram(packet)) => Some(packet),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(telegrams.len(), 2);
+        assert_eq!(telegrams[0].slave_payload(), &[0x01, 0x02]);
+        assert!(telegrams[1].slave_payload().is_empty());
+        assert_eq!((telegrams[1].slave_crc, telegrams[1].computed_slave_crc), (0, 0));
+        assert!(telegrams[1].is_master_crc_valid());
+    }
+
+    #[test]
+    fn busreader_reports_repeated_nack_and_timeout() {
+        let request = Packet::request(0x31, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap();
+        let master = [0x31, 0x08, 0xb5, 0x09, 0x03, 0x0d, 0x18, 0x00, request.master_crc];
+        let mut bus_reader = BusReader::new();
+
+        bus_reader.read_byte(EBUS_SYN);
+        assert!(master.iter().all(|c| bus_reader.read_byte(*c).is_none()));
+        match bus_reader.read_byte(EBUS_ACKKO) {
+            Some(BusEvent::Nack(packet, TelegramComponent::SlaveACK)) => assert_eq!(packet.destination(), 0x08),
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        // the master repeats its part right after the NACK
+        assert!(master.iter().all(|c| bus_reader.read_byte(*c).is_none()));
+        assert!(bus_reader.read_byte(EBUS_ACKOK).is_none());
+        assert_eq!(bus_reader.waiting_for, TelegramComponent::SlavePayloadLength);
+
+        assert!(matches!(bus_reader.read_byte(EBUS_SYN), Some(BusEvent::Timeout(TelegramComponent::SlavePayloadLength))));
+        assert!(matches!(bus_reader.read_byte(0x50), Some(BusEvent::Unexpected(TelegramComponent::Source, 0x50))));
+    }
 }
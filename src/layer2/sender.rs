@@ -0,0 +1,436 @@
+//! Send state machine of a master on a raw bus: every byte written comes back as an echo, which
+//! is how the arbitration is won or lost and how collisions are detected.
+//! It does no I/O, the transport feeds it every received byte and writes what it asks for.
+//! Adapters speaking the enhanced protocol arbitrate by themselves, the sender then only asks
+//! them to and is told the winner.
+
+use core::fmt;
+use arrayvec::ArrayVec;
+
+use super::*;
+use super::crc::stack_crc;
+use super::encoder::{encode_master, MAX_MASTER_LENGTH};
+
+/// Number of SYN a master competes for before giving up
+pub const ARBITRATION_ATTEMPTS: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SendError {
+    /// Another master kept winning the arbitration
+    ArbitrationLost,
+    /// A byte read back differs from the byte written
+    Collision,
+    /// The addressee refused the telegram twice
+    Nack,
+    /// The addressee did not answer before the bus was released
+    Timeout,
+    /// The response was corrupted twice or is malformed
+    InvalidResponse,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SendError::ArbitrationLost => "arbitration lost",
+            SendError::Collision => "collision",
+            SendError::Nack => "refused by the addressee",
+            SendError::Timeout => "no answer from the addressee",
+            SendError::InvalidResponse => "invalid response",
+        })
+    }
+}
+
+/// What the transport has to do after a byte has been received
+#[derive(Debug)]
+pub enum Action {
+    /// Nothing to send yet
+    Wait,
+    /// Write a byte to the bus, its echo is the next byte expected
+    Write(u8),
+    /// Ask the adapter to win the arbitration at the next SYN with this master address
+    Arbitrate(u8),
+    /// The exchange is over, with the telegram completed by its response
+    Done(Result<Packet, SendError>),
+}
+
+#[derive(Debug)]
+enum State {
+    Syn,
+    Arbitration,
+    /// Waiting for the echo of the master byte at this index
+    Master(usize),
+    SlaveAck,
+    SlaveLength,
+    SlavePayload,
+    SlaveEscapedPayload,
+    SlaveCrc,
+    SlaveEscapedCrc,
+    /// Waiting for the echo of our positive (`true`) or negative acknowledge
+    MasterAck(bool),
+    /// Waiting for the echo of the SYN releasing the bus
+    Release(Result<Packet, SendError>),
+    Finished,
+}
+
+#[derive(Debug)]
+pub struct Sender {
+    request: Packet,
+    bytes: ArrayVec<u8, MAX_MASTER_LENGTH>,
+    state: State,
+    attempts: u8,
+    /// The adapter arbitrates, see `Sender::delegating_arbitration`
+    delegated: bool,
+    master_repeated: bool,
+    slave_repeated: bool,
+    response: Packet,
+}
+
+impl Sender {
+    /// Prepare to send a telegram built by `Packet::request`, the exchange starts at the next SYN
+    pub fn new(request: &Packet) -> Sender {
+        Sender {
+            request: request.clone(),
+            bytes: encode_master(request),
+            state: State::Syn,
+            attempts: 0,
+            delegated: false,
+            master_repeated: false,
+            slave_repeated: false,
+            response: request.clone(),
+        }
+    }
+
+    /// Prepare to send a telegram through an adapter arbitrating by itself.
+    /// The result of the arbitration has to be given to `Sender::on_arbitration`.
+    pub fn delegating_arbitration(request: &Packet) -> Sender {
+        Sender {
+            delegated: true,
+            ..Sender::new(request)
+        }
+    }
+
+    /// What to do before any byte is received: arbitration is asked right away to an adapter
+    pub fn start(&mut self) -> Action {
+        if self.delegated {
+            self.arbitrate()
+        } else {
+            Action::Wait
+        }
+    }
+
+    fn arbitrate(&mut self) -> Action {
+        self.attempts += 1;
+        self.state = State::Arbitration;
+        Action::Arbitrate(self.bytes[0])
+    }
+
+    /// The adapter reports the master which won the arbitration, its address being on the bus
+    pub fn on_arbitration(&mut self, winner: u8) -> Action {
+        match self.state {
+            State::Arbitration if winner == self.bytes[0] => {
+                self.state = State::Master(1);
+                Action::Write(self.bytes[1])
+            },
+            State::Arbitration if self.attempts < ARBITRATION_ATTEMPTS => self.arbitrate(),
+            State::Arbitration => self.finish(Err(SendError::ArbitrationLost)),
+            _ => Action::Wait,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Finished)
+    }
+
+    /// Release the bus with a SYN, the result is reported on its echo
+    fn release(&mut self, result: Result<Packet, SendError>) -> Action {
+        self.state = State::Release(result);
+        Action::Write(EBUS_SYN)
+    }
+
+    fn finish(&mut self, result: Result<Packet, SendError>) -> Action {
+        self.state = State::Finished;
+        Action::Done(result)
+    }
+
+    fn acknowledge(&mut self, positive: bool) -> Action {
+        self.state = State::MasterAck(positive);
+        Action::Write(if positive { EBUS_ACKOK } else { EBUS_ACKKO })
+    }
+
+    fn on_slave_payload(&mut self, c: u8) {
+        self.response.slave_payload.push(c);
+        self.state = if self.response.slave_payload.len() == self.response.slave_payload_length as usize {
+            State::SlaveCrc
+        } else {
+            State::SlavePayload
+        };
+    }
+
+    fn on_slave_crc(&mut self, crc: u8) -> Action {
+        self.response.slave_crc = crc;
+        let valid = self.response.is_slave_crc_valid();
+        self.acknowledge(valid)
+    }
+
+    /// Feed a byte received from the bus, our own echoes included
+    pub fn on_byte(&mut self, received: u8) -> Action {
+        let destination = AddressClass::of(self.request.destination);
+        let awaits_answer = !matches!(self.state, State::Syn | State::Release(_) | State::Finished);
+        let arbitrating = self.delegated && matches!(self.state, State::Arbitration);
+        if received == EBUS_SYN && awaits_answer && !arbitrating {
+            return self.finish(Err(SendError::Timeout));
+        }
+
+        match self.state {
+            // the traffic of the others while the adapter waits for the bus
+            State::Arbitration if self.delegated => Action::Wait,
+            State::Syn if received == EBUS_SYN => {
+                self.attempts += 1;
+                self.state = State::Arbitration;
+                Action::Write(self.bytes[0])
+            },
+            State::Syn => Action::Wait,
+            State::Arbitration if received == self.bytes[0] => {
+                self.state = State::Master(1);
+                Action::Write(self.bytes[1])
+            },
+            State::Arbitration if self.attempts < ARBITRATION_ATTEMPTS => {
+                self.state = State::Syn;
+                Action::Wait
+            },
+            State::Arbitration => self.finish(Err(SendError::ArbitrationLost)),
+            State::Master(i) if received != self.bytes[i] => self.finish(Err(SendError::Collision)),
+            State::Master(i) if i + 1 < self.bytes.len() => {
+                self.state = State::Master(i + 1);
+                Action::Write(self.bytes[i + 1])
+            },
+            State::Master(_) => match destination {
+                AddressClass::Broadcast => self.release(Ok(self.request.clone())),
+                _ => {
+                    self.state = State::SlaveAck;
+                    Action::Wait
+                },
+            },
+            State::SlaveAck => match (received, destination) {
+                (EBUS_ACKOK, AddressClass::Master(_)) => self.release(Ok(self.request.clone())),
+                (EBUS_ACKOK, _) => {
+                    self.state = State::SlaveLength;
+                    Action::Wait
+                },
+                (EBUS_ACKKO, _) if !self.master_repeated => {
+                    self.master_repeated = true;
+                    self.state = State::Master(0);
+                    Action::Write(self.bytes[0])
+                },
+                (EBUS_ACKKO, _) => self.release(Err(SendError::Nack)),
+                _ => self.release(Err(SendError::InvalidResponse)),
+            },
+            State::SlaveLength => {
+                self.response.slave_payload.clear();
+                self.response.slave_payload_length = received;
+                self.response.computed_slave_crc = 0;
+                stack_crc(&mut self.response.computed_slave_crc, received);
+                match received as usize {
+                    0 => {
+                        self.state = State::SlaveCrc;
+                        Action::Wait
+                    },
+                    n if n > MAX_NN => self.acknowledge(false),
+                    _ => {
+                        self.state = State::SlavePayload;
+                        Action::Wait
+                    },
+                }
+            },
+            State::SlavePayload => {
+                stack_crc(&mut self.response.computed_slave_crc, received);
+                if received == EBUS_ESCAPE {
+                    self.state = State::SlaveEscapedPayload;
+                } else {
+                    self.on_slave_payload(received);
+                }
+                Action::Wait
+            },
+            State::SlaveEscapedPayload => {
+                stack_crc(&mut self.response.computed_slave_crc, received);
+                match received {
+                    0x00 => self.on_slave_payload(EBUS_ESCAPE),
+                    0x01 => self.on_slave_payload(EBUS_SYN),
+                    _ => return self.acknowledge(false),
+                }
+                Action::Wait
+            },
+            State::SlaveCrc if received == EBUS_ESCAPE => {
+                self.state = State::SlaveEscapedCrc;
+                Action::Wait
+            },
+            State::SlaveCrc => self.on_slave_crc(received),
+            State::SlaveEscapedCrc => match received {
+                0x00 => self.on_slave_crc(EBUS_ESCAPE),
+                0x01 => self.on_slave_crc(EBUS_SYN),
+                _ => self.acknowledge(false),
+            },
+            State::MasterAck(positive) => match (received, positive) {
+                (EBUS_ACKOK, true) => self.release(Ok(self.response.clone())),
+                (EBUS_ACKKO, false) if !self.slave_repeated => {
+                    self.slave_repeated = true;
+                    self.state = State::SlaveLength;
+                    Action::Wait
+                },
+                (EBUS_ACKKO, false) => self.release(Err(SendError::InvalidResponse)),
+                _ => self.finish(Err(SendError::Collision)),
+            },
+            State::Release(_) => {
+                let result = match core::mem::replace(&mut self.state, State::Finished) {
+                    State::Release(result) if received == EBUS_SYN => result,
+                    _ => Err(SendError::Collision),
+                };
+                self.finish(result)
+            },
+            State::Finished => Action::Wait,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::encoder::encode_slave;
+
+    /// A bus echoing every byte written, with a slave answering from a script
+    struct SimulatedBus {
+        /// Bytes the other participants send after the master part (or its repetition) has been echoed
+        answers: Vec<Vec<u8>>,
+        /// Every byte which went through the bus
+        trace: Vec<u8>,
+    }
+
+    impl SimulatedBus {
+        fn new(answers: &[&[u8]]) -> SimulatedBus {
+            SimulatedBus {
+                answers: answers.iter().map(|a| a.to_vec()).collect(),
+                trace: Vec::new(),
+            }
+        }
+
+        /// Run the exchange from the next SYN, the bus is released with a SYN whenever it goes idle
+        fn run(&mut self, sender: &mut Sender) -> Result<Packet, SendError> {
+            let mut pending = vec![EBUS_SYN];
+            loop {
+                let received = pending.remove(0);
+                self.trace.push(received);
+                match sender.on_byte(received) {
+                    Action::Done(result) => return result,
+                    Action::Write(c) => pending.push(c),
+                    Action::Wait | Action::Arbitrate(_) => (),
+                }
+
+                let awaits_answer = matches!(sender.state, State::SlaveAck | State::SlaveLength);
+                if pending.is_empty() && awaits_answer && !self.answers.is_empty() {
+                    pending.extend(self.answers.remove(0));
+                }
+                if pending.is_empty() {
+                    pending.push(EBUS_SYN);
+                }
+            }
+        }
+    }
+
+    fn read_request() -> Packet {
+        Packet::request(0x31, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap()
+    }
+
+    fn response() -> Vec<u8> {
+        let packet = read_request().with_response(&[0x40, 0x02]).unwrap();
+        [&[EBUS_ACKOK][..], &encode_slave(&packet)].concat()
+    }
+
+    #[test]
+    fn read_is_answered() {
+        let request = read_request();
+        let mut sender = Sender::new(&request);
+        let mut bus = SimulatedBus::new(&[&response()]);
+
+        let packet = bus.run(&mut sender).unwrap();
+        assert_eq!(packet.slave_payload(), &[0x40, 0x02]);
+        assert!(sender.is_done());
+        // our ACK and SYN have been echoed at the end
+        assert_eq!(&bus.trace[bus.trace.len() - 2..], &[EBUS_ACKOK, EBUS_SYN]);
+    }
+
+    #[test]
+    fn broadcast_releases_the_bus() {
+        let request = Packet::request(0x10, 0xfe, 0x07, 0xfe, &[]).unwrap();
+        let mut sender = Sender::new(&request);
+        let mut bus = SimulatedBus::new(&[]);
+
+        assert!(bus.run(&mut sender).is_ok());
+        assert_eq!(bus.trace, vec![EBUS_SYN, 0x10, 0xfe, 0x07, 0xfe, 0x00, request.master_crc, EBUS_SYN]);
+    }
+
+    #[test]
+    fn corrupted_response_is_refused_then_repeated() {
+        let request = read_request();
+        let mut corrupted = response();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        let repeated = &response()[1..];
+
+        let mut sender = Sender::new(&request);
+        let mut bus = SimulatedBus::new(&[&corrupted, repeated]);
+        let packet = bus.run(&mut sender).unwrap();
+        assert_eq!(packet.slave_payload(), &[0x40, 0x02]);
+        assert!(bus.trace.contains(&EBUS_ACKKO));
+    }
+
+    #[test]
+    fn nack_is_repeated_once() {
+        let request = read_request();
+        let mut sender = Sender::new(&request);
+        let mut bus = SimulatedBus::new(&[&[EBUS_ACKKO], &[EBUS_ACKKO]]);
+
+        assert_eq!(bus.run(&mut sender).unwrap_err(), SendError::Nack);
+        let sent = bus.trace.iter().filter(|c| **c == request.source).count();
+        assert_eq!(sent, 2);
+    }
+
+    #[test]
+    fn silent_slave_times_out() {
+        let request = read_request();
+        let mut sender = Sender::new(&request);
+        let mut bus = SimulatedBus::new(&[]);
+
+        assert_eq!(bus.run(&mut sender).unwrap_err(), SendError::Timeout);
+    }
+
+    #[test]
+    fn arbitration_is_lost_to_another_master() {
+        let mut sender = Sender::new(&read_request());
+        for _ in 0..ARBITRATION_ATTEMPTS - 1 {
+            assert!(matches!(sender.on_byte(EBUS_SYN), Action::Write(0x31)));
+            assert!(matches!(sender.on_byte(0x10), Action::Wait));
+        }
+        assert!(matches!(sender.on_byte(EBUS_SYN), Action::Write(0x31)));
+        assert!(matches!(sender.on_byte(0x10), Action::Done(Err(SendError::ArbitrationLost))));
+    }
+
+    #[test]
+    fn arbitration_is_delegated() {
+        let request = read_request();
+        let mut sender = Sender::delegating_arbitration(&request);
+        assert!(matches!(sender.start(), Action::Arbitrate(0x31)));
+        // the traffic of other masters is ignored until the adapter reports the arbitration
+        assert!(matches!(sender.on_byte(EBUS_SYN), Action::Wait));
+        assert!(matches!(sender.on_byte(0x10), Action::Wait));
+        assert!(matches!(sender.on_arbitration(0x10), Action::Arbitrate(0x31)));
+        assert!(matches!(sender.on_arbitration(0x31), Action::Write(0x08)));
+    }
+
+    #[test]
+    fn echo_mismatch_is_a_collision() {
+        let mut sender = Sender::new(&read_request());
+        assert!(matches!(sender.on_byte(EBUS_SYN), Action::Write(0x31)));
+        assert!(matches!(sender.on_byte(0x31), Action::Write(0x08)));
+        assert!(matches!(sender.on_byte(0x09), Action::Done(Err(SendError::Collision))));
+    }
+}
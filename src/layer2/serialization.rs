@@ -0,0 +1,194 @@
+//! Serde representation of the telegrams, addresses and payloads being lowercase hex strings:
+//!
+//! ```json
+//! {"source":"31","destination":"f6","primary":"50","secondary":"22","master_payload":"ec1100",
+//!  "master_crc":"87","slave_payload":"bd00","slave_crc":"32"}
+//! ```
+//!
+//! When deserializing, missing CRCs are the computed ones and missing slave fields mean no response.
+
+use core::fmt;
+
+use arrayvec::ArrayVec;
+use serde::de::{self, Deserializer, Visitor};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+
+use super::{Packet, MAX_NN};
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|c| write!(f, "{:02x}", c))
+    }
+}
+
+impl Serialize for Hex<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Up to `MAX_NN` bytes read from a hex string
+#[derive(Default)]
+struct HexBytes(ArrayVec<u8, MAX_NN>);
+
+struct HexVisitor<const N: usize>;
+
+impl<const N: usize> Visitor<'_> for HexVisitor<N> {
+    type Value = ArrayVec<u8, N>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a hex string of at most {} bytes", N)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        if !v.len().is_multiple_of(2) || v.len() > 2 * N || !v.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(E::invalid_value(de::Unexpected::Str(v), &self));
+        }
+        (0..v.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&v[i..i + 2], 16).ok())
+            .collect::<Option<ArrayVec<u8, N>>>()
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for HexBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HexBytes, D::Error> {
+        deserializer.deserialize_str(HexVisitor::<MAX_NN>).map(HexBytes)
+    }
+}
+
+/// `#[serde(with = "hex_byte")]` for a byte written as a hex string
+pub(crate) mod hex_byte {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(c: &u8, serializer: S) -> Result<S::Ok, S::Error> {
+        Hex(&[*c]).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+        deserializer.deserialize_str(HexVisitor::<1>)?
+            .first()
+            .copied()
+            .ok_or_else(|| de::Error::invalid_length(0, &"a byte"))
+    }
+}
+
+/// `#[serde(with = "priority")]` for the priority of a master, written as a hex string as well
+pub(crate) mod priority {
+    use super::*;
+    use crate::layer2::MASTER_NIBBLES;
+
+    pub fn serialize<S: Serializer>(p: &u8, serializer: S) -> Result<S::Ok, S::Error> {
+        hex_byte::serialize(p, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+        let p = hex_byte::deserialize(deserializer)?;
+        if usize::from(p) >= MASTER_NIBBLES.len() {
+            return Err(de::Error::invalid_value(de::Unexpected::Unsigned(p.into()), &"a priority from 00 to 04"));
+        }
+        Ok(p)
+    }
+}
+
+struct HexByte(u8);
+
+impl<'de> Deserialize<'de> for HexByte {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HexByte, D::Error> {
+        hex_byte::deserialize(deserializer).map(HexByte)
+    }
+}
+
+impl Serialize for Packet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut packet = serializer.serialize_struct("Packet", 8)?;
+        packet.serialize_field("source", &Hex(&[self.source]))?;
+        packet.serialize_field("destination", &Hex(&[self.destination]))?;
+        packet.serialize_field("primary", &Hex(&[self.primary]))?;
+        packet.serialize_field("secondary", &Hex(&[self.secondary]))?;
+        packet.serialize_field("master_payload", &Hex(&self.master_payload))?;
+        packet.serialize_field("master_crc", &Hex(&[self.master_crc]))?;
+        packet.serialize_field("slave_payload", &Hex(&self.slave_payload))?;
+        packet.serialize_field("slave_crc", &Hex(&[self.slave_crc]))?;
+        packet.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Packet")]
+struct PacketDocument {
+    source: HexByte,
+    destination: HexByte,
+    primary: HexByte,
+    secondary: HexByte,
+    #[serde(default)]
+    master_payload: HexBytes,
+    master_crc: Option<HexByte>,
+    slave_payload: Option<HexBytes>,
+    slave_crc: Option<HexByte>,
+}
+
+impl<'de> Deserialize<'de> for Packet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Packet, D::Error> {
+        let document = PacketDocument::deserialize(deserializer)?;
+        let mut packet = Packet::request(document.source.0, document.destination.0, document.primary.0, document.secondary.0, &document.master_payload.0)
+            .expect("the payload is bounded by MAX_NN");
+        if let Some(crc) = document.master_crc {
+            packet.master_crc = crc.0;
+        }
+        if document.slave_payload.is_some() || document.slave_crc.is_some() {
+            packet = packet.with_response(&document.slave_payload.unwrap_or_default().0)
+                .expect("the payload is bounded by MAX_NN");
+        }
+        if let Some(crc) = document.slave_crc {
+            packet.slave_crc = crc.0;
+        }
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::layer2::{AddressClass, BusEvent, Packet, TelegramComponent};
+
+    #[test]
+    fn packets_are_written_in_hex() {
+        let packet = Packet::request(0x31, 0xf6, 0x50, 0x22, &[0xec, 0x11, 0x00]).unwrap()
+            .with_response(&[0xbd, 0x00]).unwrap();
+        let json = serde_json::to_string(&packet).unwrap();
+        assert_eq!(json, r#"{"source":"31","destination":"f6","primary":"50","secondary":"22","master_payload":"ec1100","master_crc":"87","slave_payload":"bd00","slave_crc":"32"}"#);
+
+        let read: Packet = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&read).unwrap(), json);
+        assert!(read.is_master_crc_valid() && read.is_slave_crc_valid());
+
+        let corrupted: Packet = serde_json::from_str(&json.replace(r#""slave_crc":"32""#, r#""slave_crc":"33""#)).unwrap();
+        assert!(!corrupted.is_slave_crc_valid());
+
+        let request: Packet = serde_json::from_str(r#"{"source":"10","destination":"fe","primary":"07","secondary":"00"}"#).unwrap();
+        assert!(request.is_master_crc_valid() && request.slave_payload().is_empty());
+        assert!(serde_json::from_str::<Packet>(r#"{"source":"310","destination":"f6","primary":"50","secondary":"22"}"#).is_err());
+        assert!(serde_json::from_str::<Packet>(r#"{"source":"+f","destination":"f6","primary":"50","secondary":"22"}"#).is_err());
+        assert!(serde_json::from_str::<Packet>(r#"{"source":"31","destination":"f6","primary":"50","secondary":"22","master_payload":"ec-1"}"#).is_err());
+        assert!(serde_json::from_str::<Packet>(&format!(r#"{{"source":"31","destination":"f6","primary":"50","secondary":"22","master_payload":"{}"}}"#, "00".repeat(17))).is_err());
+    }
+
+    #[test]
+    fn events_and_address_classes() {
+        assert_eq!(serde_json::to_string(&AddressClass::of(0x36)).unwrap(), r#"{"MasterSlave":"31"}"#);
+        assert_eq!(serde_json::to_string(&AddressClass::of(0x31)).unwrap(), r#"{"Master":"01"}"#);
+        assert_eq!(serde_json::from_str::<AddressClass>(r#"{"Master":"04"}"#).unwrap(), AddressClass::Master(4));
+        assert!(serde_json::from_str::<AddressClass>(r#"{"Master":"05"}"#).is_err());
+        assert!(serde_json::from_str::<AddressClass>(r#"{"Master":1}"#).is_err());
+        assert_eq!(serde_json::from_str::<AddressClass>(r#""Broadcast""#).unwrap(), AddressClass::Broadcast);
+
+        let event = BusEvent::Unexpected(TelegramComponent::SlaveACK, 0x31);
+        assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"Unexpected":["SlaveACK","31"]}"#);
+        let event: BusEvent = serde_json::from_str(r#"{"Nack":[{"source":"10","destination":"08","primary":"b5","secondary":"09"},"SlaveACK"]}"#).unwrap();
+        assert!(matches!(event, BusEvent::Nack(packet, TelegramComponent::SlaveACK) if packet.destination() == 0x08));
+        assert!(matches!(serde_json::from_str(r#""ConnectionLost""#).unwrap(), BusEvent::ConnectionLost));
+    }
+}
@@ -0,0 +1,248 @@
+//! Changes of the bytes of the telegrams over time, to find out which bytes follow an action on a
+//! device (e.g. a setting changed on its panel) and what they might encode.
+//!
+//! The telegrams are grouped by PB SB and the leading bytes of their master payload, each byte
+//! position of the master and slave payloads forming a series. The changes of a series are
+//! correlated with markers, the times of the actions.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+use super::types::DataType;
+use super::Part;
+use crate::layer2::Packet;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MessageKey {
+    pub primary: u8,
+    pub secondary: u8,
+    /// Leading bytes of the master payload, shorter when the payload is
+    pub id: Vec<u8>,
+}
+
+/// `PBSB ID`
+impl fmt::Display for MessageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}{:02x} ", self.primary, self.secondary)?;
+        self.id.iter().try_for_each(|c| write!(f, "{:02x}", c))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Time of the telegram, from the start of the capture
+    pub at: Duration,
+    pub master: Vec<u8>,
+    pub slave: Vec<u8>,
+}
+
+impl Snapshot {
+    fn byte(&self, part: Part, offset: usize) -> Option<u8> {
+        match part {
+            Part::Master => self.master.get(offset).copied(),
+            Part::Slave => self.slave.get(offset).copied(),
+        }
+    }
+}
+
+/// The payloads of a message each time they changed
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    /// Number of telegrams
+    pub count: usize,
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl History {
+    fn length(&self, part: Part) -> usize {
+        self.snapshots.iter()
+            .map(|s| if part == Part::Master { s.master.len() } else { s.slave.len() })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Times a byte changed, appearing or disappearing with the payload length included
+    pub fn changes(&self, part: Part, offset: usize) -> Vec<Duration> {
+        self.snapshots.windows(2)
+            .filter(|pair| pair[0].byte(part, offset) != pair[1].byte(part, offset))
+            .map(|pair| pair[1].at)
+            .collect()
+    }
+
+    /// Data types fitting the values taken by a byte, the most likely first
+    pub fn suggest(&self, part: Part, offset: usize) -> Vec<DataType> {
+        let values: Vec<u8> = self.snapshots.iter().filter_map(|s| s.byte(part, offset)).collect();
+        let words: Vec<i16> = self.snapshots.iter()
+            .filter_map(|s| Some(i16::from_le_bytes([s.byte(part, offset)?, s.byte(part, offset + 1)?])))
+            .collect();
+        suggest(&values, &words)
+    }
+}
+
+/// Data types fitting the values of a byte, `words` being the values it forms with the next byte.
+/// Temperatures are expected between -30 and 110.
+fn suggest(values: &[u8], words: &[i16]) -> Vec<DataType> {
+    let mut distinct = values.to_vec();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() < 2 {
+        return Vec::new();
+    }
+
+    let mut types = Vec::new();
+    let flipped = distinct.iter().fold(0, |acc, v| acc | (v ^ distinct[0]));
+    if distinct.len() == 2 && flipped.count_ones() == 1 {
+        types.push(DataType::Bit(flipped.trailing_zeros() as u8));
+    }
+    if distinct.iter().all(|v| v >> 4 <= 9 && v & 0x0f <= 9) {
+        types.push(DataType::Bcd);
+    }
+    if !words.is_empty() {
+        let high = |w: &i16| w >> 8;
+        if words.iter().all(|w| (-2..=6).contains(&high(w))) {
+            types.push(DataType::Data2c);
+        } else if words.iter().all(|w| (-30..=110).contains(&high(w))) {
+            types.push(DataType::Data2b);
+        }
+    }
+    if distinct.iter().all(|v| *v <= 220) {
+        types.push(DataType::Data1c);
+    }
+    if distinct.iter().any(|v| *v >= 0x80) && distinct.iter().all(|v| (-30..=110).contains(&(*v as i8))) {
+        types.push(DataType::Data1b);
+    }
+    types.push(DataType::Uch);
+    types
+}
+
+/// A byte whose changes have been compared with the markers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correlation<'a> {
+    pub key: &'a MessageKey,
+    pub part: Part,
+    /// Position into the payload of `part`, the ID included
+    pub offset: usize,
+    pub changes: Vec<Duration>,
+    /// Markers followed by a change within the window
+    pub matched: usize,
+    /// Changes following no marker
+    pub unmatched: usize,
+    pub suggestions: Vec<DataType>,
+}
+
+/// Histories of the messages of a capture
+#[derive(Debug, Clone)]
+pub struct Analyser {
+    id_length: usize,
+    messages: BTreeMap<MessageKey, History>,
+}
+
+impl Analyser {
+    /// `id_length` leading bytes of the master payload tell messages apart
+    pub fn new(id_length: usize) -> Analyser {
+        Analyser {
+            id_length,
+            messages: BTreeMap::new(),
+        }
+    }
+
+    /// Record a telegram, unless it is corrupted
+    pub fn push(&mut self, at: Duration, packet: &Packet) {
+        if !packet.is_master_crc_valid() || !packet.is_slave_crc_valid() {
+            return;
+        }
+        let master = packet.master_payload();
+        let key = MessageKey {
+            primary: packet.primary(),
+            secondary: packet.secondary(),
+            id: master[..master.len().min(self.id_length)].to_vec(),
+        };
+        let history = self.messages.entry(key).or_default();
+        history.count += 1;
+        if history.snapshots.last().is_none_or(|s| s.master != master || s.slave != packet.slave_payload()) {
+            history.snapshots.push(Snapshot { at, master: master.to_vec(), slave: packet.slave_payload().to_vec() });
+        }
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = (&MessageKey, &History)> {
+        self.messages.iter()
+    }
+
+    /// Every byte which changed, those following most markers within `window` first, then those
+    /// changing the least otherwise
+    pub fn correlate(&self, markers: &[Duration], window: Duration) -> Vec<Correlation<'_>> {
+        let follows = |marker: &Duration, at: &Duration| *at >= *marker && *at <= *marker + window;
+        let mut correlations: Vec<Correlation> = self.messages.iter()
+            .flat_map(|(key, history)| {
+                let master = (key.id.len()..history.length(Part::Master)).map(|offset| (Part::Master, offset));
+                let slave = (0..history.length(Part::Slave)).map(|offset| (Part::Slave, offset));
+                master.chain(slave).map(move |(part, offset)| (key, history, part, offset))
+            })
+            .filter_map(|(key, history, part, offset)| {
+                let changes = history.changes(part, offset);
+                if changes.is_empty() {
+                    return None;
+                }
+                Some(Correlation {
+                    key,
+                    part,
+                    offset,
+                    matched: markers.iter().filter(|m| changes.iter().any(|at| follows(m, at))).count(),
+                    unmatched: changes.iter().filter(|at| !markers.iter().any(|m| follows(m, at))).count(),
+                    changes,
+                    suggestions: history.suggest(part, offset),
+                })
+            })
+            .collect();
+        correlations.sort_by_key(|c| (usize::MAX - c.matched, c.unmatched));
+        correlations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn second(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn changes_follow_the_markers() {
+        let mut analyser = Analyser::new(2);
+        // the flow temperature drifts, the desired temperature is set at 100 s and 200 s
+        let temps = [0x40, 0x41, 0x41, 0x42, 0x43, 0x44];
+        for (i, temp) in temps.iter().enumerate() {
+            let at = second(i as u64 * 50);
+            let flow = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap().with_response(&[*temp, 0x02]).unwrap();
+            analyser.push(at, &flow);
+            let desired = match i { 0 | 1 => 0x20, 2 | 3 => 0x21, _ => 0x22 };
+            let setting = Packet::request(0x10, 0x15, 0xb5, 0x10, &[0x00, 0x00, desired]).unwrap();
+            analyser.push(at, &setting);
+        }
+
+        let correlations = analyser.correlate(&[second(98), second(198)], second(5));
+        let top = &correlations[0];
+        assert_eq!((top.key.to_string().as_str(), top.part, top.offset), ("b510 0000", Part::Master, 2));
+        assert_eq!((top.matched, top.unmatched), (2, 0));
+        assert_eq!(top.changes, vec![second(100), second(200)]);
+
+        let flow = &correlations[1];
+        assert_eq!((flow.part, flow.offset), (Part::Slave, 0));
+        assert_eq!((flow.matched, flow.unmatched), (1, 3));
+        assert_eq!(flow.suggestions, vec![DataType::Bcd, DataType::Data2c, DataType::Data1c, DataType::Uch]);
+        assert_eq!(correlations.len(), 2);
+    }
+
+    #[test]
+    fn suggestions_from_ranges() {
+        assert_eq!(suggest(&[0x04, 0x04], &[]), vec![]);
+        assert_eq!(suggest(&[0x00, 0x04], &[])[0], DataType::Bit(2));
+        assert_eq!(suggest(&[0x09, 0x10, 0x11], &[]), vec![DataType::Bcd, DataType::Data1c, DataType::Uch]);
+        assert_eq!(suggest(&[0xfe, 0x05], &[]), vec![DataType::Data1b, DataType::Uch]);
+        assert_eq!(suggest(&[0x9a, 0x40], &[0x019a, 0x0240]), vec![DataType::Data2c, DataType::Data1c, DataType::Uch]);
+        assert_eq!(suggest(&[0x80, 0x00], &[0x2d80, 0x2e00]), vec![DataType::Bit(7), DataType::Bcd, DataType::Data2b, DataType::Data1c, DataType::Uch]);
+    }
+}
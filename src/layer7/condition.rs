@@ -0,0 +1,188 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::identification::Identification;
+
+/// Inclusive range of software or hardware versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VersionRange {
+    pub min: Option<u16>,
+    pub max: Option<u16>,
+}
+
+impl VersionRange {
+    /// Build a range from a comparison such as `>= 0409`, `None` on an unknown operator
+    pub fn from_comparison(operator: &str, version: u16) -> Option<VersionRange> {
+        let range = match operator {
+            "=" | "==" => VersionRange { min: Some(version), max: Some(version) },
+            ">=" => VersionRange { min: Some(version), max: None },
+            ">" => VersionRange { min: Some(version.saturating_add(1)), max: None },
+            "<=" => VersionRange { min: None, max: Some(version) },
+            "<" => VersionRange { min: None, max: Some(version.saturating_sub(1)) },
+            _ => return None,
+        };
+        Some(range)
+    }
+
+    /// Narrow the range with another one
+    pub fn intersect(self, other: VersionRange) -> VersionRange {
+        VersionRange {
+            min: self.min.max(other.min),
+            max: match (self.max, other.max) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+
+    /// Whether no version is in the range
+    pub fn is_empty(&self) -> bool {
+        matches!((self.min, self.max), (Some(min), Some(max)) if min > max)
+    }
+
+    pub fn contains(&self, version: u16) -> bool {
+        self.min.is_none_or(|min| version >= min) && self.max.is_none_or(|max| version <= max)
+    }
+}
+
+/// Requirements on the identification (07 04) of a device for a definition to be active.
+/// Every set criterion must be satisfied.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Condition {
+    /// Address whose identification is checked, the address of the message when `None`
+    pub address: Option<u8>,
+    /// Accepted device identifier prefixes (case insensitive), any device when empty
+    pub device_ids: Vec<String>,
+    pub manufacturer: Option<u8>,
+    pub software: Option<VersionRange>,
+    pub hardware: Option<VersionRange>,
+}
+
+impl Condition {
+    pub fn is_empty(&self) -> bool {
+        self.device_ids.is_empty() && self.manufacturer.is_none() && self.software.is_none() && self.hardware.is_none()
+    }
+
+    /// The condition satisfied by the identifications satisfying both, `None` when none can:
+    /// the device identifiers must start with a prefix of each list, the ranges are narrowed.
+    pub fn intersect(self, other: Condition) -> Option<Condition> {
+        let address = match (self.address, other.address) {
+            (Some(a), Some(b)) if a != b => return None,
+            (a, b) => a.or(b),
+        };
+        let manufacturer = match (self.manufacturer, other.manufacturer) {
+            (Some(a), Some(b)) if a != b => return None,
+            (a, b) => a.or(b),
+        };
+        let range = |a: Option<VersionRange>, b: Option<VersionRange>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.intersect(b)).filter(|r| !r.is_empty()).map(Some),
+            (a, b) => Some(a.or(b)),
+        };
+        let software = range(self.software, other.software)?;
+        let hardware = range(self.hardware, other.hardware)?;
+
+        let device_ids = if self.device_ids.is_empty() || other.device_ids.is_empty() {
+            if self.device_ids.is_empty() { other.device_ids } else { self.device_ids }
+        } else {
+            // the longer of two prefixes of the same identifiers
+            let mut device_ids: Vec<String> = Vec::new();
+            for a in &self.device_ids {
+                for b in &other.device_ids {
+                    let (lower_a, lower_b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+                    let longer = if lower_a.starts_with(&lower_b) { a } else if lower_b.starts_with(&lower_a) { b } else { continue };
+                    if !device_ids.iter().any(|id| id.eq_ignore_ascii_case(longer)) {
+                        device_ids.push(longer.clone());
+                    }
+                }
+            }
+            if device_ids.is_empty() {
+                return None;
+            }
+            device_ids
+        };
+        Some(Condition { address, device_ids, manufacturer, software, hardware })
+    }
+
+    /// An unknown identification never satisfies a non-empty condition,
+    /// so that nothing is decoded with a definition which may not apply.
+    pub fn is_satisfied_by(&self, identification: Option<&Identification>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(identification) = identification else {
+            return false;
+        };
+
+        let device_id = identification.device_id.to_ascii_lowercase();
+        (self.device_ids.is_empty() || self.device_ids.iter().any(|id| device_id.starts_with(&id.to_ascii_lowercase())))
+            && self.manufacturer.is_none_or(|m| m == identification.manufacturer)
+            && self.software.is_none_or(|r| r.contains(identification.software))
+            && self.hardware.is_none_or(|r| r.contains(identification.hardware))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bai(software: u16) -> Identification {
+        Identification { manufacturer: 0xb5, device_id: "BAI00".to_string(), software, hardware: 7603 }
+    }
+
+    #[test]
+    fn empty_condition_is_always_satisfied() {
+        assert!(Condition::default().is_satisfied_by(None));
+    }
+
+    #[test]
+    fn unknown_device_does_not_satisfy() {
+        let condition = Condition { device_ids: vec!["bai".to_string()], ..Default::default() };
+        assert!(!condition.is_satisfied_by(None));
+    }
+
+    #[test]
+    fn device_id_and_versions_are_checked() {
+        let condition = Condition {
+            device_ids: vec!["bai".to_string()],
+            software: VersionRange::from_comparison(">=", 409),
+            ..Default::default()
+        };
+        assert!(condition.is_satisfied_by(Some(&bai(409))));
+        assert!(!condition.is_satisfied_by(Some(&bai(408))));
+
+        let mut other = bai(500);
+        other.device_id = "VRC70".to_string();
+        assert!(!condition.is_satisfied_by(Some(&other)));
+    }
+
+    #[test]
+    fn ranges_intersect() {
+        let range = VersionRange::from_comparison(">", 100).unwrap()
+            .intersect(VersionRange::from_comparison("<", 200).unwrap());
+        assert_eq!(range, VersionRange { min: Some(101), max: Some(199) });
+        assert!(range.contains(150));
+        assert!(!range.contains(200));
+    }
+
+    #[test]
+    fn conditions_intersect() {
+        let file = Condition {
+            device_ids: vec!["bai".to_string()],
+            software: VersionRange::from_comparison(">=", 400),
+            ..Default::default()
+        };
+        let row = Condition {
+            device_ids: vec!["BAI00".to_string(), "VRC".to_string()],
+            software: VersionRange::from_comparison("<", 500),
+            ..Default::default()
+        };
+        let both = file.clone().intersect(row).unwrap();
+        assert_eq!(both.device_ids, vec!["BAI00".to_string()]);
+        assert_eq!(both.software, Some(VersionRange { min: Some(400), max: Some(499) }));
+
+        let other = Condition { device_ids: vec!["xyz".to_string()], ..Default::default() };
+        assert_eq!(file.clone().intersect(other), None);
+        let older = Condition { software: VersionRange::from_comparison("<", 300), ..Default::default() };
+        assert_eq!(file.intersect(older), None);
+    }
+}
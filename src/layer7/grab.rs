@@ -0,0 +1,264 @@
+//! Collection of the telegrams no definition describes, to reverse-engineer new devices (like
+//! `ebusd grab`). The telegrams are grouped by QQ ZZ PB SB and the leading bytes of their master
+//! payload, the distinct payloads and the changes of each byte position being kept by group.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::identification::Inventory;
+use super::types::DataType;
+use super::{Catalogue, FieldDefinition, MessageDefinition, MessageKind, Part};
+use crate::layer2::{AddressClass, Packet};
+
+/// Leading bytes of the master payload told apart by default
+pub const DEFAULT_ID_LENGTH: usize = 2;
+/// Distinct payloads kept by group, the next ones being only counted
+pub const MAX_SAMPLES: usize = 32;
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    bytes.iter().try_for_each(|c| write!(f, "{:02x}", c))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|c| format!("{:02x}", c)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GrabKey {
+    pub source: u8,
+    pub destination: u8,
+    pub primary: u8,
+    pub secondary: u8,
+    /// Leading bytes of the master payload, shorter when the payload is
+    pub id: Vec<u8>,
+}
+
+/// `QQ ZZ PBSB ID`
+impl fmt::Display for GrabKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x} {:02x} {:02x}{:02x} ", self.source, self.destination, self.primary, self.secondary)?;
+        write_hex(f, &self.id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub master: Vec<u8>,
+    pub slave: Vec<u8>,
+    pub count: usize,
+}
+
+/// The telegrams of a group
+#[derive(Debug, Clone, Default)]
+pub struct Grabbed {
+    pub count: usize,
+    /// Distinct payloads, in order of appearance
+    pub samples: Vec<Sample>,
+    /// Number of telegrams whose payloads are not among the samples
+    pub unsampled: usize,
+    /// For each byte of the master payload, the number of times it differed from the previous telegram
+    pub master_changes: Vec<usize>,
+    pub slave_changes: Vec<usize>,
+    last: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Count the positions which differ, a missing byte differing from any byte
+fn count_changes(changes: &mut Vec<usize>, previous: &[u8], current: &[u8]) {
+    let length = previous.len().max(current.len());
+    if changes.len() < length {
+        changes.resize(length, 0);
+    }
+    for (i, changes) in changes.iter_mut().enumerate().take(length) {
+        if previous.get(i) != current.get(i) {
+            *changes += 1;
+        }
+    }
+}
+
+impl Grabbed {
+    fn push(&mut self, master: &[u8], slave: &[u8]) {
+        self.count += 1;
+        match self.samples.iter().position(|s| s.master == master && s.slave == slave) {
+            Some(i) => self.samples[i].count += 1,
+            None if self.samples.len() < MAX_SAMPLES => self.samples.push(Sample { master: master.to_vec(), slave: slave.to_vec(), count: 1 }),
+            None => self.unsampled += 1,
+        }
+
+        let (previous_master, previous_slave) = self.last.take().unwrap_or_else(|| (master.to_vec(), slave.to_vec()));
+        count_changes(&mut self.master_changes, &previous_master, master);
+        count_changes(&mut self.slave_changes, &previous_slave, slave);
+        self.last = Some((master.to_vec(), slave.to_vec()));
+    }
+}
+
+/// Unknown telegrams grouped by `GrabKey`
+#[derive(Debug, Clone)]
+pub struct Grabber {
+    id_length: usize,
+    groups: BTreeMap<GrabKey, Grabbed>,
+}
+
+impl Default for Grabber {
+    fn default() -> Self {
+        Grabber::new(DEFAULT_ID_LENGTH)
+    }
+}
+
+impl Grabber {
+    pub fn new(id_length: usize) -> Grabber {
+        Grabber {
+            id_length,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn key(&self, packet: &Packet) -> GrabKey {
+        let master = packet.master_payload();
+        GrabKey {
+            source: packet.source(),
+            destination: packet.destination(),
+            primary: packet.primary(),
+            secondary: packet.secondary(),
+            id: master[..master.len().min(self.id_length)].to_vec(),
+        }
+    }
+
+    /// Keep a telegram unless an active definition of the catalogue describes it or it is corrupted.
+    /// Return `true` when it is the first telegram of its group.
+    pub fn grab(&mut self, packet: &Packet, catalogue: &Catalogue, inventory: &Inventory) -> bool {
+        if !packet.is_master_crc_valid() || !packet.is_slave_crc_valid() || catalogue.find(packet, inventory).is_some() {
+            return false;
+        }
+        let key = self.key(packet);
+        let first = !self.groups.contains_key(&key);
+        self.groups.entry(key).or_default().push(packet.master_payload(), packet.slave_payload());
+        first
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&GrabKey, &Grabbed)> {
+        self.groups.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// A passive definition by group, with an `UCH` field by byte after the ID named after its
+    /// position (`m3`, `s0`) and commented with how often it changed
+    pub fn skeletons(&self) -> Vec<MessageDefinition> {
+        self.groups.iter()
+            .map(|(key, grabbed)| {
+                let circuit = match AddressClass::of(key.destination) {
+                    AddressClass::Broadcast => "broadcast".to_string(),
+                    _ => format!("unknown_{:02x}", key.destination),
+                };
+                let name = format!("{:02x}{:02x}_{}", key.primary, key.secondary, hex(&key.id));
+                let mut definition = MessageDefinition::new(&circuit, &name, MessageKind::Passive, key.primary, key.secondary);
+                definition.source = Some(key.source);
+                definition.destination = Some(key.destination);
+                definition.id = key.id.clone();
+
+                let master_length = grabbed.samples.iter().map(|s| s.master.len()).max().unwrap_or_default();
+                let slave_length = grabbed.samples.iter().map(|s| s.slave.len()).max().unwrap_or_default();
+                let fields = (key.id.len()..master_length).map(|i| (Part::Master, i, &grabbed.master_changes))
+                    .chain((0..slave_length).map(|i| (Part::Slave, i, &grabbed.slave_changes)));
+                for (part, i, changes) in fields {
+                    let prefix = if part == Part::Master { "m" } else { "s" };
+                    let mut field = FieldDefinition::new(&format!("{}{}", prefix, i), part, i, DataType::Uch);
+                    field.comment = Some(match changes.get(i).copied().unwrap_or_default() {
+                        0 => "constant".to_string(),
+                        n => format!("changed {} time(s)", n),
+                    });
+                    definition.fields.push(field);
+                }
+                definition
+            })
+            .collect()
+    }
+}
+
+/// A report of the groups: their samples and the number of changes of each byte position
+impl fmt::Display for Grabber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, grabbed) in &self.groups {
+            let more = if grabbed.unsampled > 0 { "+" } else { "" };
+            writeln!(f, "{}: {} telegram(s), {}{} distinct", key, grabbed.count, grabbed.samples.len(), more)?;
+            for (part, changes) in [("master", &grabbed.master_changes), ("slave", &grabbed.slave_changes)] {
+                if !changes.is_empty() {
+                    let changes: Vec<String> = changes.iter().map(usize::to_string).collect();
+                    writeln!(f, "  {} changes: {}", part, changes.join(" "))?;
+                }
+            }
+            for sample in &grabbed.samples {
+                write!(f, "  ")?;
+                write_hex(f, &sample.master)?;
+                write!(f, " / ")?;
+                write_hex(f, &sample.slave)?;
+                writeln!(f, " x{}", sample.count)?;
+            }
+            if grabbed.unsampled > 0 {
+                writeln!(f, "  ... {} more", grabbed.unsampled)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn telegram(master: &[u8], slave: &[u8]) -> Packet {
+        Packet::request(0x10, 0x08, 0xb5, 0x09, master).unwrap().with_response(slave).unwrap()
+    }
+
+    #[test]
+    fn unknown_telegrams_are_grouped() {
+        let mut known = MessageDefinition::new("bai", "FlowTemp", MessageKind::Read, 0xb5, 0x09);
+        known.id = vec![0x0d, 0x18, 0x00];
+        let catalogue: Catalogue = [known].into_iter().collect();
+        let inventory = Inventory::new();
+        let mut grabber = Grabber::default();
+
+        assert!(!grabber.grab(&telegram(&[0x0d, 0x18, 0x00], &[0x40, 0x02]), &catalogue, &inventory));
+        assert!(grabber.grab(&telegram(&[0x0d, 0x28, 0x00], &[0x10, 0x02]), &catalogue, &inventory));
+        assert!(!grabber.grab(&telegram(&[0x0d, 0x28, 0x00], &[0x11, 0x02]), &catalogue, &inventory));
+        assert!(!grabber.grab(&telegram(&[0x0d, 0x28, 0x00], &[0x11, 0x02]), &catalogue, &inventory));
+        assert!(grabber.grab(&telegram(&[0x0d, 0x29], &[0x01]), &catalogue, &inventory));
+
+        let groups: Vec<_> = grabber.groups().collect();
+        assert_eq!(groups.len(), 2);
+        let (key, grabbed) = groups[0];
+        assert_eq!(key.to_string(), "10 08 b509 0d28");
+        assert_eq!(grabbed.count, 3);
+        assert_eq!(grabbed.samples.len(), 2);
+        assert_eq!(grabbed.samples[1].count, 2);
+        assert_eq!(grabbed.master_changes, vec![0, 0, 0]);
+        assert_eq!(grabbed.slave_changes, vec![1, 0]);
+
+        assert!(grabber.to_string().starts_with("10 08 b509 0d28: 3 telegram(s), 2 distinct\n  master changes: 0 0 0\n  slave changes: 1 0\n  0d2800 / 1002 x1\n"));
+    }
+
+    #[test]
+    fn skeletons_have_a_field_by_byte() {
+        let mut grabber = Grabber::new(1);
+        grabber.grab(&telegram(&[0x0d, 0x28, 0x00], &[0x10, 0x02]), &Catalogue::new(), &Inventory::new());
+        grabber.grab(&telegram(&[0x0d, 0x28, 0x01], &[0x10, 0x02]), &Catalogue::new(), &Inventory::new());
+
+        let skeletons = grabber.skeletons();
+        let definition = &skeletons[0];
+        assert_eq!((definition.circuit.as_str(), definition.name.as_str()), ("unknown_08", "b509_0d"));
+        assert_eq!((definition.source, definition.destination), (Some(0x10), Some(0x08)));
+        let fields: Vec<(&str, usize, Option<&str>)> = definition.fields.iter().map(|f| (f.name.as_str(), f.offset, f.comment.as_deref())).collect();
+        assert_eq!(fields, vec![
+            ("m1", 1, Some("constant")),
+            ("m2", 2, Some("changed 1 time(s)")),
+            ("s0", 0, Some("constant")),
+            ("s1", 1, Some("constant")),
+        ]);
+        assert!(definition.matches(&telegram(&[0x0d, 0x28, 0x00], &[0x10, 0x02])));
+    }
+}
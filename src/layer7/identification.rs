@@ -0,0 +1,170 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use core::fmt;
+
+use crate::layer2::{AddressClass, Packet};
+
+/// PB/SB of the identification service
+pub const IDENTIFICATION_COMMAND: (u8, u8) = (0x07, 0x04);
+
+/// Answer of the identification service (07 04)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identification {
+    pub manufacturer: u8,
+    /// Up to 5 ASCII characters, e.g. `BAI00`
+    pub device_id: String,
+    /// BCD `xx.yy` read as the decimal `xxyy`
+    pub software: u16,
+    /// BCD `xx.yy` read as the decimal `xxyy`
+    pub hardware: u16,
+}
+
+fn bcd_version(high: u8, low: u8) -> Option<u16> {
+    let digit = |c: u8| if c >> 4 > 9 || c & 0x0f > 9 { None } else { Some((c >> 4) as u16 * 10 + (c & 0x0f) as u16) };
+    Some(digit(high)? * 100 + digit(low)?)
+}
+
+impl Identification {
+    /// Parse the slave payload of a 07 04 telegram
+    pub fn parse(slave_payload: &[u8]) -> Option<Identification> {
+        if slave_payload.len() < 10 {
+            return None;
+        }
+        let device_id = slave_payload[1..6].iter()
+            .take_while(|&&c| c != 0x00)
+            .map(|&c| c as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        Some(Identification {
+            manufacturer: slave_payload[0],
+            device_id,
+            software: bcd_version(slave_payload[6], slave_payload[7])?,
+            hardware: bcd_version(slave_payload[8], slave_payload[9])?,
+        })
+    }
+
+    /// Name of the manufacturer, as registered with the eBUS association
+    pub fn manufacturer_name(&self) -> Option<&'static str> {
+        Some(match self.manufacturer {
+            0x06 => "Dungs",
+            0x0f => "FH Ostfalia",
+            0x10 => "TEM",
+            0x11 => "Lamberti",
+            0x14 => "CEB",
+            0x15 => "Landis-Staefa",
+            0x16 => "FERRO",
+            0x17 => "MONDIAL",
+            0x18 => "Wikon",
+            0x19 => "Wolf",
+            0x20 => "RAWE",
+            0x30 => "Satronic",
+            0x40 => "ENCON",
+            0x50 => "Kromschroeder",
+            0x60 => "Eberle",
+            0x65 => "EBV",
+            0x75 => "Graesslin",
+            0x85 => "ebm-papst",
+            0x95 => "SIG",
+            0xa5 => "Theben",
+            0xa7 => "Thermowatt",
+            0xb5 => "Vaillant",
+            0xc0 => "Toby",
+            0xc5 => "Weishaupt",
+            0xfd => "ebusd.eu",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Identification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MF={:02x};ID={};SW={:04};HW={:04}", self.manufacturer, self.device_id, self.software, self.hardware)
+    }
+}
+
+/// Identifications learned for each bus address
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    devices: BTreeMap<u8, Identification>,
+}
+
+impl Inventory {
+    pub fn new() -> Inventory {
+        Inventory::default()
+    }
+
+    pub fn insert(&mut self, address: u8, identification: Identification) {
+        self.devices.insert(address, identification);
+    }
+
+    pub fn get(&self, address: u8) -> Option<&Identification> {
+        self.devices.get(&address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &Identification)> {
+        self.devices.iter().map(|(a, i)| (*a, i))
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Learn from an observed telegram.
+    /// Return `true` when the identification of an address has been added or changed.
+    pub fn observe(&mut self, packet: &Packet) -> bool {
+        if (packet.primary(), packet.secondary()) != IDENTIFICATION_COMMAND
+            || !packet.master_payload().is_empty()
+            || !packet.is_slave_crc_valid()
+            || matches!(AddressClass::of(packet.destination()), AddressClass::Broadcast | AddressClass::Invalid)
+        {
+            return false;
+        }
+
+        match Identification::parse(packet.slave_payload()) {
+            Some(identification) if self.get(packet.destination()) != Some(&identification) => {
+                self.insert(packet.destination(), identification);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identification_is_learned() {
+        // >1008070400<0ab5424149303007037603
+        let packet = Packet::request(0x10, 0x08, 0x07, 0x04, &[]).unwrap()
+            .with_response(&[0xb5, b'B', b'A', b'I', b'0', b'0', 0x07, 0x03, 0x76, 0x03]).unwrap();
+        let mut inventory = Inventory::new();
+
+        assert!(inventory.observe(&packet));
+        assert!(!inventory.observe(&packet));
+
+        let identification = inventory.get(0x08).unwrap();
+        assert_eq!(identification.manufacturer, 0xb5);
+        assert_eq!(identification.manufacturer_name(), Some("Vaillant"));
+        assert_eq!(identification.device_id, "BAI00");
+        assert_eq!(identification.software, 703);
+        assert_eq!(identification.hardware, 7603);
+    }
+
+    #[test]
+    fn other_telegrams_are_ignored() {
+        let packet = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d]).unwrap()
+            .with_response(&[0xb5, b'B', b'A', b'I', b'0', b'0', 0x07, 0x03, 0x76, 0x03]).unwrap();
+        let mut inventory = Inventory::new();
+
+        assert!(!inventory.observe(&packet));
+        assert!(inventory.is_empty());
+    }
+}
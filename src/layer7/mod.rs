@@ -0,0 +1,442 @@
+pub mod analysis;
+pub mod condition;
+pub mod grab;
+pub mod identification;
+pub mod paged;
+pub mod types;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::layer2::{AddressClass, Packet};
+use condition::Condition;
+use identification::Inventory;
+use paged::Paging;
+use types::{DataType, DecodeError, EncodeError, Value};
+
+/// Where a definition has been read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// Path of the file, as displayed
+    pub file: String,
+    /// 1-based, 0 when unknown
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Part of the telegram holding a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part {
+    Master,
+    Slave,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Actively read by querying the destination
+    Read,
+    /// Actively written by sending values to the destination
+    Write,
+    /// Only observed on the bus (broadcasts, exchanges between other participants)
+    Passive,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDefinition {
+    pub name: String,
+    pub part: Part,
+    /// Position of the first byte into the unescaped payload of `part`, including the message ID
+    pub offset: usize,
+    pub data_type: DataType,
+    /// Divide the value, a negative divider multiplies by its absolute value instead (ebusd convention)
+    pub divider: Option<f64>,
+    /// Names of the raw values
+    pub values: Option<Vec<(i64, String)>>,
+    pub unit: Option<String>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub comment: Option<String>,
+}
+
+impl FieldDefinition {
+    pub fn new(name: &str, part: Part, offset: usize, data_type: DataType) -> FieldDefinition {
+        FieldDefinition {
+            name: name.to_string(),
+            part,
+            offset,
+            data_type,
+            divider: None,
+            values: None,
+            unit: None,
+            min: None,
+            max: None,
+            comment: None,
+        }
+    }
+
+    /// Position following the last byte of the field
+    pub fn end(&self) -> usize {
+        self.offset + self.data_type.len()
+    }
+
+    fn scale(&self, value: f64) -> f64 {
+        match self.divider {
+            Some(d) if d < 0.0 => value * -d,
+            Some(d) => value / d,
+            None => value,
+        }
+    }
+
+    fn unscale(&self, value: f64) -> f64 {
+        match self.divider {
+            Some(d) if d < 0.0 => value / -d,
+            Some(d) => value * d,
+            None => value,
+        }
+    }
+
+    /// Decode the field from the payload of its part, `None` being the replacement value
+    pub fn decode(&self, payload: &[u8]) -> Result<Option<Value>, DecodeError> {
+        let data = payload.get(self.offset..).ok_or(DecodeError::Truncated)?;
+
+        if let (Some(values), true) = (&self.values, self.data_type.is_numeric()) {
+            return Ok(self.data_type.decode_raw(data)?.map(|raw| {
+                match values.iter().find(|(v, _)| *v == raw) {
+                    Some((_, name)) => Value::Named(raw, name.clone()),
+                    None => Value::Integer(raw),
+                }
+            }));
+        }
+
+        let value = self.data_type.decode(data)?;
+        Ok(match (value, self.divider) {
+            (Some(Value::Integer(i)), Some(_)) => Some(Value::Float(self.scale(i as f64))),
+            (Some(Value::Float(v)), Some(_)) => Some(Value::Float(self.scale(v))),
+            (value, _) => value,
+        })
+    }
+
+    /// Encode a value of the field, names of the value list being accepted as `Value::Text`
+    pub fn encode(&self, value: Option<&Value>) -> Result<Vec<u8>, EncodeError> {
+        if let (Some(values), Some(Value::Text(name))) = (&self.values, value) {
+            let (raw, _) = values.iter().find(|(_, n)| n == name).ok_or(EncodeError::OutOfRange)?;
+            return self.data_type.encode_raw(Some(*raw));
+        }
+
+        match (value, self.divider) {
+            (Some(Value::Integer(i)), Some(_)) => self.data_type.encode(Some(&Value::Float(self.unscale(*i as f64)))),
+            (Some(Value::Float(v)), Some(_)) => self.data_type.encode(Some(&Value::Float(self.unscale(*v)))),
+            (value, _) => self.data_type.encode(value),
+        }
+    }
+
+    /// Parse a value of the field, a name of its value list being kept as `Value::Text`.
+    /// `-` is the replacement value.
+    pub fn parse(&self, text: &str) -> Result<Option<Value>, EncodeError> {
+        let text = text.trim();
+        if text == "-" {
+            return Ok(None);
+        }
+        match &self.values {
+            Some(values) if values.iter().any(|(_, name)| name == text) => Ok(Some(Value::Text(text.to_string()))),
+            _ => self.data_type.parse(text).map(Some),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageDefinition {
+    pub circuit: String,
+    pub name: String,
+    pub kind: MessageKind,
+    /// QQ, any master when `None`
+    pub source: Option<u8>,
+    /// ZZ, any address when `None`
+    pub destination: Option<u8>,
+    pub primary: u8,
+    pub secondary: u8,
+    /// Leading master data bytes identifying the message
+    pub id: Vec<u8>,
+    pub fields: Vec<FieldDefinition>,
+    /// Set when the slave fields span the responses of several telegrams
+    pub paging: Option<Paging>,
+    /// Requirements on the identification of the device, always active when `None`
+    pub condition: Option<Condition>,
+    pub comment: Option<String>,
+    pub location: Option<Location>,
+}
+
+impl MessageDefinition {
+    pub fn new(circuit: &str, name: &str, kind: MessageKind, primary: u8, secondary: u8) -> MessageDefinition {
+        MessageDefinition {
+            circuit: circuit.to_string(),
+            name: name.to_string(),
+            kind,
+            source: None,
+            destination: None,
+            primary,
+            secondary,
+            id: Vec::new(),
+            fields: Vec::new(),
+            paging: None,
+            condition: None,
+            comment: None,
+            location: None,
+        }
+    }
+
+    /// Length of the master payload described by the ID, the page index and the master fields
+    pub fn master_length(&self) -> usize {
+        let index_end = self.paging.as_ref().map_or(0, |p| p.index_offset + 1);
+        self.fields.iter()
+            .filter(|f| f.part == Part::Master)
+            .map(FieldDefinition::end)
+            .fold(self.id.len().max(index_end), usize::max)
+    }
+
+    /// Whether a telegram is an instance of this message
+    pub fn matches(&self, packet: &Packet) -> bool {
+        packet.primary() == self.primary
+            && packet.secondary() == self.secondary
+            && packet.master_payload().starts_with(&self.id)
+            && self.source.is_none_or(|qq| qq == packet.source())
+            && self.destination.is_none_or(|zz| zz == packet.destination())
+    }
+
+    /// Address whose identification is checked by the condition
+    pub fn condition_address(&self) -> Option<u8> {
+        let condition = self.condition.as_ref()?;
+        condition.address.or(match self.kind {
+            MessageKind::Passive if matches!(self.destination.map(AddressClass::of), None | Some(AddressClass::Broadcast)) => self.source,
+            _ => self.destination,
+        })
+    }
+
+    /// Whether the condition of the definition is satisfied by the identifications learned so far
+    pub fn is_active(&self, inventory: &Inventory) -> bool {
+        match &self.condition {
+            None => true,
+            Some(condition) => condition.is_satisfied_by(self.condition_address().and_then(|a| inventory.get(a))),
+        }
+    }
+
+    /// The master fields which values are given to `request`, in order
+    pub fn inputs(&self) -> impl Iterator<Item = &FieldDefinition> {
+        self.fields.iter().filter(|f| f.part == Part::Master && !matches!(f.data_type, DataType::Ignore(_)))
+    }
+
+    /// Build the request carrying the ID and the values of the master fields.
+    /// Bits sharing a byte are combined.
+    pub fn request(&self, source: u8, destination: u8, values: &[Option<Value>]) -> Result<Packet, EncodeError> {
+        if values.len() != self.inputs().count() {
+            return Err(EncodeError::Mismatch);
+        }
+        let mut payload = self.id.clone();
+        payload.resize(self.master_length(), 0x00);
+
+        for (field, value) in self.inputs().zip(values) {
+            let bytes = field.encode(value.as_ref())?;
+            for (i, c) in bytes.into_iter().enumerate() {
+                payload[field.offset + i] |= c;
+            }
+        }
+        Packet::request(source, destination, self.primary, self.secondary, &payload).ok_or(EncodeError::OutOfRange)
+    }
+
+    /// Decode a telegram. A paged message needs all its pages, see `paged::PagedRecord`.
+    pub fn decode(&self, packet: &Packet) -> Result<DecodedMessage, DecodeError> {
+        self.decode_payloads(packet.master_payload(), packet.slave_payload())
+    }
+
+    /// Decode the fields from the master and slave payloads
+    pub fn decode_payloads(&self, master: &[u8], slave: &[u8]) -> Result<DecodedMessage, DecodeError> {
+        let fields = self.fields.iter()
+            .filter(|f| !matches!(f.data_type, DataType::Ignore(_)))
+            .map(|f| {
+                let payload = match f.part {
+                    Part::Master => master,
+                    Part::Slave => slave,
+                };
+                Ok(DecodedField {
+                    name: f.name.clone(),
+                    value: f.decode(payload)?,
+                    unit: f.unit.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, DecodeError>>()?;
+
+        Ok(DecodedMessage {
+            circuit: self.circuit.clone(),
+            name: self.name.clone(),
+            fields,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedField {
+    pub name: String,
+    /// `None` for the replacement value
+    pub value: Option<Value>,
+    pub unit: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedMessage {
+    pub circuit: String,
+    pub name: String,
+    pub fields: Vec<DecodedField>,
+}
+
+/// Set of message definitions, usually loaded from configuration files
+#[derive(Debug, Clone, Default)]
+pub struct Catalogue {
+    definitions: Vec<MessageDefinition>,
+}
+
+impl Catalogue {
+    pub fn new() -> Catalogue {
+        Catalogue::default()
+    }
+
+    pub fn push(&mut self, definition: MessageDefinition) {
+        self.definitions.push(definition);
+    }
+
+    pub fn definitions(&self) -> &[MessageDefinition] {
+        &self.definitions
+    }
+
+    /// Definitions whose condition is satisfied by the identifications learned so far
+    pub fn active<'a>(&'a self, inventory: &'a Inventory) -> impl Iterator<Item = &'a MessageDefinition> {
+        self.definitions.iter().filter(move |d| d.is_active(inventory))
+    }
+
+    /// The active definition describing a telegram.
+    /// The longest ID wins, then a definition which length fits the payload,
+    /// then a conditional definition over a generic one.
+    pub fn find(&self, packet: &Packet, inventory: &Inventory) -> Option<&MessageDefinition> {
+        self.definitions.iter()
+            .filter(|d| d.is_active(inventory))
+            .filter(|d| d.matches(packet))
+            .max_by_key(|d| (d.id.len(), d.master_length() == packet.master_payload().len(), d.condition.is_some()))
+    }
+
+    /// Decode a telegram with its active definition, `None` when no definition matches or when it
+    /// is paged, its pages having to be gathered by `paged::PagedRecords`
+    pub fn decode(&self, packet: &Packet, inventory: &Inventory) -> Option<Result<DecodedMessage, DecodeError>> {
+        self.find(packet, inventory).filter(|d| d.paging.is_none()).map(|d| d.decode(packet))
+    }
+}
+
+impl Extend<MessageDefinition> for Catalogue {
+    fn extend<T: IntoIterator<Item = MessageDefinition>>(&mut self, iter: T) {
+        self.definitions.extend(iter)
+    }
+}
+
+impl FromIterator<MessageDefinition> for Catalogue {
+    fn from_iter<T: IntoIterator<Item = MessageDefinition>>(iter: T) -> Self {
+        Catalogue { definitions: iter.into_iter().collect() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use identification::Identification;
+
+    fn flow_temp(condition: Option<Condition>, name: &str) -> MessageDefinition {
+        let mut definition = MessageDefinition::new("bai", name, MessageKind::Read, 0xb5, 0x09);
+        definition.destination = Some(0x08);
+        definition.id = vec![0x0d, 0x18, 0x00];
+        definition.fields.push(FieldDefinition::new("temp", Part::Slave, 0, DataType::Data2c));
+        definition.condition = condition;
+        definition
+    }
+
+    fn bai_condition() -> Condition {
+        Condition { device_ids: vec!["BAI".to_string()], ..Default::default() }
+    }
+
+    #[test]
+    fn field_divider_and_values() {
+        let mut field = FieldDefinition::new("pressure", Part::Slave, 1, DataType::Uch);
+        field.divider = Some(10.0);
+        assert_eq!(field.decode(&[0x01, 0x0f]), Ok(Some(Value::Float(1.5))));
+        assert_eq!(field.encode(Some(&Value::Float(1.5))), Ok(vec![0x0f]));
+        assert_eq!(field.decode(&[0x01]), Err(DecodeError::Truncated));
+
+        field.divider = Some(-10.0);
+        assert_eq!(field.decode(&[0x01, 0x0f]), Ok(Some(Value::Float(150.0))));
+
+        field.divider = None;
+        field.values = Some(vec![(0, "off".to_string()), (1, "on".to_string())]);
+        assert_eq!(field.decode(&[0x00, 0x01]), Ok(Some(Value::Named(1, "on".to_string()))));
+        assert_eq!(field.encode(Some(&Value::Text("off".to_string()))), Ok(vec![0x00]));
+
+        // rounded half away from zero
+        let temp = FieldDefinition::new("temp", Part::Slave, 0, DataType::Data2c);
+        assert_eq!(temp.encode(Some(&Value::Float(-1.53))), Ok(vec![0xe8, 0xff]));
+        assert_eq!(temp.encode(Some(&Value::Float(-1.5625 - 0.5 / 16.0))), Ok(vec![0xe6, 0xff]));
+        assert_eq!(temp.encode(Some(&Value::Float(1.55))), Ok(vec![0x19, 0x00]));
+    }
+
+    #[test]
+    fn request_from_values() {
+        let mut definition = MessageDefinition::new("hc1", "Program", MessageKind::Write, 0xb5, 0x10);
+        definition.id = vec![0x00];
+        let mut mode = FieldDefinition::new("mode", Part::Master, 1, DataType::Uch);
+        mode.values = Some(vec![(0, "auto".to_string()), (1, "day".to_string())]);
+        definition.fields.push(mode);
+        definition.fields.push(FieldDefinition::new("temp", Part::Master, 2, DataType::Data1c));
+        definition.fields.push(FieldDefinition::new("eco", Part::Master, 3, DataType::Bit(0)));
+        definition.fields.push(FieldDefinition::new("party", Part::Master, 3, DataType::Bit(2)));
+
+        let texts = ["day", "21.5", "1", "true"];
+        let values: Vec<_> = definition.inputs().zip(texts).map(|(f, t)| f.parse(t).unwrap()).collect();
+        let packet = definition.request(0x31, 0x15, &values).unwrap();
+        assert_eq!(packet.master_payload(), &[0x00, 0x01, 0x2b, 0x05]);
+        assert!(packet.is_master_crc_valid());
+
+        assert_eq!(definition.inputs().nth(1).unwrap().parse("-"), Ok(None));
+        assert_eq!(definition.inputs().next().unwrap().parse("night"), Err(EncodeError::Mismatch));
+        assert!(matches!(definition.request(0x31, 0x15, &values[..3]), Err(EncodeError::Mismatch)));
+        assert_eq!(DataType::Time.parse("6:30"), Ok(Value::Time { hour: 6, minute: 30, second: 0 }));
+        assert_eq!(DataType::Date.parse("24.12.2024"), Ok(Value::Date { day: 24, month: 12, year: 2024 }));
+        assert_eq!(DataType::Hex(2).parse("0a ff"), Ok(Value::Bytes(vec![0x0a, 0xff])));
+    }
+
+    #[test]
+    fn decode_with_catalogue() {
+        let catalogue: Catalogue = [flow_temp(None, "FlowTemp")].into_iter().collect();
+        let packet = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap()
+            .with_response(&[0x50, 0x02]).unwrap();
+
+        let message = catalogue.decode(&packet, &Inventory::new()).unwrap().unwrap();
+        assert_eq!(message.name, "FlowTemp");
+        assert_eq!(message.fields[0].value, Some(Value::Float(37.0)));
+    }
+
+    #[test]
+    fn conditional_definition_is_activated_by_identification() {
+        let catalogue: Catalogue = [flow_temp(None, "Generic"), flow_temp(Some(bai_condition()), "Specific")].into_iter().collect();
+        let packet = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap()
+            .with_response(&[0x50, 0x02]).unwrap();
+        let mut inventory = Inventory::new();
+
+        assert_eq!(catalogue.find(&packet, &inventory).unwrap().name, "Generic");
+
+        inventory.insert(0x08, Identification { manufacturer: 0xb5, device_id: "BAI00".to_string(), software: 703, hardware: 7603 });
+        assert_eq!(catalogue.find(&packet, &inventory).unwrap().name, "Specific");
+
+        inventory.insert(0x08, Identification { manufacturer: 0xb5, device_id: "VWZ00".to_string(), software: 703, hardware: 7603 });
+        assert_eq!(catalogue.find(&packet, &inventory).unwrap().name, "Generic");
+    }
+}
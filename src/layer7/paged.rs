@@ -0,0 +1,276 @@
+//! Messages whose value spans the responses of several telegrams, as each payload is limited
+//! to `MAX_NN` bytes (e.g. Vaillant B5 09 register blocks, error histories, timer programs).
+//! Every request carries a page index into its master payload, the slave fields are decoded
+//! over the concatenation of the responses.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+
+use super::types::DecodeError;
+use super::{DecodedMessage, MessageDefinition};
+use crate::layer2::Packet;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paging {
+    /// Position of the page index into the master payload, at or after the message ID
+    pub index_offset: usize,
+    /// Index of the first page
+    pub first: u8,
+    /// Increment of the index from a page to the next one
+    pub step: u8,
+    /// Number of pages
+    pub count: u8,
+    /// Leading bytes of each response which are not part of the record (e.g. an echo of the index)
+    pub skip: usize,
+}
+
+impl Paging {
+    pub fn new(index_offset: usize, count: u8) -> Paging {
+        Paging {
+            index_offset,
+            first: 0,
+            step: 1,
+            count,
+            skip: 0,
+        }
+    }
+
+    /// Index sent to request a page
+    pub fn index(&self, page: u8) -> u8 {
+        self.first.wrapping_add(page.wrapping_mul(self.step))
+    }
+
+    /// Page requested by an index, `None` when the index is out of the sequence
+    pub fn page_of(&self, index: u8) -> Option<u8> {
+        let delta = index.wrapping_sub(self.first);
+        match self.step {
+            0 => None,
+            step if !delta.is_multiple_of(step) => None,
+            step => Some(delta / step).filter(|page| *page < self.count),
+        }
+    }
+}
+
+impl MessageDefinition {
+    /// The requests reading every page of a paged message, in order.
+    /// `None` when the message is not paged, has no page or a request would exceed `MAX_NN`.
+    pub fn page_requests(&self, source: u8, destination: u8) -> Option<Vec<Packet>> {
+        let paging = self.paging.as_ref().filter(|paging| paging.count > 0)?;
+        let mut payload = self.id.clone();
+        payload.resize(self.master_length(), 0x00);
+
+        (0..paging.count)
+            .map(|page| {
+                payload[paging.index_offset] = paging.index(page);
+                Packet::request(source, destination, self.primary, self.secondary, &payload)
+            })
+            .collect()
+    }
+}
+
+/// Responses of a paged message collected until it can be decoded
+#[derive(Debug, Clone)]
+pub struct PagedRecord<'a> {
+    definition: &'a MessageDefinition,
+    paging: &'a Paging,
+    master: Option<Vec<u8>>,
+    pages: Vec<Option<Vec<u8>>>,
+}
+
+impl<'a> PagedRecord<'a> {
+    /// `None` when the message is not paged
+    pub fn new(definition: &'a MessageDefinition) -> Option<PagedRecord<'a>> {
+        let paging = definition.paging.as_ref()?;
+        Some(PagedRecord {
+            definition,
+            paging,
+            master: None,
+            pages: vec![None; paging.count as usize],
+        })
+    }
+
+    /// Store the response of a telegram.
+    /// Return `false` when the telegram is not a page of the message or its response is corrupted.
+    pub fn push(&mut self, packet: &Packet) -> bool {
+        if !self.definition.matches(packet) || !packet.is_slave_crc_valid() {
+            return false;
+        }
+        let page = match packet.master_payload().get(self.paging.index_offset).and_then(|i| self.paging.page_of(*i)) {
+            Some(page) => page,
+            None => return false,
+        };
+        let data = packet.slave_payload().get(self.paging.skip..).unwrap_or_default();
+
+        if page == 0 {
+            self.master = Some(packet.master_payload().to_vec());
+        }
+        self.pages[page as usize] = Some(data.to_vec());
+        true
+    }
+
+    /// Pages which have not been received yet
+    pub fn missing(&self) -> impl Iterator<Item = u8> + '_ {
+        self.pages.iter()
+            .enumerate()
+            .filter(|(_, p)| p.is_none())
+            .map(|(i, _)| i as u8)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pages.iter().all(Option::is_some)
+    }
+
+    /// Concatenation of the responses, `None` until every page has been received
+    pub fn payload(&self) -> Option<Vec<u8>> {
+        self.pages.iter()
+            .map(Option::as_deref)
+            .collect::<Option<Vec<&[u8]>>>()
+            .map(|pages| pages.concat())
+    }
+
+    /// Decode the record, the master fields being read from the request of the first page
+    pub fn decode(&self) -> Result<DecodedMessage, DecodeError> {
+        match (&self.master, self.payload()) {
+            (Some(master), Some(slave)) => self.definition.decode_payloads(master, &slave),
+            _ => Err(DecodeError::Truncated),
+        }
+    }
+}
+
+/// The records of the paged messages seen on the bus, each message being decoded once its
+/// pages have all been received. A record is kept by definition, source and destination.
+#[derive(Debug, Clone, Default)]
+pub struct PagedRecords<'a> {
+    records: Vec<(u8, u8, PagedRecord<'a>)>,
+}
+
+impl<'a> PagedRecords<'a> {
+    pub fn new() -> PagedRecords<'a> {
+        PagedRecords::default()
+    }
+
+    /// Decode a telegram with its definition: at once when the message is not paged, else `None`
+    /// until the telegram brings the last missing page, the record then starting over
+    pub fn decode(&mut self, definition: &'a MessageDefinition, packet: &Packet) -> Option<Result<DecodedMessage, DecodeError>> {
+        if definition.paging.is_none() {
+            return Some(definition.decode(packet));
+        }
+        let key = (packet.source(), packet.destination());
+        let i = match self.records.iter().position(|(source, destination, r)| (*source, *destination) == key && ptr::eq(r.definition, definition)) {
+            Some(i) => i,
+            None => {
+                self.records.push((key.0, key.1, PagedRecord::new(definition)?));
+                self.records.len() - 1
+            },
+        };
+        let record = &mut self.records[i].2;
+        if !record.push(packet) || !record.is_complete() {
+            return None;
+        }
+        Some(self.records.swap_remove(i).2.decode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer7::types::{DataType, Value};
+    use crate::layer7::{FieldDefinition, MessageKind, Part};
+
+    fn timer_program() -> MessageDefinition {
+        // 3 pages of 4 bytes, each response starting with the echo of the page index
+        let mut definition = MessageDefinition::new("hc1", "TimerMonday", MessageKind::Read, 0xb5, 0x55);
+        definition.id = vec![0xa3];
+        definition.paging = Some(Paging { skip: 1, ..Paging::new(1, 3) });
+        for (i, name) in ["from1", "to1", "from2", "to2", "from3", "to3"].iter().enumerate() {
+            definition.fields.push(FieldDefinition::new(name, Part::Slave, i * 2, DataType::Uin));
+        }
+        definition
+    }
+
+    #[test]
+    fn requests_iterate_the_index() {
+        let definition = timer_program();
+        let requests = definition.page_requests(0x10, 0x15).unwrap();
+
+        let payloads: Vec<&[u8]> = requests.iter().map(Packet::master_payload).collect();
+        assert_eq!(payloads, vec![&[0xa3, 0x00][..], &[0xa3, 0x01], &[0xa3, 0x02]]);
+
+        let mut empty = definition.clone();
+        empty.paging.as_mut().unwrap().count = 0;
+        assert!(empty.page_requests(0x10, 0x15).is_none());
+    }
+
+    #[test]
+    fn responses_are_assembled() {
+        let definition = timer_program();
+        let requests = definition.page_requests(0x10, 0x15).unwrap();
+        let mut record = PagedRecord::new(&definition).unwrap();
+
+        let responses: [&[u8]; 3] = [
+            &[0x00, 0x68, 0x01, 0x84, 0x03],
+            &[0x01, 0xff, 0xff, 0xff, 0xff],
+            &[0x02, 0x00, 0x00, 0x00, 0x00],
+        ];
+        // pages may arrive out of order
+        for i in [2, 0] {
+            assert!(record.push(&requests[i].clone().with_response(responses[i]).unwrap()));
+        }
+        assert_eq!(record.missing().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(record.decode(), Err(DecodeError::Truncated));
+
+        assert!(record.push(&requests[1].clone().with_response(responses[1]).unwrap()));
+        assert!(record.is_complete());
+
+        let message = record.decode().unwrap();
+        assert_eq!(message.fields[0].value, Some(Value::Integer(360)));
+        assert_eq!(message.fields[1].value, Some(Value::Integer(900)));
+        assert_eq!(message.fields[2].value, None);
+        assert_eq!(message.fields[5].value, Some(Value::Integer(0)));
+    }
+
+    #[test]
+    fn pages_are_decoded_together() {
+        let definition = timer_program();
+        let not_paged = MessageDefinition::new("hc1", "Mode", MessageKind::Read, 0xb5, 0x55);
+        let responses: [&[u8]; 3] = [
+            &[0x00, 0x68, 0x01, 0x84, 0x03],
+            &[0x01, 0xff, 0xff, 0xff, 0xff],
+            &[0x02, 0x00, 0x00, 0x00, 0x00],
+        ];
+        let pages = |source| -> Vec<Packet> {
+            definition.page_requests(source, 0x15).unwrap().into_iter()
+                .zip(responses)
+                .map(|(request, response)| request.with_response(response).unwrap())
+                .collect()
+        };
+        let (mine, other) = (pages(0x10), pages(0x30));
+        let mut records = PagedRecords::new();
+
+        assert!(records.decode(&definition, &mine[0]).is_none());
+        assert!(records.decode(&not_paged, &mine[0]).is_some());
+        assert!(records.decode(&definition, &mine[1]).is_none());
+        let message = records.decode(&definition, &mine[2]);
+        assert_eq!(message.unwrap().unwrap().fields[0].value, Some(Value::Integer(360)));
+
+        // the next reading starts over
+        assert!(records.decode(&definition, &mine[2]).is_none());
+
+        // the pages read by another master make another record
+        assert!(records.decode(&definition, &other[0]).is_none());
+        assert!(records.decode(&definition, &other[1]).is_none());
+        assert!(records.decode(&definition, &mine[0]).is_none());
+        assert!(records.decode(&definition, &other[2]).is_some());
+        assert!(records.decode(&definition, &mine[1]).is_some());
+    }
+
+    #[test]
+    fn index_out_of_sequence_is_rejected() {
+        let paging = Paging { first: 0x10, step: 2, ..Paging::new(1, 3) };
+        assert_eq!(paging.page_of(0x14), Some(2));
+        assert_eq!(paging.page_of(0x13), None);
+        assert_eq!(paging.page_of(0x16), None);
+        assert_eq!(paging.page_of(0x0e), None);
+    }
+}
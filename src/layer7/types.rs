@@ -0,0 +1,363 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Base data types of the eBUS application layer (see "Spezifikation Anwendungsschicht") plus
+/// the common vendor extensions used by ebusd and csowada configurations.
+/// Multi-bytes integers are little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    /// BCD coded byte, 0 - 99
+    Bcd,
+    /// Signed byte, -127 - 127
+    Data1b,
+    /// Unsigned byte divided by 2, 0 - 100
+    Data1c,
+    /// Signed word divided by 256
+    Data2b,
+    /// Signed word divided by 16
+    Data2c,
+    /// Unsigned byte
+    Uch,
+    /// Signed byte
+    Sch,
+    /// Unsigned word
+    Uin,
+    /// Signed word
+    Sin,
+    /// Unsigned double word
+    Ulg,
+    /// Signed double word
+    Slg,
+    /// Single bit of a byte, 0 being the least significant
+    Bit(u8),
+    /// ASCII string of a fixed length, padded with spaces or zeros
+    Str(u8),
+    /// Raw bytes of a fixed length
+    Hex(u8),
+    /// BCD date `dd mm ww yy`
+    Date,
+    /// BCD time `ss mm hh`
+    Time,
+    /// Bytes of a fixed length which are skipped
+    Ignore(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Text(String),
+    Bytes(Vec<u8>),
+    /// A raw value translated through the value list of its field
+    Named(i64, String),
+    Date { day: u8, month: u8, year: u16 },
+    Time { hour: u8, minute: u8, second: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The payload is shorter than the field layout
+    Truncated,
+    /// The bytes are not a valid value of the data type (e.g. a BCD nibble above 9)
+    Invalid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The value variant does not suit the data type
+    Mismatch,
+    /// The value does not fit into the data type range
+    OutOfRange,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "payload too short"),
+            DecodeError::Invalid => write!(f, "invalid value"),
+        }
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::Mismatch => write!(f, "value does not match the data type"),
+            EncodeError::OutOfRange => write!(f, "value out of range"),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Boolean(b) => write!(f, "{}", if *b { 1 } else { 0 }),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Bytes(b) => b.iter().try_for_each(|c| write!(f, "{:02x}", c)),
+            Value::Named(_, name) => write!(f, "{}", name),
+            Value::Date { day, month, year } => write!(f, "{:02}.{:02}.{:04}", day, month, year),
+            Value::Time { hour, minute, second } => write!(f, "{:02}:{:02}:{:02}", hour, minute, second),
+        }
+    }
+}
+
+/// Round half away from zero, as `f64::round` which is not in `core`
+fn round(x: f64) -> i64 {
+    let truncated = x as i64;
+    let fraction = x - truncated as f64;
+    if fraction >= 0.5 {
+        truncated + 1
+    } else if fraction <= -0.5 {
+        truncated - 1
+    } else {
+        truncated
+    }
+}
+
+fn from_bcd(c: u8) -> Result<u8, DecodeError> {
+    let (high, low) = (c >> 4, c & 0x0f);
+    if high > 9 || low > 9 {
+        return Err(DecodeError::Invalid);
+    }
+    Ok(high * 10 + low)
+}
+
+fn to_bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+impl DataType {
+    /// Number of bytes occupied by the type
+    pub fn len(&self) -> usize {
+        match self {
+            DataType::Bcd | DataType::Data1b | DataType::Data1c | DataType::Uch | DataType::Sch | DataType::Bit(_) => 1,
+            DataType::Data2b | DataType::Data2c | DataType::Uin | DataType::Sin => 2,
+            DataType::Ulg | DataType::Slg | DataType::Date => 4,
+            DataType::Time => 3,
+            DataType::Str(n) | DataType::Hex(n) | DataType::Ignore(n) => *n as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the type decodes to an integer which can be scaled and mapped through a value list
+    pub fn is_numeric(&self) -> bool {
+        !matches!(self, DataType::Str(_) | DataType::Hex(_) | DataType::Date | DataType::Time | DataType::Ignore(_))
+    }
+
+    /// The raw integer of a numeric type, `None` for the replacement value (meaning "no value").
+    /// `Data1c`, `Data2b` and `Data2c` are returned unscaled.
+    pub fn decode_raw(&self, data: &[u8]) -> Result<Option<i64>, DecodeError> {
+        let data = data.get(..self.len()).ok_or(DecodeError::Truncated)?;
+        let raw = match self {
+            DataType::Bcd => match data[0] {
+                0xff => None,
+                c => Some(from_bcd(c)? as i64),
+            },
+            DataType::Data1b | DataType::Sch => match data[0] {
+                0x80 => None,
+                c => Some(c as i8 as i64),
+            },
+            DataType::Data1c | DataType::Uch => match data[0] {
+                0xff => None,
+                c => Some(c as i64),
+            },
+            DataType::Data2b | DataType::Data2c | DataType::Sin => match i16::from_le_bytes([data[0], data[1]]) {
+                i16::MIN => None,
+                v => Some(v as i64),
+            },
+            DataType::Uin => match u16::from_le_bytes([data[0], data[1]]) {
+                u16::MAX => None,
+                v => Some(v as i64),
+            },
+            DataType::Ulg => match u32::from_le_bytes([data[0], data[1], data[2], data[3]]) {
+                u32::MAX => None,
+                v => Some(v as i64),
+            },
+            DataType::Slg => match i32::from_le_bytes([data[0], data[1], data[2], data[3]]) {
+                i32::MIN => None,
+                v => Some(v as i64),
+            },
+            DataType::Bit(n) => Some(((data[0] >> n) & 0x01) as i64),
+            _ => return Err(DecodeError::Invalid),
+        };
+        Ok(raw)
+    }
+
+    /// Scale applied by the type itself on its raw integer
+    fn intrinsic_divider(&self) -> Option<f64> {
+        match self {
+            DataType::Data1c => Some(2.0),
+            DataType::Data2b => Some(256.0),
+            DataType::Data2c => Some(16.0),
+            _ => None,
+        }
+    }
+
+    /// Decode a value, `None` being the replacement value (meaning "no value")
+    pub fn decode(&self, data: &[u8]) -> Result<Option<Value>, DecodeError> {
+        let data = data.get(..self.len()).ok_or(DecodeError::Truncated)?;
+        let value = match self {
+            DataType::Str(_) => {
+                let text: String = data.iter()
+                    .take_while(|&&c| c != 0x00)
+                    .map(|&c| c as char)
+                    .collect();
+                Some(Value::Text(text.trim_end().to_string()))
+            },
+            DataType::Hex(_) => Some(Value::Bytes(data.to_vec())),
+            DataType::Ignore(_) => None,
+            DataType::Date => {
+                if data == [0xff; 4] {
+                    return Ok(None);
+                }
+                Some(Value::Date {
+                    day: from_bcd(data[0])?,
+                    month: from_bcd(data[1])?,
+                    year: 2000 + from_bcd(data[3])? as u16,
+                })
+            },
+            DataType::Time => {
+                if data == [0xff; 3] {
+                    return Ok(None);
+                }
+                Some(Value::Time {
+                    hour: from_bcd(data[2])?,
+                    minute: from_bcd(data[1])?,
+                    second: from_bcd(data[0])?,
+                })
+            },
+            DataType::Bit(_) => self.decode_raw(data)?.map(|v| Value::Boolean(v != 0)),
+            numeric => match (numeric.decode_raw(data)?, numeric.intrinsic_divider()) {
+                (None, _) => None,
+                (Some(raw), None) => Some(Value::Integer(raw)),
+                (Some(raw), Some(divider)) => Some(Value::Float(raw as f64 / divider)),
+            },
+        };
+        Ok(value)
+    }
+
+    /// Encode a raw integer of a numeric type, `None` giving the replacement value.
+    /// `Data1c`, `Data2b` and `Data2c` expect the unscaled integer.
+    pub fn encode_raw(&self, raw: Option<i64>) -> Result<Vec<u8>, EncodeError> {
+        fn check(raw: i64, min: i64, max: i64) -> Result<i64, EncodeError> {
+            if raw < min || raw > max {
+                return Err(EncodeError::OutOfRange);
+            }
+            Ok(raw)
+        }
+
+        let bytes = match (self, raw) {
+            (DataType::Bcd, None) | (DataType::Data1c, None) | (DataType::Uch, None) => vec![0xff],
+            (DataType::Data1b, None) | (DataType::Sch, None) => vec![0x80],
+            (DataType::Data2b, None) | (DataType::Data2c, None) | (DataType::Sin, None) => i16::MIN.to_le_bytes().to_vec(),
+            (DataType::Uin, None) => u16::MAX.to_le_bytes().to_vec(),
+            (DataType::Ulg, None) => u32::MAX.to_le_bytes().to_vec(),
+            (DataType::Slg, None) => i32::MIN.to_le_bytes().to_vec(),
+            (DataType::Bcd, Some(v)) => vec![to_bcd(check(v, 0, 99)? as u8)],
+            (DataType::Data1b, Some(v)) | (DataType::Sch, Some(v)) => vec![check(v, -127, 127)? as i8 as u8],
+            (DataType::Data1c, Some(v)) | (DataType::Uch, Some(v)) => vec![check(v, 0, 254)? as u8],
+            (DataType::Data2b, Some(v)) | (DataType::Data2c, Some(v)) | (DataType::Sin, Some(v)) => (check(v, -32767, 32767)? as i16).to_le_bytes().to_vec(),
+            (DataType::Uin, Some(v)) => (check(v, 0, 65534)? as u16).to_le_bytes().to_vec(),
+            (DataType::Ulg, Some(v)) => (check(v, 0, u32::MAX as i64 - 1)? as u32).to_le_bytes().to_vec(),
+            (DataType::Slg, Some(v)) => (check(v, i32::MIN as i64 + 1, i32::MAX as i64)? as i32).to_le_bytes().to_vec(),
+            (DataType::Bit(n), Some(v)) => vec![(check(v, 0, 1)? as u8) << n],
+            _ => return Err(EncodeError::Mismatch),
+        };
+        Ok(bytes)
+    }
+
+    /// Encode a value, `None` giving the replacement value
+    pub fn encode(&self, value: Option<&Value>) -> Result<Vec<u8>, EncodeError> {
+        match (self, value) {
+            (DataType::Str(n), Some(Value::Text(s))) => {
+                if s.len() > *n as usize || !s.is_ascii() {
+                    return Err(EncodeError::OutOfRange);
+                }
+                let mut bytes = s.as_bytes().to_vec();
+                bytes.resize(*n as usize, b' ');
+                Ok(bytes)
+            },
+            (DataType::Hex(n), Some(Value::Bytes(b))) if b.len() == *n as usize => Ok(b.clone()),
+            (DataType::Hex(_), Some(Value::Bytes(_))) => Err(EncodeError::OutOfRange),
+            (DataType::Ignore(n), _) => Ok(vec![0x00; *n as usize]),
+            (DataType::Date, None) => Ok(vec![0xff; 4]),
+            (DataType::Date, Some(Value::Date { day, month, year })) => {
+                if *day > 31 || *month > 12 || *year < 2000 || *year > 2099 {
+                    return Err(EncodeError::OutOfRange);
+                }
+                // the week day is ignored by the devices
+                Ok(vec![to_bcd(*day), to_bcd(*month), 0x00, to_bcd((*year - 2000) as u8)])
+            },
+            (DataType::Time, None) => Ok(vec![0xff; 3]),
+            (DataType::Time, Some(Value::Time { hour, minute, second })) => {
+                if *hour > 23 || *minute > 59 || *second > 59 {
+                    return Err(EncodeError::OutOfRange);
+                }
+                Ok(vec![to_bcd(*second), to_bcd(*minute), to_bcd(*hour)])
+            },
+            (DataType::Bit(_), Some(Value::Boolean(b))) => self.encode_raw(Some(*b as i64)),
+            (numeric, None) if numeric.is_numeric() => self.encode_raw(None),
+            (numeric, Some(Value::Integer(i))) if numeric.is_numeric() => match numeric.intrinsic_divider() {
+                None => self.encode_raw(Some(*i)),
+                Some(divider) => self.encode_raw(Some(round(*i as f64 * divider))),
+            },
+            (numeric, Some(Value::Float(v))) if numeric.is_numeric() => {
+                let divider = numeric.intrinsic_divider().unwrap_or(1.0);
+                self.encode_raw(Some(round(v * divider)))
+            },
+            (numeric, Some(Value::Named(raw, _))) if numeric.is_numeric() => self.encode_raw(Some(*raw)),
+            _ => Err(EncodeError::Mismatch),
+        }
+    }
+
+    /// Parse a value written as by `Value`'s `Display`: numbers, `0`/`1` bits, hex bytes,
+    /// `dd.mm.yyyy` dates and `hh:mm[:ss]` times
+    pub fn parse(&self, text: &str) -> Result<Value, EncodeError> {
+        fn number<T: core::str::FromStr>(s: &str) -> Result<T, EncodeError> {
+            s.trim().parse().map_err(|_| EncodeError::Mismatch)
+        }
+
+        let text = text.trim();
+        match self {
+            DataType::Str(_) => Ok(Value::Text(text.to_string())),
+            DataType::Hex(_) => {
+                let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+                if !digits.len().is_multiple_of(2) {
+                    return Err(EncodeError::Mismatch);
+                }
+                digits.chunks(2)
+                    .map(|pair| Some((pair[0].to_digit(16)? << 4 | pair[1].to_digit(16)?) as u8))
+                    .collect::<Option<Vec<u8>>>()
+                    .map(Value::Bytes)
+                    .ok_or(EncodeError::Mismatch)
+            },
+            DataType::Date => match text.split('.').collect::<Vec<_>>()[..] {
+                [day, month, year] => Ok(Value::Date { day: number(day)?, month: number(month)?, year: number(year)? }),
+                _ => Err(EncodeError::Mismatch),
+            },
+            DataType::Time => match text.split(':').collect::<Vec<_>>()[..] {
+                [hour, minute] => Ok(Value::Time { hour: number(hour)?, minute: number(minute)?, second: 0 }),
+                [hour, minute, second] => Ok(Value::Time { hour: number(hour)?, minute: number(minute)?, second: number(second)? }),
+                _ => Err(EncodeError::Mismatch),
+            },
+            DataType::Bit(_) => match text {
+                "0" | "false" => Ok(Value::Boolean(false)),
+                "1" | "true" => Ok(Value::Boolean(true)),
+                _ => Err(EncodeError::Mismatch),
+            },
+            DataType::Ignore(_) => Err(EncodeError::Mismatch),
+            _ => match text.parse::<i64>() {
+                Ok(i) => Ok(Value::Integer(i)),
+                Err(_) => number(text).map(Value::Float),
+            },
+        }
+    }
+}
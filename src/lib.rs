@@ -1,7 +1,14 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-#[cfg_attr(all(no_std, not(test)), no_std)]
-
-#[cfg(no_std)]
-extern crate core as std;
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
 pub mod layer2;
+#[cfg(feature = "alloc")]
+pub mod layer7;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "embedded")]
+pub mod embedded;
@@ -0,0 +1,149 @@
+//! A bus shared by async tasks. A blocking task of the tokio runtime owns the transport and the
+//! reader: it publishes the events of the bus and sends the telegrams requested meanwhile.
+
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+
+use super::{Bus, Error, Transport};
+use crate::layer2::{BusEvent, Packet};
+
+/// Events kept for a late subscriber, which then misses the older ones
+pub const EVENT_CAPACITY: usize = 256;
+/// Telegrams waiting to be sent, a request waiting for room beyond
+pub const REQUEST_CAPACITY: usize = 16;
+/// Wait after a failed read before reading again, doubled by each following failure
+pub const MIN_RETRY: Duration = Duration::from_millis(100);
+pub const MAX_RETRY: Duration = Duration::from_secs(5);
+
+/// An event of the bus, or the error of a read such as a failed attempt to reconnect
+pub type Published = Result<BusEvent, Arc<io::Error>>;
+
+type Request = (Packet, oneshot::Sender<Result<Packet, Error>>);
+
+/// A handle on the bus task, cheap to clone
+#[derive(Clone)]
+pub struct BusHandle {
+    events: broadcast::Sender<Published>,
+    requests: mpsc::Sender<Request>,
+}
+
+impl BusHandle {
+    /// Start the bus task, which runs until every handle is dropped or the end of a replayed capture.
+    /// Must be called from a tokio runtime.
+    pub fn spawn<T: Transport + Send + 'static>(transport: T) -> (BusHandle, JoinHandle<()>) {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let (requests, pending) = mpsc::channel(REQUEST_CAPACITY);
+        let publisher = events.clone();
+        let task = task::spawn_blocking(move || run(Bus::new(transport), publisher, pending));
+        (BusHandle { events, requests }, task)
+    }
+
+    /// Receive the events of the bus and the errors of its reads from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Published> {
+        self.events.subscribe()
+    }
+
+    /// Send a telegram built by `Packet::request` once the telegrams requested before are sent,
+    /// and return it completed by its response
+    pub async fn send(&self, request: Packet) -> Result<Packet, Error> {
+        let (respond, response) = oneshot::channel();
+        self.requests.send((request, respond)).await.map_err(|_| stopped())?;
+        response.await.map_err(|_| stopped())?
+    }
+}
+
+fn stopped() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "the bus task has stopped"))
+}
+
+fn run<T: Transport>(mut bus: Bus<T>, events: broadcast::Sender<Published>, mut requests: mpsc::Receiver<Request>) {
+    let mut retry = MIN_RETRY;
+    loop {
+        match requests.try_recv() {
+            Ok((request, respond)) => {
+                let _ = respond.send(bus.send(&request));
+            },
+            Err(TryRecvError::Disconnected) => return,
+            Err(TryRecvError::Empty) => (),
+        }
+
+        // nobody listening is not an error
+        match bus.next_event() {
+            Ok(Some(event)) => {
+                retry = MIN_RETRY;
+                drop(events.send(Ok(event)));
+            },
+            Ok(None) if bus.is_finished() => return,
+            Ok(None) => retry = MIN_RETRY,
+            Err(e) => {
+                drop(events.send(Err(Arc::new(e))));
+                thread::sleep(retry);
+                retry = (retry * 2).min(MAX_RETRY);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::simulator::SimulatedBus;
+
+    #[tokio::test]
+    async fn telegrams_are_sent_and_published() {
+        let request = Packet::request(0x31, 0xf6, 0x50, 0x22, &[0xec, 0x11, 0x00]).unwrap();
+        let transport = SimulatedBus::answering(&request, &[0xbd, 0x00]);
+
+        let (bus, task) = BusHandle::spawn(transport);
+        let mut events = bus.subscribe();
+        let read = bus.send(request).await.unwrap();
+        assert_eq!(read.slave_payload(), &[0xbd, 0x00]);
+
+        match events.recv().await.unwrap().unwrap() {
+            BusEvent::Telegram(telegram) => assert!(telegram.is_slave_crc_valid() && telegram.source() == 0x31),
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        drop((bus, events));
+        task.await.unwrap();
+    }
+
+    struct Unplugged;
+
+    impl Transport for Unplugged {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "adapter unplugged"))
+        }
+
+        fn write(&mut self, _bytes: &[u8]) -> io::Result<()> {
+            Err(io::ErrorKind::NotConnected.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn read_errors_are_published_and_retried_later() {
+        let (bus, task) = BusHandle::spawn(Unplugged);
+        let mut events = bus.subscribe();
+
+        let first = events.recv().await.unwrap().unwrap_err();
+        assert_eq!(first.kind(), io::ErrorKind::NotConnected);
+
+        // 100 ms, then 200 ms before the next attempts
+        thread::sleep(Duration::from_millis(250));
+        let mut retried = 0;
+        while let Ok(published) = events.try_recv() {
+            assert!(published.is_err());
+            retried += 1;
+        }
+        assert!((1..=2).contains(&retried));
+
+        drop((bus, events));
+        task.await.unwrap();
+    }
+}
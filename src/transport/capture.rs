@@ -0,0 +1,226 @@
+//! Capture files of the raw byte stream, to reproduce what a transport received.
+//!
+//! A capture is a text file, one line per chunk read: the seconds elapsed since the start of
+//! the recording then the bytes in hex. Lines starting with `#` are comments.
+//!
+//! ```text
+//! # rebus capture
+//! 0.000000 aa
+//! 0.041802 3108b509030d1800
+//! ```
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Transport;
+use crate::config::{format_hex_bytes, parse_hex_bytes};
+
+pub const HEADER: &str = "# rebus capture";
+
+/// Bytes received together
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// Time elapsed since the start of the recording
+    pub at: Duration,
+    pub bytes: Vec<u8>,
+}
+
+pub fn write_chunk<W: Write>(output: &mut W, chunk: &Chunk) -> io::Result<()> {
+    writeln!(output, "{}.{:06} {}", chunk.at.as_secs(), chunk.at.subsec_micros(), format_hex_bytes(&chunk.bytes, ""))
+}
+
+fn parse_line(line: &str) -> Option<Chunk> {
+    let (at, bytes) = line.split_once(char::is_whitespace)?;
+    let at = at.parse::<f64>().ok().filter(|s| s.is_finite() && *s >= 0.0)?;
+    Some(Chunk {
+        at: Duration::from_secs_f64(at),
+        bytes: parse_hex_bytes(bytes)?,
+    })
+}
+
+/// Parse the chunks of a capture
+pub fn parse(source: &str) -> io::Result<Vec<Chunk>> {
+    source.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| parse_line(line.trim())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: invalid chunk `{}`", i + 1, line))))
+        .collect()
+}
+
+/// A transport whose received bytes are written to a capture
+pub struct Recorder<T, W: Write> {
+    inner: T,
+    output: W,
+    start: Instant,
+}
+
+impl<T: Transport, W: Write> Recorder<T, W> {
+    pub fn new(inner: T, mut output: W) -> io::Result<Recorder<T, W>> {
+        writeln!(output, "{}", HEADER)?;
+        Ok(Recorder {
+            inner,
+            output,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.output)
+    }
+}
+
+impl<T: Transport, W: Write> Transport for Recorder<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            write_chunk(&mut self.output, &Chunk { at: self.start.elapsed(), bytes: buf[..n].to_vec() })?;
+            self.output.flush()?;
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write(bytes)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.inner.is_finished()
+    }
+
+    fn arbitrates(&self) -> bool {
+        self.inner.arbitrates()
+    }
+
+    fn start_arbitration(&mut self, master: u8) -> io::Result<()> {
+        self.inner.start_arbitration(master)
+    }
+
+    fn take_arbitration(&mut self) -> Option<u8> {
+        self.inner.take_arbitration()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pacing {
+    /// Every chunk is available immediately
+    Fast,
+    /// Chunks are available at the time they were recorded
+    Original,
+}
+
+/// A read-only transport giving back the chunks of a capture.
+/// It `is_finished` once every chunk has been read, reading further failing with
+/// `io::ErrorKind::UnexpectedEof`.
+pub struct Replay {
+    chunks: VecDeque<Chunk>,
+    pacing: Pacing,
+    start: Option<Instant>,
+}
+
+impl Replay {
+    pub fn new(chunks: Vec<Chunk>, pacing: Pacing) -> Replay {
+        Replay {
+            chunks: chunks.into(),
+            pacing,
+            start: None,
+        }
+    }
+
+    pub fn open(path: &Path, pacing: Pacing) -> io::Result<Replay> {
+        Ok(Replay::new(parse(&fs::read_to_string(path)?)?, pacing))
+    }
+}
+
+impl Transport for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(chunk) = self.chunks.front_mut() else {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of the capture"));
+        };
+        if self.pacing == Pacing::Original {
+            let start = *self.start.get_or_insert_with(Instant::now);
+            thread::sleep((start + chunk.at).saturating_duration_since(Instant::now()));
+        }
+
+        let n = chunk.bytes.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk.bytes[..n]);
+        chunk.bytes.drain(..n);
+        if chunk.bytes.is_empty() {
+            self.chunks.pop_front();
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, _bytes: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "a capture cannot be written to"))
+    }
+
+    fn is_finished(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer2::{BusEvent, EBUS_SYN};
+    use crate::transport::Bus;
+
+    /// >31f6502203ec110087<0002bd0032>00, broken into several chunks
+    const CAPTURE: &str = "# rebus capture
+0.000000 aa
+0.041802 31f6502203
+0.052000 ec110087
+0.080500 0002bd003200
+0.095000 aa
+";
+
+    #[test]
+    fn recorded_bytes_are_replayed() {
+        let mut recorder = Recorder::new(Replay::new(parse(CAPTURE).unwrap(), Pacing::Fast), Vec::new()).unwrap();
+        let mut buf = [0; 4];
+        while recorder.read(&mut buf).is_ok() {}
+
+        let (_, output) = recorder.into_inner();
+        let recorded = parse(&String::from_utf8(output).unwrap()).unwrap();
+        let bytes: Vec<u8> = recorded.iter().flat_map(|c| c.bytes.clone()).collect();
+        assert_eq!(bytes, parse(CAPTURE).unwrap().iter().flat_map(|c| c.bytes.clone()).collect::<Vec<u8>>());
+        assert!(recorded.windows(2).all(|w| w[0].at <= w[1].at));
+    }
+
+    #[test]
+    fn replay_keeps_the_original_timing() {
+        let mut bus = Bus::new(Replay::new(parse(CAPTURE).unwrap(), Pacing::Original));
+        let start = Instant::now();
+
+        let event = loop {
+            if let Some(event) = bus.next_event().unwrap() {
+                break event;
+            }
+        };
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        match event {
+            BusEvent::Telegram(packet) => assert_eq!(packet.slave_payload(), &[0xbd, 0x00]),
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        assert!(bus.next_event().unwrap().is_none());
+        assert!(bus.is_finished());
+        assert!(bus.next_event().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_line_is_located() {
+        let error = parse("# rebus capture\n0.1 aa\nnow 31f6\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 3: invalid chunk `now 31f6`");
+        assert_eq!(parse("0.5 aa").unwrap(), vec![Chunk { at: Duration::from_millis(500), bytes: vec![EBUS_SYN] }]);
+    }
+}
@@ -0,0 +1,175 @@
+//! Logs and dumps of ebusd, read as captures to analyse a bus without recording it again.
+//!
+//! With `--lograwdata`, ebusd logs the received bytes one by one or whole telegrams, `<` marking
+//! what was received and `>` what was sent:
+//!
+//! ```text
+//! 2024-01-15 10:23:45.120 [bus notice] <aa
+//! 2024-01-15 10:23:45.161 [bus notice] <31f6502203ec110087<0002bd0032>00
+//! ```
+//!
+//! A dump (`--dump`) holds the received bytes as is.
+
+use std::io;
+use std::time::Duration;
+
+use super::capture::Chunk;
+use crate::config::parse_hex_bytes;
+use crate::layer2::EBUS_SYN;
+
+/// Time since the epoch of a `YYYY-MM-DD HH:MM:SS.fff` timestamp
+fn parse_timestamp(date: &str, time: &str) -> Option<Duration> {
+    let mut date = date.splitn(3, '-').map(|n| n.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut time = time.splitn(3, ':');
+    let (hours, minutes) = (time.next()?.parse::<u32>().ok()?, time.next()?.parse::<u32>().ok()?);
+    let seconds = time.next()?.parse::<f64>().ok().filter(|s| s.is_finite() && *s >= 0.0)?;
+
+    // days since 1970-01-01 in the proleptic Gregorian calendar
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let whole = u64::try_from(days * 86400 + (hours * 3600 + minutes * 60) as i64).ok()?;
+    Some(Duration::from_secs(whole) + Duration::from_secs_f64(seconds))
+}
+
+/// The time and the bytes of a line of raw data
+type RawData = (Option<Duration>, Vec<u8>);
+
+/// The raw data of a line, `None` when it has none
+fn parse_raw_data(line: &str) -> Option<Result<RawData, ()>> {
+    let start = line.find("[bus ")?;
+    let (_, data) = line[start..].split_once(']')?;
+    let data = data.trim();
+    if !data.starts_with(['<', '>']) {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    for segment in data.split(['<', '>']).filter(|s| !s.is_empty()) {
+        let Some(bytes) = parse_hex_bytes(segment.trim()) else {
+            return Some(Err(()));
+        };
+        segments.push(bytes);
+    }
+    // a byte sent alone comes back as a received one
+    let sent_alone = data.starts_with('>') && segments.len() == 1 && segments[0].len() == 1;
+    let bytes = if sent_alone { Vec::new() } else { segments.concat() };
+
+    let mut prefix = line[..start].split_whitespace();
+    let at = match (prefix.next(), prefix.next()) {
+        (Some(date), Some(time)) => parse_timestamp(date, time),
+        _ => None,
+    };
+    Some(Ok((at, bytes)))
+}
+
+/// Read the raw data logged by ebusd as the chunks of a capture, the other lines being skipped.
+/// A line of several bytes is a whole telegram, separated by a SYN when the log lacks it.
+pub fn parse_log(source: &str) -> io::Result<Vec<Chunk>> {
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut start = None;
+    for (i, line) in source.lines().enumerate() {
+        let Some(parsed) = parse_raw_data(line) else {
+            continue;
+        };
+        let (at, mut bytes) = parsed
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: invalid raw data `{}`", i + 1, line)))?;
+        if bytes.is_empty() {
+            continue;
+        }
+
+        let last = chunks.last().and_then(|c| c.bytes.last().copied());
+        if bytes.len() > 1 && bytes[0] != EBUS_SYN && last.is_none_or(|c| c != EBUS_SYN) {
+            bytes.insert(0, EBUS_SYN);
+        }
+        let at = match at {
+            Some(at) => at.saturating_sub(*start.get_or_insert(at)),
+            None => chunks.last().map(|c| c.at).unwrap_or_default(),
+        };
+        chunks.push(Chunk { at, bytes });
+    }
+    Ok(chunks)
+}
+
+/// Read a dump of ebusd, its timing being lost
+pub fn parse_dump(dump: &[u8]) -> Vec<Chunk> {
+    if dump.is_empty() {
+        return Vec::new();
+    }
+    vec![Chunk { at: Duration::ZERO, bytes: dump.to_vec() }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer2::reader::BusReader;
+    use crate::layer2::BusEvent;
+
+    fn telegrams(chunks: &[Chunk]) -> Vec<BusEvent> {
+        let mut reader = BusReader::new();
+        chunks.iter()
+            .flat_map(|c| c.bytes.clone())
+            .chain([EBUS_SYN])
+            .filter_map(|c| reader.read_byte(c))
+            .collect()
+    }
+
+    #[test]
+    fn raw_data_of_logs_is_read() {
+        let log = "2024-01-15 10:23:45.000 [main notice] ebusd 23.3 started with auto scan
+2024-01-15 10:23:45.120 [bus notice] <aa
+2024-01-15 10:23:45.130 [bus notice] >10
+2024-01-15 10:23:45.131 [bus notice] <10
+2024-01-15 10:23:45.135 [bus notice] <fe
+2024-01-15 10:23:45.140 [bus notice] <07
+2024-01-15 10:23:45.145 [bus notice] <00
+2024-01-15 10:23:45.150 [bus notice] <00
+2024-01-15 10:23:45.155 [bus notice] <13
+2024-01-15 10:23:45.160 [bus notice] <aa
+2024-01-15 10:23:45.161 [bus notice] <31f6502203ec110087<0002bd0032>00
+2024-01-15 10:23:45.170 [update notice] received read bai Status01
+2024-01-15 10:23:46.500 [bus notice] >1008b509030d1800a6<00024002e6>00
+";
+        let chunks = parse_log(log).unwrap();
+        assert_eq!(chunks[0], Chunk { at: Duration::ZERO, bytes: vec![EBUS_SYN] });
+        // the byte sent is only read once, from its echo
+        assert_eq!(chunks[1], Chunk { at: Duration::from_millis(11), bytes: vec![0x10] });
+        assert_eq!(chunks.last().unwrap().at, Duration::from_millis(1380));
+        assert_eq!(chunks.last().unwrap().bytes[0], EBUS_SYN);
+
+        let events = telegrams(&chunks);
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], BusEvent::Telegram(packet) if packet.destination() == 0xfe && packet.is_master_crc_valid()));
+        assert!(matches!(&events[1], BusEvent::Telegram(packet) if packet.slave_payload() == [0xbd, 0x00] && packet.is_slave_crc_valid()));
+        assert!(matches!(&events[2], BusEvent::Telegram(packet) if packet.source() == 0x10 && packet.slave_payload() == [0x40, 0x02]));
+    }
+
+    #[test]
+    fn telegram_lines_are_read_without_a_syn_before() {
+        let log = "[bus notice] <31f6502203ec110087<0002bd0032>00\n[bus notice] <31f6502203ec110087<0002bd0032>00\n";
+        let events = telegrams(&parse_log(log).unwrap());
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| matches!(e, BusEvent::Telegram(packet) if packet.is_slave_crc_valid())));
+    }
+
+    #[test]
+    fn invalid_raw_data_is_located() {
+        let error = parse_log("[bus notice] <aa\n[bus notice] <31zz\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: invalid raw data `[bus notice] <31zz`");
+        assert_eq!(parse_timestamp("2000-03-01", "00:00:01.5"), Some(Duration::from_millis(951868801500)));
+    }
+
+    #[test]
+    fn dumps_are_read_as_is() {
+        let dump = [EBUS_SYN, 0x10, 0xfe, 0x07, 0x00, 0x00, 0x13, EBUS_SYN];
+        assert_eq!(parse_dump(&dump)[0].bytes, dump);
+        assert!(parse_dump(&[]).is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+//! ebusd "enhanced" adapter protocol (adapter v5, ebusd-esp), where the adapter arbitrates by
+//! itself. Bytes below `0x80` are exchanged as is, any other symbol takes 2 bytes:
+//! `11cccc dd` then `10dddddd`, `cccc` being the command and `dddddddd` its data.
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use arrayvec::ArrayVec;
+
+use super::Transport;
+
+/// Speed of the serial adapters speaking the enhanced protocol
+pub const BAUD_RATE: u32 = 9600;
+/// How long the adapter may take to answer the initialization
+pub const INIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Host to adapter: initialize, the data being the requested features
+pub const INIT: u8 = 0x0;
+/// Host to adapter: send a byte on the bus
+pub const SEND: u8 = 0x1;
+/// Host to adapter: arbitrate with the master address at the next SYN (`0xaa` cancels)
+pub const START: u8 = 0x2;
+/// Both ways: request or report an information
+pub const INFO: u8 = 0x3;
+/// Adapter to host: reset done, the data being the supported features
+pub const RESETTED: u8 = 0x0;
+/// Adapter to host: a byte has been received from the bus
+pub const RECEIVED: u8 = 0x1;
+/// Adapter to host: the arbitration has been won for this master address
+pub const STARTED: u8 = 0x2;
+/// Adapter to host: the arbitration has been lost to this master address
+pub const FAILED: u8 = 0xa;
+/// Adapter to host: error on the bus side (`0x00` framing, `0x01` overrun)
+pub const ERROR_EBUS: u8 = 0xb;
+/// Adapter to host: error on the host side (`0x00` framing, `0x01` overrun)
+pub const ERROR_HOST: u8 = 0xc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub command: u8,
+    pub data: u8,
+}
+
+impl Frame {
+    pub fn new(command: u8, data: u8) -> Frame {
+        Frame { command, data }
+    }
+
+    /// A byte sent or received, the short form being used when possible
+    pub fn byte(data: u8) -> Frame {
+        Frame::new(SEND, data)
+    }
+
+    pub fn encode(&self) -> ArrayVec<u8, 2> {
+        let mut bytes = ArrayVec::new();
+        if self.command == SEND && self.data < 0x80 {
+            bytes.push(self.data);
+        } else {
+            bytes.push(0xc0 | (self.command & 0x0f) << 2 | self.data >> 6);
+            bytes.push(0x80 | (self.data & 0x3f));
+        }
+        bytes
+    }
+}
+
+/// Reassemble the frames from the bytes of a stream. A broken 2 bytes sequence is dropped.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    first: Option<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    pub fn push(&mut self, c: u8) -> Option<Frame> {
+        match (c & 0xc0, self.first.take()) {
+            (0xc0, _) => {
+                self.first = Some(c);
+                None
+            },
+            (0x80, Some(first)) => Some(Frame::new((first >> 2) & 0x0f, (first & 0x03) << 6 | (c & 0x3f))),
+            (0x80, None) => None,
+            _ => Some(Frame::byte(c)),
+        }
+    }
+}
+
+/// An adapter speaking the enhanced protocol over a serial or TCP transport
+pub struct EnhancedTransport<T> {
+    inner: T,
+    decoder: Decoder,
+    frames: VecDeque<Frame>,
+    arbitration: Option<u8>,
+    features: Option<u8>,
+}
+
+impl<T: Transport> EnhancedTransport<T> {
+    pub fn new(inner: T) -> EnhancedTransport<T> {
+        EnhancedTransport {
+            inner,
+            decoder: Decoder::new(),
+            frames: VecDeque::new(),
+            arbitration: None,
+            features: None,
+        }
+    }
+
+    /// Reset the adapter and return the features it supports
+    pub fn init(&mut self) -> io::Result<u8> {
+        self.inner.write(&Frame::new(INIT, 0x00).encode())?;
+        let deadline = Instant::now() + INIT_TIMEOUT;
+        while Instant::now() < deadline {
+            self.fill()?;
+            while let Some(frame) = self.frames.pop_front() {
+                if frame.command == RESETTED {
+                    self.features = Some(frame.data);
+                    return Ok(frame.data);
+                }
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no answer to the initialization of the adapter"))
+    }
+
+    /// Features reported by the adapter on its last reset
+    pub fn features(&self) -> Option<u8> {
+        self.features
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let mut buf = [0; 64];
+        let n = self.inner.read(&mut buf)?;
+        self.frames.extend(buf[..n].iter().filter_map(|c| self.decoder.push(*c)));
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for EnhancedTransport<T> {
+    /// Read the bytes received from the bus, a read stopping after the result of an arbitration
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.frames.is_empty() {
+            if let Err(e) = self.fill() {
+                self.decoder = Decoder::new();
+                return Err(e);
+            }
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            let Some(frame) = self.frames.front().copied() else {
+                break;
+            };
+            match frame.command {
+                RECEIVED => buf[n] = frame.data,
+                STARTED | FAILED => {
+                    self.frames.pop_front();
+                    buf[n] = frame.data;
+                    self.arbitration = Some(frame.data);
+                    return Ok(n + 1);
+                },
+                ERROR_EBUS | ERROR_HOST if n > 0 => break,
+                ERROR_EBUS | ERROR_HOST => {
+                    self.frames.pop_front();
+                    let side = if frame.command == ERROR_EBUS { "bus" } else { "host" };
+                    let error = if frame.data == 0x00 { "framing" } else { "overrun" };
+                    return Err(io::Error::other(format!("adapter reports a {} error on the {} side", error, side)));
+                },
+                RESETTED => {
+                    self.features = Some(frame.data);
+                    self.frames.pop_front();
+                    continue;
+                },
+                _ => {
+                    self.frames.pop_front();
+                    continue;
+                },
+            }
+            self.frames.pop_front();
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let encoded: Vec<u8> = bytes.iter().flat_map(|c| Frame::byte(*c).encode()).collect();
+        self.inner.write(&encoded)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.inner.is_finished()
+    }
+
+    fn arbitrates(&self) -> bool {
+        true
+    }
+
+    fn start_arbitration(&mut self, master: u8) -> io::Result<()> {
+        self.arbitration = None;
+        self.inner.write(&Frame::new(START, master).encode())
+    }
+
+    fn take_arbitration(&mut self) -> Option<u8> {
+        self.arbitration.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer2::encoder::{encode_master, encode_slave};
+    use crate::layer2::{BusEvent, Packet, EBUS_ACKOK, EBUS_SYN};
+    use crate::transport::tcp::TcpTransport;
+    use crate::transport::Bus;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn frames_are_encoded_and_decoded() {
+        assert_eq!(Frame::byte(0x31).encode().as_slice(), &[0x31]);
+        assert_eq!(Frame::byte(EBUS_SYN).encode().as_slice(), &[0xc6, 0xaa]);
+        assert_eq!(Frame::new(START, 0x31).encode().as_slice(), &[0xc8, 0xb1]);
+
+        let mut decoder = Decoder::new();
+        let bytes = [0x31, 0xc6, 0xaa, 0xe8, 0x90, 0x80, 0xc8, 0x10, 0xc0, 0x81];
+        let frames: Vec<Frame> = bytes.iter().filter_map(|c| decoder.push(*c)).collect();
+        assert_eq!(frames, vec![
+            Frame::byte(0x31),
+            Frame::new(RECEIVED, EBUS_SYN),
+            Frame::new(FAILED, 0x10),
+            // the orphan `0x80` is dropped, so is the `0xc8` interrupted by a short byte
+            Frame::byte(0x10),
+            Frame::new(RESETTED, 0x01),
+        ]);
+    }
+
+    /// An adapter on the other end of the connection: it loses the first arbitration to
+    /// master `0x10`, echoes every byte sent and answers as a slave once the master part is over
+    fn fake_adapter(mut stream: TcpStream, master_length: usize, answer: Vec<u8>) -> Vec<Frame> {
+        let mut decoder = Decoder::new();
+        let mut requests = Vec::new();
+        let mut sent = 0;
+        let mut byte = [0];
+        while stream.read(&mut byte).unwrap() > 0 {
+            let Some(frame) = decoder.push(byte[0]) else {
+                continue;
+            };
+            requests.push(frame);
+            let reply: Vec<Frame> = match (frame.command, frame.data) {
+                (INIT, _) => vec![Frame::new(RESETTED, 0x01)],
+                (START, _) if requests.iter().filter(|f| f.command == START).count() == 1 => {
+                    vec![Frame::byte(EBUS_SYN), Frame::new(FAILED, 0x10)]
+                },
+                (START, master) => vec![Frame::byte(EBUS_SYN), Frame::new(STARTED, master)],
+                (SEND, EBUS_SYN) if sent >= master_length => {
+                    stream.write_all(&Frame::byte(EBUS_SYN).encode()).unwrap();
+                    return requests;
+                },
+                (SEND, c) => {
+                    sent += 1;
+                    let mut reply = vec![Frame::byte(c)];
+                    if sent + 1 == master_length {
+                        reply.extend(answer.iter().map(|c| Frame::byte(*c)));
+                    }
+                    reply
+                },
+                _ => vec![],
+            };
+            let bytes: Vec<u8> = reply.iter().flat_map(Frame::encode).collect();
+            stream.write_all(&bytes).unwrap();
+        }
+        requests
+    }
+
+    #[test]
+    fn arbitration_is_left_to_the_adapter() {
+        let request = Packet::request(0x31, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap();
+        let master_length = encode_master(&request).len();
+        let answer = [&[EBUS_ACKOK][..], &encode_slave(&request.clone().with_response(&[0x40, 0x02]).unwrap())].concat();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let adapter = thread::spawn(move || fake_adapter(listener.accept().unwrap().0, master_length, answer));
+
+        let mut transport = EnhancedTransport::new(TcpTransport::connect(&address).unwrap());
+        assert_eq!(transport.init().unwrap(), 0x01);
+        let mut bus = Bus::new(transport);
+        let response = bus.send(&request).unwrap();
+        assert_eq!(response.slave_payload(), &[0x40, 0x02]);
+
+        // the address of our master is sent by the adapter, not by us
+        let requests = adapter.join().unwrap();
+        let starts: Vec<&Frame> = requests.iter().filter(|f| f.command == START).collect();
+        assert_eq!(starts, vec![&Frame::new(START, 0x31), &Frame::new(START, 0x31)]);
+        assert!(!requests.contains(&Frame::byte(0x31)));
+
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = bus.next_event() {
+            events.push(event);
+        }
+        assert!(events.iter().any(|e| matches!(e, BusEvent::Telegram(read) if read.source() == 0x31 && read.is_slave_crc_valid())));
+    }
+}
@@ -0,0 +1,279 @@
+//! Connections to the bus. A transport only moves bytes, `Bus` pumps them through the reader
+//! and drives the sender when a telegram has to be sent.
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod capture;
+pub mod ebusd;
+pub mod enhanced;
+pub mod pcap;
+pub mod serial;
+#[cfg(test)]
+pub(crate) mod simulator;
+pub mod tcp;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::config::native::TransportConfig;
+use enhanced::EnhancedTransport;
+use crate::layer2::reader::BusReader;
+use crate::layer2::sender::{Action, SendError, Sender};
+use crate::layer2::{BusEvent, Packet};
+
+/// Longest silence tolerated while sending, the bus master generates a SYN every few tens of ms
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub trait Transport {
+    /// Read the bytes received so far, waiting up to the read timeout of the transport.
+    /// `Ok(0)` when nothing has been received meanwhile.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write bytes to the bus
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// `false` once the connection is lost, until it is restored by a later read
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /// `true` once a finite source, such as a replayed capture, has given all its bytes
+    fn is_finished(&self) -> bool {
+        false
+    }
+
+    /// Whether the adapter arbitrates by itself, `Bus::send` then relies on `start_arbitration`
+    fn arbitrates(&self) -> bool {
+        false
+    }
+
+    /// Ask the adapter to take the bus for a master at the next SYN. A later read ends with
+    /// the address of the master which won, `take_arbitration` then returning it.
+    fn start_arbitration(&mut self, _master: u8) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// The winner of the arbitration when the last read ended with it
+    fn take_arbitration(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read(buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        (**self).write(bytes)
+    }
+
+    fn is_connected(&self) -> bool {
+        (**self).is_connected()
+    }
+
+    fn is_finished(&self) -> bool {
+        (**self).is_finished()
+    }
+
+    fn arbitrates(&self) -> bool {
+        (**self).arbitrates()
+    }
+
+    fn start_arbitration(&mut self, master: u8) -> io::Result<()> {
+        (**self).start_arbitration(master)
+    }
+
+    fn take_arbitration(&mut self) -> Option<u8> {
+        (**self).take_arbitration()
+    }
+}
+
+/// Open the transport described by the configuration, an enhanced adapter being initialized. The
+/// transport can be moved to another thread, such as the task of an async bus handle.
+pub fn open(config: &TransportConfig) -> io::Result<Box<dyn Transport + Send>> {
+    Ok(match config {
+        TransportConfig::Serial { device, enhanced: false } => Box::new(serial::SerialTransport::open(device)?),
+        TransportConfig::Serial { device, enhanced: true } => {
+            let mut transport = EnhancedTransport::new(serial::SerialTransport::open_at(device, enhanced::BAUD_RATE)?);
+            transport.init()?;
+            Box::new(transport)
+        },
+        TransportConfig::Tcp { host, port, enhanced } => {
+            let transport = tcp::TcpTransport::connect(&format!("{}:{}", host, port))?;
+            if *enhanced {
+                let mut transport = EnhancedTransport::new(transport);
+                transport.init()?;
+                Box::new(transport)
+            } else {
+                Box::new(transport)
+            }
+        },
+    })
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Send(SendError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Send(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<SendError> for Error {
+    fn from(e: SendError) -> Error {
+        Error::Send(e)
+    }
+}
+
+/// A transport with the reader of its byte stream
+pub struct Bus<T> {
+    transport: T,
+    reader: BusReader,
+    events: VecDeque<BusEvent>,
+    connected: bool,
+}
+
+impl<T: Transport> Bus<T> {
+    pub fn new(transport: T) -> Bus<T> {
+        Bus {
+            connected: transport.is_connected(),
+            transport,
+            reader: BusReader::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        for c in bytes {
+            self.events.extend(self.reader.read_byte(*c));
+        }
+    }
+
+    /// Report the changes of the connection of the transport
+    fn track_connection(&mut self) {
+        match (self.connected, self.transport.is_connected()) {
+            (true, false) => {
+                self.reader = BusReader::new();
+                self.events.push_back(BusEvent::ConnectionLost);
+            },
+            (false, true) => self.events.push_back(BusEvent::ConnectionRestored),
+            _ => (),
+        }
+        self.connected = self.transport.is_connected();
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.transport.read(buf);
+        self.track_connection();
+        result
+    }
+
+    fn write(&mut self, c: u8) -> io::Result<()> {
+        let result = self.transport.write(&[c]);
+        self.track_connection();
+        result
+    }
+
+    /// Whether every event of a finite transport has been read
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty() && self.transport.is_finished()
+    }
+
+    /// Wait for the next event of the bus, `Ok(None)` when the read timeout of the transport expires
+    /// first or once the bus `is_finished`.
+    /// The error losing the connection is reported as `BusEvent::ConnectionLost`, the errors
+    /// of the attempts to restore it are returned.
+    pub fn next_event(&mut self) -> io::Result<Option<BusEvent>> {
+        if self.is_finished() {
+            return Ok(None);
+        }
+        if self.events.is_empty() {
+            let mut buf = [0; 64];
+            match self.read(&mut buf) {
+                Ok(n) => self.feed(&buf[..n]),
+                Err(e) if self.events.is_empty() => return Err(e),
+                Err(_) => (),
+            }
+        }
+        Ok(self.events.pop_front())
+    }
+
+    /// Carry out what the sender asks for, `Some` with the result once the exchange is over
+    fn act(&mut self, action: Action) -> Result<Option<Packet>, Error> {
+        match action {
+            Action::Wait => Ok(None),
+            Action::Write(c) => Ok(self.write(c).map(|_| None)?),
+            Action::Arbitrate(master) => {
+                let result = self.transport.start_arbitration(master);
+                self.track_connection();
+                Ok(result.map(|_| None)?)
+            },
+            Action::Done(result) => Ok(Some(result?)),
+        }
+    }
+
+    /// Send a telegram built by `Packet::request` and return it completed by its response.
+    /// The events read meanwhile, the telegram itself included, are kept for `next_event`.
+    pub fn send(&mut self, request: &Packet) -> Result<Packet, Error> {
+        let mut sender = if self.transport.arbitrates() {
+            Sender::delegating_arbitration(request)
+        } else {
+            Sender::new(request)
+        };
+        let mut buf = [0; 64];
+        let mut last_received = Instant::now();
+
+        let action = sender.start();
+        self.act(action)?;
+        loop {
+            let n = self.read(&mut buf)?;
+            if n > 0 {
+                last_received = Instant::now();
+            } else if last_received.elapsed() > SEND_TIMEOUT {
+                return Err(SendError::Timeout.into());
+            }
+            let winner = self.transport.take_arbitration();
+
+            for (i, c) in buf[..n].iter().enumerate() {
+                self.events.extend(self.reader.read_byte(*c));
+                let action = match winner {
+                    Some(winner) if i + 1 == n => sender.on_arbitration(winner),
+                    _ => sender.on_byte(*c),
+                };
+                match self.act(action) {
+                    Ok(None) => (),
+                    result => {
+                        self.feed(&buf[i + 1..n]);
+                        return result.map(Option::unwrap);
+                    },
+                }
+            }
+        }
+    }
+}
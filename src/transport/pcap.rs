@@ -0,0 +1,411 @@
+//! pcapng files of telegrams, to open traces with standard packet tools.
+//!
+//! Every telegram is an enhanced packet holding its bytes as transmitted, from QQ to the last
+//! acknowledge (SYN excluded), on an interface of a user link type. The direction and the CRC
+//! errors are given by the packet flags.
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::capture::Chunk;
+use super::Transport;
+use crate::layer2::encoder::{encode_master, encode_slave};
+use crate::layer2::reader::BusReader;
+use crate::layer2::{AddressClass, BusEvent, Packet, TelegramComponent, EBUS_ACKKO, EBUS_ACKOK, EBUS_SYN};
+
+/// `LINKTYPE_USER0`, eBUS having no link type of its own
+pub const LINKTYPE: u16 = 147;
+
+const SECTION_HEADER: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const OPTION_END: u16 = 0;
+const OPTION_TSRESOL: u16 = 9;
+const OPTION_FLAGS: u16 = 2;
+const FLAG_INBOUND: u32 = 0b01;
+const FLAG_OUTBOUND: u32 = 0b10;
+const FLAG_CRC_ERROR: u32 = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by another master
+    Inbound,
+    /// Sent by us
+    Outbound,
+}
+
+/// The bytes of a telegram as transmitted, with the acknowledges read
+fn telegram_bytes(packet: &Packet, refused_at: Option<TelegramComponent>) -> Vec<u8> {
+    let mut bytes = encode_master(packet).to_vec();
+    if AddressClass::of(packet.destination()) == AddressClass::Broadcast {
+        return bytes;
+    }
+    if refused_at == Some(TelegramComponent::SlaveACK) {
+        bytes.push(EBUS_ACKKO);
+        return bytes;
+    }
+    bytes.push(EBUS_ACKOK);
+    if matches!(AddressClass::of(packet.destination()), AddressClass::Slave | AddressClass::MasterSlave(_)) {
+        bytes.extend(encode_slave(packet));
+        bytes.push(if refused_at.is_some() { EBUS_ACKKO } else { EBUS_ACKOK });
+    }
+    bytes
+}
+
+fn padding(length: usize) -> usize {
+    (4 - length % 4) % 4
+}
+
+pub struct PcapWriter<W: Write> {
+    output: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the section header and the interface description
+    pub fn new(mut output: W) -> io::Result<PcapWriter<W>> {
+        let mut section = Vec::new();
+        section.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend(1u16.to_le_bytes());
+        section.extend(0u16.to_le_bytes());
+        section.extend((-1i64).to_le_bytes());
+        write_block(&mut output, SECTION_HEADER, &section)?;
+
+        let mut interface = Vec::new();
+        interface.extend(LINKTYPE.to_le_bytes());
+        interface.extend(0u16.to_le_bytes());
+        interface.extend(0u32.to_le_bytes());
+        // timestamps in microseconds
+        interface.extend(OPTION_TSRESOL.to_le_bytes());
+        interface.extend(1u16.to_le_bytes());
+        interface.extend([6, 0, 0, 0]);
+        interface.extend(OPTION_END.to_le_bytes());
+        interface.extend(0u16.to_le_bytes());
+        write_block(&mut output, INTERFACE_DESCRIPTION, &interface)?;
+
+        Ok(PcapWriter { output })
+    }
+
+    /// Write a telegram or a refused telegram, the other events having no bytes to write.
+    /// Return whether the event has been written.
+    pub fn write_event(&mut self, at: SystemTime, event: &BusEvent, direction: Direction) -> io::Result<bool> {
+        let (packet, refused_at) = match event {
+            BusEvent::Telegram(packet) => (packet, None),
+            BusEvent::Nack(packet, component) => (packet, Some(*component)),
+            _ => return Ok(false),
+        };
+        let data = telegram_bytes(packet, refused_at);
+        let mut flags = match direction {
+            Direction::Inbound => FLAG_INBOUND,
+            Direction::Outbound => FLAG_OUTBOUND,
+        };
+        let has_response = matches!(AddressClass::of(packet.destination()), AddressClass::Slave | AddressClass::MasterSlave(_))
+            && refused_at != Some(TelegramComponent::SlaveACK);
+        if !packet.is_master_crc_valid() || (has_response && !packet.is_slave_crc_valid()) {
+            flags |= FLAG_CRC_ERROR;
+        }
+
+        let micros = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let mut block = Vec::new();
+        block.extend(0u32.to_le_bytes());
+        block.extend(((micros >> 32) as u32).to_le_bytes());
+        block.extend((micros as u32).to_le_bytes());
+        block.extend((data.len() as u32).to_le_bytes());
+        block.extend((data.len() as u32).to_le_bytes());
+        block.extend(&data);
+        block.extend(vec![0; padding(data.len())]);
+        block.extend(OPTION_FLAGS.to_le_bytes());
+        block.extend(4u16.to_le_bytes());
+        block.extend(flags.to_le_bytes());
+        block.extend(OPTION_END.to_le_bytes());
+        block.extend(0u16.to_le_bytes());
+        write_block(&mut self.output, ENHANCED_PACKET, &block)?;
+        self.output.flush()?;
+        Ok(true)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// A transport whose telegrams are written to a pcapng file, those of the master `own` as sent
+/// by us and the others as received
+pub struct PcapRecorder<T, W: Write> {
+    inner: T,
+    writer: PcapWriter<W>,
+    reader: BusReader,
+    own: u8,
+}
+
+impl<T: Transport, W: Write> PcapRecorder<T, W> {
+    pub fn new(inner: T, output: W, own: u8) -> io::Result<PcapRecorder<T, W>> {
+        Ok(PcapRecorder {
+            inner,
+            writer: PcapWriter::new(output)?,
+            reader: BusReader::new(),
+            own,
+        })
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.writer.into_inner())
+    }
+}
+
+impl<T: Transport, W: Write> Transport for PcapRecorder<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.inner.read(buf) {
+            Ok(n) => n,
+            Err(e) => {
+                self.reader = BusReader::new();
+                return Err(e);
+            },
+        };
+        let now = SystemTime::now();
+        for c in &buf[..n] {
+            let Some(event) = self.reader.read_byte(*c) else {
+                continue;
+            };
+            let direction = match &event {
+                BusEvent::Telegram(packet) | BusEvent::Nack(packet, _) if packet.source() == self.own => Direction::Outbound,
+                _ => Direction::Inbound,
+            };
+            self.writer.write_event(now, &event, direction)?;
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write(bytes)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.inner.is_finished()
+    }
+
+    fn arbitrates(&self) -> bool {
+        self.inner.arbitrates()
+    }
+
+    fn start_arbitration(&mut self, master: u8) -> io::Result<()> {
+        self.inner.start_arbitration(master)
+    }
+
+    fn take_arbitration(&mut self) -> Option<u8> {
+        self.inner.take_arbitration()
+    }
+}
+
+fn write_block<W: Write>(output: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let length = (12 + body.len()) as u32;
+    output.write_all(&block_type.to_le_bytes())?;
+    output.write_all(&length.to_le_bytes())?;
+    output.write_all(body)?;
+    output.write_all(&length.to_le_bytes())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the fields of a block in the byte order of its section
+struct Fields<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(invalid("truncated block"));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?.try_into().unwrap();
+        Ok(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+}
+
+struct Interface {
+    link_type: u16,
+    /// Number of timestamp units per second
+    units: u64,
+}
+
+fn parse_interface(fields: &mut Fields) -> io::Result<Interface> {
+    let link_type = fields.u16()?;
+    fields.take(6)?;
+    let mut units = 1_000_000;
+    while fields.bytes.len() >= 4 {
+        let (code, length) = (fields.u16()?, fields.u16()? as usize);
+        let value = fields.take(length + padding(length))?;
+        match (code, value.first()) {
+            (OPTION_END, _) => break,
+            (OPTION_TSRESOL, Some(resolution)) if resolution & 0x80 != 0 => units = 1 << (resolution & 0x7f).min(63),
+            (OPTION_TSRESOL, Some(resolution)) => units = 10u64.saturating_pow(*resolution as u32),
+            _ => (),
+        }
+    }
+    Ok(Interface { link_type, units })
+}
+
+/// Read the telegrams of a pcapng file as chunks of a capture, to replay them through the reader.
+/// Packets of other link types are skipped.
+pub fn import<R: Read>(mut input: R) -> io::Result<Vec<Chunk>> {
+    let mut content = Vec::new();
+    input.read_to_end(&mut content)?;
+
+    let mut big_endian = false;
+    let mut interfaces = Vec::new();
+    let mut telegrams: Vec<(Duration, Vec<u8>)> = Vec::new();
+    let mut rest = &content[..];
+
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(invalid("truncated block"));
+        }
+        let block_type = u32::from_le_bytes(rest[..4].try_into().unwrap());
+        if block_type == SECTION_HEADER {
+            let magic = &rest[8..12];
+            big_endian = match magic {
+                [0x1a, 0x2b, 0x3c, 0x4d] => true,
+                [0x4d, 0x3c, 0x2b, 0x1a] => false,
+                _ => return Err(invalid("not a pcapng file")),
+            };
+            interfaces.clear();
+        } else if rest.len() == content.len() {
+            return Err(invalid("not a pcapng file"));
+        }
+
+        let mut header = Fields { bytes: &rest[..8], big_endian };
+        let block_type = header.u32()?;
+        let length = header.u32()? as usize;
+        if length < 12 || length > rest.len() || !length.is_multiple_of(4) {
+            return Err(invalid("invalid block length"));
+        }
+        let mut fields = Fields { bytes: &rest[8..length - 4], big_endian };
+        rest = &rest[length..];
+
+        match block_type {
+            INTERFACE_DESCRIPTION => interfaces.push(parse_interface(&mut fields)?),
+            ENHANCED_PACKET => {
+                let interface = interfaces.get(fields.u32()? as usize).ok_or_else(|| invalid("unknown interface"))?;
+                let timestamp = (fields.u32()? as u64) << 32 | fields.u32()? as u64;
+                let captured = fields.u32()? as usize;
+                fields.u32()?;
+                let data = fields.take(captured)?;
+                if interface.link_type == LINKTYPE {
+                    // in u128, the units of a second going up to 2^63
+                    let nanos = (timestamp % interface.units) as u128 * 1_000_000_000 / interface.units as u128;
+                    let at = Duration::from_secs(timestamp / interface.units) + Duration::from_nanos(nanos as u64);
+                    telegrams.push((at, data.to_vec()));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    let start = telegrams.first().map(|(at, _)| *at).unwrap_or_default();
+    Ok(telegrams.into_iter()
+        .map(|(at, data)| Chunk {
+            at: at.saturating_sub(start),
+            bytes: [&[EBUS_SYN][..], &data].concat(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::capture::{Pacing, Replay};
+
+    #[test]
+    fn telegrams_are_exported_and_imported() {
+        let read = Packet::request(0x31, 0xf6, 0x50, 0x22, &[0xec, 0x11, 0x00]).unwrap()
+            .with_response(&[0xbd, 0x00]).unwrap();
+        let refused = Packet::request(0x10, 0x08, 0xb5, 0x09, &[0x0d]).unwrap();
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        assert!(writer.write_event(start, &BusEvent::Telegram(read.clone()), Direction::Outbound).unwrap());
+        assert!(writer.write_event(start + Duration::from_millis(250), &BusEvent::Nack(refused, TelegramComponent::SlaveACK), Direction::Inbound).unwrap());
+        assert!(!writer.write_event(start, &BusEvent::Timeout(TelegramComponent::SlaveACK), Direction::Inbound).unwrap());
+        let file = writer.into_inner();
+        assert_eq!(&file[..4], &SECTION_HEADER.to_le_bytes());
+        assert_eq!(file.len() % 4, 0);
+
+        let chunks = import(&file[..]).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].bytes, [&[EBUS_SYN][..], &[0x31, 0xf6, 0x50, 0x22, 0x03, 0xec, 0x11, 0x00, 0x87, 0x00, 0x02, 0xbd, 0x00, 0x32, 0x00]].concat());
+        assert_eq!(chunks[1].at, Duration::from_millis(250));
+
+        let mut reader = BusReader::new();
+        let events: Vec<BusEvent> = chunks.iter().flat_map(|c| c.bytes.clone()).filter_map(|c| reader.read_byte(c)).collect();
+        assert!(matches!(&events[0], BusEvent::Telegram(packet) if packet.slave_payload() == [0xbd, 0x00]));
+        assert!(matches!(&events[1], BusEvent::Nack(packet, TelegramComponent::SlaveACK) if packet.source() == 0x10));
+    }
+
+    #[test]
+    fn own_telegrams_are_recorded_as_outbound() {
+        let own = Packet::request(0x31, 0xfe, 0xb5, 0x16, &[0x00]).unwrap();
+        let other = Packet::request(0x10, 0xfe, 0xb5, 0x16, &[0x01]).unwrap();
+        let bytes: Vec<u8> = [EBUS_SYN].into_iter()
+            .chain(encode_master(&own))
+            .chain([EBUS_SYN])
+            .chain(encode_master(&other))
+            .chain([EBUS_SYN])
+            .collect();
+        let replay = Replay::new(vec![Chunk { at: Duration::ZERO, bytes }], Pacing::Fast);
+        let mut recorder = PcapRecorder::new(replay, Vec::new(), 0x31).unwrap();
+        let mut buf = [0; 64];
+        while recorder.read(&mut buf).is_ok() {}
+
+        let (_, file) = recorder.into_inner();
+        let flags: Vec<u32> = file.windows(8)
+            .filter(|w| w[..4] == [&OPTION_FLAGS.to_le_bytes()[..], &4u16.to_le_bytes()].concat())
+            .map(|w| u32::from_le_bytes(w[4..].try_into().unwrap()))
+            .collect();
+        assert_eq!(flags, [FLAG_OUTBOUND, FLAG_INBOUND]);
+        assert_eq!(import(&file[..]).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn fine_resolutions_are_imported() {
+        let packet = Packet::request(0x10, 0xfe, 0xb5, 0x16, &[0x00]).unwrap();
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_050_000);
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_event(start, &BusEvent::Telegram(packet.clone()), Direction::Inbound).unwrap();
+        writer.write_event(start + Duration::from_millis(250), &BusEvent::Telegram(packet), Direction::Inbound).unwrap();
+        let mut file = writer.into_inner();
+
+        // the microseconds written read as units of 10^-11 s
+        let option = [&OPTION_TSRESOL.to_le_bytes()[..], &[1, 0, 6]].concat();
+        let at = file.windows(option.len()).position(|w| w == option).unwrap() + option.len() - 1;
+        file[at] = 11;
+        let chunks = import(&file[..]).unwrap();
+        assert_eq!(chunks[1].at, Duration::from_nanos(2500));
+
+        file[at] = 0x80 | 63;
+        assert_eq!(import(&file[..]).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert_eq!(import(&b"# rebus capture\n0.0 aa\n"[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(import(&[][..]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+//! USB and TTL adapters, which expose the raw bus as a tty at 2400 baud 8N1.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serialport::{DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use super::Transport;
+
+pub const BAUD_RATE: u32 = 2400;
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+/// Delay between two attempts to reopen an unplugged adapter
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct SerialTransport {
+    device: String,
+    baud_rate: u32,
+    port: Option<Box<dyn SerialPort>>,
+    read_timeout: Duration,
+    reconnect_interval: Duration,
+    last_attempt: Option<Instant>,
+}
+
+impl SerialTransport {
+    /// Open a tty (e.g. `/dev/ttyUSB0`), failing when it cannot be opened.
+    /// Once opened, the tty is reopened whenever the adapter is unplugged.
+    pub fn open(device: &str) -> io::Result<SerialTransport> {
+        SerialTransport::open_at(device, BAUD_RATE)
+    }
+
+    /// Open a tty at another speed than the raw bus one, as enhanced adapters do
+    pub fn open_at(device: &str, baud_rate: u32) -> io::Result<SerialTransport> {
+        let mut transport = SerialTransport {
+            device: device.to_string(),
+            baud_rate,
+            port: None,
+            read_timeout: READ_TIMEOUT,
+            reconnect_interval: RECONNECT_INTERVAL,
+            last_attempt: None,
+        };
+        transport.connect()?;
+        Ok(transport)
+    }
+
+    pub fn with_read_timeout(mut self, timeout: Duration) -> SerialTransport {
+        self.read_timeout = timeout;
+        if let Some(port) = &mut self.port {
+            let _ = port.set_timeout(timeout);
+        }
+        self
+    }
+
+    pub fn with_reconnect_interval(mut self, interval: Duration) -> SerialTransport {
+        self.reconnect_interval = interval;
+        self
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    /// The opened tty, reopened when needed but no more often than the reconnect interval
+    fn connect(&mut self) -> io::Result<&mut Box<dyn SerialPort>> {
+        if self.port.is_none() {
+            if let Some(last_attempt) = self.last_attempt {
+                thread::sleep(self.reconnect_interval.saturating_sub(last_attempt.elapsed()));
+            }
+            self.last_attempt = Some(Instant::now());
+
+            let port = serialport::new(&self.device, self.baud_rate)
+                .data_bits(DataBits::Eight)
+                .parity(Parity::None)
+                .stop_bits(StopBits::One)
+                .flow_control(FlowControl::None)
+                .timeout(self.read_timeout)
+                .open()?;
+            self.port = Some(port);
+        }
+        Ok(self.port.as_mut().unwrap())
+    }
+
+    /// Drop the tty after an I/O error, it will be reopened on the next call
+    fn disconnect(&mut self, error: io::Error) -> io::Error {
+        self.port = None;
+        error
+    }
+}
+
+impl Transport for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.connect()?.read(buf) {
+            Ok(0) => Err(self.disconnect(io::ErrorKind::UnexpectedEof.into())),
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(self.disconnect(e)),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.connect()?.write_all(bytes) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.disconnect(e)),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.port.is_some()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::layer2::encoder::encode_master;
+    use crate::layer2::{Packet, EBUS_ACKOK, EBUS_SYN};
+    use crate::transport::simulator::SimulatedBus;
+    use crate::transport::Bus;
+    use serialport::TTYPort;
+    use std::io::{Read, Write};
+    use std::{env, fs, os::unix::fs::symlink};
+
+    /// Connect the other end of the pseudo-terminal to the simulated bus, until the SYN ending
+    /// the telegram, and return what was written to it.
+    /// The port is given back so that it is not closed while the last bytes are read.
+    fn simulate_bus(mut port: TTYPort, mut bus: SimulatedBus, master_length: usize) -> (TTYPort, Vec<u8>) {
+        let mut written = Vec::new();
+        let mut byte = [0];
+        let mut buf = [0; 64];
+        loop {
+            match port.read(&mut byte) {
+                Ok(_) => {
+                    bus.write(&byte).unwrap();
+                    written.push(byte[0]);
+                },
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => panic!("{}", e),
+            }
+            let n = bus.read(&mut buf).unwrap();
+            port.write_all(&buf[..n]).unwrap();
+            if written.len() > master_length && written.last() == Some(&EBUS_SYN) {
+                return (port, written);
+            }
+        }
+    }
+
+    #[test]
+    fn request_is_answered_through_a_pseudo_terminal() {
+        let (bus_end, device_end) = TTYPort::pair().unwrap();
+        let request = Packet::request(0x31, 0x08, 0xb5, 0x09, &[0x0d, 0x18, 0x00]).unwrap();
+        let master = encode_master(&request);
+        let simulated = SimulatedBus::answering(&request, &[0x40, 0x02]);
+
+        let master_length = master.len();
+        let simulation = thread::spawn(move || simulate_bus(bus_end, simulated, master_length));
+
+        let mut bus = Bus::new(SerialTransport::open(&device_end.name().unwrap()).unwrap());
+        let response = bus.send(&request).unwrap();
+        assert_eq!(response.slave_payload(), &[0x40, 0x02]);
+
+        let (_, echoed) = simulation.join().unwrap();
+        assert_eq!(&echoed[..master.len()], &master[..]);
+        assert_eq!(&echoed[master.len()..], &[EBUS_ACKOK, EBUS_SYN]);
+    }
+
+    #[test]
+    fn unplugged_device_is_reopened() {
+        let dir = env::temp_dir().join(format!("rebus-serial-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let link = dir.join("ttyEBUS");
+
+        let (bus_end, device_end) = TTYPort::pair().unwrap();
+        symlink(device_end.name().unwrap(), &link).unwrap();
+        let mut transport = SerialTransport::open(link.to_str().unwrap()).unwrap()
+            .with_reconnect_interval(Duration::from_millis(10));
+
+        drop((bus_end, device_end));
+        let mut buf = [0; 8];
+        assert!(transport.read(&mut buf).is_err());
+        assert!(!transport.is_connected());
+
+        // the adapter comes back under the same name
+        let (mut bus_end, device_end) = TTYPort::pair().unwrap();
+        fs::remove_file(&link).unwrap();
+        symlink(device_end.name().unwrap(), &link).unwrap();
+        assert_eq!(transport.read(&mut buf).unwrap(), 0);
+        assert!(transport.is_connected());
+
+        bus_end.write_all(&[EBUS_SYN]).unwrap();
+        assert_eq!(transport.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], EBUS_SYN);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+//! A bus for the tests of the transports and the buses: it echoes what is written, generates a
+//! SYN when idle and answers as a slave once the master part of a telegram has been written
+
+use std::collections::VecDeque;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use super::Transport;
+use crate::layer2::encoder::{encode_master, encode_slave};
+use crate::layer2::{Packet, EBUS_ACKOK, EBUS_SYN};
+
+/// Silence before each SYN generated
+const IDLE: Duration = Duration::from_millis(5);
+
+pub struct SimulatedBus {
+    pending: VecDeque<u8>,
+    written: usize,
+    master_length: usize,
+    answer: Vec<u8>,
+}
+
+impl SimulatedBus {
+    /// A bus whose slave acknowledges `request` and answers `response`
+    pub fn answering(request: &Packet, response: &[u8]) -> SimulatedBus {
+        let response = request.clone().with_response(response).unwrap();
+        SimulatedBus {
+            pending: VecDeque::new(),
+            written: 0,
+            master_length: encode_master(request).len(),
+            answer: [&[EBUS_ACKOK][..], &encode_slave(&response)].concat(),
+        }
+    }
+}
+
+impl Transport for SimulatedBus {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            thread::sleep(IDLE);
+            self.pending.push_back(EBUS_SYN);
+        }
+        let n = self.pending.len().min(buf.len());
+        for (i, c) in self.pending.drain(..n).enumerate() {
+            buf[i] = c;
+        }
+        Ok(n)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.pending.extend(bytes);
+        self.written += bytes.len();
+        if self.written == self.master_length {
+            self.pending.extend(&self.answer);
+        }
+        Ok(())
+    }
+}
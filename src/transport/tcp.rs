@@ -0,0 +1,180 @@
+//! Network adapters (ser2net, ESP based) exposing the raw bus on a TCP port.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Transport;
+
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before the second attempt to restore a lost connection, doubled after each failure
+pub const MIN_BACKOFF: Duration = Duration::from_millis(500);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct TcpTransport {
+    address: String,
+    stream: Option<TcpStream>,
+    read_timeout: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl TcpTransport {
+    /// Connect to an adapter (e.g. `192.168.1.10:9999`), failing when it cannot be reached.
+    /// Once connected, a lost connection is restored with an exponential backoff.
+    pub fn connect(address: &str) -> io::Result<TcpTransport> {
+        let mut transport = TcpTransport {
+            address: address.to_string(),
+            stream: None,
+            read_timeout: READ_TIMEOUT,
+            min_backoff: MIN_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            backoff: MIN_BACKOFF,
+            next_attempt: None,
+        };
+        transport.stream()?;
+        Ok(transport)
+    }
+
+    pub fn with_read_timeout(mut self, timeout: Duration) -> TcpTransport {
+        self.read_timeout = timeout;
+        if let Some(stream) = &self.stream {
+            let _ = stream.set_read_timeout(Some(timeout));
+        }
+        self
+    }
+
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> TcpTransport {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self.backoff = min;
+        self
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn open(&self) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", self.address));
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(Some(self.read_timeout))?;
+                    return Ok(stream);
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// The connected stream, the connection being restored when needed after the backoff delay
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            if let Some(next_attempt) = self.next_attempt {
+                thread::sleep(next_attempt.saturating_duration_since(Instant::now()));
+            }
+
+            match self.open() {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.backoff = self.min_backoff;
+                    self.next_attempt = None;
+                },
+                Err(e) => {
+                    self.next_attempt = Some(Instant::now() + self.backoff);
+                    self.backoff = (self.backoff * 2).min(self.max_backoff);
+                    return Err(e);
+                },
+            }
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Drop the connection after an I/O error, a first attempt to restore it is made on the next call
+    fn disconnect(&mut self, error: io::Error) -> io::Error {
+        self.stream = None;
+        error
+    }
+}
+
+impl Transport for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream()?.read(buf) {
+            Ok(0) => Err(self.disconnect(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the adapter"))),
+            Ok(n) => Ok(n),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(0),
+            Err(e) => Err(self.disconnect(e)),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.stream()?.write_all(bytes) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.disconnect(e)),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer2::encoder::encode_master;
+    use crate::layer2::{BusEvent, Packet, EBUS_ACKOK, EBUS_SYN};
+    use crate::transport::Bus;
+    use std::net::TcpListener;
+
+    #[test]
+    fn lost_connection_is_reported_and_restored() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut bus = Bus::new(TcpTransport::connect(&address).unwrap());
+
+        let (adapter, _) = listener.accept().unwrap();
+        drop(adapter);
+        assert!(matches!(bus.next_event().unwrap(), Some(BusEvent::ConnectionLost)));
+
+        assert!(matches!(bus.next_event().unwrap(), Some(BusEvent::ConnectionRestored)));
+
+        // master-master telegram
+        let packet = Packet::request(0x10, 0x03, 0x08, 0x00, &[]).unwrap();
+        let (mut adapter, _) = listener.accept().unwrap();
+        adapter.write_all(&[&[EBUS_SYN][..], &encode_master(&packet), &[EBUS_ACKOK, EBUS_SYN]].concat()).unwrap();
+        match bus.next_event().unwrap() {
+            Some(BusEvent::Telegram(read)) => assert!(read.is_master_crc_valid() && read.destination() == 0x03),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reconnection_backs_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut transport = TcpTransport::connect(&address).unwrap()
+            .with_backoff(Duration::from_millis(50), Duration::from_millis(100));
+        drop(listener);
+
+        let mut buf = [0; 8];
+        assert!(transport.read(&mut buf).is_err());
+        assert!(!transport.is_connected());
+
+        // the first attempt is immediate, the next ones wait for 50 then 100 ms
+        let start = Instant::now();
+        assert!(transport.read(&mut buf).is_err());
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert!(transport.read(&mut buf).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(transport.read(&mut buf).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}